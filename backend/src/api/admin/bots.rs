@@ -0,0 +1,89 @@
+//! Bot User-Agent pattern handlers
+
+use axum::extract::Query;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::runtime;
+use crate::core::bot;
+use crate::middleware::real_ip::client_ip;
+use crate::state;
+
+#[derive(Debug, Deserialize)]
+pub struct PatternParams {
+    pub pattern: String,
+}
+
+/// GET /api/admin/bots - Built-in and custom patterns with hits since startup
+pub async fn bots_handler() -> impl IntoResponse {
+    let (builtin, custom): (Vec<_>, Vec<_>) = bot::rules().into_iter().partition(|r| r.builtin);
+    let hits = |rules: Vec<bot::RuleInfo>| {
+        rules
+            .into_iter()
+            .map(|r| json!({"pattern": r.pattern, "hits": r.hits}))
+            .collect::<Vec<_>>()
+    };
+    Json(json!({
+        "success": true,
+        "data": {
+            "builtin": hits(builtin),
+            "custom": hits(custom),
+            "empty_ua_is_bot": runtime().empty_ua_is_bot
+        }
+    }))
+}
+
+/// POST /api/admin/bots {"pattern": "uptimerobot"} - Add a custom pattern
+pub async fn add_bot_handler(
+    headers: HeaderMap,
+    Json(params): Json<PatternParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+
+    match bot::add_pattern(&params.pattern) {
+        Ok((pattern, true)) => {
+            state::add_log("add_bot", &pattern, &ip);
+            Json(json!({
+                "success": true,
+                "message": format!("已添加规则 {}", pattern)
+            }))
+        }
+        Ok((pattern, false)) => Json(json!({
+            "success": false,
+            "message": format!("规则 {} 已存在", pattern)
+        })),
+        Err(message) => Json(json!({
+            "success": false,
+            "message": message
+        })),
+    }
+}
+
+/// DELETE /api/admin/bots?pattern=uptimerobot - Remove a custom pattern
+pub async fn delete_bot_handler(
+    headers: HeaderMap,
+    Query(params): Query<PatternParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+
+    match bot::remove_pattern(&params.pattern) {
+        Ok(true) => {
+            let pattern = params.pattern.trim().to_lowercase();
+            state::add_log("delete_bot", &pattern, &ip);
+            Json(json!({
+                "success": true,
+                "message": format!("已删除规则 {}", pattern)
+            }))
+        }
+        Ok(false) => Json(json!({
+            "success": false,
+            "message": "规则不存在（内置规则不可删除）"
+        })),
+        Err(message) => Json(json!({
+            "success": false,
+            "message": message
+        })),
+    }
+}
@@ -0,0 +1,474 @@
+//! Store consistency check and repair
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::CONFIG;
+use crate::middleware::admin_auth::AdminAccess;
+use crate::middleware::real_ip::client_ip;
+use crate::state::{self, Store};
+
+/// Offending keys reported per check
+const SAMPLE: usize = 20;
+
+/// Deleted page keys listed in a cleanup response
+const FREED_SAMPLE: usize = 100;
+
+/// How many keys failed a check, and the first few of them
+#[derive(Debug, Serialize)]
+struct Finding<T> {
+    count: usize,
+    sample: Vec<T>,
+}
+
+impl<T> Default for Finding<T> {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sample: Vec::new(),
+        }
+    }
+}
+
+impl<T> Finding<T> {
+    fn add(&mut self, item: T) {
+        self.count += 1;
+        if self.sample.len() < SAMPLE {
+            self.sample.push(item);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UvMismatch {
+    key: String,
+    uv: u64,
+    visitors: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    /// Pages whose site has no `site_pv` entry
+    orphan_pages: Finding<String>,
+    /// Sites with no visitor set (only checked when UV_SCOPE tracks sites)
+    sites_without_visitors: Finding<String>,
+    /// Visitor sets with no `site_uv` entry
+    visitors_without_uv: Finding<String>,
+    /// Sites whose UV is below the size of their visitor set. UV above it is
+    /// normal: syncs and admin edits set UV without adding visitors.
+    uv_below_visitors: Finding<UvMismatch>,
+}
+
+impl Report {
+    fn ok(&self) -> bool {
+        self.orphan_pages.count == 0
+            && self.sites_without_visitors.count == 0
+            && self.visitors_without_uv.count == 0
+            && self.uv_below_visitors.count == 0
+    }
+}
+
+fn page_site(page_key: &str) -> &str {
+    page_key.split_once(':').map_or(page_key, |(site, _)| site)
+}
+
+/// Pages whose site has no `site_pv` entry, sorted
+fn orphan_pages(store: &Store) -> Vec<String> {
+    let mut pages: Vec<String> = store
+        .page_pv
+        .iter()
+        .filter(|e| !store.site_pv.contains_key(page_site(e.key())))
+        .map(|e| e.key().clone())
+        .collect();
+    pages.sort();
+    pages
+}
+
+fn check(store: &Store, tracks_site_uv: bool) -> Report {
+    let mut report = Report::default();
+
+    for page in orphan_pages(store) {
+        report.orphan_pages.add(page);
+    }
+
+    if tracks_site_uv {
+        for entry in store.site_pv.iter() {
+            if !store.site_visitors.contains_key(entry.key()) {
+                report.sites_without_visitors.add(entry.key().clone());
+            }
+        }
+    }
+
+    for entry in store.site_visitors.iter() {
+        let key = entry.key();
+        let visitors = entry.value().len();
+        match store.site_uv.get(key) {
+            None => report.visitors_without_uv.add(key.clone()),
+            Some(uv) => {
+                let uv = uv.load(Ordering::Relaxed);
+                if uv < visitors as u64 {
+                    report.uv_below_visitors.add(UvMismatch {
+                        key: key.clone(),
+                        uv,
+                        visitors,
+                    });
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// 500 for a scan or repair whose blocking task panicked, rather than an empty
+/// result that would read as "nothing found"
+fn task_failed(e: tokio::task::JoinError) -> Response {
+    tracing::error!("Store check task failed: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "success": false,
+            "message": format!("检查任务失败: {}", e)
+        })),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckParams {
+    /// Delete orphaned pages before scanning
+    #[serde(default)]
+    pub fix: bool,
+}
+
+/// GET /api/admin/check - Scan the store for broken invariants. `?fix=true` first
+/// deletes orphaned pages, which needs full access.
+pub async fn check_handler(
+    State(store): State<Arc<Store>>,
+    Extension(access): Extension<AdminAccess>,
+    headers: HeaderMap,
+    Query(params): Query<CheckParams>,
+) -> Response {
+    if params.fix && access != AdminAccess::Full {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "success": false,
+                "message": "read-only token"
+            })),
+        )
+            .into_response();
+    }
+
+    let fixed = if params.fix {
+        let store = store.clone();
+        match tokio::task::spawn_blocking(move || cleanup_orphans(&store)).await {
+            Ok(removed) => Some(record_cleanup(&removed, &client_ip(&headers))),
+            Err(e) => return task_failed(e),
+        }
+    } else {
+        None
+    };
+    let report =
+        match tokio::task::spawn_blocking(move || check(&store, CONFIG.uv_scope.tracks_site()))
+            .await
+        {
+            Ok(report) => report,
+            Err(e) => return task_failed(e),
+        };
+
+    let mut data = json!({
+        "ok": report.ok(),
+        "checks": report
+    });
+    if let Some(fixed) = fixed {
+        data["fixed"] = fixed;
+    }
+    Json(json!({
+        "success": true,
+        "data": data
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepairMode {
+    /// Give orphaned pages their site back, with the pages' PV summed
+    Create,
+    /// Delete orphaned pages
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RepairParams {
+    pub mode: RepairMode,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Repaired {
+    key: String,
+    pv: u64,
+    /// Pages the site was created for (create mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<usize>,
+}
+
+/// Fix orphaned pages. Returns the sites created or the pages deleted; running it
+/// again finds nothing left to do.
+fn repair(store: &Store, mode: RepairMode, tracks_site_uv: bool) -> Vec<Repaired> {
+    let orphans = orphan_pages(store);
+    match mode {
+        RepairMode::Create => {
+            let mut sites: BTreeMap<String, (u64, usize)> = BTreeMap::new();
+            for page in &orphans {
+                let pv = store.get_page(page);
+                let site = sites.entry(page_site(page).to_string()).or_default();
+                site.0 += pv;
+                site.1 += 1;
+            }
+            for (site, (pv, _)) in &sites {
+                store
+                    .site_pv
+                    .entry(site.clone())
+                    .or_insert_with(|| AtomicU64::new(*pv));
+                store
+                    .site_uv
+                    .entry(site.clone())
+                    .or_insert_with(|| AtomicU64::new(0));
+                if tracks_site_uv {
+                    store.site_visitors.entry(site.clone()).or_default();
+                }
+            }
+            sites
+                .into_iter()
+                .map(|(key, (pv, pages))| Repaired {
+                    key,
+                    pv,
+                    pages: Some(pages),
+                })
+                .collect()
+        }
+        RepairMode::Delete => remove_pages(store, orphans),
+    }
+}
+
+/// Delete every orphaned page
+fn cleanup_orphans(store: &Store) -> Vec<Repaired> {
+    remove_pages(store, orphan_pages(store))
+}
+
+/// Log a cleanup as `cleanup_orphans` and summarize it for the response
+fn record_cleanup(removed: &[Repaired], ip: &str) -> Value {
+    let keys: Vec<&str> = removed.iter().map(|r| r.key.as_str()).collect();
+    let detail = if keys.is_empty() {
+        "无孤立页面".to_string()
+    } else if keys.len() > SAMPLE {
+        format!("{} pages: {} ...", keys.len(), keys[..SAMPLE].join(", "))
+    } else {
+        format!("{} pages: {}", keys.len(), keys.join(", "))
+    };
+    state::add_log("cleanup_orphans", &detail, ip);
+
+    json!({
+        "deleted": keys.len(),
+        "freed_page_keys": &keys[..keys.len().min(FREED_SAMPLE)],
+    })
+}
+
+/// After sites are deleted: remove pages a hit recreated while their site was being
+/// removed. Logged only when there were any.
+pub(super) async fn cleanup_after_delete(store: Arc<Store>, ip: &str) {
+    match tokio::task::spawn_blocking(move || cleanup_orphans(&store)).await {
+        Ok(removed) if !removed.is_empty() => {
+            record_cleanup(&removed, ip);
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("Orphan cleanup after site deletion failed: {}", e),
+    }
+}
+
+fn remove_pages(store: &Store, keys: Vec<String>) -> Vec<Repaired> {
+    keys.into_iter()
+        .filter_map(|key| {
+            let pv = store.remove_page(&key)?;
+            Some(Repaired {
+                key,
+                pv,
+                pages: None,
+            })
+        })
+        .collect()
+}
+
+/// POST /api/admin/repair - Create the missing sites of orphaned pages, or delete the pages
+pub async fn repair_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Json(params): Json<RepairParams>,
+) -> Response {
+    let ip = client_ip(&headers);
+    let mode = params.mode;
+    let repaired = match tokio::task::spawn_blocking(move || {
+        repair(&store, mode, CONFIG.uv_scope.tracks_site())
+    })
+    .await
+    {
+        Ok(repaired) => repaired,
+        Err(e) => return task_failed(e),
+    };
+
+    let (action, message) = match mode {
+        RepairMode::Create => (
+            "repair_create_sites",
+            format!("已为孤立页面创建 {} 个站点", repaired.len()),
+        ),
+        RepairMode::Delete => (
+            "repair_delete_pages",
+            format!("已删除 {} 个孤立页面", repaired.len()),
+        ),
+    };
+    let detail = if repaired.is_empty() {
+        "无孤立页面".to_string()
+    } else {
+        repaired
+            .iter()
+            .map(|r| match r.pages {
+                Some(pages) => format!("{} (pv {}, {} pages)", r.key, r.pv, pages),
+                None => format!("{} (pv {})", r.key, r.pv),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    state::add_log(action, &detail, &ip);
+
+    Json(json!({
+        "success": true,
+        "message": message,
+        "data": repaired
+    }))
+    .into_response()
+}
+
+/// DELETE /api/admin/pages/orphans - Delete pages whose site no longer exists
+pub async fn cleanup_orphans_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+) -> Response {
+    let removed = match tokio::task::spawn_blocking(move || cleanup_orphans(&store)).await {
+        Ok(removed) => removed,
+        Err(e) => return task_failed(e),
+    };
+    let data = record_cleanup(&removed, &client_ip(&headers));
+
+    Json(json!({
+        "success": true,
+        "message": format!("已删除 {} 个孤立页面", removed.len()),
+        "data": data
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    #[test]
+    fn finds_broken_invariants() {
+        let store = Store::new();
+        store.incr_site("good.test", "v1");
+        store.incr_page("good.test:/", "v1");
+        assert!(check(&store, true).ok());
+
+        store.add_page_pv("gone.test:/a", 1);
+        store.site_pv.insert("bare.test".into(), AtomicU64::new(3));
+        store.site_visitors.entry("nouv.test".into()).or_default();
+        store.site_uv.insert("good.test".into(), AtomicU64::new(0));
+
+        let report = check(&store, true);
+        assert!(!report.ok());
+        assert_eq!(report.orphan_pages.sample, vec!["gone.test:/a"]);
+        assert_eq!(report.sites_without_visitors.sample, vec!["bare.test"]);
+        assert_eq!(report.visitors_without_uv.sample, vec!["nouv.test"]);
+        assert_eq!(report.uv_below_visitors.count, 1);
+        assert_eq!(report.uv_below_visitors.sample[0].visitors, 1);
+
+        // Page-only UV never creates site visitor sets
+        assert_eq!(check(&store, false).sites_without_visitors.count, 0);
+    }
+
+    #[test]
+    fn repair_creates_missing_sites_once() {
+        let store = Store::new();
+        store.add_page_pv("lost.test:/a", 3);
+        store.add_page_pv("lost.test:/b", 4);
+        store.incr_site("kept.test", "v1");
+        store.add_page_pv("kept.test:/", 1);
+
+        let created = repair(&store, RepairMode::Create, true);
+        assert_eq!(
+            created,
+            vec![Repaired {
+                key: "lost.test".into(),
+                pv: 7,
+                pages: Some(2),
+            }]
+        );
+        assert_eq!(store.get_site("lost.test"), (7, 0));
+        assert!(check(&store, true).ok());
+        assert!(repair(&store, RepairMode::Create, true).is_empty());
+    }
+
+    #[test]
+    fn repair_deletes_orphans_once() {
+        let store = Store::new();
+        store.add_page_pv("lost.test:/a", 3);
+        store.incr_site("kept.test", "v1");
+        store.add_page_pv("kept.test:/", 1);
+
+        let deleted = repair(&store, RepairMode::Delete, true);
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(
+            (deleted[0].key.as_str(), deleted[0].pv),
+            ("lost.test:/a", 3)
+        );
+        assert_eq!(store.get_page("kept.test:/"), 1);
+        assert!(repair(&store, RepairMode::Delete, true).is_empty());
+    }
+
+    #[test]
+    fn cleanup_removes_only_orphans() {
+        let store = Store::new();
+        store.add_page_pv("lost.test:/a", 3);
+        store.add_page_pv("lost.test:/b", 1);
+        store.incr_site("kept.test", "v1");
+        store.incr_page("kept.test:/", "v1");
+
+        let removed = cleanup_orphans(&store);
+        let keys: Vec<&str> = removed.iter().map(|r| r.key.as_str()).collect();
+        assert_eq!(keys, vec!["lost.test:/a", "lost.test:/b"]);
+        assert_eq!(store.get_page("kept.test:/"), 1);
+        assert!(check(&store, true).ok());
+        assert!(cleanup_orphans(&store).is_empty());
+    }
+
+    #[test]
+    fn samples_are_capped() {
+        let mut finding = Finding::default();
+        for i in 0..SAMPLE + 5 {
+            finding.add(i);
+        }
+        assert_eq!(finding.count, SAMPLE + 5);
+        assert_eq!(finding.sample.len(), SAMPLE);
+    }
+}
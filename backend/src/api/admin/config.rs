@@ -0,0 +1,303 @@
+//! Effective configuration and reload handlers
+
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json};
+use axum::Extension;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+
+use crate::config::{
+    self, runtime, Config, Encrypt, IdentityMode, LogFormat, ProxyHeader, RuntimeConfig, UvScope,
+    CONFIG,
+};
+use crate::core::site_quota::QUOTA;
+use crate::core::site_secret;
+use crate::middleware::admin_auth::AdminAccess;
+use crate::middleware::cors;
+use crate::middleware::rate_limit::{ADMIN_LIMITER, LIMITER};
+use crate::middleware::real_ip::client_ip;
+use crate::state;
+
+/// Key for [`fingerprint`], new on every start so a fingerprint can't be checked
+/// against guesses offline
+static FINGERPRINT_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// Identifies a secret without revealing any of it: 16 hex digits of its HMAC-SHA256
+/// under [`FINGERPRINT_KEY`], or null when unset. Equal secrets match until the next
+/// restart, which tells whether two tokens are the same or a reload changed one.
+fn fingerprint(secret: &str) -> Value {
+    if secret.is_empty() {
+        return Value::Null;
+    }
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&*FINGERPRINT_KEY)
+        .expect("HMAC accepts any key length");
+    mac.update(secret.as_bytes());
+    json!(hex::encode(&mac.finalize().into_bytes()[..8]))
+}
+
+/// Keys of [`startup_values`] holding fingerprints, left out for read-only tokens
+const FINGERPRINT_KEYS: [&str; 4] = [
+    "admin_token_fingerprints",
+    "admin_readonly_token_fingerprints",
+    "bsz_secret_fingerprint",
+    "bsz_secret_previous_fingerprint",
+];
+
+/// What the running process uses, minus anything secret: tokens, hashes and
+/// BSZ_SECRET are reported only as set / not set (or a count) and a fingerprint.
+fn redacted(c: &Config, rt: &RuntimeConfig) -> Value {
+    let mut value = startup_values(c);
+    if let Value::Object(map) = &mut value {
+        if let Ok(Value::Object(runtime)) = serde_json::to_value(rt) {
+            map.extend(runtime);
+        }
+        // Derived from the settings, and may change while running
+        let db_path = std::path::absolute(&c.db_path)
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| c.db_path.clone());
+        map.insert("db_path_resolved".into(), json!(db_path));
+        map.insert(
+            "listen_url".into(),
+            json!(format!(
+                "{}://{}",
+                if c.tls_enabled() { "https" } else { "http" },
+                c.web_addr
+            )),
+        );
+        map.insert("sites_with_write_key".into(), json!(site_secret::count()));
+    }
+    value
+}
+
+/// The settings only a restart applies, redacted
+fn startup_values(c: &Config) -> Value {
+    json!({
+        "web_addr": c.web_addr,
+        "db_path": c.db_path,
+        "admin_tokens": c.admin_tokens.len(),
+        "admin_token_fingerprints": c.admin_tokens.iter().map(|t| fingerprint(t)).collect::<Vec<_>>(),
+        "admin_readonly_tokens": c.admin_readonly_tokens.len(),
+        "admin_readonly_token_fingerprints": c.admin_readonly_tokens.iter().map(|t| fingerprint(t)).collect::<Vec<_>>(),
+        "admin_token_hash_set": !c.admin_token_hash.is_empty(),
+        "admin_api": c.admin_api,
+        "admin_ip_allowlist": c.admin_ip_allowlist.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+        "disable_2fa": c.disable_2fa,
+        "max_body_size": c.max_body_size,
+        "api_max_body_size": c.api_max_body_size,
+        "bsz_secret_set": !c.bsz_secret.is_empty(),
+        "bsz_secret_fingerprint": fingerprint(&c.bsz_secret),
+        "bsz_secret_previous_set": !c.bsz_secret_previous.is_empty(),
+        "bsz_secret_previous_fingerprint": fingerprint(&c.bsz_secret_previous),
+        "bsz_encrypt": match c.bsz_encrypt {
+            Encrypt::None => "none",
+            Encrypt::Md5 => "md5",
+            Encrypt::Md516 => "md516",
+        },
+        "bsz_path_style": c.bsz_path_style,
+        "bsz_strip_www": c.bsz_strip_www,
+        "bsz_site_from_path_segments": c.bsz_site_from_path_segments,
+        "bsz_count_root_page": c.bsz_count_root_page,
+        "bsz_root_paths": c.bsz_root_paths,
+        "uv_scope": match c.uv_scope {
+            UvScope::Site => "site",
+            UvScope::Page => "page",
+            UvScope::Both => "both",
+        },
+        "identity_mode": match c.identity_mode {
+            IdentityMode::IpUa => "ip_ua",
+            IdentityMode::Ip => "ip",
+            IdentityMode::Ua => "ua",
+            IdentityMode::IpUaSaltedDaily => "ip_ua_salted_daily",
+        },
+        "visitor_hash": crate::core::visitor_hash::VERSION,
+        "header_referer": c.header_referer,
+        "header_key": c.header_key,
+        "header_nonce": c.header_nonce,
+        "header_identity": c.header_identity,
+        "identity_cookie": c.identity_cookie,
+        "cors_expose_headers": c.cors_expose_headers,
+        "lockout_sweep_interval": c.lockout_sweep_interval,
+        "max_tracked_failures": c.max_tracked_failures,
+        "cors_allow_credentials": c.cors_allow_credentials,
+        "tls": !c.tls_cert.is_empty() && !c.tls_key.is_empty(),
+        "trust_proxy_headers": c.trust_proxy_headers,
+        "proxy_header_precedence": match c.proxy_header_precedence {
+            ProxyHeader::Forwarded => "forwarded",
+            ProxyHeader::XForwardedFor => "x-forwarded-for",
+        },
+        "trusted_proxies": c.trusted_proxies.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+        "log_format": match c.log_format {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Compact => "compact",
+            LogFormat::Json => "json",
+        },
+        "log_file": c.log_file,
+        "warnings": c.warnings,
+    })
+}
+
+/// GET /api/admin/config - Effective configuration, secrets redacted
+pub async fn config_handler(Extension(access): Extension<AdminAccess>) -> impl IntoResponse {
+    let mut data = redacted(&CONFIG, &runtime());
+    if access != AdminAccess::Full {
+        if let Value::Object(map) = &mut data {
+            for key in FINGERPRINT_KEYS {
+                map.remove(key);
+            }
+        }
+    }
+    Json(json!({
+        "success": true,
+        "data": data
+    }))
+}
+
+/// Push runtime settings into the components that keep their own copy
+pub fn apply(rt: &RuntimeConfig) {
+    LIMITER.set_per_minute(rt.rate_limit_per_minute);
+    ADMIN_LIMITER.set_per_minute(rt.admin_rate_limit_per_minute);
+    QUOTA.set_max(rt.max_new_sites_per_ip_per_hour);
+    // Both were validated when the config was loaded
+    cors::PUBLIC.set(cors::parse_rules(&rt.cors).0);
+    cors::ADMIN.set(cors::parse_rules(&rt.admin_cors).0);
+}
+
+/// Top-level keys whose values differ between two objects
+fn changed_keys(old: &Value, new: &Value) -> Vec<(String, Value, Value)> {
+    let empty = Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    new.iter()
+        .filter(|(key, value)| old.get(*key) != Some(value))
+        .map(|(key, value)| {
+            let before = old.get(key).cloned().unwrap_or(Value::Null);
+            (key.clone(), before, value.clone())
+        })
+        .collect()
+}
+
+/// Re-read the environment and `.env`, and switch to the new runtime settings.
+/// Returns which of them changed and which other settings differ but need a
+/// restart, or the errors that kept the old settings in place. Used by
+/// POST /config/reload and SIGHUP.
+pub fn reload(ip: &str) -> Result<Value, Vec<String>> {
+    let fresh = config::reread();
+    if !fresh.errors.is_empty() {
+        return Err(fresh.errors);
+    }
+    let previous = config::set_runtime(fresh.runtime.clone());
+    apply(&fresh.runtime);
+
+    let changed: Vec<Value> = changed_keys(
+        &serde_json::to_value(&*previous).unwrap_or_default(),
+        &serde_json::to_value(&fresh.runtime).unwrap_or_default(),
+    )
+    .into_iter()
+    .map(|(key, old, new)| json!({ "key": key, "old": old, "new": new }))
+    .collect();
+    let restart_required: Vec<String> =
+        changed_keys(&startup_values(&CONFIG), &startup_values(&fresh))
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .filter(|key| key != "warnings")
+            .collect();
+
+    let names: Vec<&str> = changed.iter().filter_map(|c| c["key"].as_str()).collect();
+    state::add_log(
+        "config_reload",
+        &if names.is_empty() {
+            "无变化".to_string()
+        } else {
+            names.join(", ")
+        },
+        ip,
+    );
+    Ok(json!({
+        "changed": changed,
+        "restart_required": restart_required,
+        "warnings": fresh.warnings,
+    }))
+}
+
+/// POST /api/admin/config/reload - Apply changed runtime settings without a restart
+pub async fn config_reload_handler(headers: HeaderMap) -> impl IntoResponse {
+    match reload(&client_ip(&headers)) {
+        Ok(data) => Json(json!({
+            "success": true,
+            "message": "已重新加载配置",
+            "data": data
+        })),
+        Err(errors) => Json(json!({
+            "success": false,
+            "message": format!("配置有误，未重新加载: {}", errors.join("; "))
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn never_reveals_secrets() {
+        let vars: HashMap<&str, &str> = [
+            ("ADMIN_TOKEN", "full-token-value"),
+            ("ADMIN_READONLY_TOKENS", "readonly-token-value"),
+            ("BSZ_SECRET", "current-secret-value"),
+            ("BSZ_SECRET_PREVIOUS", "previous-secret-value"),
+            ("UV_SCOPE", "both"),
+        ]
+        .into_iter()
+        .collect();
+        let c = Config::from_lookup(|key| vars.get(key).map(|v| v.to_string()));
+
+        let value = redacted(&c, &c.runtime);
+        let text = value.to_string();
+        for secret in vars.values().filter(|v| v.ends_with("-value")) {
+            assert!(!text.contains(secret), "{} leaked", secret);
+        }
+        assert_eq!(value["admin_tokens"], 1);
+        assert_eq!(value["admin_readonly_tokens"], 1);
+        assert_eq!(value["bsz_secret_set"], true);
+        assert_eq!(value["bsz_secret_previous_set"], true);
+        assert_eq!(value["uv_scope"], "both");
+        assert_eq!(value["rate_limit_per_minute"], 60);
+        for key in FINGERPRINT_KEYS {
+            assert!(!value[key].is_null(), "{} missing", key);
+        }
+        assert_eq!(
+            value["admin_token_fingerprints"][0].as_str().unwrap().len(),
+            16
+        );
+        assert!(value["db_path_resolved"]
+            .as_str()
+            .unwrap()
+            .ends_with("data.db"));
+    }
+
+    #[test]
+    fn fingerprints_identify_without_revealing() {
+        assert_eq!(fingerprint(""), Value::Null);
+        assert_eq!(fingerprint("abc"), fingerprint("abc"));
+        assert_ne!(fingerprint("abd"), fingerprint("abc"));
+        // Keyed, so not the plain digest anyone could compute from a guess:
+        // sha256("abc") = ba7816bf8f01cfea...
+        assert_ne!(fingerprint("abc"), json!("ba7816bf8f01cfea"));
+        // Nothing about the length either
+        assert_eq!(
+            fingerprint("a").as_str().unwrap().len(),
+            fingerprint(&"a".repeat(100)).as_str().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn reports_changed_keys() {
+        let old = json!({ "a": 1, "b": "x", "c": [1] });
+        let new = json!({ "a": 1, "b": "y", "c": [1, 2] });
+        let keys: Vec<String> = changed_keys(&old, &new).into_iter().map(|c| c.0).collect();
+        assert_eq!(keys, vec!["b", "c"]);
+    }
+}
@@ -0,0 +1,31 @@
+//! Health handler
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::config::runtime;
+use crate::state::{self, Store};
+
+/// GET /api/admin/health
+/// `degraded` is true while the database can't be opened and counts live only in memory.
+/// Save times are unix seconds; `last_save_at` is null until the first save of this run.
+pub async fn health_handler(State(store): State<Arc<Store>>) -> impl IntoResponse {
+    let persistence = state::persistence_enabled();
+
+    Json(json!({
+        "success": true,
+        "data": {
+            "status": if persistence { "ok" } else { "degraded" },
+            "degraded": !persistence,
+            "persistence": persistence,
+            "db_file": state::db_file(),
+            "db_error": state::db_error(),
+            "last_save_at": state::last_save(),
+            "next_save_at": state::next_save(),
+            "save_interval": runtime().save_interval,
+            "unsaved_changes": store.has_unsaved_changes()
+        }
+    }))
+}
@@ -1,53 +1,276 @@
 //! Import/Export handlers for data.db
 
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use axum::body::Body;
-use axum::extract::Multipart;
-use axum::http::{header, HeaderMap};
+use axum::extract::{Multipart, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Json, Response};
+use serde::Deserialize;
 use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
 
-use crate::state;
+use crate::config::{format_size, runtime, CONFIG};
+use crate::middleware::real_ip::client_ip;
+use crate::middleware::request_id;
+use crate::state::{self, Store};
 
-fn client_ip(headers: &HeaderMap) -> String {
-    headers
-        .get("X-Forwarded-For")
-        .or_else(|| headers.get("X-Real-IP"))
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .unwrap_or("unknown")
-        .trim()
+/// 413 with the configured limit, for uploads rejected by the admin body limit
+pub(super) fn payload_too_large() -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({
+            "success": false,
+            "message": format!("文件超过大小限制 ({})", format_size(CONFIG.max_body_size)),
+            "limit": CONFIG.max_body_size
+        })),
+    )
+        .into_response()
+}
+
+/// Form field names an uploaded file is accepted under
+const FILE_FIELDS: [&str; 5] = ["file", "data", "db", "sitemap", "upload"];
+
+/// Whether a multipart field carries the upload: one of FILE_FIELDS, or any
+/// field sent as a file (with a filename)
+fn is_file_field(name: Option<&str>, file_name: Option<&str>) -> bool {
+    name.is_some_and(|n| FILE_FIELDS.contains(&n)) || file_name.is_some_and(|f| !f.is_empty())
+}
+
+/// Read the uploaded file of a multipart form. When there is none (or it is empty),
+/// the error carries `missing` and the names of the fields that were sent instead.
+pub(super) async fn read_upload(
+    multipart: &mut Multipart,
+    missing: &str,
+) -> Result<Vec<u8>, Response> {
+    let mut others = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(payload_too_large())
+            }
+            Err(_) => break,
+        };
+        if !is_file_field(field.name(), field.file_name()) {
+            others.push(field.name().unwrap_or("").to_string());
+            continue;
+        }
+        match field.bytes().await {
+            Ok(bytes) if !bytes.is_empty() => return Ok(bytes.to_vec()),
+            Ok(_) => {}
+            Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                return Err(payload_too_large())
+            }
+            Err(e) => {
+                return Err(Json(json!({
+                    "success": false,
+                    "message": format!("读取文件失败: {}", e)
+                }))
+                .into_response());
+            }
+        }
+    }
+
+    let message = if others.is_empty() {
+        missing.to_string()
+    } else {
+        format!(
+            "{}（文件字段名应为 file，收到的字段: {}）",
+            missing,
+            others.join(", ")
+        )
+    };
+    Err(Json(json!({ "success": false, "message": message })).into_response())
+}
+
+/// HTTP-date as used by Last-Modified / If-Modified-Since
+fn http_date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
         .to_string()
 }
 
-const DB_FILE: &str = "data.db";
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    chrono::DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(SystemTime::from)
+}
 
-/// GET /api/admin/export - Download data.db file
-pub async fn export_handler(headers: HeaderMap) -> impl IntoResponse {
+/// Whether a file last modified at `mtime` is unchanged since `since` (1s resolution, like the header)
+fn not_modified(mtime: SystemTime, since: SystemTime) -> bool {
+    let secs = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    secs(mtime) <= secs(since)
+}
+
+/// Gzip magic bytes, which `export?compress=gzip` files start with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How far a gzipped upload may inflate, as a multiple of MAX_BODY_SIZE
+const MAX_INFLATE_RATIO: u64 = 10;
+
+/// Decompress a gzipped upload, refusing anything larger than `limit` once inflated
+async fn gunzip(data: &[u8], limit: u64) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    GzipDecoder::new(data)
+        .take(limit + 1)
+        .read_to_end(&mut out)
+        .await
+        .map_err(|e| format!("解压失败: {}", e))?;
+    if out.len() as u64 > limit {
+        return Err(format!(
+            "解压后超过大小限制 ({})",
+            format_size(limit as usize)
+        ));
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// `gzip` for a gzipped download
+    pub compress: Option<String>,
+}
+
+enum Export {
+    NotModified(SystemTime),
+    File(Vec<u8>, SystemTime),
+}
+
+/// GET /api/admin/export - Download data.db file, gzipped with `?compress=gzip`.
+/// Sends Last-Modified; with a matching If-Modified-Since and nothing unsaved, answers 304.
+pub async fn export_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    let gzip = match params.compress.as_deref() {
+        None | Some("") | Some("none") => false,
+        Some("gzip") => true,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "success": false,
+                    "message": format!("不支持的压缩方式: {}（可选 gzip）", other)
+                })),
+            )
+                .into_response();
+        }
+    };
     let ip = client_ip(&headers);
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_http_date);
 
-    // Save current data first, then read file — all synchronous to avoid races
-    let result = tokio::task::spawn_blocking(|| -> Result<Vec<u8>, String> {
-        state::save_blocking().map_err(|e| format!("保存失败: {}", e))?;
-        std::fs::read(DB_FILE).map_err(|e| format!("读取失败: {}", e))
-    })
-    .await;
+    // Save current data first (only if something changed, so the mtime stays meaningful),
+    // then read file — all synchronous to avoid races
+    let request_id = request_id::current();
+    // Set once EXPORT_SAVE_TIMEOUT_SECS passes: the save still finishes, the export doesn't
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let task_abandoned = abandoned.clone();
+    let task = tokio::task::spawn_blocking(move || -> Result<Export, String> {
+        request_id::sync_scope(request_id, || {
+            if store.has_unsaved_changes() || !std::path::Path::new(state::db_file()).exists() {
+                state::save_blocking(&store).map_err(|e| format!("保存失败: {}", e))?;
+            }
+            if task_abandoned.load(Ordering::Relaxed) {
+                return Err("导出已超时".to_string());
+            }
+            let mtime = std::fs::metadata(state::db_file())
+                .and_then(|m| m.modified())
+                .map_err(|e| format!("读取失败: {}", e))?;
+            if since.is_some_and(|since| not_modified(mtime, since)) {
+                return Ok(Export::NotModified(mtime));
+            }
+            // The log entry lands in data.db too; write it first so the Last-Modified sent
+            // below already covers it and the next poll gets a 304
+            state::add_log(
+                "export",
+                if gzip {
+                    "导出数据库 (gzip)"
+                } else {
+                    "导出数据库"
+                },
+                &ip,
+            );
+            let mtime = std::fs::metadata(state::db_file())
+                .and_then(|m| m.modified())
+                .unwrap_or(mtime);
+            let data = std::fs::read(state::db_file()).map_err(|e| format!("读取失败: {}", e))?;
+            Ok(Export::File(data, mtime))
+        })
+    });
+
+    let result = match runtime().export_save_timeout_secs {
+        0 => task.await,
+        secs => match tokio::time::timeout(Duration::from_secs(secs), task).await {
+            Ok(result) => result,
+            Err(_) => {
+                abandoned.store(true, Ordering::Relaxed);
+                tracing::warn!("Export abandoned: save took longer than {}s", secs);
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::RETRY_AFTER, secs)
+                    .body(Body::from(
+                        json!({
+                            "success": false,
+                            "message": format!("保存超过 {} 秒仍未完成，已放弃导出，请稍后重试", secs)
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap();
+            }
+        },
+    };
 
     match result {
-        Ok(Ok(data)) => {
-            state::add_log("export", "导出数据库", &ip);
-            Response::builder()
-                .status(200)
-                .header(header::CONTENT_TYPE, "application/x-sqlite3")
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    format!(
-                        "attachment; filename=\"busuanzi-{}.db\"",
-                        chrono::Local::now().format("%Y%m%d-%H%M%S")
-                    ),
-                )
-                .body(Body::from(data))
-                .unwrap()
-        }
+        Ok(Ok(Export::NotModified(mtime))) => Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::LAST_MODIFIED, http_date(mtime))
+            .body(Body::empty())
+            .unwrap(),
+        // Sent as a .gz file rather than with Content-Encoding, which clients would
+        // undo on the way in, leaving a plain database under a .gz name
+        Ok(Ok(Export::File(data, mtime))) if gzip => Response::builder()
+            .status(200)
+            .header(header::CONTENT_TYPE, "application/gzip")
+            .header(header::LAST_MODIFIED, http_date(mtime))
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"busuanzi-{}.db.gz\"",
+                    chrono::Local::now().format("%Y%m%d-%H%M%S")
+                ),
+            )
+            .body(Body::from_stream(ReaderStream::new(GzipEncoder::new(
+                std::io::Cursor::new(data),
+            ))))
+            .unwrap(),
+        Ok(Ok(Export::File(data, mtime))) => Response::builder()
+            .status(200)
+            .header(header::CONTENT_TYPE, "application/x-sqlite3")
+            .header(header::CONTENT_LENGTH, data.len())
+            .header(header::LAST_MODIFIED, http_date(mtime))
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"busuanzi-{}.db\"",
+                    chrono::Local::now().format("%Y%m%d-%H%M%S")
+                ),
+            )
+            .body(Body::from(data))
+            .unwrap(),
         Ok(Err(msg)) => Response::builder()
             .status(500)
             .header(header::CONTENT_TYPE, "application/json")
@@ -66,61 +289,55 @@ pub async fn export_handler(headers: HeaderMap) -> impl IntoResponse {
 }
 
 /// POST /api/admin/import - Upload and replace data.db file
-pub async fn import_handler(headers: HeaderMap, mut multipart: Multipart) -> impl IntoResponse {
+pub async fn import_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
     let ip = client_ip(&headers);
 
-    // Get uploaded file
-    let mut db_data: Option<Vec<u8>> = None;
+    let mut data = match read_upload(&mut multipart, "请上传 data.db 文件").await {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
 
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        if field.name() == Some("file") {
-            match field.bytes().await {
-                Ok(bytes) => {
-                    db_data = Some(bytes.to_vec());
-                    break;
-                }
-                Err(e) => {
-                    return Json(json!({
-                        "success": false,
-                        "message": format!("读取文件失败: {}", e)
-                    }));
-                }
+    // Exports made with ?compress=gzip
+    if data.starts_with(&GZIP_MAGIC) {
+        let limit = (CONFIG.max_body_size as u64).saturating_mul(MAX_INFLATE_RATIO);
+        data = match gunzip(&data, limit).await {
+            Ok(inflated) => inflated,
+            Err(msg) => {
+                return Json(json!({ "success": false, "message": msg })).into_response();
             }
-        }
+        };
     }
 
-    let data = match db_data {
-        Some(d) if !d.is_empty() => d,
-        _ => {
-            return Json(json!({
-                "success": false,
-                "message": "请上传 data.db 文件"
-            }));
-        }
-    };
-
     // Validate it's a valid SQLite database
     if data.len() < 16 || &data[0..16] != b"SQLite format 3\0" {
         return Json(json!({
             "success": false,
             "message": "无效的 SQLite 数据库文件"
-        }));
+        }))
+        .into_response();
     }
 
     // Write to temp file
-    let temp_file = "data.db.import";
-    if let Err(e) = tokio::fs::write(temp_file, &data).await {
+    let temp_file = format!("{}.import", state::db_file());
+    if let Err(e) = tokio::fs::write(&temp_file, &data).await {
         return Json(json!({
             "success": false,
             "message": format!("写入临时文件失败: {}", e)
-        }));
+        }))
+        .into_response();
     }
 
-    // Atomically import: load into STORE + persist to main DB (holds DB lock)
-    let result = tokio::task::spawn_blocking(move || state::import_from_file(temp_file)).await;
+    // Atomically import: load into the store + persist to main DB (holds DB lock)
+    let import_path = temp_file.clone();
+    let result =
+        tokio::task::spawn_blocking(move || state::import_from_file(&store, &import_path)).await;
 
     // Clean up temp file
-    let _ = tokio::fs::remove_file(temp_file).await;
+    let _ = tokio::fs::remove_file(&temp_file).await;
 
     match result {
         Ok(Ok((sites, pages, visitors))) => {
@@ -139,14 +356,150 @@ pub async fn import_handler(headers: HeaderMap, mut multipart: Multipart) -> imp
                     "visitors": visitors
                 }
             }))
+            .into_response()
         }
         Ok(Err(e)) => Json(json!({
             "success": false,
             "message": format!("导入失败: {}", e)
-        })),
+        }))
+        .into_response(),
         Err(e) => Json(json!({
             "success": false,
             "message": format!("内部错误: {}", e)
-        })),
+        }))
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::DefaultBodyLimit;
+    use axum::http::Request;
+    use axum::routing::post;
+    use axum::Router;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn multipart_upload(uri: &str, size: usize) -> Request<Body> {
+        multipart_form(uri, "file", Some("data.db"), &vec![b'a'; size])
+    }
+
+    fn multipart_form(
+        uri: &str,
+        name: &str,
+        file_name: Option<&str>,
+        content: &[u8],
+    ) -> Request<Body> {
+        let boundary = "bsz-test-boundary";
+        let file_name = file_name
+            .map(|f| format!("; filename=\"{}\"", f))
+            .unwrap_or_default();
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"{}\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            boundary, name, file_name
+        )
+        .into_bytes();
+        body.extend_from_slice(content);
+        body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+        Request::post(uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn assert_payload_too_large(app: Router, uri: &str) {
+        let res = app.oneshot(multipart_upload(uri, 4096)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["limit"], CONFIG.max_body_size);
+    }
+
+    #[tokio::test]
+    async fn oversized_import_returns_413_json() {
+        let app = Router::new()
+            .route("/import", post(import_handler))
+            .layer(DefaultBodyLimit::max(1024))
+            .with_state(Arc::new(Store::new()));
+        assert_payload_too_large(app, "/import").await;
+    }
+
+    #[tokio::test]
+    async fn oversized_sitemap_upload_returns_413_json() {
+        let app = Router::new()
+            .route("/sync/upload", post(super::super::sync_upload_handler))
+            .layer(DefaultBodyLimit::max(1024));
+        assert_payload_too_large(app, "/sync/upload").await;
+    }
+
+    #[tokio::test]
+    async fn accepts_common_file_field_names() {
+        async fn upload(name: &str, file_name: Option<&str>) -> serde_json::Value {
+            let app = Router::new().route("/sync/upload", post(super::super::sync_upload_handler));
+            let xml = b"<urlset><url><loc>https://a.test/</loc></url></urlset>";
+            let res = app
+                .oneshot(multipart_form("/sync/upload", name, file_name, xml))
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        assert_eq!(upload("sitemap", None).await["success"], true);
+        assert_eq!(
+            upload("anything", Some("sitemap.xml")).await["success"],
+            true
+        );
+        let refused = upload("xml", None).await;
+        assert_eq!(refused["success"], false);
+        assert!(refused["message"]
+            .as_str()
+            .unwrap()
+            .contains("收到的字段: xml"));
+    }
+
+    #[tokio::test]
+    async fn gunzips_within_limit() {
+        let original = b"SQLite format 3\0 and then some pages".repeat(100);
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&original[..])
+            .read_to_end(&mut gzipped)
+            .await
+            .unwrap();
+        assert!(gzipped.starts_with(&GZIP_MAGIC));
+        assert!(gzipped.len() < original.len());
+
+        let limit = original.len() as u64;
+        assert_eq!(gunzip(&gzipped, limit).await.unwrap(), original);
+        assert!(gunzip(&gzipped, limit - 1).await.is_err());
+        assert!(gunzip(&GZIP_MAGIC, limit).await.is_err());
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(t));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn compares_at_second_resolution() {
+        let since = UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(not_modified(since + Duration::from_millis(900), since));
+        assert!(not_modified(since - Duration::from_secs(5), since));
+        assert!(!not_modified(since + Duration::from_secs(1), since));
     }
 }
@@ -1,28 +1,22 @@
 //! Site keys management handlers
 
-use axum::extract::Query;
+use axum::extract::{Query, State};
 use axum::http::HeaderMap;
 use axum::response::{IntoResponse, Json};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use crate::state::{self, STORE};
-
-fn client_ip(headers: &HeaderMap) -> String {
-    headers
-        .get("X-Forwarded-For")
-        .or_else(|| headers.get("X-Real-IP"))
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .unwrap_or("unknown")
-        .trim()
-        .to_string()
-}
+use crate::config::runtime;
+use crate::core::{anomaly, count, site_secret};
+use crate::middleware::real_ip::client_ip;
+use crate::state::{self, Store};
 
 #[derive(Debug, Deserialize)]
 pub struct ListKeysParams {
-    pub cursor: Option<usize>,
+    /// Last `site_key` of the previous page
+    pub cursor: Option<String>,
     pub count: Option<usize>,
 }
 
@@ -31,52 +25,67 @@ pub struct KeyInfo {
     pub site_key: String,
     pub site_pv: u64,
     pub site_uv: u64,
-    pub page_count: usize,
+    pub page_count: u64,
+    /// Whether counting requires the site's write key
+    pub has_secret: bool,
+    /// Hits on new paths after the site reached MAX_PAGES_PER_SITE pages
+    pub overflow_pv: u64,
 }
 
-/// GET /api/admin/keys
-pub async fn list_keys_handler(Query(params): Query<ListKeysParams>) -> impl IntoResponse {
-    let cursor = params.cursor.unwrap_or(0);
-    let count = params.count.unwrap_or(20);
-
-    let mut keys: Vec<KeyInfo> = Vec::new();
-
-    for (i, entry) in STORE.site_pv.iter().enumerate() {
-        if i < cursor {
-            continue;
-        }
-        if keys.len() >= count {
-            break;
-        }
-
-        let site_key = entry.key().clone();
-        let site_pv = entry.value().load(Ordering::Relaxed);
-        let site_uv = STORE
-            .site_uv
-            .get(&site_key)
-            .map(|v| v.load(Ordering::Relaxed))
-            .unwrap_or(0);
+/// GET /api/admin/keys?cursor=<last site_key>&count=20
+/// Sites are ordered by key, so pages stay stable while counting adds or removes entries.
+pub async fn list_keys_handler(
+    State(store): State<Arc<Store>>,
+    Query(params): Query<ListKeysParams>,
+) -> impl IntoResponse {
+    let rt = runtime();
+    let count = rt.page_size(params.count, rt.page_size_keys);
+    let cursor = params.cursor.unwrap_or_default();
 
-        let prefix = format!("{}:", site_key);
-        let page_count = STORE
-            .page_pv
-            .iter()
-            .filter(|p| p.key().starts_with(&prefix))
-            .count();
-
-        keys.push(KeyInfo {
-            site_key,
-            site_pv,
-            site_uv,
-            page_count,
-        });
-    }
+    let mut site_keys: Vec<String> = store
+        .site_pv
+        .iter()
+        .map(|e| e.key().clone())
+        .filter(|k| cursor.is_empty() || *k > cursor)
+        .collect();
+    site_keys.sort_unstable();
+    let has_more = site_keys.len() > count;
+    site_keys.truncate(count);
+
+    let keys: Vec<KeyInfo> = site_keys
+        .into_iter()
+        .map(|site_key| {
+            let site_pv = store
+                .site_pv
+                .get(&site_key)
+                .map(|v| v.load(Ordering::Relaxed))
+                .unwrap_or(0);
+            let site_uv = store
+                .site_uv
+                .get(&site_key)
+                .map(|v| v.load(Ordering::Relaxed))
+                .unwrap_or(0);
+
+            let page_count = store.site_page_count(&site_key);
+
+            let has_secret = site_secret::has_secret(&site_key);
+            let overflow_pv = store.get_page(&count::overflow_key(&site_key));
+            KeyInfo {
+                site_key,
+                site_pv,
+                site_uv,
+                page_count,
+                has_secret,
+                overflow_pv,
+            }
+        })
+        .collect();
 
-    let total = STORE.site_pv.len();
-    let next_cursor = if keys.len() == count {
-        cursor + count
+    let total = store.site_pv.len();
+    let next_cursor = if has_more {
+        keys.last().map(|k| k.site_key.clone())
     } else {
-        0
+        None
     };
 
     Json(json!({
@@ -87,6 +96,17 @@ pub async fn list_keys_handler(Query(params): Query<ListKeysParams>) -> impl Int
     }))
 }
 
+/// GET /api/admin/keys/count - Number of sites and pages, without listing them
+pub async fn count_keys_handler(State(store): State<Arc<Store>>) -> impl IntoResponse {
+    Json(json!({
+        "success": true,
+        "data": {
+            "sites": store.site_pv.len(),
+            "pages": store.page_pv.len()
+        }
+    }))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeleteKeyParams {
     pub site_key: String,
@@ -95,13 +115,14 @@ pub struct DeleteKeyParams {
 
 /// DELETE /api/admin/keys
 pub async fn delete_key_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Query(params): Query<DeleteKeyParams>,
 ) -> impl IntoResponse {
     let ip = client_ip(&headers);
 
     if let Some(page_key) = &params.page_key {
-        STORE.page_pv.remove(page_key);
+        store.remove_page(page_key);
         state::add_log("delete_page", page_key, &ip);
 
         return Json(json!({
@@ -112,14 +133,17 @@ pub async fn delete_key_handler(
 
     let key = &params.site_key;
 
-    STORE.site_pv.remove(key);
-    STORE.site_uv.remove(key);
-    STORE.site_visitors.remove(key);
+    store.site_pv.remove(key);
+    store.site_uv.remove(key);
+    store.site_visitors.remove(key);
+    site_secret::forget(key);
+    anomaly::forget(key);
 
     let prefix = format!("{}:", key);
-    STORE.page_pv.retain(|k, _| !k.starts_with(&prefix));
+    store.remove_pages_with_prefix(&prefix);
 
     state::add_log("delete_site", key, &ip);
+    super::check::cleanup_after_delete(store, &ip).await;
 
     Json(json!({
         "success": true,
@@ -136,6 +160,7 @@ pub struct UpdateKeyParams {
 
 /// POST /api/admin/keys/update
 pub async fn update_key_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<UpdateKeyParams>,
 ) -> impl IntoResponse {
@@ -145,7 +170,7 @@ pub async fn update_key_handler(
     match params.key_type.as_str() {
         "site_pv" => {
             if let Some(val) = params.value {
-                STORE
+                store
                     .site_pv
                     .entry(key.to_string())
                     .or_insert_with(|| AtomicU64::new(0))
@@ -154,16 +179,16 @@ pub async fn update_key_handler(
         }
         "site_uv" => {
             if let Some(val) = params.value {
-                STORE
+                store
                     .site_uv
                     .entry(key.to_string())
                     .or_insert_with(|| AtomicU64::new(0))
                     .store(val, Ordering::Relaxed);
             } else {
-                if let Some(uv) = STORE.site_uv.get(key) {
+                if let Some(uv) = store.site_uv.get(key) {
                     uv.store(0, Ordering::Relaxed);
                 }
-                if let Some(visitors) = STORE.site_visitors.get(key) {
+                if let Some(visitors) = store.site_visitors.get(key) {
                     visitors.clear();
                 }
             }
@@ -196,6 +221,7 @@ pub struct RenameKeyParams {
 
 /// POST /api/admin/keys/rename - Rename a site (change domain)
 pub async fn rename_key_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<RenameKeyParams>,
 ) -> impl IntoResponse {
@@ -210,43 +236,51 @@ pub async fn rename_key_handler(
         }));
     }
 
-    if !STORE.site_pv.contains_key(old_key) {
+    if !store.site_pv.contains_key(old_key) {
         return Json(json!({
             "success": false,
             "message": "源站点不存在"
         }));
     }
 
-    if STORE.site_pv.contains_key(new_key) {
+    if store.site_pv.contains_key(new_key) {
         return Json(json!({
             "success": false,
             "message": "目标站点已存在，请使用合并功能"
         }));
     }
 
-    if let Some((_, pv)) = STORE.site_pv.remove(old_key) {
-        STORE.site_pv.insert(new_key.clone(), pv);
+    if let Some((_, pv)) = store.site_pv.remove(old_key) {
+        store.site_pv.insert(new_key.clone(), pv);
     }
-    if let Some((_, uv)) = STORE.site_uv.remove(old_key) {
-        STORE.site_uv.insert(new_key.clone(), uv);
+    if let Some((_, uv)) = store.site_uv.remove(old_key) {
+        store.site_uv.insert(new_key.clone(), uv);
     }
-    if let Some((_, visitors)) = STORE.site_visitors.remove(old_key) {
-        STORE.site_visitors.insert(new_key.clone(), visitors);
+    if let Some((_, visitors)) = store.site_visitors.remove(old_key) {
+        store.site_visitors.insert(new_key.clone(), visitors);
     }
+    site_secret::rename(old_key, new_key);
+    anomaly::forget(old_key);
 
     let old_prefix = format!("{}:", old_key);
-    let pages_to_move: Vec<_> = STORE
+    let pages_to_move: Vec<_> = store
         .page_pv
         .iter()
         .filter(|e| e.key().starts_with(&old_prefix))
-        .map(|e| (e.key().clone(), e.value().load(Ordering::Relaxed)))
+        .map(|e| e.key().clone())
         .collect();
 
-    for (old_page_key, pv) in pages_to_move {
-        STORE.page_pv.remove(&old_page_key);
+    for old_page_key in pages_to_move {
+        let pv = store.take_page_pv(&old_page_key).unwrap_or(0);
         let path = old_page_key.strip_prefix(&old_prefix).unwrap_or("");
         let new_page_key = format!("{}:{}", new_key, path);
-        STORE.page_pv.insert(new_page_key, AtomicU64::new(pv));
+        if let Some((_, uv)) = store.page_uv.remove(&old_page_key) {
+            store.page_uv.insert(new_page_key.clone(), uv);
+        }
+        if let Some((_, visitors)) = store.page_visitors.remove(&old_page_key) {
+            store.page_visitors.insert(new_page_key.clone(), visitors);
+        }
+        store.set_page_pv(&new_page_key, pv);
     }
 
     state::add_log("rename_site", &format!("{} -> {}", old_key, new_key), &ip);
@@ -265,6 +299,7 @@ pub struct MergeKeyParams {
 
 /// POST /api/admin/keys/merge - Merge source site into target site
 pub async fn merge_key_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<MergeKeyParams>,
 ) -> impl IntoResponse {
@@ -279,30 +314,32 @@ pub async fn merge_key_handler(
         }));
     }
 
-    if !STORE.site_pv.contains_key(source) {
+    if !store.site_pv.contains_key(source) {
         return Json(json!({
             "success": false,
             "message": "源站点不存在"
         }));
     }
 
-    let source_pv = STORE
+    // Take the source counters out of the map before reading them: once removed, no
+    // concurrent request can increment them, so nothing is lost between read and delete
+    let source_pv = store
         .site_pv
-        .get(source)
-        .map(|v| v.load(Ordering::Relaxed))
+        .remove(source)
+        .map(|(_, v)| v.load(Ordering::Relaxed))
         .unwrap_or(0);
-    STORE
+    store
         .site_pv
         .entry(target.to_string())
         .or_insert_with(|| AtomicU64::new(0))
         .fetch_add(source_pv, Ordering::Relaxed);
 
-    let source_uv = STORE
+    let source_uv = store
         .site_uv
-        .get(source)
-        .map(|v| v.load(Ordering::Relaxed))
+        .remove(source)
+        .map(|(_, v)| v.load(Ordering::Relaxed))
         .unwrap_or(0);
-    let target_uv = STORE
+    let target_uv = store
         .site_uv
         .entry(target.to_string())
         .or_insert_with(|| AtomicU64::new(0));
@@ -310,42 +347,66 @@ pub async fn merge_key_handler(
     if source_uv > current_uv {
         target_uv.store(source_uv, Ordering::Relaxed);
     }
+    drop(target_uv);
 
-    if let Some(source_visitors) = STORE.site_visitors.get(source) {
-        let target_visitors = STORE.site_visitors.entry(target.to_string()).or_default();
+    if let Some((_, source_visitors)) = store.site_visitors.remove(source) {
+        let target_visitors = store.site_visitors.entry(target.to_string()).or_default();
         for vh in source_visitors.iter() {
             target_visitors.insert(*vh);
         }
     }
 
+    // The target's own write key (if any) keeps guarding the merged site
+    site_secret::forget(source);
+    anomaly::forget(source);
+
     let source_prefix = format!("{}:", source);
     let target_prefix = format!("{}:", target);
-    let pages_to_merge: Vec<_> = STORE
+    let pages_to_merge: Vec<String> = store
         .page_pv
         .iter()
         .filter(|e| e.key().starts_with(&source_prefix))
-        .map(|e| (e.key().clone(), e.value().load(Ordering::Relaxed)))
+        .map(|e| e.key().clone())
         .collect();
 
     let mut pages_merged = 0;
-    for (source_page_key, source_page_pv) in pages_to_merge {
+    for source_page_key in pages_to_merge {
+        let Some(source_page_pv) = store.take_page_pv(&source_page_key) else {
+            continue;
+        };
         let path = source_page_key.strip_prefix(&source_prefix).unwrap_or("");
         let target_page_key = format!("{}{}", target_prefix, path);
 
-        STORE
-            .page_pv
-            .entry(target_page_key)
-            .or_insert_with(|| AtomicU64::new(0))
-            .fetch_add(source_page_pv, Ordering::Relaxed);
+        store.add_page_pv(&target_page_key, source_page_pv);
+
+        // Page UV: union the visitor sets, keep the larger count (same as site UV)
+        if let Some((_, source_visitors)) = store.page_visitors.remove(&source_page_key) {
+            let target_visitors = store
+                .page_visitors
+                .entry(target_page_key.clone())
+                .or_default();
+            for vh in source_visitors.iter() {
+                target_visitors.insert(*vh);
+            }
+        }
+        let source_page_uv = store
+            .page_uv
+            .remove(&source_page_key)
+            .map(|(_, v)| v.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        if source_page_uv > 0 {
+            let target_uv = store
+                .page_uv
+                .entry(target_page_key)
+                .or_insert_with(|| AtomicU64::new(0));
+            if source_page_uv > target_uv.load(Ordering::Relaxed) {
+                target_uv.store(source_page_uv, Ordering::Relaxed);
+            }
+        }
 
         pages_merged += 1;
     }
 
-    STORE.site_pv.remove(source);
-    STORE.site_uv.remove(source);
-    STORE.site_visitors.remove(source);
-    STORE.page_pv.retain(|k, _| !k.starts_with(&source_prefix));
-
     state::add_log(
         "merge_site",
         &format!("{} -> {} ({} pages)", source, target, pages_merged),
@@ -365,6 +426,7 @@ pub struct BatchDeleteKeysParams {
 
 /// POST /api/admin/keys/batch-delete
 pub async fn batch_delete_keys_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<BatchDeleteKeysParams>,
 ) -> impl IntoResponse {
@@ -372,13 +434,15 @@ pub async fn batch_delete_keys_handler(
     let mut deleted = 0usize;
 
     for key in &params.site_keys {
-        if STORE.site_pv.remove(key).is_some() {
+        if store.site_pv.remove(key).is_some() {
             deleted += 1;
         }
-        STORE.site_uv.remove(key);
-        STORE.site_visitors.remove(key);
+        store.site_uv.remove(key);
+        store.site_visitors.remove(key);
+        site_secret::forget(key);
+        anomaly::forget(key);
         let prefix = format!("{}:", key);
-        STORE.page_pv.retain(|k, _| !k.starts_with(&prefix));
+        store.remove_pages_with_prefix(&prefix);
     }
 
     state::add_log(
@@ -386,6 +450,7 @@ pub async fn batch_delete_keys_handler(
         &format!("{} sites deleted", deleted),
         &ip,
     );
+    super::check::cleanup_after_delete(store, &ip).await;
 
     Json(json!({
         "success": true,
@@ -393,3 +458,65 @@ pub async fn batch_delete_keys_handler(
         "deleted": deleted
     }))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct SiteSecretParams {
+    pub site_key: String,
+    /// Remove the key instead of issuing a new one
+    #[serde(default)]
+    pub revoke: bool,
+}
+
+/// POST /api/admin/keys/site-secret - Issue, rotate or revoke a site's write key
+pub async fn site_secret_handler(
+    headers: HeaderMap,
+    Json(params): Json<SiteSecretParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+    let key = &params.site_key;
+
+    if params.revoke {
+        return match site_secret::revoke(key) {
+            Ok(true) => {
+                state::add_log("revoke_site_secret", key, &ip);
+                Json(json!({
+                    "success": true,
+                    "message": "已移除写入密钥"
+                }))
+            }
+            Ok(false) => Json(json!({
+                "success": false,
+                "message": "该站点没有写入密钥"
+            })),
+            Err(message) => Json(json!({
+                "success": false,
+                "message": message
+            })),
+        };
+    }
+
+    let rotated = site_secret::has_secret(key);
+    match site_secret::issue(key) {
+        Ok(secret) => {
+            state::add_log(
+                if rotated {
+                    "rotate_site_secret"
+                } else {
+                    "issue_site_secret"
+                },
+                key,
+                &ip,
+            );
+            Json(json!({
+                "success": true,
+                "message": if rotated { "写入密钥已轮换" } else { "写入密钥已生成" },
+                "site_key": key,
+                "secret": secret
+            }))
+        }
+        Err(message) => Json(json!({
+            "success": false,
+            "message": message
+        })),
+    }
+}
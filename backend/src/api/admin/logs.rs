@@ -1,34 +1,53 @@
 //! Operation logs handler
 
+use axum::body::{Body, Bytes};
 use axum::extract::Query;
-use axum::response::{IntoResponse, Json};
+use axum::http::header;
+use axum::response::{IntoResponse, Json, Response};
 use serde::Deserialize;
 use serde_json::json;
 
+use crate::config::runtime;
 use crate::state;
 
+/// Rows fetched per DB round-trip when streaming the CSV export
+const CSV_BATCH: usize = 500;
+
 #[derive(Debug, Deserialize)]
 pub struct LogsParams {
     pub page: Option<usize>,
     pub size: Option<usize>,
+    /// Comma-separated actions to keep, e.g. `auth_failed,auth_locked`
+    pub action: Option<String>,
 }
 
-/// GET /api/admin/logs?page=1&size=20
+/// GET /api/admin/logs?page=1&size=20&action=auth_failed,auth_locked
 pub async fn logs_handler(Query(params): Query<LogsParams>) -> impl IntoResponse {
     let page = params.page.unwrap_or(1);
-    let size = params.size.unwrap_or(20);
+    let rt = runtime();
+    let size = rt.page_size(params.size, rt.page_size_logs);
+    let actions: Vec<String> = params
+        .action
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(str::to_string)
+        .collect();
 
-    match state::query_logs(page, size) {
+    match state::query_logs(page, size, &actions) {
         Ok((rows, total)) => {
             let logs: Vec<_> = rows
                 .into_iter()
-                .map(|(id, timestamp, action, detail, ip)| {
+                .map(|(id, timestamp, action, detail, ip, request_id)| {
                     json!({
                         "id": id,
                         "timestamp": timestamp,
                         "action": action,
                         "detail": detail,
-                        "ip": ip
+                        "ip": ip,
+                        "request_id": request_id
                     })
                 })
                 .collect();
@@ -47,3 +66,71 @@ pub async fn logs_handler(Query(params): Query<LogsParams>) -> impl IntoResponse
         })),
     }
 }
+
+/// GET /api/admin/logs/export.csv - Stream all operation logs as CSV
+pub async fn logs_csv_handler() -> impl IntoResponse {
+    let stream = async_stream::stream! {
+        yield Ok::<_, std::io::Error>(Bytes::from_static(b"id,timestamp,action,detail,ip,request_id\n"));
+
+        let mut after_id = 0i64;
+        loop {
+            let batch =
+                tokio::task::spawn_blocking(move || state::query_logs_after(after_id, CSV_BATCH))
+                    .await;
+            let rows = match batch {
+                Ok(Ok(rows)) => rows,
+                Ok(Err(e)) => {
+                    yield Err(std::io::Error::other(e.to_string()));
+                    return;
+                }
+                Err(e) => {
+                    yield Err(std::io::Error::other(e));
+                    return;
+                }
+            };
+
+            let mut chunk = String::new();
+            for (id, timestamp, action, detail, ip, request_id) in &rows {
+                chunk.push_str(&format!(
+                    "{},{},{},{},{},{}\n",
+                    id,
+                    csv_field(timestamp),
+                    csv_field(action),
+                    csv_field(detail),
+                    csv_field(ip),
+                    csv_field(request_id)
+                ));
+            }
+            if !chunk.is_empty() {
+                yield Ok(Bytes::from(chunk));
+            }
+
+            match rows.last() {
+                Some(last) if rows.len() == CSV_BATCH => after_id = last.0,
+                _ => break,
+            }
+        }
+    };
+
+    Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"busuanzi-logs-{}.csv\"",
+                chrono::Local::now().format("%Y%m%d-%H%M%S")
+            ),
+        )
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+/// Quote a CSV field when it contains a delimiter, quote or line break (RFC 4180)
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
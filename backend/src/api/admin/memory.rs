@@ -0,0 +1,48 @@
+//! Memory usage handler
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::state::Store;
+
+/// Resident set size from /proc (Linux only)
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// GET /api/admin/memory
+/// Estimates only: sizes are derived from entry counts, not measured allocations.
+pub async fn memory_handler(State(store): State<Arc<Store>>) -> impl IntoResponse {
+    let [site_pv, site_uv, page_pv, page_uv] = store.counter_maps_memory();
+    let site_visitors = store.site_visitor_memory();
+    let page_visitors = store.page_visitor_memory();
+
+    let estimated_total = site_pv.bytes
+        + site_uv.bytes
+        + page_pv.bytes
+        + page_uv.bytes
+        + site_visitors.bytes
+        + page_visitors.bytes;
+
+    Json(json!({
+        "success": true,
+        "data": {
+            "maps": {
+                "site_pv": site_pv,
+                "site_uv": site_uv,
+                "page_pv": page_pv,
+                "page_uv": page_uv,
+                "site_visitors": site_visitors,
+                "page_visitors": page_visitors
+            },
+            "pending_visitors": store.pending_visitors(),
+            "estimated_total_bytes": estimated_total,
+            "rss_bytes": rss_bytes()
+        }
+    }))
+}
@@ -1,18 +1,44 @@
 //! Admin API handlers
 
+mod bots;
+mod check;
+mod config;
+mod health;
 mod import;
 mod keys;
 mod logs;
+mod memory;
 mod pages;
+mod save;
+mod security;
+mod site_transfer;
 mod stats;
 mod sync;
+mod two_factor;
 
+pub use bots::{add_bot_handler, bots_handler, delete_bot_handler};
+pub use check::{check_handler, cleanup_orphans_handler, repair_handler};
+pub use config::{
+    apply as apply_runtime_config, config_handler, config_reload_handler, reload as reload_config,
+};
+pub use health::health_handler;
 pub use import::{export_handler, import_handler};
 pub use keys::{
-    batch_delete_keys_handler, delete_key_handler, list_keys_handler, merge_key_handler,
-    rename_key_handler, update_key_handler,
+    batch_delete_keys_handler, count_keys_handler, delete_key_handler, list_keys_handler,
+    merge_key_handler, rename_key_handler, site_secret_handler, update_key_handler,
+};
+pub use logs::{logs_csv_handler, logs_handler};
+pub use memory::memory_handler;
+pub use pages::{
+    batch_delete_pages_handler, clear_pages_cache, list_pages_handler, page_stats_handler,
+    update_page_handler,
 };
-pub use logs::logs_handler;
-pub use pages::{batch_delete_pages_handler, list_pages_handler, update_page_handler};
+pub use save::save_handler;
+pub use security::{lockouts_handler, secret_rotation_handler, unlock_handler};
+pub use site_transfer::{export_site_handler, import_site_handler};
 pub use stats::stats_handler;
-pub use sync::{sync_handler, sync_upload_handler};
+pub use sync::{sync_handler, sync_ping_handler, sync_upload_handler};
+pub use two_factor::{
+    csrf_handler, login_handler, two_factor_disable_handler, two_factor_enable_handler,
+    two_factor_setup_handler, two_factor_status_handler,
+};
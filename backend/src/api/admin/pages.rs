@@ -1,23 +1,31 @@
 //! Page management handlers
 
-use axum::extract::Query;
+use axum::extract::{Path, Query, State};
 use axum::http::HeaderMap;
 use axum::response::{IntoResponse, Json};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::atomic::{AtomicU64, Ordering};
-
-use crate::state::{self, STORE};
-
-fn client_ip(headers: &HeaderMap) -> String {
-    headers
-        .get("X-Forwarded-For")
-        .or_else(|| headers.get("X-Real-IP"))
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .unwrap_or("unknown")
-        .trim()
-        .to_string()
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{runtime, CONFIG};
+use crate::core::count;
+use crate::middleware::real_ip::client_ip;
+use crate::state::{self, Store};
+
+/// Sorted page lists by site, reused for PAGES_CACHE_SECS so paging through a
+/// large site doesn't collect and sort every page on each request
+static SORTED_PAGES: Lazy<DashMap<String, (Instant, SortedPages)>> = Lazy::new(DashMap::new);
+
+type SortedPages = Arc<Vec<PageInfo>>;
+
+/// Forget every cached page list. Called after admin writes and sync imports,
+/// which may add, remove or re-count pages.
+pub fn clear_pages_cache() {
+    SORTED_PAGES.clear();
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,17 +40,17 @@ pub struct PageInfo {
     pub page_key: String,
     pub path: String,
     pub pv: u64,
+    /// Present when UV_SCOPE tracks pages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uv: Option<u64>,
 }
 
-/// GET /api/admin/pages?site_key=xxx&cursor=0&count=20
-pub async fn list_pages_handler(Query(params): Query<ListPagesParams>) -> impl IntoResponse {
-    let prefix = format!("{}:", params.site_key);
-    let cursor = params.cursor.unwrap_or(0);
-    let count = params.count.unwrap_or(50);
-
+/// A site's pages, by PV descending
+fn collect_pages(store: &Store, site_key: &str) -> Vec<PageInfo> {
+    let prefix = format!("{}:", site_key);
     let mut all_pages: Vec<PageInfo> = Vec::new();
 
-    for entry in STORE.page_pv.iter() {
+    for entry in store.page_pv.iter() {
         let key = entry.key();
         if key.starts_with(&prefix) {
             let pv = entry.value().load(Ordering::Relaxed);
@@ -52,15 +60,52 @@ pub async fn list_pages_handler(Query(params): Query<ListPagesParams>) -> impl I
                 page_key: key.clone(),
                 path,
                 pv,
+                uv: CONFIG
+                    .uv_scope
+                    .tracks_page()
+                    .then(|| store.get_page_uv(key)),
             });
         }
     }
 
     // Sort by PV descending
     all_pages.sort_by_key(|page| std::cmp::Reverse(page.pv));
+    all_pages
+}
+
+/// The cached page list of a site while younger than `ttl`, else a fresh one
+fn sorted_pages(store: &Store, site_key: &str, ttl: Duration) -> SortedPages {
+    if ttl.is_zero() {
+        return Arc::new(collect_pages(store, site_key));
+    }
+    if let Some(cached) = SORTED_PAGES.get(site_key) {
+        if cached.0.elapsed() < ttl {
+            return cached.1.clone();
+        }
+    }
+    let pages = Arc::new(collect_pages(store, site_key));
+    SORTED_PAGES.retain(|_, (built, _)| built.elapsed() < ttl);
+    SORTED_PAGES.insert(site_key.to_string(), (Instant::now(), pages.clone()));
+    pages
+}
+
+/// GET /api/admin/pages?site_key=xxx&cursor=0&count=20
+/// PVs may be up to PAGES_CACHE_SECS old.
+pub async fn list_pages_handler(
+    State(store): State<Arc<Store>>,
+    Query(params): Query<ListPagesParams>,
+) -> impl IntoResponse {
+    let cursor = params.cursor.unwrap_or(0);
+    let rt = runtime();
+    let count = rt.page_size(params.count, rt.page_size_pages);
 
+    let all_pages = sorted_pages(
+        &store,
+        &params.site_key,
+        Duration::from_secs(rt.pages_cache_secs),
+    );
     let total = all_pages.len();
-    let pages: Vec<PageInfo> = all_pages.into_iter().skip(cursor).take(count).collect();
+    let pages: Vec<&PageInfo> = all_pages.iter().skip(cursor).take(count).collect();
     let next_cursor = if pages.len() == count {
         cursor + count
     } else {
@@ -75,6 +120,59 @@ pub async fn list_pages_handler(Query(params): Query<ListPagesParams>) -> impl I
     }))
 }
 
+/// GET /api/admin/keys/{site_key}/pages/stats - Aggregate PV statistics of a site's pages
+pub async fn page_stats_handler(
+    State(store): State<Arc<Store>>,
+    Path(site_key): Path<String>,
+) -> impl IntoResponse {
+    let prefix = format!("{}:", site_key);
+
+    let mut pvs: Vec<u64> = store
+        .page_pv
+        .iter()
+        .filter(|e| e.key().starts_with(&prefix))
+        .map(|e| e.value().load(Ordering::Relaxed))
+        .collect();
+
+    let total_pages = pvs.len();
+    let total_pv: u64 = pvs.iter().sum();
+    let max_pv = pvs.iter().copied().max().unwrap_or(0);
+    let min_pv = pvs.iter().copied().min().unwrap_or(0);
+    let zero_pv_pages = pvs.iter().filter(|&&pv| pv == 0).count();
+    let avg_pv = if total_pages > 0 {
+        total_pv as f64 / total_pages as f64
+    } else {
+        0.0
+    };
+    // Upper median via selection, no full sort needed
+    let median_pv = if total_pages > 0 {
+        let mid = total_pages / 2;
+        *pvs.select_nth_unstable(mid).1
+    } else {
+        0
+    };
+
+    let overflow_pv = store.get_page(&count::overflow_key(&site_key));
+
+    Json(json!({
+        "success": true,
+        "site_key": site_key,
+        "overflow": {
+            "pv": overflow_pv,
+            "max_pages_per_site": runtime().max_pages_per_site,
+        },
+        "stats": {
+            "total_pages": total_pages,
+            "total_pv": total_pv,
+            "avg_pv": avg_pv,
+            "median_pv": median_pv,
+            "max_pv": max_pv,
+            "min_pv": min_pv,
+            "zero_pv_pages": zero_pv_pages
+        }
+    }))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UpdatePageParams {
     pub page_key: String,
@@ -83,6 +181,7 @@ pub struct UpdatePageParams {
 
 /// POST /api/admin/pages/update
 pub async fn update_page_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<UpdatePageParams>,
 ) -> impl IntoResponse {
@@ -90,11 +189,7 @@ pub async fn update_page_handler(
     let key = &params.page_key;
 
     if let Some(pv) = params.pv {
-        STORE
-            .page_pv
-            .entry(key.to_string())
-            .or_insert_with(|| AtomicU64::new(0))
-            .store(pv, Ordering::Relaxed);
+        store.set_page_pv(key, pv);
     }
 
     state::add_log("edit_page", &format!("{} pv = {:?}", key, params.pv), &ip);
@@ -112,6 +207,7 @@ pub struct BatchDeletePagesParams {
 
 /// POST /api/admin/pages/batch-delete
 pub async fn batch_delete_pages_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Json(params): Json<BatchDeletePagesParams>,
 ) -> impl IntoResponse {
@@ -119,7 +215,7 @@ pub async fn batch_delete_pages_handler(
     let mut deleted = 0usize;
 
     for key in &params.page_keys {
-        if STORE.page_pv.remove(key).is_some() {
+        if store.remove_page(key).is_some() {
             deleted += 1;
         }
     }
@@ -136,3 +232,29 @@ pub async fn batch_delete_pages_handler(
         "deleted": deleted
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_sorted_pages_until_cleared() {
+        let store = Store::new();
+        store.add_page_pv("pagecache.test:/a", 1);
+        store.add_page_pv("pagecache.test:/b", 5);
+        let ttl = Duration::from_secs(60);
+        let pages = sorted_pages(&store, "pagecache.test", ttl);
+        let paths: Vec<&str> = pages.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, vec!["/b", "/a"]);
+
+        store.add_page_pv("pagecache.test:/c", 3);
+        assert_eq!(sorted_pages(&store, "pagecache.test", ttl).len(), 2);
+        assert_eq!(
+            sorted_pages(&store, "pagecache.test", Duration::ZERO).len(),
+            3
+        );
+
+        clear_pages_cache();
+        assert_eq!(sorted_pages(&store, "pagecache.test", ttl).len(), 3);
+    }
+}
@@ -0,0 +1,44 @@
+//! Force-save handler
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::middleware::real_ip::client_ip;
+use crate::state::{self, Store};
+
+/// POST /api/admin/save - Persist the store now instead of waiting for the next SAVE_INTERVAL
+pub async fn save_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+    let started = Instant::now();
+
+    match state::save(store).await {
+        Ok(stats) => {
+            let duration_ms = started.elapsed().as_millis() as u64;
+            state::add_log(
+                "manual_save",
+                &format!(
+                    "{} sites, {} pages in {}ms",
+                    stats.sites, stats.pages, duration_ms
+                ),
+                &ip,
+            );
+            Json(json!({
+                "success": true,
+                "message": "已保存",
+                "duration_ms": duration_ms,
+                "rows": stats
+            }))
+        }
+        Err(e) => Json(json!({
+            "success": false,
+            "message": format!("保存失败: {}", e)
+        })),
+    }
+}
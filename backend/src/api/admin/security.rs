@@ -0,0 +1,69 @@
+//! Admin login lockout handlers
+
+use axum::extract::Query;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::CONFIG;
+use crate::core::challenge;
+use crate::middleware::admin_auth;
+use crate::middleware::rate_limit::ADMIN_LIMITER;
+use crate::middleware::real_ip::client_ip;
+use crate::state;
+
+/// GET /api/admin/security/lockouts
+pub async fn lockouts_handler() -> impl IntoResponse {
+    let data = admin_auth::lockouts();
+    Json(json!({
+        "success": true,
+        "data": data,
+        "total": data.len(),
+        "tracked": admin_auth::tracked_failures(),
+        "max_tracked": CONFIG.max_tracked_failures,
+        "admin_rate_limited": ADMIN_LIMITER.limited()
+    }))
+}
+
+/// GET /api/admin/security/secret - BSZ_SECRET rotation status: how many challenge
+/// nonces still needed BSZ_SECRET_PREVIOUS since startup
+pub async fn secret_rotation_handler() -> impl IntoResponse {
+    let (fallback_hits, last_fallback) = challenge::fallback_hits();
+    Json(json!({
+        "success": true,
+        "data": {
+            "previous_configured": !CONFIG.bsz_secret_previous.is_empty(),
+            "fallback_hits": fallback_hits,
+            "last_fallback": last_fallback
+        }
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockParams {
+    pub ip: String,
+}
+
+/// POST /api/admin/security/unlock?ip=...
+pub async fn unlock_handler(
+    headers: HeaderMap,
+    Query(params): Query<UnlockParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+    let target = params.ip.trim();
+
+    if !admin_auth::unlock(target) {
+        return Json(json!({
+            "success": false,
+            "message": "该 IP 没有失败记录"
+        }));
+    }
+
+    state::add_log("unlock_ip", target, &ip);
+
+    Json(json!({
+        "success": true,
+        "message": format!("已解除 {} 的锁定", target)
+    }))
+}
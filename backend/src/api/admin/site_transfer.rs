@@ -0,0 +1,370 @@
+//! Per-site export / import, for moving one site between instances
+
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::{Encrypt, CONFIG};
+use crate::core::{anomaly, visitor_hash};
+use crate::middleware::real_ip::client_ip;
+use crate::state::{self, Store};
+
+/// Default cap on exported visitor hashes
+const DEFAULT_MAX_VISITORS: usize = 100_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SiteData {
+    pub key: String,
+    /// The host, when keys are stored in plaintext
+    #[serde(default)]
+    pub host: Option<String>,
+    pub pv: u64,
+    pub uv: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PageData {
+    pub key: String,
+    /// Page key without the `site_key:` prefix
+    pub path: String,
+    pub pv: u64,
+    /// Only present when UV_SCOPE tracks pages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uv: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SiteExport {
+    pub site: SiteData,
+    pub pages: Vec<PageData>,
+    /// Visitor hashes (16 hex chars each) used for site UV deduplication
+    #[serde(default)]
+    pub visitors: Vec<String>,
+    /// Whether `visitors` was cut short by `max_visitors`
+    #[serde(default)]
+    pub visitors_truncated: bool,
+    /// Hash version of `visitors` (core::visitor_hash::VERSION); older exports lack it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visitor_hash: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportSiteParams {
+    pub site_key: String,
+    /// `false` leaves the visitor hashes out entirely
+    pub visitors: Option<bool>,
+    pub max_visitors: Option<usize>,
+}
+
+fn export_site(
+    store: &Store,
+    site_key: &str,
+    include_visitors: bool,
+    max_visitors: usize,
+) -> SiteExport {
+    let (pv, uv) = store.get_site(site_key);
+    let host = (CONFIG.bsz_encrypt == Encrypt::None).then(|| site_key.to_string());
+
+    let prefix = format!("{}:", site_key);
+    let mut pages: Vec<PageData> = store
+        .page_pv
+        .iter()
+        .filter_map(|e| {
+            let path = e.key().strip_prefix(&prefix)?;
+            Some(PageData {
+                key: e.key().clone(),
+                path: path.to_string(),
+                pv: e.value().load(Ordering::Relaxed),
+                uv: store
+                    .page_uv
+                    .get(e.key())
+                    .map(|v| v.load(Ordering::Relaxed)),
+            })
+        })
+        .collect();
+    pages.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut visitors = Vec::new();
+    let mut visitors_truncated = false;
+    if include_visitors {
+        if let Some(set) = store.site_visitors.get(site_key) {
+            visitors_truncated = set.len() > max_visitors;
+            visitors = set
+                .iter()
+                .take(max_visitors)
+                .map(|vh| format!("{:016x}", *vh))
+                .collect();
+        }
+    }
+
+    SiteExport {
+        site: SiteData {
+            key: site_key.to_string(),
+            host,
+            pv,
+            uv,
+        },
+        pages,
+        visitors,
+        visitors_truncated,
+        visitor_hash: Some(visitor_hash::VERSION.to_string()),
+    }
+}
+
+/// GET /api/admin/export-site?site_key=xxx&visitors=true&max_visitors=100000
+pub async fn export_site_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Query(params): Query<ExportSiteParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+
+    if !store.site_pv.contains_key(&params.site_key) {
+        return Json(json!({
+            "success": false,
+            "message": "站点不存在"
+        }));
+    }
+
+    let export = export_site(
+        &store,
+        &params.site_key,
+        params.visitors.unwrap_or(true),
+        params.max_visitors.unwrap_or(DEFAULT_MAX_VISITORS),
+    );
+    state::add_log(
+        "export_site",
+        &format!("{} ({} pages)", params.site_key, export.pages.len()),
+        &ip,
+    );
+
+    Json(json!({
+        "success": true,
+        "data": export
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportSiteParams {
+    /// `merge` (default) or `replace`, which wipes the site first
+    pub mode: Option<String>,
+    /// Import under another key than the exported one
+    pub site_key: Option<String>,
+}
+
+/// Body of POST /api/admin/import-site: the export response can be posted as-is,
+/// optionally with `mode` next to `data`
+#[derive(Debug, Deserialize)]
+pub struct ImportSiteBody {
+    pub data: SiteExport,
+    /// Used when the query string has no `mode`
+    pub mode: Option<String>,
+}
+
+/// Whether the import replaces the site; None for an unknown mode
+fn parse_mode(mode: Option<&str>) -> Option<bool> {
+    match mode.map(str::trim) {
+        None | Some("") | Some("merge") => Some(false),
+        Some("replace") => Some(true),
+        Some(_) => None,
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ImportStats {
+    pages: usize,
+    visitors: usize,
+    invalid_visitors: usize,
+}
+
+/// Write an export into `store` under `site_key`. Merging adds PV, unions visitor sets
+/// and keeps the larger UV (same rules as merging two sites).
+fn import_site(store: &Store, site_key: &str, data: &SiteExport, replace: bool) -> ImportStats {
+    let prefix = format!("{}:", site_key);
+    if replace {
+        store.site_pv.remove(site_key);
+        store.site_uv.remove(site_key);
+        store.site_visitors.remove(site_key);
+        store.remove_pages_with_prefix(&prefix);
+        anomaly::forget(site_key);
+    }
+
+    let mut stats = ImportStats::default();
+
+    store
+        .site_pv
+        .entry(site_key.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_add(data.site.pv, Ordering::Relaxed);
+    store
+        .site_uv
+        .entry(site_key.to_string())
+        .or_insert_with(|| AtomicU64::new(0))
+        .fetch_max(data.site.uv, Ordering::Relaxed);
+
+    if !data.visitors.is_empty() {
+        let set = store.site_visitors.entry(site_key.to_string()).or_default();
+        for hex in &data.visitors {
+            match u64::from_str_radix(hex, 16) {
+                Ok(vh) => {
+                    set.insert(vh);
+                    stats.visitors += 1;
+                }
+                Err(_) => stats.invalid_visitors += 1,
+            }
+        }
+    }
+
+    for page in &data.pages {
+        let page_key = format!("{}{}", prefix, page.path);
+        store.add_page_pv(&page_key, page.pv);
+        if let Some(uv) = page.uv {
+            store
+                .page_uv
+                .entry(page_key)
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_max(uv, Ordering::Relaxed);
+        }
+        stats.pages += 1;
+    }
+
+    stats
+}
+
+/// POST /api/admin/import-site?mode=merge|replace&site_key= - Ingest an export-site document
+pub async fn import_site_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Query(params): Query<ImportSiteParams>,
+    Json(body): Json<ImportSiteBody>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+
+    let site_key = params
+        .site_key
+        .as_deref()
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .unwrap_or(&body.data.site.key)
+        .to_string();
+    if site_key.is_empty() || site_key.contains(':') {
+        return Json(json!({
+            "success": false,
+            "message": "无效的站点 key"
+        }));
+    }
+
+    let Some(replace) = parse_mode(params.mode.as_deref().or(body.mode.as_deref())) else {
+        return Json(json!({
+            "success": false,
+            "message": "mode 只能是 merge 或 replace"
+        }));
+    };
+    if let Some(version) = body.data.visitor_hash.as_deref() {
+        if version != visitor_hash::VERSION && !body.data.visitors.is_empty() {
+            return Json(json!({
+                "success": false,
+                "message": format!("访客哈希版本 {} 与当前版本 {} 不兼容", version, visitor_hash::VERSION)
+            }));
+        }
+    }
+    let stats = import_site(&store, &site_key, &body.data, replace);
+
+    let mode = if replace { "replace" } else { "merge" };
+    state::add_log(
+        "import_site",
+        &format!(
+            "{} ({}, {} pages, {} visitors)",
+            site_key, mode, stats.pages, stats.visitors
+        ),
+        &ip,
+    );
+
+    Json(json!({
+        "success": true,
+        "message": format!("已导入 {}：{} 个页面，{} 个访客", site_key, stats.pages, stats.visitors),
+        "data": {
+            "site_key": site_key,
+            "mode": mode,
+            "pages": stats.pages,
+            "visitors": stats.visitors,
+            "invalid_visitors": stats.invalid_visitors
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed(store: &Store, site_key: &str) {
+        store
+            .site_pv
+            .insert(site_key.to_string(), AtomicU64::new(10));
+        store
+            .site_uv
+            .insert(site_key.to_string(), AtomicU64::new(2));
+        let set = store.site_visitors.entry(site_key.to_string()).or_default();
+        set.insert(1);
+        set.insert(0xdead_beef);
+        drop(set);
+        store.set_page_pv(&format!("{}:/a", site_key), 7);
+        store.set_page_pv(&format!("{}:/b", site_key), 3);
+    }
+
+    #[test]
+    fn export_round_trips_into_another_key() {
+        let store = Store::new();
+        seed(&store, "transfer-src.test");
+        let export = export_site(&store, "transfer-src.test", true, 10);
+        assert_eq!(export.pages.len(), 2);
+        assert_eq!(export.visitors.len(), 2);
+        assert!(!export.visitors_truncated);
+
+        // Through JSON, as between two instances
+        let json = serde_json::to_string(&export).unwrap();
+        let parsed: SiteExport = serde_json::from_str(&json).unwrap();
+        let stats = import_site(&store, "transfer-dst.test", &parsed, false);
+        assert_eq!(stats.pages, 2);
+        assert_eq!(stats.visitors, 2);
+        assert_eq!(store.get_site("transfer-dst.test"), (10, 2));
+        assert_eq!(store.get_page("transfer-dst.test:/a"), 7);
+        assert!(store
+            .site_visitors
+            .get("transfer-dst.test")
+            .unwrap()
+            .contains(&0xdead_beef));
+
+        // Merging again adds PV; replacing resets it
+        import_site(&store, "transfer-dst.test", &parsed, false);
+        assert_eq!(store.get_site("transfer-dst.test"), (20, 2));
+        import_site(&store, "transfer-dst.test", &parsed, true);
+        assert_eq!(store.get_site("transfer-dst.test"), (10, 2));
+        assert_eq!(store.get_page("transfer-dst.test:/b"), 3);
+        assert_eq!(store.site_page_count("transfer-dst.test"), 2);
+    }
+
+    #[test]
+    fn parses_mode() {
+        assert_eq!(parse_mode(None), Some(false));
+        assert_eq!(parse_mode(Some("merge")), Some(false));
+        assert_eq!(parse_mode(Some("replace")), Some(true));
+        assert_eq!(parse_mode(Some("overwrite")), None);
+    }
+
+    #[test]
+    fn caps_or_omits_visitors() {
+        let store = Store::new();
+        seed(&store, "transfer-cap.test");
+        let capped = export_site(&store, "transfer-cap.test", true, 1);
+        assert_eq!(capped.visitors.len(), 1);
+        assert!(capped.visitors_truncated);
+        let omitted = export_site(&store, "transfer-cap.test", false, 10);
+        assert!(omitted.visitors.is_empty());
+        assert!(!omitted.visitors_truncated);
+    }
+}
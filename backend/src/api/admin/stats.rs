@@ -1,33 +1,49 @@
 //! Stats handler
 
+use axum::extract::State;
 use axum::response::{IntoResponse, Json};
 use serde_json::json;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-use crate::state::STORE;
+use crate::core::site_quota::QUOTA;
+use crate::middleware::rate_limit::LIMITER;
+use crate::middleware::service_stats;
+use crate::state::{self, Store};
 
 /// GET /api/admin/stats
-pub async fn stats_handler() -> impl IntoResponse {
-    let total_sites = STORE.site_pv.len() as u64;
-    let total_pages = STORE.page_pv.len() as u64;
+pub async fn stats_handler(State(store): State<Arc<Store>>) -> impl IntoResponse {
+    let total_sites = store.site_pv.len() as u64;
+    let total_pages = store.page_pv.len() as u64;
 
     let mut total_site_pv: u64 = 0;
     let mut total_site_uv: u64 = 0;
 
-    for entry in STORE.site_pv.iter() {
+    for entry in store.site_pv.iter() {
         total_site_pv += entry.value().load(Ordering::Relaxed);
     }
-    for entry in STORE.site_uv.iter() {
+    for entry in store.site_uv.iter() {
         total_site_uv += entry.value().load(Ordering::Relaxed);
     }
 
+    let visitors = store.site_visitor_memory();
+
     Json(json!({
         "success": true,
         "data": {
             "total_sites": total_sites,
             "total_pages": total_pages,
             "total_site_pv": total_site_pv,
-            "total_site_uv": total_site_uv
+            "total_site_uv": total_site_uv,
+            "rate_limited": LIMITER.limited(),
+            "sites_throttled": QUOTA.refused(),
+            "total_unique_visitors": visitors.visitors,
+            "visitor_memory_estimate_bytes": visitors.bytes,
+            "uv_accurate": state::uv_accurate(),
+            "service": {
+                "since_boot": service_stats::since_boot(),
+                "lifetime": service_stats::lifetime(),
+            }
         }
     }))
 }
@@ -1,23 +1,36 @@
 //! Sitemap sync handler
 
-use axum::extract::{Multipart, Query};
+use axum::extract::{Multipart, Query, State};
 use axum::response::sse::{Event, KeepAlive, Sse};
-use axum::response::{IntoResponse, Json};
+use axum::response::{IntoResponse, Json, Response};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use dashmap::DashMap;
 use futures::stream::Stream;
 use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use super::import::read_upload;
 use crate::core::count::get_keys;
-use crate::state::STORE;
-
-// Temporary storage for uploaded sitemap URLs
-static UPLOADED_SITEMAPS: Lazy<DashMap<String, Vec<String>>> = Lazy::new(DashMap::new);
+use crate::state::{self, Store};
+
+// Temporary storage for uploaded sitemap entries
+static UPLOADED_SITEMAPS: Lazy<DashMap<String, Vec<SitemapEntry>>> = Lazy::new(DashMap::new);
+
+/// One `<url>` of a sitemap
+#[derive(Debug, Clone, PartialEq)]
+struct SitemapEntry {
+    url: String,
+    lastmod: Option<String>,
+    changefreq: Option<String>,
+    priority: Option<f32>,
+}
 
 enum SitemapSource {
     Remote(String),
@@ -30,64 +43,47 @@ pub struct SitemapSyncParams {
     pub sitemap_url: Option<String>,
     pub sync_id: Option<String>,
     pub concurrency: Option<usize>,
+    /// Only sync pages whose `lastmod` is at or after this date / datetime.
+    /// Pages without a (parseable) lastmod are always synced.
+    pub since: Option<String>,
+    /// Regex a page URL must match to be synced
+    pub include: Option<String>,
+    /// Regex of page URLs to leave out (e.g. `/page/\d+`, `/tags/`)
+    pub exclude: Option<String>,
 }
 
 /// POST /api/admin/sync/upload - Upload XML file and get sync_id
-pub async fn sync_upload_handler(mut multipart: Multipart) -> impl IntoResponse {
-    let mut xml_content: Option<String> = None;
-
-    while let Some(field) = multipart.next_field().await.unwrap_or(None) {
-        if field.name() == Some("file") {
-            match field.text().await {
-                Ok(text) => {
-                    xml_content = Some(text);
-                    break;
-                }
-                Err(e) => {
-                    return Json(json!({
-                        "success": false,
-                        "message": format!("读取文件失败: {}", e)
-                    }));
-                }
-            }
-        }
-    }
-
-    let xml = match xml_content {
-        Some(x) if !x.is_empty() => x,
-        _ => {
-            return Json(json!({
-                "success": false,
-                "message": "请上传 XML 文件"
-            }));
-        }
+pub async fn sync_upload_handler(mut multipart: Multipart) -> Response {
+    let xml = match read_upload(&mut multipart, "请上传 XML 文件").await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(response) => return response,
     };
 
     // Parse sitemap
-    let urls = match parse_sitemap(&xml) {
-        Ok(urls) => urls,
+    let entries = match parse_sitemap(&xml) {
+        Ok(entries) => entries,
         Err(e) => {
             return Json(json!({
                 "success": false,
                 "message": format!("XML 解析失败: {}", e)
-            }));
+            }))
+            .into_response();
         }
     };
 
-    if urls.is_empty() {
+    if entries.is_empty() {
         return Json(json!({
             "success": false,
             "message": "未找到有效的 URL"
-        }));
+        }))
+        .into_response();
     }
 
-    // Generate sync_id and store URLs
-    let sync_id = format!(
-        "{:x}",
-        md5::compute(format!("{}{:?}", chrono::Utc::now(), urls))
-    );
-    let url_count = urls.len();
-    UPLOADED_SITEMAPS.insert(sync_id.clone(), urls);
+    // Random 128-bit sync_id: it must be unguessable, since whoever presents it
+    // first consumes the uploaded URL list
+    let sync_id = hex::encode(rand::random::<[u8; 16]>());
+    let url_count = entries.len();
+    UPLOADED_SITEMAPS.insert(sync_id.clone(), entries);
 
     // Auto cleanup after 5 minutes
     let cleanup_id = sync_id.clone();
@@ -101,15 +97,36 @@ pub async fn sync_upload_handler(mut multipart: Multipart) -> impl IntoResponse
         "sync_id": sync_id,
         "url_count": url_count
     }))
+    .into_response()
+}
+
+/// HTTP client for a sync run, shared by the sitemap fetch and every busuanzi
+/// request. Cloning it is cheap and shares the connection pool.
+fn build_sync_client(concurrency: usize) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(concurrency)
+        .gzip(true)
+        .build()
+        .unwrap()
 }
 
-/// GET /api/admin/sync?sitemap_url=...&concurrency=3
+/// GET /api/admin/sync?sitemap_url=...&concurrency=3&since=2024-01-01&include=&exclude=
 /// GET /api/admin/sync?sync_id=...&concurrency=3
 /// Sync data from sitemap + busuanzi.ibruce.info with SSE progress
 pub async fn sync_handler(
+    State(store): State<Arc<Store>>,
     Query(params): Query<SitemapSyncParams>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     let concurrency = params.concurrency.unwrap_or(3).clamp(1, 10);
+    let client = build_sync_client(concurrency);
+    let since = params
+        .since
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_lastmod(s).ok_or_else(|| s.to_string()));
+    let filter = UrlFilter::new(params.include.as_deref(), params.exclude.as_deref());
 
     // Get URLs from either uploaded file or remote sitemap
     let urls_source = if let Some(sync_id) = params.sync_id {
@@ -121,14 +138,32 @@ pub async fn sync_handler(
     };
 
     let stream = async_stream::stream! {
-        let urls = match urls_source {
+        let since = match since {
+            Some(Err(raw)) => {
+                yield Ok(Event::default().event("error").data(
+                    json!({"message": format!("since 格式无效: {}（应为 YYYY-MM-DD 或 ISO 8601 时间）", raw)}).to_string()
+                ));
+                return;
+            }
+            Some(Ok(since)) => Some(since),
+            None => None,
+        };
+        let filter = match filter {
+            Ok(filter) => filter,
+            Err(e) => {
+                yield Ok(Event::default().event("error").data(json!({"message": e}).to_string()));
+                return;
+            }
+        };
+
+        let entries = match urls_source {
             SitemapSource::Uploaded(sync_id) => {
                 yield Ok(Event::default().event("progress").data(
                     json!({"status": "parsing", "message": format!("使用上传的 sitemap (并发: {})...", concurrency)}).to_string()
                 ));
 
                 match UPLOADED_SITEMAPS.remove(&sync_id) {
-                    Some((_, urls)) => urls,
+                    Some((_, entries)) => entries,
                     None => {
                         yield Ok(Event::default().event("error").data(
                             json!({"message": "Sync ID 已过期或无效"}).to_string()
@@ -142,11 +177,6 @@ pub async fn sync_handler(
                     json!({"status": "fetching", "message": format!("正在获取 sitemap (并发: {})...", concurrency)}).to_string()
                 ));
 
-                let client = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(30))
-                    .build()
-                    .unwrap();
-
                 let sitemap_text = match client.get(&sitemap_url).send().await {
                     Ok(res) => match res.text().await {
                         Ok(text) => text,
@@ -166,7 +196,7 @@ pub async fn sync_handler(
                 };
 
                 match parse_sitemap(&sitemap_text) {
-                    Ok(urls) => urls,
+                    Ok(entries) => entries,
                     Err(e) => {
                         yield Ok(Event::default().event("error").data(
                             json!({"message": format!("Failed to parse sitemap: {}", e)}).to_string()
@@ -183,33 +213,50 @@ pub async fn sync_handler(
             }
         };
 
-        if urls.is_empty() {
+        if entries.is_empty() {
             yield Ok(Event::default().event("error").data(
                 json!({"message": "No URLs found in sitemap"}).to_string()
             ));
             return;
         }
 
-        let total = urls.len();
+        let found = entries.len();
+        let entries: Vec<SitemapEntry> = entries
+            .into_iter()
+            .filter(|entry| filter.keeps(&entry.url))
+            .collect();
+        let filtered = found - entries.len();
+        let matched = entries.len();
+        let entries: Vec<SitemapEntry> = entries
+            .into_iter()
+            .filter(|entry| since.is_none_or(|since| !modified_before(entry, since)))
+            .collect();
+        let skipped = matched - entries.len();
+        let lastmods: Vec<Option<String>> = entries.iter().map(|e| e.lastmod.clone()).collect();
+
+        let total = entries.len();
+        let mut message = format!("发现 {} 个页面", found);
+        if filtered > 0 {
+            message += &format!("，{} 个被 include/exclude 过滤", filtered);
+        }
+        if skipped > 0 {
+            message += &format!("，{} 个在 since 之前未更新已跳过", skipped);
+        }
+        if filtered + skipped > 0 {
+            message += &format!("，开始并发同步 {} 个...", total);
+        } else {
+            message += "，开始并发同步...";
+        }
         yield Ok(Event::default().event("progress").data(
-            json!({"status": "syncing", "message": format!("发现 {} 个页面，开始并发同步...", total), "total": total, "current": 0}).to_string()
+            json!({"status": "syncing", "message": message, "total": total, "current": 0, "skipped": skipped, "filtered": filtered}).to_string()
         ));
 
-        // Create HTTP client for fetching busuanzi stats
-        let client = Arc::new(
-            reqwest::Client::builder()
-                .timeout(Duration::from_secs(30))
-                .pool_max_idle_per_host(concurrency)
-                .build()
-                .unwrap()
-        );
-
         // Use channel for concurrent results
         let (tx, mut rx) = tokio::sync::mpsc::channel::<(usize, String, Result<(u64, u64, u64, String, String), String>)>(concurrency * 2);
         let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency));
 
         // Spawn concurrent tasks
-        for (i, url) in urls.into_iter().enumerate() {
+        for (i, SitemapEntry { url, .. }) in entries.into_iter().enumerate() {
             let tx = tx.clone();
             let sem = semaphore.clone();
             let client = client.clone();
@@ -229,6 +276,7 @@ pub async fn sync_handler(
         let mut imported = 0usize;
         let mut errors = 0usize;
         let mut completed = 0usize;
+        let mut aggregate = SyncAggregate::default();
 
         while let Some((idx, short_path, result)) = rx.recv().await {
             completed += 1;
@@ -236,7 +284,9 @@ pub async fn sync_handler(
             match result {
                 Ok((site_pv, site_uv, page_pv, host, path)) => {
                     let keys = get_keys(&host, &path);
-                    store_stats(&keys.site_key, &keys.page_key, site_pv, site_uv, page_pv);
+                    let (site_pv, site_uv, page_pv) =
+                        aggregate.add(&keys.site_key, &keys.page_key, &host, site_pv, site_uv, page_pv);
+                    store_stats(&store, &keys.site_key, &keys.page_key, site_pv, site_uv, page_pv);
                     imported += 1;
 
                     yield Ok(Event::default().event("progress").data(
@@ -247,6 +297,7 @@ pub async fn sync_handler(
                             "imported": imported,
                             "errors": errors,
                             "path": short_path,
+                            "lastmod": lastmods[idx],
                             "page_pv": page_pv,
                             "site_pv": site_pv,
                             "site_uv": site_uv
@@ -272,16 +323,18 @@ pub async fn sync_handler(
             }
         }
 
-        if let Err(e) = crate::state::save().await {
+        if let Err(e) = state::save(store.clone()).await {
             tracing::error!("Failed to save after sync: {}", e);
         }
 
         yield Ok(Event::default().event("complete").data(
             json!({
-                "message": format!("同步完成: {}/{} 成功, {} 失败", imported, total, errors),
+                "message": format!("同步完成: {}/{} 成功, {} 失败, {} 个别名已合并", imported, total, errors, aggregate.alias_collapses),
                 "total": total,
                 "imported": imported,
-                "errors": errors
+                "errors": errors,
+                "skipped": skipped,
+                "alias_collapses": aggregate.alias_collapses
             }).to_string()
         ));
     };
@@ -305,85 +358,320 @@ async fn fetch_and_parse(
     client: &reqwest::Client,
     url: &str,
 ) -> Result<(u64, u64, u64, String, String), String> {
-    let (site_pv, site_uv, page_pv) = fetch_busuanzi_stats(client, url).await?;
+    let stats = fetch_busuanzi_stats(client, url).await?;
 
     let parsed = url::Url::parse(url).map_err(|_| "Invalid URL")?;
     let host = parsed.host_str().unwrap_or("").to_string();
     let path = parsed.path().to_string();
 
-    Ok((site_pv, site_uv, page_pv, host, path))
+    Ok((stats.site_pv, stats.site_uv, stats.page_pv, host, path))
+}
+
+/// Running totals for one sync run. Upstream busuanzi counts every host separately,
+/// so when several hosts normalize to the same site (`www.` aliases etc.) their
+/// numbers are summed instead of the last URL overwriting the others.
+#[derive(Default)]
+struct SyncAggregate {
+    /// site_key -> upstream host -> (site_pv, site_uv)
+    sites: HashMap<String, HashMap<String, (u64, u64)>>,
+    /// page_key -> upstream host -> page_pv
+    pages: HashMap<String, HashMap<String, u64>>,
+    /// Extra hosts folded into a site already seen in this run
+    alias_collapses: usize,
+}
+
+impl SyncAggregate {
+    /// Record one fetched URL and return the combined (site_pv, site_uv, page_pv)
+    fn add(
+        &mut self,
+        site_key: &str,
+        page_key: &str,
+        host: &str,
+        site_pv: u64,
+        site_uv: u64,
+        page_pv: u64,
+    ) -> (u64, u64, u64) {
+        let hosts = self.sites.entry(site_key.to_string()).or_default();
+        if !hosts.is_empty() && !hosts.contains_key(host) {
+            self.alias_collapses += 1;
+        }
+        // Every page of one upstream host reports the same site totals; keep the highest
+        let entry = hosts.entry(host.to_string()).or_insert((0, 0));
+        entry.0 = entry.0.max(site_pv);
+        entry.1 = entry.1.max(site_uv);
+        let (total_pv, total_uv) = hosts
+            .values()
+            .fold((0, 0), |(pv, uv), &(p, u)| (pv + p, uv + u));
+
+        let page_hosts = self.pages.entry(page_key.to_string()).or_default();
+        let page_entry = page_hosts.entry(host.to_string()).or_insert(0);
+        *page_entry = (*page_entry).max(page_pv);
+        let total_page_pv = page_hosts.values().sum();
+
+        (total_pv, total_uv, total_page_pv)
+    }
 }
 
-fn store_stats(site_key: &str, page_key: &str, site_pv: u64, site_uv: u64, page_pv: u64) {
+fn store_stats(
+    store: &Store,
+    site_key: &str,
+    page_key: &str,
+    site_pv: u64,
+    site_uv: u64,
+    page_pv: u64,
+) {
     // Only update if higher
-    let current_site_pv = STORE
+    let current_site_pv = store
         .site_pv
         .get(site_key)
         .map(|v| v.load(Ordering::Relaxed))
         .unwrap_or(0);
 
     if site_pv > current_site_pv {
-        STORE
+        store
             .site_pv
             .entry(site_key.to_string())
             .or_insert_with(|| AtomicU64::new(0))
             .store(site_pv, Ordering::Relaxed);
     }
 
-    let current_site_uv = STORE
+    let current_site_uv = store
         .site_uv
         .get(site_key)
         .map(|v| v.load(Ordering::Relaxed))
         .unwrap_or(0);
 
     if site_uv > current_site_uv {
-        STORE
+        store
             .site_uv
             .entry(site_key.to_string())
             .or_insert_with(|| AtomicU64::new(0))
             .store(site_uv, Ordering::Relaxed);
     }
 
-    STORE.site_visitors.entry(site_key.to_string()).or_default();
+    store.site_visitors.entry(site_key.to_string()).or_default();
 
-    STORE
-        .page_pv
-        .entry(page_key.to_string())
-        .or_insert_with(|| AtomicU64::new(0))
-        .store(page_pv, Ordering::Relaxed);
+    store.set_page_pv(page_key, page_pv);
+    store.mark_dirty();
+    super::clear_pages_cache();
 }
 
-fn parse_sitemap(xml: &str) -> Result<Vec<String>, String> {
+fn parse_sitemap(xml: &str) -> Result<Vec<SitemapEntry>, String> {
     let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
 
-    let mut urls = Vec::new();
+    let mut entries = Vec::new();
 
     for node in doc.descendants() {
         if node.tag_name().name() == "loc" {
             if let Some(text) = node.text() {
                 let url = text.trim();
                 // Skip sitemap index files
-                if !url.ends_with(".xml") {
-                    urls.push(url.to_string());
+                if url.ends_with(".xml") {
+                    continue;
                 }
+                // lastmod & co. are siblings of <loc> inside <url>
+                let sibling = |name: &str| {
+                    node.parent()?
+                        .children()
+                        .find(|n| n.tag_name().name() == name)?
+                        .text()
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_string)
+                };
+                entries.push(SitemapEntry {
+                    url: url.to_string(),
+                    lastmod: sibling("lastmod"),
+                    changefreq: sibling("changefreq"),
+                    priority: sibling("priority").and_then(|p| p.parse().ok()),
+                });
             }
         }
     }
 
-    Ok(urls)
+    Ok(entries)
+}
+
+/// Parse a sitemap `lastmod` (W3C datetime): `YYYY-MM-DD`, or a full ISO 8601
+/// datetime with or without seconds / offset (no offset means UTC)
+fn parse_lastmod(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    if let Ok(dt) = DateTime::parse_from_str(s, "%Y-%m-%dT%H:%M%:z") {
+        return Some(dt.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(dt.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .map(|d| d.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// Whether the entry's lastmod is known and earlier than `since`
+fn modified_before(entry: &SitemapEntry, since: DateTime<Utc>) -> bool {
+    entry
+        .lastmod
+        .as_deref()
+        .and_then(parse_lastmod)
+        .is_some_and(|lastmod| lastmod < since)
+}
+
+/// `include` / `exclude` regexes of a sync, matched anywhere in the page URL
+#[derive(Debug, Default)]
+struct UrlFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl UrlFilter {
+    /// Compile the optional patterns; blank ones are ignored
+    fn new(include: Option<&str>, exclude: Option<&str>) -> Result<Self, String> {
+        let compile = |name: &str, pattern: Option<&str>| {
+            pattern
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| Regex::new(p).map_err(|e| format!("{} 不是有效的正则表达式: {}", name, e)))
+                .transpose()
+        };
+        Ok(Self {
+            include: compile("include", include)?,
+            exclude: compile("exclude", exclude)?,
+        })
+    }
+
+    fn keeps(&self, url: &str) -> bool {
+        self.include.as_ref().is_none_or(|re| re.is_match(url))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(url))
+    }
+}
+
+/// Counters returned by the original busuanzi for one page
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BusuanziStats {
+    site_pv: u64,
+    site_uv: u64,
+    page_pv: u64,
+}
+
+impl BusuanziStats {
+    /// Read the counters, accepting numeric strings too. Missing or unusable fields
+    /// count as 0 and are logged, since they would otherwise silently lose data.
+    fn from_json(data: &serde_json::Value, page_url: &str) -> Self {
+        let field = |name: &str| {
+            let value = &data[name];
+            let parsed = match value {
+                serde_json::Value::Number(n) => n.as_u64(),
+                serde_json::Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            };
+            parsed.unwrap_or_else(|| {
+                tracing::warn!(
+                    "{} missing or non-numeric for {}: {:?}",
+                    name,
+                    page_url,
+                    value
+                );
+                0
+            })
+        };
+        Self {
+            site_pv: field("site_pv"),
+            site_uv: field("site_uv"),
+            page_pv: field("page_pv"),
+        }
+    }
 }
 
 /// Fetch stats from original busuanzi with retry
+/// Page sent as the Referer by /sync/ping when no `url` is given
+const PING_URL: &str = "https://busuanzi.ibruce.info/";
+
+#[derive(Debug, Deserialize)]
+pub struct PingParams {
+    /// Page to query instead of PING_URL
+    pub url: Option<String>,
+}
+
+/// Kind of a fetch_busuanzi_stats_once error, for the admin UI
+fn error_kind(error: &str) -> &'static str {
+    if error.starts_with("Rate limited") {
+        "rate_limited"
+    } else if error.starts_with("Invalid JSONP") || error.starts_with("JSON parse error") {
+        "parse"
+    } else {
+        "network"
+    }
+}
+
+/// GET /api/admin/sync/ping?url= - One upstream busuanzi request (no retries), to check
+/// reachability before a sync: latency, the stats returned, or why it failed
+pub async fn sync_ping_handler(Query(params): Query<PingParams>) -> impl IntoResponse {
+    let url = params
+        .url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+        .unwrap_or(PING_URL)
+        .to_string();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Json(json!({
+            "success": false,
+            "message": "url 必须以 http:// 或 https:// 开头"
+        }));
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    let started = std::time::Instant::now();
+    let result = fetch_busuanzi_stats_once(&client, &url).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    let data = match result {
+        Ok(stats) => json!({
+            "reachable": true,
+            "url": url,
+            "latency_ms": latency_ms,
+            "stats": {
+                "site_pv": stats.site_pv,
+                "site_uv": stats.site_uv,
+                "page_pv": stats.page_pv,
+            },
+        }),
+        Err(e) => json!({
+            "reachable": false,
+            "url": url,
+            "latency_ms": latency_ms,
+            "error_kind": error_kind(&e),
+            "error": e,
+        }),
+    };
+    Json(json!({ "success": true, "data": data }))
+}
+
 async fn fetch_busuanzi_stats(
     client: &reqwest::Client,
     page_url: &str,
-) -> Result<(u64, u64, u64), String> {
+) -> Result<BusuanziStats, String> {
     const MAX_RETRIES: u32 = 3;
 
     for attempt in 0..MAX_RETRIES {
         match fetch_busuanzi_stats_once(client, page_url).await {
             Ok(result) => return Ok(result),
-            Err(_) if attempt < MAX_RETRIES - 1 => {
+            Err(e) if attempt < MAX_RETRIES - 1 => {
+                tracing::debug!(
+                    "Retry {}/{} for {}: {}",
+                    attempt + 1,
+                    MAX_RETRIES,
+                    page_url,
+                    e
+                );
                 let delay = 500 * (1 << attempt);
                 tokio::time::sleep(Duration::from_millis(delay as u64)).await;
                 continue;
@@ -395,10 +683,56 @@ async fn fetch_busuanzi_stats(
     Err("Max retries exceeded".to_string())
 }
 
+/// The JSON object passed to the JSONP callback, whatever the callback is called
+/// and however it is wrapped: the first `name(` whose argument is an object, up to
+/// the brace matching its opening one (braces inside strings don't count).
+fn extract_jsonp(text: &str) -> Option<&str> {
+    let bytes = text.as_bytes();
+    for (paren, _) in text.match_indices('(') {
+        let callee = text[..paren].trim_end();
+        let is_call = callee
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '$' | '.' | ']'));
+        let arg = paren + 1 + (text[paren + 1..].len() - text[paren + 1..].trim_start().len());
+        if !is_call || bytes.get(arg) != Some(&b'{') {
+            continue;
+        }
+
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        for (i, &b) in bytes.iter().enumerate().skip(arg) {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'"' => in_string = true,
+                b'{' => depth += 1,
+                b'}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(&text[arg..=i]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        return None;
+    }
+    None
+}
+
 async fn fetch_busuanzi_stats_once(
     client: &reqwest::Client,
     page_url: &str,
-) -> Result<(u64, u64, u64), String> {
+) -> Result<BusuanziStats, String> {
     let ts = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
@@ -433,15 +767,7 @@ async fn fetch_busuanzi_stats_once(
     }
 
     // Parse JSONP: try{cb({"site_uv":123,"page_pv":456,...});}catch(e){}
-    let start = text.find("cb(").map(|i| i + 3);
-    let end = text.find(");");
-
-    let json_str = match (start, end) {
-        (Some(s), Some(e)) if s < e => Some(&text[s..e]),
-        _ => None,
-    };
-
-    let json_str = json_str.ok_or_else(|| {
+    let json_str = extract_jsonp(&text).ok_or_else(|| {
         let preview = if text.len() > 200 {
             &text[..200]
         } else {
@@ -453,9 +779,186 @@ async fn fetch_busuanzi_stats_once(
     let data: serde_json::Value = serde_json::from_str(json_str)
         .map_err(|e| format!("JSON parse error: {} for: {}", e, json_str))?;
 
-    Ok((
-        data["site_pv"].as_u64().unwrap_or(0),
-        data["site_uv"].as_u64().unwrap_or(0),
-        data["page_pv"].as_u64().unwrap_or(0),
-    ))
+    Ok(BusuanziStats::from_json(&data, page_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_urls_by_include_and_exclude() {
+        let filter = UrlFilter::new(Some("/posts/"), Some(r"/page/\d+|/tags/")).unwrap();
+        assert!(filter.keeps("https://a.com/posts/hello/"));
+        assert!(!filter.keeps("https://a.com/posts/page/2/"));
+        assert!(!filter.keeps("https://a.com/tags/rust/"));
+        assert!(!filter.keeps("https://a.com/about/"));
+
+        let none = UrlFilter::new(None, Some(" ")).unwrap();
+        assert!(none.keeps("https://a.com/anything"));
+
+        let err = UrlFilter::new(Some("("), None).unwrap_err();
+        assert!(err.starts_with("include"), "{}", err);
+    }
+
+    #[test]
+    fn classifies_fetch_errors() {
+        assert_eq!(error_kind("Rate limited"), "rate_limited");
+        assert_eq!(error_kind("Rate limited (HTML response)"), "rate_limited");
+        assert_eq!(error_kind("Invalid JSONP: nope"), "parse");
+        assert_eq!(error_kind("JSON parse error: eof for: {"), "parse");
+        assert_eq!(error_kind("error sending request for url"), "network");
+    }
+
+    #[test]
+    fn extracts_jsonp_from_real_world_wrappings() {
+        let obj = r#"{"site_uv":1,"page_pv":2,"version":2.4,"site_pv":3}"#;
+        for text in [
+            format!("try{{cb({});}}catch(e){{}}", obj),
+            format!("try{{ cb( {} ); }}catch(e){{}}", obj),
+            format!(
+                "try {{\n  BusuanziCallback_777 (\n{}\n);\n}} catch (e) {{}}",
+                obj
+            ),
+            format!("window.cb({})", obj),
+            format!("cb({})\n", obj),
+        ] {
+            assert_eq!(extract_jsonp(&text), Some(obj), "{}", text);
+        }
+    }
+
+    #[test]
+    fn extracts_nested_objects_and_braces_in_strings() {
+        let obj = r#"{"a":{"b":"})\"{"},"c":1}"#;
+        assert_eq!(extract_jsonp(&format!("cb({});", obj)), Some(obj));
+    }
+
+    #[test]
+    fn rejects_text_without_a_callback_object() {
+        assert_eq!(extract_jsonp("try{}catch(e){}"), None);
+        assert_eq!(extract_jsonp(r#"cb({"site_uv":1"#), None);
+        assert_eq!(extract_jsonp(r#"({"site_uv":1})"#), None);
+        assert_eq!(extract_jsonp(""), None);
+    }
+
+    #[test]
+    fn parses_sitemap_entries() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url>
+    <loc> https://example.com/a </loc>
+    <lastmod>2024-03-01</lastmod>
+    <changefreq>weekly</changefreq>
+    <priority>0.8</priority>
+  </url>
+  <url><loc>https://example.com/b</loc></url>
+  <url><loc>https://example.com/c</loc><priority>high</priority></url>
+  <sitemap><loc>https://example.com/more.xml</loc></sitemap>
+</urlset>"#;
+        let entries = parse_sitemap(xml).unwrap();
+        assert_eq!(
+            entries[0],
+            SitemapEntry {
+                url: "https://example.com/a".to_string(),
+                lastmod: Some("2024-03-01".to_string()),
+                changefreq: Some("weekly".to_string()),
+                priority: Some(0.8),
+            }
+        );
+        assert_eq!(entries[1].url, "https://example.com/b");
+        assert_eq!(entries[1].lastmod, None);
+        assert_eq!(entries[2].priority, None);
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn parses_lastmod_formats() {
+        let day = |s| parse_lastmod(s).map(|d| d.to_rfc3339());
+        assert_eq!(day("2024-03-01").unwrap(), "2024-03-01T00:00:00+00:00");
+        assert_eq!(
+            day("2024-03-01T10:20:30+08:00").unwrap(),
+            "2024-03-01T02:20:30+00:00"
+        );
+        assert_eq!(
+            day("2024-03-01T10:20:30Z").unwrap(),
+            "2024-03-01T10:20:30+00:00"
+        );
+        assert_eq!(
+            day("2024-03-01T10:20+01:00").unwrap(),
+            "2024-03-01T09:20:00+00:00"
+        );
+        assert_eq!(
+            day("2024-03-01T10:20:30").unwrap(),
+            "2024-03-01T10:20:30+00:00"
+        );
+        assert_eq!(day("01/03/2024"), None);
+        assert_eq!(day("2024-13-01"), None);
+    }
+
+    #[test]
+    fn skips_only_known_older_entries() {
+        let since = parse_lastmod("2024-03-01").unwrap();
+        let entry = |lastmod: Option<&str>| SitemapEntry {
+            url: "https://example.com/".to_string(),
+            lastmod: lastmod.map(str::to_string),
+            changefreq: None,
+            priority: None,
+        };
+        assert!(modified_before(&entry(Some("2024-02-29T23:59:59Z")), since));
+        assert!(!modified_before(&entry(Some("2024-03-01")), since));
+        assert!(!modified_before(
+            &entry(Some("2024-05-01T08:00:00+08:00")),
+            since
+        ));
+        // Unknown freshness: sync it rather than risk missing data
+        assert!(!modified_before(&entry(None), since));
+        assert!(!modified_before(&entry(Some("yesterday")), since));
+    }
+
+    #[test]
+    fn reads_busuanzi_fields() {
+        let data = serde_json::json!({"site_pv": 120, "site_uv": "45", "page_pv": 7});
+        assert_eq!(
+            BusuanziStats::from_json(&data, "https://a.com/"),
+            BusuanziStats {
+                site_pv: 120,
+                site_uv: 45,
+                page_pv: 7
+            }
+        );
+
+        // Missing or garbled fields fall back to 0 (with a warning)
+        let data = serde_json::json!({"site_pv": "n/a", "page_pv": -1});
+        assert_eq!(
+            BusuanziStats::from_json(&data, "https://a.com/"),
+            BusuanziStats::default()
+        );
+    }
+
+    #[test]
+    fn aggregates_aliases_instead_of_overwriting() {
+        let mut agg = SyncAggregate::default();
+        assert_eq!(
+            agg.add("a.com", "a.com:/", "a.com", 100, 10, 7),
+            (100, 10, 7)
+        );
+        // Same upstream host again (e.g. http:// vs https://): not double counted
+        assert_eq!(
+            agg.add("a.com", "a.com:/x", "a.com", 100, 10, 3),
+            (100, 10, 3)
+        );
+        assert_eq!(agg.alias_collapses, 0);
+
+        // www alias of the same site adds up
+        assert_eq!(
+            agg.add("a.com", "a.com:/", "www.a.com", 50, 5, 2),
+            (150, 15, 9)
+        );
+        assert_eq!(agg.alias_collapses, 1);
+        assert_eq!(
+            agg.add("a.com", "a.com:/x", "www.a.com", 50, 5, 1),
+            (150, 15, 4)
+        );
+        assert_eq!(agg.alias_collapses, 1);
+    }
 }
@@ -0,0 +1,212 @@
+//! TOTP two-factor handlers
+
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Json, Response};
+use axum::Extension;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::CONFIG;
+use crate::core::totp;
+use crate::middleware::admin_auth::{self, AdminAccess, Credential};
+use crate::middleware::identity::parse_cookie;
+use crate::middleware::real_ip::client_ip;
+use crate::state;
+
+#[derive(Debug, Deserialize)]
+pub struct CodeParams {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    pub totp: Option<String>,
+}
+
+/// GET /api/admin/2fa/status
+pub async fn two_factor_status_handler() -> impl IntoResponse {
+    Json(json!({
+        "success": true,
+        "data": {
+            "enabled": totp::enabled(),
+            "required": totp::required(),
+            "pending": totp::has_pending(),
+            "bypassed": totp::enabled() && CONFIG.disable_2fa
+        }
+    }))
+}
+
+/// POST /api/admin/2fa/setup - Generate a secret; the client renders `otpauth_url` as a QR code
+pub async fn two_factor_setup_handler() -> impl IntoResponse {
+    if totp::required() {
+        return Json(json!({
+            "success": false,
+            "message": "两步验证已启用，请先关闭"
+        }));
+    }
+
+    let (secret, otpauth_url) = totp::begin_setup();
+    Json(json!({
+        "success": true,
+        "secret": secret,
+        "otpauth_url": otpauth_url
+    }))
+}
+
+/// Session fields of a login response, with the cookie set alongside
+fn session_response(mut body: serde_json::Value, access: AdminAccess) -> Response {
+    let session = admin_auth::create_session(access);
+    body["session"] = json!(session.id);
+    body["read_only"] = json!(access == AdminAccess::ReadOnly);
+    body["csrf_token"] = json!(session.csrf_token);
+    body["expires_in"] = json!(admin_auth::SESSION_TTL.as_secs());
+    (
+        [(header::SET_COOKIE, admin_auth::session_cookie(&session.id))],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// POST /api/admin/2fa/enable {"code": "123456"} - Confirm the secret from setup
+pub async fn two_factor_enable_handler(
+    Extension(Credential(credential)): Extension<Credential>,
+    headers: HeaderMap,
+    Json(params): Json<CodeParams>,
+) -> Response {
+    let ip = client_ip(&headers);
+    let attempt = totp::Attempt {
+        ip: &ip,
+        credential: &credential,
+    };
+
+    if let Err(message) = totp::confirm_setup(params.code.trim(), &attempt) {
+        return Json(json!({
+            "success": false,
+            "message": message
+        }))
+        .into_response();
+    }
+
+    admin_auth::clear_sessions();
+    state::add_log("2fa_enabled", "", &ip);
+
+    // Hand back a session right away so the panel doesn't have to log in again
+    session_response(
+        json!({
+            "success": true,
+            "message": "两步验证已启用"
+        }),
+        AdminAccess::Full,
+    )
+}
+
+/// POST /api/admin/2fa/disable {"code": "123456"}
+/// With DISABLE_2FA set the code is not checked (break-glass recovery).
+pub async fn two_factor_disable_handler(
+    Extension(Credential(credential)): Extension<Credential>,
+    headers: HeaderMap,
+    Json(params): Json<CodeParams>,
+) -> impl IntoResponse {
+    let ip = client_ip(&headers);
+    let attempt = totp::Attempt {
+        ip: &ip,
+        credential: &credential,
+    };
+
+    if !totp::enabled() {
+        return Json(json!({
+            "success": false,
+            "message": "两步验证未启用"
+        }));
+    }
+    if !CONFIG.disable_2fa {
+        if let Err(e) = totp::verify(params.code.trim(), &attempt) {
+            return Json(json!({
+                "success": false,
+                "message": e.to_string()
+            }));
+        }
+    }
+
+    if let Err(message) = totp::disable() {
+        return Json(json!({
+            "success": false,
+            "message": message
+        }));
+    }
+
+    admin_auth::clear_sessions();
+    state::add_log("2fa_disabled", "", &ip);
+
+    Json(json!({
+        "success": true,
+        "message": "两步验证已关闭"
+    }))
+}
+
+/// POST /api/admin/login {"totp": "123456"} - Exchange the token (+ code when 2FA is on)
+/// for a session. The session is set as a cookie and also returned for `X-Admin-Session`
+/// (or `?session=` for SSE/downloads); cookie-only writes must send `csrf_token` in `X-CSRF-Token`.
+pub async fn login_handler(
+    Extension(access): Extension<AdminAccess>,
+    Extension(Credential(credential)): Extension<Credential>,
+    headers: HeaderMap,
+    Json(params): Json<LoginParams>,
+) -> Response {
+    let ip = client_ip(&headers);
+    let attempt = totp::Attempt {
+        ip: &ip,
+        credential: &credential,
+    };
+
+    if !totp::required() {
+        return session_response(
+            json!({
+                "success": true,
+                "two_factor": false
+            }),
+            access,
+        );
+    }
+
+    let code = params.totp.unwrap_or_default();
+    if let Err(e) = totp::verify(code.trim(), &attempt) {
+        state::add_log("login_failed", "invalid totp", &ip);
+        return Json(json!({
+            "success": false,
+            "two_factor": true,
+            "message": e.to_string()
+        }))
+        .into_response();
+    }
+
+    state::add_log("login", "totp", &ip);
+
+    session_response(
+        json!({
+            "success": true,
+            "two_factor": true
+        }),
+        access,
+    )
+}
+
+/// GET /api/admin/csrf - CSRF token of the session cookie, for re-fetching after a 403
+pub async fn csrf_handler(headers: HeaderMap) -> impl IntoResponse {
+    let token = headers
+        .get(header::COOKIE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|cookies| parse_cookie(cookies, admin_auth::SESSION_COOKIE))
+        .and_then(|id| admin_auth::session_csrf(&id));
+
+    match token {
+        Some(token) => Json(json!({
+            "success": true,
+            "csrf_token": token
+        })),
+        None => Json(json!({
+            "success": false,
+            "message": "没有有效的会话，请先登录"
+        })),
+    }
+}
@@ -1,13 +1,21 @@
 //! API handlers
 
-use crate::core::count;
+use crate::config::{runtime, CONFIG};
+use crate::core::challenge;
+use crate::core::referer::{parse_bsz_referer, parse_referer_header};
+use crate::core::{bot, count, site_quota};
+use crate::middleware::rate_limit;
+use crate::middleware::real_ip::client_ip;
+use crate::state::Store;
 use axum::{
-    http::{HeaderMap, StatusCode},
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Json},
     Extension,
 };
+use serde::Deserialize;
 use serde_json::json;
-use url::Url;
+use std::sync::Arc;
 
 fn default_data() -> serde_json::Value {
     json!({
@@ -15,24 +23,21 @@ fn default_data() -> serde_json::Value {
     })
 }
 
-fn parse_referer(headers: &HeaderMap, header_name: &str) -> Result<(String, String), &'static str> {
-    let referer = headers
-        .get(header_name)
+fn write_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(CONFIG.header_key.as_str())
         .and_then(|h| h.to_str().ok())
-        .unwrap_or("");
-
-    if referer.is_empty() {
-        return Err("invalid referer");
-    }
-
-    let u = Url::parse(referer).map_err(|_| "unable to parse referer")?;
-    let host = u.host_str().ok_or("invalid referer")?.to_string();
+}
 
-    if host.is_empty() {
-        return Err("invalid referer");
-    }
+fn nonce(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(CONFIG.header_nonce.as_str())
+        .and_then(|h| h.to_str().ok())
+}
 
-    Ok((host, u.path().to_string()))
+/// MAX_NEW_SITES_PER_IP_PER_HOUR: whether this request may create the page's site if it's new
+fn may_create_site(store: &Store, host: &str, path: &str, headers: &HeaderMap) -> bool {
+    site_quota::allows(store, &count::site_key(host, path), &client_ip(headers))
 }
 
 pub async fn ping_handler() -> impl IntoResponse {
@@ -41,10 +46,11 @@ pub async fn ping_handler() -> impl IntoResponse {
 
 /// POST /api - Count and return PV/UV
 pub async fn api_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Extension(user_identity): Extension<String>,
 ) -> impl IntoResponse {
-    let (host, path) = match parse_referer(&headers, "x-bsz-referer") {
+    let (host, path) = match parse_bsz_referer(&headers) {
         Ok(v) => v,
         Err(msg) => {
             return Json(json!({
@@ -55,7 +61,43 @@ pub async fn api_handler(
         }
     };
 
-    let counts = count::count(&host, &path, &user_identity);
+    // Sites with a write key only count requests that carry it; others just read
+    if !count::can_write(&host, &path, write_key(&headers)) {
+        return Json(json!({
+            "success": true,
+            "message": "read only",
+            "data": count::get(&store, &host, &path)
+        }));
+    }
+
+    // Operator-listed bots (see /api/admin/bots) read without counting
+    if bot::is_listed_bot(&headers) {
+        return Json(json!({
+            "success": true,
+            "message": "bot",
+            "data": count::get(&store, &host, &path)
+        }));
+    }
+
+    // REQUIRE_CHALLENGE: no valid nonce, no increment
+    if let Err(e) = challenge::check(&host, nonce(&headers)) {
+        return Json(json!({
+            "success": true,
+            "message": e.message(),
+            "data": count::get(&store, &host, &path)
+        }));
+    }
+
+    // Too many new sites from this IP: read without creating another
+    if !may_create_site(&store, &host, &path, &headers) {
+        return Json(json!({
+            "success": true,
+            "message": "new site limit",
+            "data": count::get(&store, &host, &path)
+        }));
+    }
+
+    let counts = count::count(&store, &host, &path, &user_identity);
     Json(json!({
         "success": true,
         "message": "ok",
@@ -64,8 +106,8 @@ pub async fn api_handler(
 }
 
 /// GET /api - Get counts without incrementing
-pub async fn get_handler(headers: HeaderMap) -> impl IntoResponse {
-    let (host, path) = match parse_referer(&headers, "x-bsz-referer") {
+pub async fn get_handler(State(store): State<Arc<Store>>, headers: HeaderMap) -> impl IntoResponse {
+    let (host, path) = match parse_bsz_referer(&headers) {
         Ok(v) => v,
         Err(msg) => {
             return Json(json!({
@@ -76,7 +118,7 @@ pub async fn get_handler(headers: HeaderMap) -> impl IntoResponse {
         }
     };
 
-    let counts = count::get(&host, &path);
+    let counts = count::get(&store, &host, &path);
     Json(json!({
         "success": true,
         "message": "ok",
@@ -86,14 +128,130 @@ pub async fn get_handler(headers: HeaderMap) -> impl IntoResponse {
 
 /// PUT /api - Submit data without returning
 pub async fn put_handler(
+    State(store): State<Arc<Store>>,
     headers: HeaderMap,
     Extension(user_identity): Extension<String>,
 ) -> impl IntoResponse {
-    let (host, path) = match parse_referer(&headers, "x-bsz-referer") {
+    let (host, path) = match parse_bsz_referer(&headers) {
         Ok(v) => v,
         Err(_) => return StatusCode::BAD_REQUEST,
     };
 
-    count::put(&host, &path, &user_identity);
+    if !count::can_write(&host, &path, write_key(&headers))
+        || challenge::check(&host, nonce(&headers)).is_err()
+    {
+        return StatusCode::FORBIDDEN;
+    }
+
+    if bot::is_listed_bot(&headers) {
+        return StatusCode::NO_CONTENT;
+    }
+
+    if !may_create_site(&store, &host, &path, &headers) {
+        return StatusCode::TOO_MANY_REQUESTS;
+    }
+
+    count::put(&store, &host, &path, &user_identity);
     StatusCode::NO_CONTENT
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeParams {
+    pub host: Option<String>,
+}
+
+/// GET /api/challenge?host=example.com - Signed nonce for the next counting request
+/// (required when REQUIRE_CHALLENGE is on). Without `host` the x-bsz-referer host is used.
+pub async fn challenge_handler(
+    headers: HeaderMap,
+    Query(params): Query<ChallengeParams>,
+) -> impl IntoResponse {
+    let host = match params.host.as_deref().map(str::trim) {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => match parse_bsz_referer(&headers) {
+            Ok((host, _)) => host,
+            Err(msg) => {
+                return Json(json!({
+                    "success": false,
+                    "message": msg,
+                    "data": default_data()
+                }))
+            }
+        },
+    };
+
+    Json(json!({
+        "success": true,
+        "message": "ok",
+        "data": {
+            "nonce": challenge::issue(&host),
+            "expires_in": challenge::NONCE_TTL,
+            "required": runtime().require_challenge,
+        }
+    }))
+}
+
+/// Transparent 1x1 GIF
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(Debug, Deserialize)]
+pub struct PixelParams {
+    pub host: Option<String>,
+    pub path: Option<String>,
+    /// Site write key, for sites that have one (an <img> can't send headers)
+    pub key: Option<String>,
+    /// Challenge nonce, when REQUIRE_CHALLENGE is on
+    pub nonce: Option<String>,
+}
+
+/// GET /pixel.gif?host=example.com&path=/a - Count via an <img> (no-JS pages, email opens).
+/// Without `host` the standard Referer header decides the page.
+/// Always answers with the pixel; crawlers, prefetches and rate-limited clients are not counted.
+pub async fn pixel_handler(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Query(params): Query<PixelParams>,
+    Extension(user_identity): Extension<String>,
+) -> impl IntoResponse {
+    // Explicit host/path, else the page the <img> is embedded in
+    let target = match params.host.as_deref().map(str::trim) {
+        Some(host) if !host.is_empty() => {
+            let path = params.path.as_deref().unwrap_or("/");
+            let path = if path.starts_with('/') {
+                path.to_string()
+            } else {
+                format!("/{}", path)
+            };
+            // Same page identity rules as the referer-based API
+            let path = match path.split_once('?') {
+                Some((p, _)) if CONFIG.bsz_path_style => p.to_string(),
+                _ => path,
+            };
+            Some((host.to_string(), path))
+        }
+        _ => parse_referer_header(&headers).ok(),
+    };
+
+    if let Some((host, path)) = target {
+        if !bot::should_skip(&headers)
+            && count::can_write(&host, &path, params.key.as_deref())
+            && challenge::check(&host, params.nonce.as_deref()).is_ok()
+            && rate_limit::try_count(&headers)
+            && may_create_site(&store, &host, &path, &headers)
+        {
+            count::put(&store, &host, &path, &user_identity);
+        }
+    }
+
+    (
+        [
+            (header::CONTENT_TYPE, "image/gif"),
+            (header::CACHE_CONTROL, "no-store, no-cache, must-revalidate"),
+        ],
+        PIXEL_GIF,
+    )
+}
@@ -1,35 +1,942 @@
 //! Configuration
 
+use ipnet::IpNet;
 use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// Hash applied to site/page keys before they are stored (`BSZ_ENCRYPT`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encrypt {
+    /// Keys stay plaintext (host, host:path)
+    None,
+    /// Full 32-char MD5 hex
+    Md5,
+    /// Middle 16 chars of the MD5 hex
+    Md516,
+}
+
+/// Which visitor sets are kept for UV deduplication (`UV_SCOPE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UvScope {
+    /// Unique visitors per site (original busuanzi behavior)
+    Site,
+    /// Unique visitors per page only; site_uv is no longer incremented
+    Page,
+    Both,
+}
+
+impl UvScope {
+    pub fn tracks_site(self) -> bool {
+        matches!(self, UvScope::Site | UvScope::Both)
+    }
+
+    pub fn tracks_page(self) -> bool {
+        matches!(self, UvScope::Page | UvScope::Both)
+    }
+}
+
+/// What a new visitor identity is derived from (`IDENTITY_MODE`). Only applies to
+/// visitors without a `busuanziId` cookie; existing cookies are used as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityMode {
+    /// MD5(BSZ_SECRET + IP + User-Agent), as the original busuanzi
+    IpUa,
+    Ip,
+    Ua,
+    /// IP + User-Agent + the UTC date, with a cookie that expires at midnight,
+    /// so each visitor counts once per day
+    IpUaSaltedDaily,
+}
+
+/// Which proxy header wins when a request carries both (`PROXY_HEADER_PRECEDENCE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// RFC 7239 `Forwarded`
+    Forwarded,
+    /// `X-Forwarded-For` / `X-Real-IP`
+    XForwardedFor,
+}
+
+/// How log lines are written (`LOG_FORMAT`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human-readable line per event, with span fields
+    Pretty,
+    Compact,
+    /// One JSON object per line; event and span fields are JSON fields
+    Json,
+}
+
+/// Settings `POST /api/admin/config/reload` (or SIGHUP) applies without a restart
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuntimeConfig {
+    pub save_interval: u64, // seconds
+    /// Saves slower than this are logged at INFO instead of DEBUG
+    pub slow_save_threshold_ms: u64,
+    /// Also save once this many increments are un-persisted, 0 = interval only
+    pub save_every_n_writes: u64,
+    /// Seconds GET /api/admin/export waits for its save before answering 503, 0 = no limit
+    pub export_save_timeout_secs: u64,
+    /// Comma-separated allowed origins; `*` mirrors any request origin
+    pub cors: String,
+    /// Origins allowed to call the admin API from a browser, same syntax as `cors`;
+    /// empty = same-origin only
+    pub admin_cors: String,
+    /// Counting requests (POST/PUT /api) allowed per client IP per minute, 0 = unlimited
+    pub rate_limit_per_minute: u64,
+    /// Admin API requests allowed per client IP per minute, 0 = unlimited
+    pub admin_rate_limit_per_minute: u64,
+    /// Client IPs never rate limited (localhost, trusted proxies)
+    pub rate_limit_exempt: Vec<String>,
+    /// Default page sizes of the admin list endpoints (keys, pages, logs)
+    pub page_size_keys: usize,
+    pub page_size_pages: usize,
+    pub page_size_logs: usize,
+    /// Upper bound for any requested page size
+    pub page_size_max: usize,
+    /// Seconds a site's sorted page list is reused by GET /api/admin/pages, 0 = never
+    pub pages_cache_secs: u64,
+    /// Treat requests without a User-Agent as bots (not counted)
+    pub empty_ua_is_bot: bool,
+    /// Counting requests only increment with a signed nonce from GET /api/challenge
+    pub require_challenge: bool,
+    /// Share of a site's recent hits one identity may make before an `anomaly` is logged, 0 = off
+    pub anomaly_threshold: f64,
+    /// Number of recent hits per site the anomaly share is measured over
+    pub anomaly_window: usize,
+    /// New site keys one client IP may create per hour through the counting API, 0 = unlimited
+    pub max_new_sites_per_ip_per_hour: usize,
+    /// Distinct pages a site may have before new paths go to its overflow page, 0 = unlimited
+    pub max_pages_per_site: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub web_addr: String,
-    /// When empty, /api/admin/* routes are not mounted at all (see main.rs).
-    pub admin_token: String,
-    pub save_interval: u64,   // seconds
-    pub max_body_size: usize, // bytes, for file upload (import/sync)
+    /// SQLite database file, from DATABASE_URL (`sqlite://path` or a bare path)
+    pub db_path: String,
+    /// ADMIN_TOKEN followed by every entry of ADMIN_TOKENS, deduplicated. Any of them is
+    /// accepted. When empty (and no hash is set), /api/admin/* routes are not mounted at all
+    /// (see main.rs).
+    pub admin_tokens: Vec<String>,
+    /// ADMIN_READONLY_TOKENS: accepted only for read-only admin routes (GET, plus /login)
+    pub admin_readonly_tokens: Vec<String>,
+    /// argon2 (`$argon2id$...`) or bcrypt (`$2b$...`) hash of the admin token,
+    /// so the plaintext never has to live in env files
+    pub admin_token_hash: String,
+    /// ADMIN_ENABLED=false: never mount the admin API, whatever tokens are set
+    pub admin_api: bool,
+    pub max_body_size: usize,     // bytes, for file upload (import/sync)
+    pub api_max_body_size: usize, // bytes, for the public counting routes
+    /// Salt mixed into newly generated visitor identities
+    pub bsz_secret: String,
+    /// BSZ_SECRET before the last rotation: still accepted for challenge nonces and
+    /// for decrypting the stored 2FA secret, never used to sign or encrypt
+    pub bsz_secret_previous: String,
+    pub bsz_encrypt: Encrypt,
+    /// true: a page is identified by its path; false: path + query string
+    pub bsz_path_style: bool,
+    /// Treat `www.example.com` and `example.com` as the same site
+    pub bsz_strip_www: bool,
+    /// Leading path segments that belong to the site rather than the page
+    /// (`/alice/post` -> site `host/alice`, page `/post`), 0 = the host alone
+    pub bsz_site_from_path_segments: usize,
+    /// Whether hits on a site's home page count towards page PV/UV (site counts always do)
+    pub bsz_count_root_page: bool,
+    /// Page paths that are the home page, `/` by default (BSZ_ROOT_PATHS)
+    pub bsz_root_paths: Vec<String>,
+    /// Per-page sets cost ~8 bytes per (page, visitor) pair plus set overhead per page
+    pub uv_scope: UvScope,
+    pub identity_mode: IdentityMode,
+    /// Request headers carrying the page URL, the site write key and the challenge
+    /// nonce (HEADER_REFERER, HEADER_KEY, HEADER_NONCE), lowercase
+    pub header_referer: String,
+    pub header_key: String,
+    pub header_nonce: String,
+    /// Header carrying the visitor identity (HEADER_IDENTITY), for embeds whose
+    /// requests can't carry cookies: read when there is no cookie, sent with new identities
+    pub header_identity: String,
+    /// Also hand out new identities as the `busuanziId` cookie (IDENTITY_COOKIE)
+    pub identity_cookie: bool,
+    /// Response headers scripts may read cross-origin, on top of the ones the
+    /// public API always exposes (CORS_EXPOSE_HEADERS), lowercase
+    pub cors_expose_headers: Vec<String>,
+    /// Send `Access-Control-Allow-Credentials` on the public API, so cross-origin
+    /// counting requests carry the identity cookie
+    pub cors_allow_credentials: bool,
+    /// Break-glass switch: skip the TOTP requirement even if 2FA is enabled
+    pub disable_2fa: bool,
+    /// Seconds between sweeps of expired admin auth failure records
+    pub lockout_sweep_interval: u64,
+    /// Most IPs with admin auth failure records kept in memory (0 = unbounded)
+    pub max_tracked_failures: usize,
+    /// PEM certificate chain and private key (TLS_CERT_PATH / TLS_KEY_PATH, or the
+    /// older TLS_CERT / TLS_KEY); HTTPS is served when both are set
+    pub tls_cert: String,
+    pub tls_key: String,
+    /// Believe X-Forwarded-For / X-Real-IP; otherwise the socket peer address is used
+    pub trust_proxy_headers: bool,
+    /// Header used when both `Forwarded` and `X-Forwarded-For` are present (trusted only)
+    pub proxy_header_precedence: ProxyHeader,
+    /// Proxies whose forwarding headers are believed; the client is the rightmost
+    /// `X-Forwarded-For` hop outside these ranges
+    pub trusted_proxies: Vec<IpNet>,
+    pub log_format: LogFormat,
+    /// Log to this file, rotated daily (a date suffix is added), instead of stdout
+    pub log_file: String,
+    /// Settings that can change without a restart, as loaded. Read them through
+    /// [`runtime()`], which a reload replaces.
+    pub runtime: RuntimeConfig,
+    /// When non-empty, admin requests from other client IPs get 403 before any token check
+    pub admin_ip_allowlist: Vec<IpNet>,
+    /// Problems found while loading, logged at startup
+    pub warnings: Vec<String>,
+    /// Misconfiguration that must stop startup rather than fall back to something permissive
+    pub errors: Vec<String>,
 }
 
+/// The process environment before `.env` was applied. On reload these keep
+/// precedence over the file, as they did at startup.
+static PROCESS_ENV: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    // vars() panics on non-UTF-8 entries; env::var treats those as unset anyway
+    env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .collect()
+});
+
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+    Lazy::force(&PROCESS_ENV);
     dotenv::dotenv().ok();
+    Config::from_lookup(|key| env::var(key).ok())
+});
 
-    let port = env::var("PORT").unwrap_or_else(|_| "12700".to_string());
+static RUNTIME: Lazy<RwLock<Arc<RuntimeConfig>>> =
+    Lazy::new(|| RwLock::new(Arc::new(CONFIG.runtime.clone())));
 
-    Config {
-        web_addr: format!("0.0.0.0:{}", port),
-        admin_token: env::var("ADMIN_TOKEN").unwrap_or_default(),
-        save_interval: env::var("SAVE_INTERVAL")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(30),
-        max_body_size: env::var("MAX_BODY_SIZE")
-            .ok()
-            .and_then(|v| parse_size(&v))
-            .unwrap_or(100 * 1024 * 1024), // default 100MB
+/// The current runtime settings
+pub fn runtime() -> Arc<RuntimeConfig> {
+    RUNTIME.read().unwrap().clone()
+}
+
+/// Replace the runtime settings, returning the previous ones
+pub fn set_runtime(runtime: RuntimeConfig) -> Arc<RuntimeConfig> {
+    std::mem::replace(&mut *RUNTIME.write().unwrap(), Arc::new(runtime))
+}
+
+/// Load the configuration again from the environment and a re-read `.env`
+pub fn reread() -> Config {
+    // The non-deprecated dotenv API only sets variables that aren't set yet, so it
+    // can't pick up a value changed in the file
+    #[allow(deprecated)]
+    let file: HashMap<String, String> = dotenv::dotenv_iter()
+        .map(|iter| iter.filter_map(Result::ok).collect())
+        .unwrap_or_default();
+    Config::from_lookup(|key| PROCESS_ENV.get(key).or_else(|| file.get(key)).cloned())
+}
+
+impl RuntimeConfig {
+    /// Page size for a list request: `requested` (or `default`), clamped to `[1, PAGE_SIZE_MAX]`
+    pub fn page_size(&self, requested: Option<usize>, default: usize) -> usize {
+        requested.unwrap_or(default).clamp(1, self.page_size_max)
     }
-});
+}
+
+impl Config {
+    /// Build the config from a variable lookup (process env in production, a map in tests)
+    pub fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Self {
+        Self::load(get, cfg!(debug_assertions))
+    }
+
+    fn load(get: impl Fn(&str) -> Option<String>, dev: bool) -> Self {
+        let mut warnings = Vec::new();
+        let mut errors = Vec::new();
+        let get = |key: &str| get(key).map(|v| v.trim().to_string());
+
+        let port = match get("PORT").filter(|v| !v.is_empty()) {
+            None => 12700,
+            Some(v) => match v.parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => {
+                    errors.push(format!("PORT={} is not a port number (1-65535)", v));
+                    12700
+                }
+            },
+        };
+
+        let bsz_encrypt = match get("BSZ_ENCRYPT")
+            .unwrap_or_default()
+            .to_uppercase()
+            .as_str()
+        {
+            "" => Encrypt::None,
+            "MD5" => Encrypt::Md5,
+            "MD516" => Encrypt::Md516,
+            other => {
+                warnings.push(format!(
+                    "BSZ_ENCRYPT={} is not one of MD5/MD516, keys stay plaintext",
+                    other
+                ));
+                Encrypt::None
+            }
+        };
+
+        let bsz_path_style = match get("BSZ_PATH_STYLE") {
+            None => true,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!("BSZ_PATH_STYLE={} is not a boolean, using true", v));
+                    true
+                }
+            },
+        };
+
+        let bsz_strip_www = match get("BSZ_STRIP_WWW") {
+            None => false,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!("BSZ_STRIP_WWW={} is not a boolean, using false", v));
+                    false
+                }
+            },
+        };
+
+        let bsz_count_root_page = match get("BSZ_COUNT_ROOT_PAGE") {
+            None => true,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "BSZ_COUNT_ROOT_PAGE={} is not a boolean, using true",
+                        v
+                    ));
+                    true
+                }
+            },
+        };
+
+        let uv_scope = match get("UV_SCOPE").unwrap_or_default().to_lowercase().as_str() {
+            "" | "site" => UvScope::Site,
+            "page" => UvScope::Page,
+            "both" => UvScope::Both,
+            other => {
+                warnings.push(format!(
+                    "UV_SCOPE={} is not one of site/page/both, using site",
+                    other
+                ));
+                UvScope::Site
+            }
+        };
+
+        let identity_mode = match get("IDENTITY_MODE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "ip_ua" => IdentityMode::IpUa,
+            "ip" => IdentityMode::Ip,
+            "ua" => IdentityMode::Ua,
+            "ip_ua_salted_daily" => IdentityMode::IpUaSaltedDaily,
+            other => {
+                warnings.push(format!(
+                    "IDENTITY_MODE={} is not one of ip_ua/ip/ua/ip_ua_salted_daily, using ip_ua",
+                    other
+                ));
+                IdentityMode::IpUa
+            }
+        };
+
+        // Unlike most switches, a typo here must not leave the admin API reachable
+        let admin_api = match get("ADMIN_ENABLED").filter(|v| !v.is_empty()) {
+            None => true,
+            Some(v) => parse_bool(&v).unwrap_or_else(|| {
+                errors.push(format!("ADMIN_ENABLED={} is not a boolean (true/false)", v));
+                false
+            }),
+        };
+
+        let disable_2fa = match get("DISABLE_2FA").filter(|v| !v.is_empty()) {
+            None => false,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!("DISABLE_2FA={} is not a boolean, using false", v));
+                    false
+                }
+            },
+        };
+        if disable_2fa {
+            warnings.push(
+                "DISABLE_2FA is set: admin two-factor authentication is bypassed".to_string(),
+            );
+        }
+
+        let proxy_header_precedence = match get("PROXY_HEADER_PRECEDENCE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "x-forwarded-for" | "xff" => ProxyHeader::XForwardedFor,
+            "forwarded" => ProxyHeader::Forwarded,
+            other => {
+                warnings.push(format!(
+                    "PROXY_HEADER_PRECEDENCE={} is not one of forwarded/x-forwarded-for, using x-forwarded-for",
+                    other
+                ));
+                ProxyHeader::XForwardedFor
+            }
+        };
+
+        let log_format = match get("LOG_FORMAT")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "pretty" => LogFormat::Pretty,
+            "compact" => LogFormat::Compact,
+            "json" => LogFormat::Json,
+            other => {
+                warnings.push(format!(
+                    "LOG_FORMAT={} is not one of pretty/compact/json, using pretty",
+                    other
+                ));
+                LogFormat::Pretty
+            }
+        };
+
+        let trust_proxy_headers = match get("TRUST_PROXY_HEADERS").filter(|v| !v.is_empty()) {
+            None => false,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "TRUST_PROXY_HEADERS={} is not a boolean, using false",
+                        v
+                    ));
+                    false
+                }
+            },
+        };
+
+        let empty_ua_is_bot = match get("EMPTY_UA_IS_BOT").filter(|v| !v.is_empty()) {
+            None => false,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "EMPTY_UA_IS_BOT={} is not a boolean, using false",
+                        v
+                    ));
+                    false
+                }
+            },
+        };
+
+        let require_challenge = match get("REQUIRE_CHALLENGE").filter(|v| !v.is_empty()) {
+            None => false,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "REQUIRE_CHALLENGE={} is not a boolean, using false",
+                        v
+                    ));
+                    false
+                }
+            },
+        };
+
+        let anomaly_threshold = match get("ANOMALY_THRESHOLD").filter(|v| !v.is_empty()) {
+            None => 0.0,
+            Some(v) => match v.parse::<f64>() {
+                Ok(t) if (0.0..=1.0).contains(&t) => t,
+                _ => {
+                    warnings.push(format!(
+                        "ANOMALY_THRESHOLD={} is not a fraction between 0 and 1, anomaly detection is off",
+                        v
+                    ));
+                    0.0
+                }
+            },
+        };
+
+        let mut header_name = |var: &str, default: &str| match get(var).filter(|v| !v.is_empty()) {
+            None => default.to_string(),
+            Some(v) => match axum::http::HeaderName::try_from(v.as_str()) {
+                Ok(name) => name.as_str().to_string(),
+                Err(_) => {
+                    warnings.push(format!(
+                        "{}={} is not a valid header name, using {}",
+                        var, v, default
+                    ));
+                    default.to_string()
+                }
+            },
+        };
+        let header_referer = header_name("HEADER_REFERER", "x-bsz-referer");
+        let header_key = header_name("HEADER_KEY", "x-bsz-key");
+        let header_nonce = header_name("HEADER_NONCE", "x-bsz-nonce");
+        let header_identity = header_name("HEADER_IDENTITY", "x-bsz-identity");
+        let cors_expose_headers = parse_list(&get("CORS_EXPOSE_HEADERS").unwrap_or_default())
+            .into_iter()
+            .filter_map(|v| match axum::http::HeaderName::try_from(v.as_str()) {
+                Ok(name) if name == axum::http::header::SET_COOKIE => {
+                    warnings.push(
+                        "CORS_EXPOSE_HEADERS: browsers never let scripts read Set-Cookie, ignored"
+                            .to_string(),
+                    );
+                    None
+                }
+                Ok(name) => Some(name.as_str().to_string()),
+                Err(_) => {
+                    warnings.push(format!(
+                        "CORS_EXPOSE_HEADERS: `{}` is not a valid header name, ignored",
+                        v
+                    ));
+                    None
+                }
+            })
+            .collect();
+
+        let identity_cookie = match get("IDENTITY_COOKIE").filter(|v| !v.is_empty()) {
+            None => true,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "IDENTITY_COOKIE={} is not a boolean, using true",
+                        v
+                    ));
+                    true
+                }
+            },
+        };
+
+        let cors_allow_credentials = match get("CORS_ALLOW_CREDENTIALS").filter(|v| !v.is_empty()) {
+            None => true,
+            Some(v) => match parse_bool(&v) {
+                Some(b) => b,
+                None => {
+                    warnings.push(format!(
+                        "CORS_ALLOW_CREDENTIALS={} is not a boolean, using true",
+                        v
+                    ));
+                    true
+                }
+            },
+        };
+
+        let db_path = match parse_database_url(&get("DATABASE_URL").unwrap_or_default()) {
+            Ok(path) => path,
+            Err(e) => {
+                errors.push(format!("DATABASE_URL: {}", e));
+                String::new()
+            }
+        };
+
+        let trusted_proxies = match parse_ip_list(
+            &get("TRUSTED_PROXIES")
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_TRUSTED_PROXIES.to_string()),
+        ) {
+            Ok(list) => list,
+            Err(e) => {
+                errors.push(format!("TRUSTED_PROXIES: {}", e));
+                Vec::new()
+            }
+        };
+
+        let admin_ip_allowlist = match parse_ip_list(&get("ADMIN_IP_ALLOWLIST").unwrap_or_default())
+        {
+            Ok(list) => list,
+            Err(e) => {
+                errors.push(format!("ADMIN_IP_ALLOWLIST: {}", e));
+                Vec::new()
+            }
+        };
+
+        let mut config = Config {
+            web_addr: format!("0.0.0.0:{}", port),
+            db_path,
+            admin_tokens: parse_admin_tokens(
+                &get("ADMIN_TOKEN").unwrap_or_default(),
+                &get("ADMIN_TOKENS").unwrap_or_default(),
+            ),
+            admin_readonly_tokens: parse_list(&get("ADMIN_READONLY_TOKENS").unwrap_or_default()),
+            admin_token_hash: get("ADMIN_TOKEN_HASH").unwrap_or_default(),
+            admin_api,
+            max_body_size: size(
+                get("MAX_BODY_SIZE"),
+                "MAX_BODY_SIZE",
+                100 * 1024 * 1024,
+                &mut errors,
+            ),
+            api_max_body_size: size(
+                get("API_MAX_BODY_SIZE"),
+                "API_MAX_BODY_SIZE",
+                8 * 1024,
+                &mut errors,
+            ),
+            bsz_secret: get("BSZ_SECRET").unwrap_or_default(),
+            bsz_secret_previous: get("BSZ_SECRET_PREVIOUS").unwrap_or_default(),
+            bsz_encrypt,
+            bsz_path_style,
+            bsz_strip_www,
+            bsz_site_from_path_segments: number(
+                get("BSZ_SITE_FROM_PATH_SEGMENTS"),
+                "BSZ_SITE_FROM_PATH_SEGMENTS",
+                0,
+                &mut errors,
+            ),
+            bsz_count_root_page,
+            bsz_root_paths: parse_list(&get("BSZ_ROOT_PATHS").unwrap_or_else(|| "/".to_string()))
+                .into_iter()
+                .map(|p| {
+                    if p.starts_with('/') {
+                        p
+                    } else {
+                        format!("/{}", p)
+                    }
+                })
+                .collect(),
+            uv_scope,
+            identity_mode,
+            header_referer,
+            header_key,
+            header_nonce,
+            header_identity,
+            identity_cookie,
+            cors_expose_headers,
+            cors_allow_credentials,
+            disable_2fa,
+            lockout_sweep_interval: number(
+                get("LOCKOUT_SWEEP_INTERVAL"),
+                "LOCKOUT_SWEEP_INTERVAL",
+                60,
+                &mut errors,
+            )
+            .max(1),
+            max_tracked_failures: number(
+                get("MAX_TRACKED_FAILURES"),
+                "MAX_TRACKED_FAILURES",
+                10_000,
+                &mut errors,
+            ),
+            tls_cert: get("TLS_CERT_PATH")
+                .filter(|path| !path.is_empty())
+                .or_else(|| get("TLS_CERT"))
+                .unwrap_or_default(),
+            tls_key: get("TLS_KEY_PATH")
+                .filter(|path| !path.is_empty())
+                .or_else(|| get("TLS_KEY"))
+                .unwrap_or_default(),
+            trust_proxy_headers,
+            proxy_header_precedence,
+            trusted_proxies,
+            log_format,
+            log_file: get("LOG_FILE").unwrap_or_default(),
+            runtime: RuntimeConfig {
+                save_interval: number(get("SAVE_INTERVAL"), "SAVE_INTERVAL", 30, &mut errors),
+                slow_save_threshold_ms: number(
+                    get("SLOW_SAVE_THRESHOLD_MS"),
+                    "SLOW_SAVE_THRESHOLD_MS",
+                    1000,
+                    &mut errors,
+                ),
+                save_every_n_writes: number(
+                    get("SAVE_EVERY_N_WRITES"),
+                    "SAVE_EVERY_N_WRITES",
+                    0,
+                    &mut errors,
+                ),
+                export_save_timeout_secs: number(
+                    get("EXPORT_SAVE_TIMEOUT_SECS"),
+                    "EXPORT_SAVE_TIMEOUT_SECS",
+                    60,
+                    &mut errors,
+                ),
+                cors: get("CORS")
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| "*".to_string()),
+                admin_cors: get("ADMIN_CORS").unwrap_or_default(),
+                rate_limit_per_minute: number(
+                    get("RATE_LIMIT_PER_MINUTE"),
+                    "RATE_LIMIT_PER_MINUTE",
+                    60,
+                    &mut errors,
+                ),
+                admin_rate_limit_per_minute: number(
+                    get("ADMIN_RATE_LIMIT_PER_MINUTE"),
+                    "ADMIN_RATE_LIMIT_PER_MINUTE",
+                    120,
+                    &mut errors,
+                ),
+                rate_limit_exempt: parse_list(
+                    &get("RATE_LIMIT_EXEMPT").unwrap_or_else(|| "127.0.0.1,::1".to_string()),
+                ),
+                page_size_keys: number(get("PAGE_SIZE_KEYS"), "PAGE_SIZE_KEYS", 20, &mut errors),
+                page_size_pages: number(get("PAGE_SIZE_PAGES"), "PAGE_SIZE_PAGES", 50, &mut errors),
+                page_size_logs: number(get("PAGE_SIZE_LOGS"), "PAGE_SIZE_LOGS", 20, &mut errors),
+                page_size_max: number(get("PAGE_SIZE_MAX"), "PAGE_SIZE_MAX", 1000, &mut errors)
+                    .max(1),
+                pages_cache_secs: number(
+                    get("PAGES_CACHE_SECS"),
+                    "PAGES_CACHE_SECS",
+                    60,
+                    &mut errors,
+                ),
+                empty_ua_is_bot,
+                require_challenge,
+                anomaly_threshold,
+                anomaly_window: number(get("ANOMALY_WINDOW"), "ANOMALY_WINDOW", 100, &mut errors)
+                    .max(1),
+                max_new_sites_per_ip_per_hour: number(
+                    get("MAX_NEW_SITES_PER_IP_PER_HOUR"),
+                    "MAX_NEW_SITES_PER_IP_PER_HOUR",
+                    10,
+                    &mut errors,
+                ),
+                max_pages_per_site: number(
+                    get("MAX_PAGES_PER_SITE"),
+                    "MAX_PAGES_PER_SITE",
+                    20_000,
+                    &mut errors,
+                ),
+            },
+            admin_ip_allowlist,
+            warnings: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        let readonly_count = config.admin_readonly_tokens.len();
+        let full_tokens = config.admin_tokens.clone();
+        config
+            .admin_readonly_tokens
+            .retain(|t| !full_tokens.contains(t));
+        if config.admin_readonly_tokens.len() < readonly_count {
+            warnings.push(
+                "A token is in both ADMIN_TOKENS and ADMIN_READONLY_TOKENS: it keeps full access"
+                    .to_string(),
+            );
+        }
+        config.validate(dev, &mut warnings, &mut errors);
+
+        Config {
+            warnings,
+            errors,
+            ..config
+        }
+    }
+
+    /// Checks across settings, run once everything is parsed. Problems that would
+    /// only show up later as odd behaviour are errors; risky but working setups
+    /// are warnings.
+    fn validate(&self, dev: bool, warnings: &mut Vec<String>, errors: &mut Vec<String>) {
+        if self.runtime.save_interval == 0 {
+            errors.push("SAVE_INTERVAL must be at least 1 (seconds)".to_string());
+        }
+        for (var, setting) in [
+            ("CORS", &self.runtime.cors),
+            ("ADMIN_CORS", &self.runtime.admin_cors),
+        ] {
+            let (rules, invalid) = crate::middleware::cors::parse_rules(setting);
+            for entry in invalid {
+                errors.push(format!("{}: `{}` is not a valid origin", var, entry));
+            }
+            if var == "ADMIN_CORS" && rules.contains(&crate::middleware::cors::OriginRule::Any) {
+                warnings.push(
+                    "ADMIN_CORS=*: any website can call the admin API from a browser holding a token"
+                        .to_string(),
+                );
+            }
+            // Allowed on purpose: embedding the counter on any site is the point of
+            // the public API, and the only credential it reads is busuanziId.
+            if var == "CORS"
+                && self.cors_allow_credentials
+                && rules.contains(&crate::middleware::cors::OriginRule::Any)
+            {
+                warnings.push(
+                    "CORS=* with CORS_ALLOW_CREDENTIALS=true: any website can send the busuanziId cookie to the public API; list the origins in CORS or set CORS_ALLOW_CREDENTIALS=false to restrict it"
+                        .to_string(),
+                );
+            }
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            errors.push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+        }
+        if self.bsz_secret.is_empty() {
+            warnings.push(
+                "BSZ_SECRET is empty: visitor identities are plain MD5(IP+UA) and can be forged by anyone"
+                    .to_string(),
+            );
+        }
+        if !self.bsz_secret_previous.is_empty() && self.bsz_secret_previous == self.bsz_secret {
+            warnings.push("BSZ_SECRET_PREVIOUS is the same as BSZ_SECRET".to_string());
+        }
+        if self.runtime.require_challenge && self.bsz_secret.is_empty() {
+            warnings.push(
+                "REQUIRE_CHALLENGE is set but BSZ_SECRET is empty: anyone can sign their own nonces"
+                    .to_string(),
+            );
+        }
+        if !self.admin_enabled() && self.admin_api && !dev {
+            warnings.push("ADMIN_TOKEN is not set: admin API is disabled".to_string());
+        }
+        if !self.admin_token_hash.is_empty() {
+            if !self.admin_token_hash.starts_with("$argon2")
+                && !self.admin_token_hash.starts_with("$2")
+            {
+                warnings.push(
+                    "ADMIN_TOKEN_HASH is neither an argon2 nor a bcrypt hash, no token will match it"
+                        .to_string(),
+                );
+            }
+            if !self.admin_tokens.is_empty() {
+                warnings.push(
+                    "Both ADMIN_TOKEN(S) and ADMIN_TOKEN_HASH are set: the plaintext tokens are accepted too"
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    /// Whether `ip` (as resolved from the request) may reach the admin API
+    pub fn admin_ip_allowed(&self, ip: &str) -> bool {
+        if self.admin_ip_allowlist.is_empty() {
+            return true;
+        }
+        match ip.parse::<IpAddr>() {
+            Ok(addr) => {
+                let addr = addr.to_canonical();
+                self.admin_ip_allowlist
+                    .iter()
+                    .any(|net| net.contains(&addr))
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `ip` is a reverse proxy whose forwarding headers can be believed
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether HTTPS is served directly (TLS_CERT and TLS_KEY)
+    pub fn tls_enabled(&self) -> bool {
+        !self.tls_cert.is_empty() && !self.tls_key.is_empty()
+    }
+
+    /// Whether any admin credential is configured
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_api
+            && (!self.admin_tokens.is_empty()
+                || !self.admin_readonly_tokens.is_empty()
+                || !self.admin_token_hash.is_empty())
+    }
+}
+
+/// Database file of a DATABASE_URL: `sqlite://path`, `sqlite:path` or a bare path,
+/// `data.db` when empty. Other schemes are refused; SQLite is the only backend.
+fn parse_database_url(url: &str) -> Result<String, String> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok("data.db".to_string());
+    }
+    let path = match url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+    {
+        Some(path) => path,
+        // Only the scheme is echoed back: the rest may hold a password
+        None => match url.split_once("://") {
+            Some((scheme, _)) => {
+                return Err(format!("{} is not supported, only sqlite", scheme));
+            }
+            None => url,
+        },
+    };
+    if path.is_empty() {
+        return Err("no database file given".to_string());
+    }
+    Ok(path.to_string())
+}
+
+/// ADMIN_TOKEN (kept for compatibility) merged with the comma-separated ADMIN_TOKENS
+fn parse_admin_tokens(single: &str, list: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for token in std::iter::once(single.to_string()).chain(parse_list(list)) {
+        if !token.is_empty() && !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// A numeric variable: `default` when unset or empty, an error naming the
+/// variable when it doesn't parse
+fn number<T: FromStr>(value: Option<String>, var: &str, default: T, errors: &mut Vec<String>) -> T {
+    match value.filter(|v| !v.is_empty()) {
+        None => default,
+        Some(v) => v.parse().unwrap_or_else(|_| {
+            errors.push(format!("{}={} is not a valid number", var, v));
+            default
+        }),
+    }
+}
+
+/// A size variable (`10MB`, `512KB`, bytes), like [`number`]
+fn size(value: Option<String>, var: &str, default: usize, errors: &mut Vec<String>) -> usize {
+    match value.filter(|v| !v.is_empty()) {
+        None => default,
+        Some(v) => parse_size(&v).unwrap_or_else(|| {
+            errors.push(format!("{}={} is not a size such as 10MB or 512KB", var, v));
+            default
+        }),
+    }
+}
+
+/// Split a comma-separated list, dropping empty entries
+fn parse_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// TRUSTED_PROXIES when unset: loopback and private networks, where a reverse
+/// proxy on the same host or in the same Docker network connects from
+pub const DEFAULT_TRUSTED_PROXIES: &str =
+    "127.0.0.0/8,::1/128,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7";
+
+/// Parse a comma-separated list of IPs and CIDR ranges (v4 or v6).
+/// A bare IP is a single-address range.
+fn parse_ip_list(s: &str) -> Result<Vec<IpNet>, String> {
+    parse_list(s)
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("`{}` is not an IP address or CIDR range", entry))
+        })
+        .collect()
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
 
 /// Parse human-readable size string, e.g. "100MB", "1GB", "512KB", or plain bytes "10485760"
 fn parse_size(s: &str) -> Option<usize> {
@@ -50,3 +957,551 @@ fn parse_size(s: &str) -> Option<usize> {
     };
     num.parse::<usize>().ok().map(|n| n * multiplier)
 }
+
+/// Format a byte count the way MAX_BODY_SIZE is usually written, e.g. "100MB"
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [(usize, &str); 3] = [
+        (1024 * 1024 * 1024, "GB"),
+        (1024 * 1024, "MB"),
+        (1024, "KB"),
+    ];
+    for (unit, suffix) in UNITS {
+        if bytes >= unit && bytes.is_multiple_of(unit) {
+            return format!("{}{}", bytes / unit, suffix);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)], dev: bool) -> Config {
+        let map: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::load(|key| map.get(key).cloned(), dev)
+    }
+
+    #[test]
+    fn defaults() {
+        let c = config(&[], true);
+        assert_eq!(c.web_addr, "0.0.0.0:12700");
+        assert_eq!(c.db_path, "data.db");
+        assert!(c.admin_tokens.is_empty());
+        assert!(c.admin_readonly_tokens.is_empty());
+        assert!(!c.admin_enabled());
+        assert_eq!(c.runtime.save_interval, 30);
+        assert_eq!(c.runtime.slow_save_threshold_ms, 1000);
+        assert_eq!(c.runtime.export_save_timeout_secs, 60);
+        assert_eq!(c.runtime.save_every_n_writes, 0);
+        assert_eq!(c.max_body_size, 100 * 1024 * 1024);
+        assert_eq!(c.api_max_body_size, 8 * 1024);
+        assert_eq!(c.bsz_secret, "");
+        assert_eq!(c.bsz_secret_previous, "");
+        assert_eq!(c.bsz_encrypt, Encrypt::None);
+        assert!(c.bsz_path_style);
+        assert!(!c.bsz_strip_www);
+        assert_eq!(c.bsz_site_from_path_segments, 0);
+        assert!(c.bsz_count_root_page);
+        assert_eq!(c.bsz_root_paths, vec!["/"]);
+        assert_eq!(c.uv_scope, UvScope::Site);
+        assert_eq!(c.identity_mode, IdentityMode::IpUa);
+        assert_eq!(c.runtime.cors, "*");
+        assert_eq!(c.runtime.admin_cors, "");
+        assert!(c.cors_allow_credentials);
+        assert!(c
+            .warnings
+            .iter()
+            .any(|w| w.contains("CORS_ALLOW_CREDENTIALS=true")));
+        assert_eq!(c.header_referer, "x-bsz-referer");
+        assert_eq!(c.header_key, "x-bsz-key");
+        assert_eq!(c.header_nonce, "x-bsz-nonce");
+        assert_eq!(c.header_identity, "x-bsz-identity");
+        assert!(c.identity_cookie);
+        assert!(c.cors_expose_headers.is_empty());
+        assert_eq!(c.runtime.rate_limit_per_minute, 60);
+        assert_eq!(c.runtime.admin_rate_limit_per_minute, 120);
+        assert_eq!(c.runtime.rate_limit_exempt, vec!["127.0.0.1", "::1"]);
+        assert_eq!(c.runtime.page_size_keys, 20);
+        assert_eq!(c.runtime.page_size_pages, 50);
+        assert_eq!(c.runtime.page_size_logs, 20);
+        assert_eq!(c.runtime.page_size_max, 1000);
+        assert_eq!(c.runtime.pages_cache_secs, 60);
+        assert!(!c.disable_2fa);
+        assert_eq!(c.lockout_sweep_interval, 60);
+        assert_eq!(c.max_tracked_failures, 10_000);
+        assert_eq!(c.log_format, LogFormat::Pretty);
+        assert!(c.log_file.is_empty());
+        assert!(!c.tls_enabled());
+        assert!(!c.trust_proxy_headers);
+        assert_eq!(c.proxy_header_precedence, ProxyHeader::XForwardedFor);
+        assert!(!c.runtime.empty_ua_is_bot);
+        assert!(!c.runtime.require_challenge);
+        assert_eq!(c.runtime.anomaly_threshold, 0.0);
+        assert_eq!(c.runtime.anomaly_window, 100);
+        assert_eq!(c.runtime.max_new_sites_per_ip_per_hour, 10);
+        assert_eq!(c.runtime.max_pages_per_site, 20_000);
+        assert!(c.admin_ip_allowlist.is_empty());
+        assert!(c.admin_ip_allowed("203.0.113.9"));
+        assert!(c.errors.is_empty());
+    }
+
+    #[test]
+    fn reads_all_fields() {
+        let c = config(
+            &[
+                ("PORT", "8080"),
+                ("DATABASE_URL", "sqlite:///var/lib/bsz/data.db"),
+                ("ADMIN_TOKEN", "tok"),
+                ("SAVE_INTERVAL", "5"),
+                ("SAVE_EVERY_N_WRITES", "1000"),
+                ("SLOW_SAVE_THRESHOLD_MS", "250"),
+                ("EXPORT_SAVE_TIMEOUT_SECS", "5"),
+                ("MAX_BODY_SIZE", "2MB"),
+                ("API_MAX_BODY_SIZE", "1KB"),
+                ("BSZ_SECRET", "s3cret"),
+                ("BSZ_SECRET_PREVIOUS", "old"),
+                ("BSZ_ENCRYPT", "md516"),
+                ("BSZ_PATH_STYLE", "false"),
+                ("BSZ_STRIP_WWW", "yes"),
+                ("BSZ_SITE_FROM_PATH_SEGMENTS", "1"),
+                ("BSZ_COUNT_ROOT_PAGE", "false"),
+                ("BSZ_ROOT_PATHS", "/, index.html"),
+                ("UV_SCOPE", "Both"),
+                ("IDENTITY_MODE", "ip_ua_salted_daily"),
+                ("CORS", "https://a.com,https://b.com"),
+                ("ADMIN_CORS", "https://dash.a.com"),
+                ("CORS_ALLOW_CREDENTIALS", "false"),
+                ("HEADER_REFERER", "X-Page-Url"),
+                ("HEADER_KEY", "x-site-key"),
+                ("HEADER_NONCE", "x-site-nonce"),
+                ("HEADER_IDENTITY", "X-Site-Visitor"),
+                ("IDENTITY_COOKIE", "false"),
+                ("CORS_EXPOSE_HEADERS", "Server-Timing, x-cache"),
+                ("RATE_LIMIT_PER_MINUTE", "0"),
+                ("ADMIN_RATE_LIMIT_PER_MINUTE", "30"),
+                ("RATE_LIMIT_EXEMPT", "10.0.0.1, ,10.0.0.2"),
+                ("PAGE_SIZE_KEYS", "10"),
+                ("PAGE_SIZE_PAGES", "30"),
+                ("PAGE_SIZE_LOGS", "15"),
+                ("PAGE_SIZE_MAX", "200"),
+                ("PAGES_CACHE_SECS", "0"),
+                ("TRUST_PROXY_HEADERS", "yes"),
+                ("PROXY_HEADER_PRECEDENCE", "Forwarded"),
+                ("EMPTY_UA_IS_BOT", "on"),
+                ("REQUIRE_CHALLENGE", "true"),
+                ("ANOMALY_THRESHOLD", "0.3"),
+                ("ANOMALY_WINDOW", "500"),
+                ("MAX_NEW_SITES_PER_IP_PER_HOUR", "0"),
+                ("MAX_PAGES_PER_SITE", "500"),
+                ("LOCKOUT_SWEEP_INTERVAL", "15"),
+                ("MAX_TRACKED_FAILURES", "0"),
+                ("LOG_FORMAT", "JSON"),
+                ("LOG_FILE", "/var/log/bsz/bsz.log"),
+            ],
+            false,
+        );
+        assert_eq!(c.web_addr, "0.0.0.0:8080");
+        assert_eq!(c.db_path, "/var/lib/bsz/data.db");
+        assert_eq!(c.admin_tokens, vec!["tok"]);
+        assert!(c.admin_enabled());
+        assert_eq!(c.runtime.save_interval, 5);
+        assert_eq!(c.runtime.save_every_n_writes, 1000);
+        assert_eq!(c.runtime.slow_save_threshold_ms, 250);
+        assert_eq!(c.runtime.export_save_timeout_secs, 5);
+        assert_eq!(c.max_body_size, 2 * 1024 * 1024);
+        assert_eq!(c.api_max_body_size, 1024);
+        assert_eq!(c.bsz_secret, "s3cret");
+        assert_eq!(c.bsz_secret_previous, "old");
+        assert_eq!(c.bsz_encrypt, Encrypt::Md516);
+        assert!(!c.bsz_path_style);
+        assert!(c.bsz_strip_www);
+        assert_eq!(c.bsz_site_from_path_segments, 1);
+        assert!(!c.bsz_count_root_page);
+        assert_eq!(c.bsz_root_paths, vec!["/", "/index.html"]);
+        assert_eq!(c.uv_scope, UvScope::Both);
+        assert!(c.uv_scope.tracks_site() && c.uv_scope.tracks_page());
+        assert_eq!(c.identity_mode, IdentityMode::IpUaSaltedDaily);
+        assert_eq!(c.runtime.cors, "https://a.com,https://b.com");
+        assert_eq!(c.runtime.admin_cors, "https://dash.a.com");
+        assert!(!c.cors_allow_credentials);
+        assert_eq!(c.header_referer, "x-page-url");
+        assert_eq!(c.header_key, "x-site-key");
+        assert_eq!(c.header_nonce, "x-site-nonce");
+        assert_eq!(c.header_identity, "x-site-visitor");
+        assert!(!c.identity_cookie);
+        assert_eq!(c.cors_expose_headers, vec!["server-timing", "x-cache"]);
+        assert_eq!(c.runtime.rate_limit_per_minute, 0);
+        assert_eq!(c.runtime.admin_rate_limit_per_minute, 30);
+        assert_eq!(c.runtime.rate_limit_exempt, vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(c.runtime.page_size_keys, 10);
+        assert_eq!(c.runtime.page_size_pages, 30);
+        assert_eq!(c.runtime.page_size_logs, 15);
+        assert_eq!(c.runtime.page_size_max, 200);
+        assert_eq!(c.runtime.pages_cache_secs, 0);
+        assert!(c.trust_proxy_headers);
+        assert_eq!(c.proxy_header_precedence, ProxyHeader::Forwarded);
+        assert!(c.runtime.empty_ua_is_bot);
+        assert!(c.runtime.require_challenge);
+        assert_eq!(c.runtime.anomaly_threshold, 0.3);
+        assert_eq!(c.runtime.anomaly_window, 500);
+        assert_eq!(c.runtime.max_new_sites_per_ip_per_hour, 0);
+        assert_eq!(c.runtime.max_pages_per_site, 500);
+        assert_eq!(c.lockout_sweep_interval, 15);
+        assert_eq!(c.max_tracked_failures, 0);
+        assert_eq!(c.log_format, LogFormat::Json);
+        assert_eq!(c.log_file, "/var/log/bsz/bsz.log");
+        assert!(c.warnings.is_empty(), "{:?}", c.warnings);
+    }
+
+    #[test]
+    fn invalid_encrypt_falls_back_to_plaintext() {
+        let c = config(&[("BSZ_ENCRYPT", "sha1"), ("BSZ_SECRET", "x")], true);
+        assert_eq!(c.bsz_encrypt, Encrypt::None);
+        assert!(c.warnings.iter().any(|w| w.contains("BSZ_ENCRYPT")));
+    }
+
+    #[test]
+    fn invalid_header_name_keeps_default() {
+        let c = config(
+            &[("HEADER_REFERER", "x bsz referer"), ("BSZ_SECRET", "x")],
+            true,
+        );
+        assert_eq!(c.header_referer, "x-bsz-referer");
+        assert!(c.warnings.iter().any(|w| w.contains("HEADER_REFERER")));
+
+        let c = config(
+            &[
+                ("CORS_EXPOSE_HEADERS", "bad header,x-ok,Set-Cookie"),
+                ("BSZ_SECRET", "x"),
+            ],
+            true,
+        );
+        assert_eq!(c.cors_expose_headers, vec!["x-ok"]);
+        assert!(c.warnings.iter().any(|w| w.contains("`bad header`")));
+        assert!(c.warnings.iter().any(|w| w.contains("Set-Cookie")));
+    }
+
+    #[test]
+    fn invalid_origin_is_an_error() {
+        let c = config(
+            &[("CORS", "https://a.com, a.com"), ("BSZ_SECRET", "x")],
+            true,
+        );
+        assert!(c.errors.iter().any(|e| e.contains("CORS: `a.com`")));
+        let c = config(&[("ADMIN_CORS", "https://*"), ("BSZ_SECRET", "x")], true);
+        assert!(c.errors.iter().any(|e| e.contains("ADMIN_CORS")));
+    }
+
+    #[test]
+    fn wildcard_cors_with_credentials_warns() {
+        for (cors, credentials, warns) in [
+            ("*", "true", true),
+            ("*", "false", false),
+            ("https://a.com, *", "true", true),
+            ("https://a.com", "true", false),
+            ("*.a.com", "true", false),
+            ("https://a.com", "false", false),
+        ] {
+            let c = config(
+                &[
+                    ("BSZ_SECRET", "x"),
+                    ("CORS", cors),
+                    ("CORS_ALLOW_CREDENTIALS", credentials),
+                ],
+                true,
+            );
+            assert!(c.errors.is_empty(), "{:?}", c.errors);
+            assert_eq!(
+                c.warnings
+                    .iter()
+                    .any(|w| w.contains("CORS_ALLOW_CREDENTIALS=true")),
+                warns,
+                "CORS={} CORS_ALLOW_CREDENTIALS={}: {:?}",
+                cors,
+                credentials,
+                c.warnings
+            );
+        }
+    }
+
+    #[test]
+    fn database_url_forms() {
+        assert_eq!(parse_database_url("sqlite:bsz.db").unwrap(), "bsz.db");
+        assert_eq!(
+            parse_database_url("./data/bsz.db").unwrap(),
+            "./data/bsz.db"
+        );
+        assert!(parse_database_url("sqlite://").is_err());
+        let err = parse_database_url("postgres://bsz:hunter2@db/bsz").unwrap_err();
+        assert!(err.starts_with("postgres"), "{}", err);
+        assert!(!err.contains("hunter2"));
+    }
+
+    #[test]
+    fn warns_on_empty_secret() {
+        let c = config(
+            &[("ADMIN_TOKEN", "tok"), ("CORS_ALLOW_CREDENTIALS", "false")],
+            false,
+        );
+        assert_eq!(c.warnings.len(), 1);
+        assert!(c.warnings[0].contains("BSZ_SECRET"));
+    }
+
+    #[test]
+    fn warns_on_missing_admin_token_outside_dev() {
+        let prod = config(&[("BSZ_SECRET", "x")], false);
+        assert!(prod.warnings.iter().any(|w| w.contains("ADMIN_TOKEN")));
+
+        let dev = config(
+            &[("BSZ_SECRET", "x"), ("CORS_ALLOW_CREDENTIALS", "false")],
+            true,
+        );
+        assert!(dev.warnings.is_empty());
+    }
+
+    #[test]
+    fn admin_enabled_false_wins_over_tokens() {
+        let c = config(
+            &[
+                ("BSZ_SECRET", "x"),
+                ("ADMIN_TOKEN", "t"),
+                ("ADMIN_ENABLED", "false"),
+                ("CORS_ALLOW_CREDENTIALS", "false"),
+            ],
+            false,
+        );
+        assert!(!c.admin_enabled());
+        assert!(c.warnings.is_empty(), "{:?}", c.warnings);
+
+        let c = config(&[("BSZ_SECRET", "x"), ("ADMIN_TOKEN", "t")], false);
+        assert!(c.admin_api && c.admin_enabled());
+
+        // An unreadable value stops startup instead of guessing
+        let c = config(&[("ADMIN_TOKEN", "t"), ("ADMIN_ENABLED", "nope")], true);
+        assert!(!c.admin_enabled());
+        assert!(c.errors.iter().any(|e| e.contains("ADMIN_ENABLED")));
+    }
+
+    #[test]
+    fn token_hash_enables_admin() {
+        let c = config(
+            &[
+                ("BSZ_SECRET", "x"),
+                (
+                    "ADMIN_TOKEN_HASH",
+                    "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA",
+                ),
+                ("CORS_ALLOW_CREDENTIALS", "false"),
+            ],
+            false,
+        );
+        assert!(c.admin_enabled());
+        assert!(c.warnings.is_empty(), "{:?}", c.warnings);
+
+        let bad = config(&[("BSZ_SECRET", "x"), ("ADMIN_TOKEN_HASH", "plain")], false);
+        assert!(bad.warnings.iter().any(|w| w.contains("ADMIN_TOKEN_HASH")));
+    }
+
+    #[test]
+    fn clamps_page_sizes() {
+        let c = config(&[("PAGE_SIZE_MAX", "100")], true);
+        assert_eq!(c.runtime.page_size(None, 20), 20);
+        assert_eq!(c.runtime.page_size(Some(0), 20), 1);
+        assert_eq!(c.runtime.page_size(Some(1_000_000), 20), 100);
+        assert_eq!(
+            config(&[("PAGE_SIZE_MAX", "0")], true)
+                .runtime
+                .page_size_max,
+            1
+        );
+    }
+
+    #[test]
+    fn admin_ip_allowlist_boundaries() {
+        let c = config(
+            &[(
+                "ADMIN_IP_ALLOWLIST",
+                "192.168.1.0/24, 10.0.0.7, 2001:db8::/32",
+            )],
+            true,
+        );
+        assert!(c.errors.is_empty(), "{:?}", c.errors);
+
+        assert!(c.admin_ip_allowed("192.168.1.0"));
+        assert!(c.admin_ip_allowed("192.168.1.255"));
+        assert!(!c.admin_ip_allowed("192.168.0.255"));
+        assert!(!c.admin_ip_allowed("192.168.2.0"));
+
+        assert!(c.admin_ip_allowed("10.0.0.7"));
+        assert!(!c.admin_ip_allowed("10.0.0.6"));
+        assert!(!c.admin_ip_allowed("10.0.0.8"));
+
+        assert!(c.admin_ip_allowed("2001:db8::"));
+        assert!(c.admin_ip_allowed("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert!(!c.admin_ip_allowed("2001:db9::"));
+
+        // IPv4-mapped IPv6 is matched as IPv4
+        assert!(c.admin_ip_allowed("::ffff:192.168.1.20"));
+        assert!(!c.admin_ip_allowed("unknown"));
+    }
+
+    #[test]
+    fn invalid_admin_ip_allowlist_is_fatal() {
+        let c = config(&[("ADMIN_IP_ALLOWLIST", "10.0.0.0/8,10.0.0.300")], true);
+        assert!(c.admin_ip_allowlist.is_empty());
+        assert_eq!(c.errors.len(), 1);
+        assert!(c.errors[0].contains("10.0.0.300"));
+
+        let c = config(&[("ADMIN_IP_ALLOWLIST", "10.0.0.0/33")], true);
+        assert_eq!(c.errors.len(), 1);
+    }
+
+    #[test]
+    fn trusted_proxies_default_to_private_networks() {
+        let c = config(&[], true);
+        assert!(c.errors.is_empty(), "{:?}", c.errors);
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.0.1",
+            "192.168.1.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(c.is_trusted_proxy(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["203.0.113.7", "172.32.0.1", "2001:db8::1"] {
+            assert!(!c.is_trusted_proxy(ip.parse().unwrap()), "{}", ip);
+        }
+        // IPv4-mapped IPv6 peers (dual-stack sockets) are matched as IPv4
+        assert!(c.is_trusted_proxy("::ffff:10.0.0.1".parse().unwrap()));
+
+        let c = config(&[("TRUSTED_PROXIES", "203.0.113.0/24")], true);
+        assert!(c.is_trusted_proxy("203.0.113.7".parse().unwrap()));
+        assert!(!c.is_trusted_proxy("127.0.0.1".parse().unwrap()));
+
+        let c = config(&[("TRUSTED_PROXIES", "10.0.0.0/8,nginx")], true);
+        assert_eq!(c.errors.len(), 1);
+        assert!(c.errors[0].contains("nginx"));
+    }
+
+    #[test]
+    fn tls_needs_cert_and_key() {
+        let c = config(&[("TLS_CERT", "/etc/bsz/cert.pem")], true);
+        assert!(!c.tls_enabled());
+        assert_eq!(c.errors.len(), 1);
+
+        let c = config(
+            &[
+                ("TLS_CERT", "/etc/bsz/cert.pem"),
+                ("TLS_KEY", "/etc/bsz/key.pem"),
+            ],
+            true,
+        );
+        assert!(c.tls_enabled());
+        assert!(c.errors.is_empty());
+
+        let c = config(
+            &[
+                ("TLS_CERT_PATH", "/etc/bsz/new.pem"),
+                ("TLS_CERT", "/etc/bsz/cert.pem"),
+                ("TLS_KEY_PATH", "/etc/bsz/key.pem"),
+            ],
+            true,
+        );
+        assert_eq!(c.tls_cert, "/etc/bsz/new.pem");
+        assert!(c.tls_enabled());
+    }
+
+    #[test]
+    fn merges_admin_tokens() {
+        let c = config(
+            &[("ADMIN_TOKEN", "old"), ("ADMIN_TOKENS", "alice, bob,,old")],
+            true,
+        );
+        assert_eq!(c.admin_tokens, vec!["old", "alice", "bob"]);
+
+        let c = config(&[("ADMIN_TOKENS", "alice")], false);
+        assert_eq!(c.admin_tokens, vec!["alice"]);
+        assert!(c.admin_enabled());
+    }
+
+    #[test]
+    fn readonly_tokens_never_shadow_full_ones() {
+        let c = config(
+            &[
+                ("ADMIN_TOKENS", "alice"),
+                ("ADMIN_READONLY_TOKENS", "carol, alice"),
+            ],
+            true,
+        );
+        assert_eq!(c.admin_readonly_tokens, vec!["carol"]);
+        assert!(c
+            .warnings
+            .iter()
+            .any(|w| w.contains("ADMIN_READONLY_TOKENS")));
+
+        let c = config(&[("ADMIN_READONLY_TOKENS", "carol")], false);
+        assert!(c.admin_enabled());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("10485760"), Some(10485760));
+        assert_eq!(parse_size("512kb"), Some(512 * 1024));
+        assert_eq!(parse_size("1 GB"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(100 * 1024 * 1024), "100MB");
+        assert_eq!(format_size(8 * 1024), "8KB");
+        assert_eq!(format_size(1500), "1500B");
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        for (var, value) in [
+            ("PORT", "abc"),
+            ("PORT", "0"),
+            ("PORT", "70000"),
+            ("SAVE_INTERVAL", "0"),
+            ("SAVE_INTERVAL", "soon"),
+            ("MAX_BODY_SIZE", "lots"),
+            ("API_MAX_BODY_SIZE", "1XB"),
+            ("RATE_LIMIT_PER_MINUTE", "-1"),
+            ("CORS", "not a url"),
+            ("TLS_CERT_PATH", "cert.pem"),
+        ] {
+            let c = config(&[(var, value)], true);
+            assert_eq!(c.errors.len(), 1, "{}={}: {:?}", var, value, c.errors);
+            assert!(c.errors[0].contains(var), "{}", c.errors[0]);
+        }
+
+        // Empty values fall back to defaults
+        let c = config(
+            &[("PORT", ""), ("SAVE_INTERVAL", ""), ("MAX_BODY_SIZE", "")],
+            true,
+        );
+        assert!(c.errors.is_empty(), "{:?}", c.errors);
+        assert_eq!(c.web_addr, "0.0.0.0:12700");
+
+        // Every problem is reported, not just the first
+        let c = config(
+            &[("PORT", "abc"), ("SAVE_INTERVAL", "0"), ("CORS", "?")],
+            true,
+        );
+        assert_eq!(c.errors.len(), 3, "{:?}", c.errors);
+
+        // Warnings alone don't stop startup
+        let c = config(&[], false);
+        assert!(!c.warnings.is_empty());
+        assert!(c.errors.is_empty());
+    }
+}
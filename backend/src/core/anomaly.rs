@@ -0,0 +1,141 @@
+//! Count-stuffing detection: flags a site when one visitor identity makes up more
+//! than ANOMALY_THRESHOLD of its last ANOMALY_WINDOW hits (off unless configured)
+
+use crate::config::runtime;
+use crate::state;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// At most one `anomaly` log entry per site in this interval
+const LOG_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Recent hits of one site
+#[derive(Default)]
+struct Window {
+    hits: VecDeque<u64>,
+    counts: HashMap<u64, usize>,
+    last_logged: Option<Instant>,
+}
+
+impl Window {
+    /// Record a hit by `visitor`; returns how many of the last `size` hits were theirs
+    /// once the window is full and that count exceeds `threshold * size`
+    fn push(&mut self, visitor: u64, size: usize, threshold: f64) -> Option<usize> {
+        self.hits.push_back(visitor);
+        *self.counts.entry(visitor).or_insert(0) += 1;
+        while self.hits.len() > size {
+            if let Some(old) = self.hits.pop_front() {
+                if let Some(n) = self.counts.get_mut(&old) {
+                    *n -= 1;
+                    if *n == 0 {
+                        self.counts.remove(&old);
+                    }
+                }
+            }
+        }
+        if self.hits.len() < size {
+            return None;
+        }
+        let n = self.counts.get(&visitor).copied().unwrap_or(0);
+        (n as f64 > threshold * size as f64).then_some(n)
+    }
+
+    fn should_log(&mut self, now: Instant) -> bool {
+        match self.last_logged {
+            Some(t) if now.duration_since(t) < LOG_INTERVAL => false,
+            _ => {
+                self.last_logged = Some(now);
+                true
+            }
+        }
+    }
+}
+
+static WINDOWS: Lazy<DashMap<String, Window>> = Lazy::new(DashMap::new);
+
+/// Whether ANOMALY_THRESHOLD turns detection on
+pub fn enabled() -> bool {
+    runtime().anomaly_threshold > 0.0
+}
+
+/// Feed one site hit; logs an `anomaly` entry when a single identity dominates the window
+pub fn observe(site_key: &str, visitor: u64, user_identity: &str) {
+    if !enabled() {
+        return;
+    }
+    let rt = runtime();
+    let size = rt.anomaly_window;
+    let flagged = {
+        let mut window = WINDOWS.entry(site_key.to_string()).or_default();
+        match window.push(visitor, size, rt.anomaly_threshold) {
+            Some(n) if window.should_log(Instant::now()) => Some(n),
+            _ => None,
+        }
+    };
+    // Log outside the map shard lock
+    if let Some(n) = flagged {
+        state::add_log(
+            "anomaly",
+            &format!(
+                "{}: visitor {} made {}/{} of recent hits",
+                site_key,
+                identity_digest(user_identity),
+                n,
+                size
+            ),
+            "",
+        );
+    }
+}
+
+/// Drop the window of a deleted or renamed site
+pub fn forget(site_key: &str) {
+    WINDOWS.remove(site_key);
+}
+
+/// Short hash of an identity for the audit log, so raw identities are never stored
+fn identity_digest(user_identity: &str) -> String {
+    hex::encode(&Sha256::digest(user_identity.as_bytes())[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_dominant_visitor_once_window_is_full() {
+        let mut w = Window::default();
+        for _ in 0..3 {
+            assert_eq!(w.push(1, 4, 0.5), None);
+        }
+        // 4/4 hits by visitor 1
+        assert_eq!(w.push(1, 4, 0.5), Some(4));
+        // 1,1,1,2 -> visitor 2 holds 1/4
+        assert_eq!(w.push(2, 4, 0.5), None);
+        assert_eq!(w.push(3, 4, 0.5), None);
+        // 1,2,3,1 -> exactly half is not over the threshold
+        assert_eq!(w.push(1, 4, 0.5), None);
+        assert_eq!(w.counts.len(), 3);
+        assert_eq!(w.hits.len(), 4);
+    }
+
+    #[test]
+    fn throttles_logging() {
+        let mut w = Window::default();
+        let now = Instant::now();
+        assert!(w.should_log(now));
+        assert!(!w.should_log(now + Duration::from_secs(60)));
+        assert!(w.should_log(now + LOG_INTERVAL));
+    }
+
+    #[test]
+    fn digest_hides_identity() {
+        let d = identity_digest("abc123");
+        assert_eq!(d.len(), 16);
+        assert!(!d.contains("abc123"));
+        assert_eq!(d, identity_digest("abc123"));
+    }
+}
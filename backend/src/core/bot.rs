@@ -0,0 +1,256 @@
+//! Crawler / prefetch detection for counting requests
+//!
+//! Built-in patterns plus operator-managed ones (`/api/admin/bots`, stored in
+//! `bot_patterns`), matched as lowercase User-Agent substrings. Each rule counts
+//! the requests it stopped since startup.
+
+use axum::http::{header, HeaderMap};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::config::runtime;
+use crate::state;
+
+/// Lowercase User-Agent substrings of well-known crawlers, previewers and HTTP libraries
+const BUILTIN_BOTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "embedly",
+    "preview",
+    "headlesschrome",
+    "lighthouse",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "go-http-client",
+];
+
+/// Longest custom pattern accepted
+const MAX_PATTERN_LEN: usize = 200;
+
+struct Rule {
+    pattern: String,
+    builtin: bool,
+    hits: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleInfo {
+    pub pattern: String,
+    pub builtin: bool,
+    pub hits: u64,
+}
+
+/// Custom rules first, so they get the hit when a built-in one would match too
+static RULES: Lazy<RwLock<Vec<Rule>>> = Lazy::new(|| RwLock::new(build(&[], &[])));
+
+/// Rule list for `custom`, carrying over hit counters from `old`
+fn build(custom: &[String], old: &[Rule]) -> Vec<Rule> {
+    let hits = |pattern: &str, builtin: bool| {
+        old.iter()
+            .find(|r| r.pattern == pattern && r.builtin == builtin)
+            .map(|r| r.hits.clone())
+            .unwrap_or_default()
+    };
+    let custom = custom.iter().map(|p| Rule {
+        pattern: p.clone(),
+        builtin: false,
+        hits: hits(p, false),
+    });
+    let builtin = BUILTIN_BOTS.iter().map(|p| Rule {
+        pattern: p.to_string(),
+        builtin: true,
+        hits: hits(p, true),
+    });
+    custom.chain(builtin).collect()
+}
+
+/// First rule matching a lowercased User-Agent
+fn find<'a>(rules: &'a [Rule], ua: &str, include_builtin: bool) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .filter(|r| include_builtin || !r.builtin)
+        .find(|r| ua.contains(r.pattern.as_str()))
+}
+
+fn matches(ua: &str, include_builtin: bool) -> bool {
+    let ua = ua.to_lowercase();
+    let rules = RULES.read().unwrap();
+    match find(&rules, &ua, include_builtin) {
+        Some(rule) => {
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+fn custom_patterns() -> Vec<String> {
+    RULES
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|r| !r.builtin)
+        .map(|r| r.pattern.clone())
+        .collect()
+}
+
+fn rebuild(custom: Vec<String>) {
+    let mut rules = RULES.write().unwrap();
+    *rules = build(&custom, &rules);
+}
+
+/// Read custom patterns on startup
+pub fn load() {
+    match state::load_bot_patterns() {
+        Ok(patterns) => rebuild(patterns),
+        Err(e) => tracing::warn!("Failed to load bot patterns: {}", e),
+    }
+}
+
+fn normalize(pattern: &str) -> Result<String, String> {
+    let pattern = pattern.trim().to_lowercase();
+    if pattern.is_empty() {
+        return Err("规则不能为空".to_string());
+    }
+    if pattern.len() > MAX_PATTERN_LEN {
+        return Err(format!("规则不能超过 {} 个字符", MAX_PATTERN_LEN));
+    }
+    Ok(pattern)
+}
+
+/// Add a custom pattern. Returns the stored (lowercased) form and whether it was new.
+pub fn add_pattern(pattern: &str) -> Result<(String, bool), String> {
+    let pattern = normalize(pattern)?;
+    let mut custom = custom_patterns();
+    if custom.contains(&pattern) {
+        return Ok((pattern, false));
+    }
+    state::set_bot_pattern(&pattern, true).map_err(|e| format!("保存失败: {}", e))?;
+    custom.push(pattern.clone());
+    custom.sort();
+    rebuild(custom);
+    Ok((pattern, true))
+}
+
+/// Remove a custom pattern. Returns false if there was no such pattern.
+pub fn remove_pattern(pattern: &str) -> Result<bool, String> {
+    let pattern = normalize(pattern)?;
+    let mut custom = custom_patterns();
+    let before = custom.len();
+    custom.retain(|p| *p != pattern);
+    if custom.len() == before {
+        return Ok(false);
+    }
+    state::set_bot_pattern(&pattern, false).map_err(|e| format!("保存失败: {}", e))?;
+    rebuild(custom);
+    Ok(true)
+}
+
+/// Every rule with its hit count
+pub fn rules() -> Vec<RuleInfo> {
+    RULES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|r| RuleInfo {
+            pattern: r.pattern.clone(),
+            builtin: r.builtin,
+            hits: r.hits.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Whether a User-Agent looks like a crawler rather than a person
+pub fn is_bot_ua(ua: &str) -> bool {
+    matches(ua, true)
+}
+
+fn user_agent(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+}
+
+fn empty_ua_is_bot(ua: &str) -> bool {
+    runtime().empty_ua_is_bot && ua.trim().is_empty()
+}
+
+/// For `/api`: only the operator's own patterns (and an empty User-Agent when
+/// EMPTY_UA_IS_BOT is set), so scripted clients like curl keep working
+pub fn is_listed_bot(headers: &HeaderMap) -> bool {
+    let ua = user_agent(headers);
+    empty_ua_is_bot(ua) || matches(ua, false)
+}
+
+/// Speculative loads announce themselves with `Purpose`/`Sec-Purpose`/`X-Moz: prefetch`
+pub fn is_prefetch(headers: &HeaderMap) -> bool {
+    ["purpose", "sec-purpose", "x-moz"].iter().any(|name| {
+        headers
+            .get(*name)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|v| v.to_lowercase().contains("prefetch"))
+    })
+}
+
+/// Whether a pixel request should be served without counting
+pub fn should_skip(headers: &HeaderMap) -> bool {
+    let ua = user_agent(headers);
+    empty_ua_is_bot(ua) || is_bot_ua(ua) || is_prefetch(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_crawlers() {
+        assert!(is_bot_ua(
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"
+        ));
+        assert!(is_bot_ua("curl/8.5.0"));
+        assert!(!is_bot_ua(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 Chrome/126.0 Safari/537.36"
+        ));
+    }
+
+    #[test]
+    fn custom_rules_match_first_and_keep_hits() {
+        let rules = build(&["uptimerobot".to_string()], &[]);
+        let ua = "mozilla/5.0+(compatible; uptimerobot/2.0; http://www.uptimerobot.com/)";
+        let rule = find(&rules, ua, true).unwrap();
+        assert_eq!(rule.pattern, "uptimerobot");
+        rule.hits.fetch_add(1, Ordering::Relaxed);
+
+        // Built-in rules are skipped for the API path
+        assert!(find(&rules, "curl/8.5.0", false).is_none());
+        assert!(find(&rules, "curl/8.5.0", true).unwrap().builtin);
+
+        // Rebuilding keeps counters of surviving rules
+        let rebuilt = build(&["uptimerobot".to_string(), "pingdom".to_string()], &rules);
+        let kept = rebuilt.iter().find(|r| r.pattern == "uptimerobot").unwrap();
+        assert_eq!(kept.hits.load(Ordering::Relaxed), 1);
+        assert!(find(&rebuilt, "pingdom.com_bot_version_1.4", false).is_some());
+    }
+
+    #[test]
+    fn normalizes_patterns() {
+        assert_eq!(normalize("  UptimeRobot ").unwrap(), "uptimerobot");
+        assert!(normalize("   ").is_err());
+        assert!(normalize(&"x".repeat(MAX_PATTERN_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn detects_prefetch() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_prefetch(&headers));
+        headers.insert("sec-purpose", "prefetch;prerender".parse().unwrap());
+        assert!(is_prefetch(&headers));
+    }
+}
@@ -0,0 +1,256 @@
+//! Signed nonce challenge for counting requests (`REQUIRE_CHALLENGE`)
+//!
+//! `GET /api/challenge?host=` hands out `{ts}.{salt}.{mac}` where mac is
+//! HMAC-SHA256(BSZ_SECRET, host + ts + salt). With the challenge required, a
+//! counting request only increments when it sends an unexpired nonce for its
+//! host in `x-bsz-nonce` (HEADER_NONCE), and each nonce counts once.
+//!
+//! Nonces are always signed with BSZ_SECRET. While BSZ_SECRET_PREVIOUS is set, ones
+//! signed with it are still accepted, and counted so the admin can tell when the old
+//! secret is no longer in use.
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{runtime, CONFIG};
+use crate::core::count::normalize_host;
+
+/// How long a nonce stays valid, in seconds
+pub const NONCE_TTL: u64 = 120;
+
+/// Nonces already spent -> unix time they expire (after which the timestamp rejects them anyway)
+static USED: Lazy<DashMap<String, u64>> = Lazy::new(DashMap::new);
+
+/// Nonces accepted only thanks to BSZ_SECRET_PREVIOUS since startup
+static FALLBACK_HITS: AtomicU64 = AtomicU64::new(0);
+/// Unix time of the last such nonce, 0 = none yet
+static LAST_FALLBACK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChallengeError {
+    Missing,
+    Invalid,
+    Expired,
+    Replayed,
+}
+
+impl ChallengeError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ChallengeError::Missing => "challenge required",
+            ChallengeError::Invalid => "invalid challenge",
+            ChallengeError::Expired => "challenge expired",
+            ChallengeError::Replayed => "challenge already used",
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mac(secret: &str, host: &str, ts: u64, salt: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}", normalize_host(host), ts, salt).as_bytes());
+    mac
+}
+
+fn sign(secret: &str, host: &str, ts: u64) -> String {
+    let salt = hex::encode(rand::random::<[u8; 8]>());
+    let tag = hex::encode(mac(secret, host, ts, &salt).finalize().into_bytes());
+    format!("{}.{}.{}", ts, salt, tag)
+}
+
+/// Issue a nonce for `host`
+pub fn issue(host: &str) -> String {
+    sign(&CONFIG.bsz_secret, host, unix_now())
+}
+
+/// Check and spend the nonce of a counting request for `host`.
+/// Always passes while REQUIRE_CHALLENGE is off.
+pub fn check(host: &str, nonce: Option<&str>) -> Result<(), ChallengeError> {
+    if !runtime().require_challenge {
+        return Ok(());
+    }
+    let mut secrets = vec![CONFIG.bsz_secret.as_str()];
+    if !CONFIG.bsz_secret_previous.is_empty() {
+        secrets.push(&CONFIG.bsz_secret_previous);
+    }
+    let now = unix_now();
+    if verify(&USED, &secrets, host, nonce, now)? > 0 {
+        FALLBACK_HITS.fetch_add(1, Ordering::Relaxed);
+        LAST_FALLBACK.store(now, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Nonces verified with BSZ_SECRET_PREVIOUS since startup, and when the last one was
+pub fn fallback_hits() -> (u64, Option<u64>) {
+    let last = LAST_FALLBACK.load(Ordering::Relaxed);
+    (
+        FALLBACK_HITS.load(Ordering::Relaxed),
+        (last > 0).then_some(last),
+    )
+}
+
+/// Check a nonce against each of `secrets` in turn (current first).
+/// Returns the index of the secret that signed it.
+fn verify(
+    used: &DashMap<String, u64>,
+    secrets: &[&str],
+    host: &str,
+    nonce: Option<&str>,
+    now: u64,
+) -> Result<usize, ChallengeError> {
+    let nonce = nonce.map(str::trim).filter(|n| !n.is_empty());
+    let nonce = nonce.ok_or(ChallengeError::Missing)?;
+
+    let mut parts = nonce.splitn(3, '.');
+    let (Some(ts), Some(salt), Some(tag)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(ChallengeError::Invalid);
+    };
+    let ts: u64 = ts.parse().map_err(|_| ChallengeError::Invalid)?;
+    let tag = hex::decode(tag).map_err(|_| ChallengeError::Invalid)?;
+    let signer = secrets
+        .iter()
+        .position(|secret| mac(secret, host, ts, salt).verify_slice(&tag).is_ok())
+        .ok_or(ChallengeError::Invalid)?;
+
+    // A signed timestamp from the future means the clock went backwards; treat it as invalid
+    if ts > now + 5 {
+        return Err(ChallengeError::Invalid);
+    }
+    let expires = ts + NONCE_TTL;
+    if now >= expires {
+        return Err(ChallengeError::Expired);
+    }
+
+    match used.entry(nonce.to_string()) {
+        Entry::Occupied(_) => Err(ChallengeError::Replayed),
+        Entry::Vacant(e) => {
+            e.insert(expires);
+            Ok(signer)
+        }
+    }
+}
+
+/// Forget spent nonces that have expired anyway
+pub fn sweep_used() {
+    let now = unix_now();
+    USED.retain(|_, expires| *expires > now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "s3cret";
+
+    #[test]
+    fn accepts_fresh_nonce_once() {
+        let used = DashMap::new();
+        let nonce = sign(SECRET, "example.com", 1000);
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some(&nonce), 1010),
+            Ok(0)
+        );
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some(&nonce), 1011),
+            Err(ChallengeError::Replayed)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_nonce() {
+        let used = DashMap::new();
+        let nonce = sign(SECRET, "example.com", 1000);
+        assert_eq!(
+            verify(
+                &used,
+                &[SECRET],
+                "example.com",
+                Some(&nonce),
+                1000 + NONCE_TTL
+            ),
+            Err(ChallengeError::Expired)
+        );
+        assert!(used.is_empty());
+    }
+
+    #[test]
+    fn rejects_forged_or_foreign_nonces() {
+        let used = DashMap::new();
+        let nonce = sign(SECRET, "example.com", 1000);
+        assert_eq!(
+            verify(&used, &[SECRET], "other.com", Some(&nonce), 1010),
+            Err(ChallengeError::Invalid)
+        );
+        assert_eq!(
+            verify(&used, &["other-secret"], "example.com", Some(&nonce), 1010),
+            Err(ChallengeError::Invalid)
+        );
+        // Moving the timestamp forward breaks the signature
+        let (_, rest) = nonce.split_once('.').unwrap();
+        let shifted = format!("2000.{}", rest);
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some(&shifted), 2010),
+            Err(ChallengeError::Invalid)
+        );
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some("garbage"), 1010),
+            Err(ChallengeError::Invalid)
+        );
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", None, 1010),
+            Err(ChallengeError::Missing)
+        );
+    }
+
+    #[test]
+    fn rejects_future_timestamps() {
+        let used = DashMap::new();
+        let nonce = sign(SECRET, "example.com", 5000);
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some(&nonce), 1000),
+            Err(ChallengeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_previous_secret() {
+        const OLD: &str = "old-secret";
+        let used = DashMap::new();
+        let both = [SECRET, OLD];
+
+        // Both secrets configured: current nonces match first, old ones via the fallback
+        let current = sign(SECRET, "example.com", 1000);
+        let old = sign(OLD, "example.com", 1000);
+        assert_eq!(
+            verify(&used, &both, "example.com", Some(&current), 1010),
+            Ok(0)
+        );
+        assert_eq!(verify(&used, &both, "example.com", Some(&old), 1010), Ok(1));
+
+        // Old secret dropped: its nonces are refused
+        let old = sign(OLD, "example.com", 1000);
+        assert_eq!(
+            verify(&used, &[SECRET], "example.com", Some(&old), 1010),
+            Err(ChallengeError::Invalid)
+        );
+
+        // Signed with neither
+        let foreign = sign("someone-else", "example.com", 1000);
+        assert_eq!(
+            verify(&used, &both, "example.com", Some(&foreign), 1010),
+            Err(ChallengeError::Invalid)
+        );
+    }
+}
@@ -1,12 +1,25 @@
-//! Counting logic - matches original busuanzi: site_pv, site_uv, page_pv only
+//! Counting logic - matches original busuanzi: site_pv, site_uv, page_pv
+//! (plus page_uv when UV_SCOPE tracks pages)
 
-use crate::state;
+use crate::config::{runtime, Encrypt, CONFIG};
+use crate::core::site_secret;
+use crate::state::{self, Store};
+use std::borrow::Cow;
+
+/// Path of the synthetic page that takes a site's hits on new paths once it has
+/// MAX_PAGES_PER_SITE pages. Stored unhashed, so it can't collide with a real page.
+pub const OVERFLOW_PATH: &str = "__overflow__";
 
 #[derive(Debug, serde::Serialize)]
 pub struct Counts {
     pub site_pv: u64,
     pub site_uv: u64,
     pub page_pv: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_uv: Option<u64>,
+    /// False when the stored visitor sets use another hash version than this build,
+    /// so returning visitors may be counted again (see state::uv_accurate)
+    pub uv_accurate: bool,
 }
 
 pub struct Keys {
@@ -14,45 +27,306 @@ pub struct Keys {
     pub page_key: String,
 }
 
-/// Generate keys directly from host and path (no hashing)
+/// Generate keys from host and path, hashed according to BSZ_ENCRYPT (plaintext by default)
 pub fn get_keys(host: &str, path: &str) -> Keys {
-    Keys {
-        site_key: host.to_string(),
-        page_key: format!("{}:{}", host, path),
+    get_keys_with(host, path, CONFIG.bsz_site_from_path_segments)
+}
+
+fn get_keys_with(host: &str, path: &str, site_segments: usize) -> Keys {
+    let (site_path, page_path) = split_site_path(path, site_segments);
+    let site_key = encrypt(&format!("{}{}", normalize_host(host), site_path));
+    let page_key = format!("{}:{}", site_key, encrypt(&page_path));
+    Keys { site_key, page_key }
+}
+
+/// Split `path` after its first `segments` segments (BSZ_SITE_FROM_PATH_SEGMENTS):
+/// `/alice/post/1` with 1 -> (`/alice`, `/post/1`). The page part always starts
+/// with `/`; a path with fewer segments is the site's root page.
+fn split_site_path(path: &str, segments: usize) -> (&str, Cow<'_, str>) {
+    let end = path.find('?').unwrap_or(path.len());
+    let mut split = 0;
+    for _ in 0..segments {
+        let Some(rest) = path[split..end].strip_prefix('/') else {
+            break;
+        };
+        let len = rest.find('/').unwrap_or(rest.len());
+        if len == 0 {
+            break;
+        }
+        split += 1 + len;
+    }
+    let (site, page) = path.split_at(split);
+    if page.starts_with('/') {
+        (site, Cow::Borrowed(page))
+    } else {
+        (site, Cow::Owned(format!("/{}", page)))
+    }
+}
+
+/// Page key of a site's overflow page
+pub fn overflow_key(site_key: &str) -> String {
+    format!("{}:{}", site_key, OVERFLOW_PATH)
+}
+
+/// The page a hit is recorded on: its own, unless that page is new and the site
+/// already has `max` pages (0 = no cap). Checked per hit, so raising the cap
+/// lets new pages through again.
+fn counted_page_key<'a>(store: &Store, keys: &'a Keys, max: usize) -> Cow<'a, str> {
+    if max == 0
+        || store.page_pv.contains_key(&keys.page_key)
+        || (store.site_page_count(&keys.site_key) as usize) < max
+    {
+        Cow::Borrowed(&keys.page_key)
+    } else {
+        Cow::Owned(overflow_key(&keys.site_key))
+    }
+}
+
+/// Whether a hit on `path` counts towards its page: always, unless BSZ_COUNT_ROOT_PAGE
+/// is off and the page part of the path is one of BSZ_ROOT_PATHS
+fn counts_page(path: &str) -> bool {
+    CONFIG.bsz_count_root_page
+        || !is_root(
+            &split_site_path(path, CONFIG.bsz_site_from_path_segments).1,
+            &CONFIG.bsz_root_paths,
+        )
+}
+
+/// Whether `page_path` is a home page; the query string doesn't matter
+fn is_root(page_path: &str, roots: &[String]) -> bool {
+    let path = page_path.split('?').next().unwrap_or(page_path);
+    roots.iter().any(|root| root == path)
+}
+
+/// Stored key of the site the page `host` + `path` belongs to
+pub fn site_key(host: &str, path: &str) -> String {
+    get_keys(host, path).site_key
+}
+
+/// Whether a counting request for a page may increment, given its `x-bsz-key` header
+pub fn can_write(host: &str, path: &str, write_key: Option<&str>) -> bool {
+    site_secret::allows(&site_key(host, path), write_key)
+}
+
+/// Canonical form of a host, so aliases of one site share a key:
+/// lowercase, no trailing dot, and no `www.` prefix when BSZ_STRIP_WWW is on
+pub fn normalize_host(host: &str) -> String {
+    normalize_host_with(host, CONFIG.bsz_strip_www)
+}
+
+fn normalize_host_with(host: &str, strip_www: bool) -> String {
+    let host = host.trim_end_matches('.').to_lowercase();
+    match host.strip_prefix("www.") {
+        Some(rest) if strip_www && !rest.is_empty() => rest.to_string(),
+        _ => host,
+    }
+}
+
+fn encrypt(s: &str) -> String {
+    encrypt_with(s, CONFIG.bsz_encrypt)
+}
+
+/// MD516 is the common "16-character MD5": hex characters 8..24 of the full
+/// digest, i.e. its middle 8 bytes, as in the original busuanzi's hashed keys.
+/// Taken from the digest bytes rather than by slicing the hex string, so it
+/// can't panic whatever the input.
+fn encrypt_with(s: &str, mode: Encrypt) -> String {
+    match mode {
+        Encrypt::None => s.to_string(),
+        Encrypt::Md5 => format!("{:x}", md5::compute(s)),
+        Encrypt::Md516 => hex::encode(&md5::compute(s).0[4..12]),
     }
 }
 
 /// Count and return PV/UV (POST /api)
-pub fn count(host: &str, path: &str, user_identity: &str) -> Counts {
+pub fn count(store: &Store, host: &str, path: &str, user_identity: &str) -> Counts {
     let keys = get_keys(host, path);
 
-    let (site_pv, site_uv) = state::incr_site(&keys.site_key, user_identity);
-    let page_pv = state::incr_page(&keys.page_key);
+    let (site_pv, site_uv) = store.incr_site(&keys.site_key, user_identity);
+    let (page_pv, page_uv) = if counts_page(path) {
+        let page_key = counted_page_key(store, &keys, runtime().max_pages_per_site);
+        store.incr_page(&page_key, user_identity)
+    } else {
+        page_counts(store, &keys.page_key)
+    };
 
     Counts {
         site_pv,
         site_uv,
         page_pv,
+        page_uv,
+        uv_accurate: state::uv_accurate(),
     }
 }
 
 /// Get counts without incrementing (GET /api)
-pub fn get(host: &str, path: &str) -> Counts {
+pub fn get(store: &Store, host: &str, path: &str) -> Counts {
     let keys = get_keys(host, path);
 
-    let (site_pv, site_uv) = state::get_site(&keys.site_key);
-    let page_pv = state::get_page(&keys.page_key);
+    let (site_pv, site_uv) = store.get_site(&keys.site_key);
+    let (page_pv, page_uv) = page_counts(store, &keys.page_key);
 
     Counts {
         site_pv,
         site_uv,
         page_pv,
+        page_uv,
+        uv_accurate: state::uv_accurate(),
     }
 }
 
+/// A page's PV, and UV when UV_SCOPE tracks pages
+fn page_counts(store: &Store, page_key: &str) -> (u64, Option<u64>) {
+    let page_pv = store.get_page(page_key);
+    let page_uv = CONFIG
+        .uv_scope
+        .tracks_page()
+        .then(|| store.get_page_uv(page_key));
+    (page_pv, page_uv)
+}
+
 /// Put data without returning (PUT /api)
-pub fn put(host: &str, path: &str, user_identity: &str) {
+pub fn put(store: &Store, host: &str, path: &str, user_identity: &str) {
     let keys = get_keys(host, path);
-    state::incr_site(&keys.site_key, user_identity);
-    state::incr_page(&keys.page_key);
+    store.incr_site(&keys.site_key, user_identity);
+    if counts_page(path) {
+        let page_key = counted_page_key(store, &keys, runtime().max_pages_per_site);
+        store.incr_page(&page_key, user_identity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_hosts() {
+        assert_eq!(normalize_host_with("Example.COM.", false), "example.com");
+        assert_eq!(
+            normalize_host_with("www.example.com", false),
+            "www.example.com"
+        );
+        assert_eq!(normalize_host_with("WWW.example.com", true), "example.com");
+        assert_eq!(normalize_host_with("www.", true), "www");
+        assert_eq!(
+            normalize_host_with("wwwexample.com", true),
+            "wwwexample.com"
+        );
+    }
+
+    #[test]
+    fn md516_is_the_middle_of_md5() {
+        for input in ["", "example.com", "example.com:/post/1"] {
+            let full = encrypt_with(input, Encrypt::Md5);
+            let short = encrypt_with(input, Encrypt::Md516);
+            assert_eq!(full.len(), 32);
+            assert_eq!(short, full[8..24]);
+        }
+        assert_eq!(encrypt_with("", Encrypt::Md516), "8f00b204e9800998");
+    }
+
+    #[test]
+    fn recognizes_root_pages() {
+        let roots = vec!["/".to_string(), "/index.html".to_string()];
+        assert!(is_root("/", &roots));
+        assert!(is_root("/?utm_source=feed", &roots));
+        assert!(is_root("/index.html", &roots));
+        assert!(!is_root("/post/1", &roots));
+        assert!(!is_root("/index.htm", &roots));
+        // With BSZ_SITE_FROM_PATH_SEGMENTS the root is that of the sub-site
+        assert!(is_root(&split_site_path("/alice", 1).1, &roots));
+        assert!(!is_root(&split_site_path("/alice/post", 1).1, &roots));
+    }
+
+    #[test]
+    fn splits_site_segments_off_the_path() {
+        assert_eq!(
+            split_site_path("/alice/post/1", 0),
+            ("", "/alice/post/1".into())
+        );
+        assert_eq!(
+            split_site_path("/alice/post/1", 1),
+            ("/alice", "/post/1".into())
+        );
+        assert_eq!(
+            split_site_path("/alice/post/1", 2),
+            ("/alice/post", "/1".into())
+        );
+        assert_eq!(split_site_path("/alice/", 1), ("/alice", "/".into()));
+        assert_eq!(split_site_path("/alice", 1), ("/alice", "/".into()));
+        assert_eq!(split_site_path("/alice?x=1", 1), ("/alice", "/?x=1".into()));
+        assert_eq!(
+            split_site_path("/a?next=/b/c", 2),
+            ("/a", "/?next=/b/c".into())
+        );
+        assert_eq!(split_site_path("/", 1), ("", "/".into()));
+        assert_eq!(split_site_path("//post", 1), ("", "//post".into()));
+    }
+
+    #[test]
+    fn path_prefixed_sites_get_distinct_keys() {
+        let alice = get_keys_with("example.com", "/alice/post", 1);
+        let bob = get_keys_with("example.com", "/bob/post", 1);
+        assert_ne!(alice.site_key, bob.site_key);
+        assert!(alice.page_key.starts_with(&format!("{}:", alice.site_key)));
+        // Pages of one tenant share its site
+        let about = get_keys_with("example.com", "/alice/about", 1);
+        assert_eq!(about.site_key, alice.site_key);
+        assert_ne!(about.page_key, alice.page_key);
+        // Default: the host alone is the site
+        assert_eq!(
+            get_keys_with("example.com", "/alice/post", 0).site_key,
+            get_keys_with("example.com", "/bob/post", 0).site_key
+        );
+    }
+
+    fn keys(site_key: &str, path: &str) -> Keys {
+        Keys {
+            site_key: site_key.to_string(),
+            page_key: format!("{}:{}", site_key, path),
+        }
+    }
+
+    #[test]
+    fn new_pages_overflow_at_the_cap() {
+        let store = Store::new();
+        let site = "example.com";
+        store.incr_page(&format!("{}:/a", site), "v");
+        store.incr_page(&format!("{}:/b", site), "v");
+        let page = |path: &str, max| counted_page_key(&store, &keys(site, path), max).into_owned();
+
+        // Under the cap and existing pages keep their own key
+        assert_eq!(page("/c", 3), format!("{}:/c", site));
+        assert_eq!(page("/a", 2), format!("{}:/a", site));
+
+        // At the cap a new path goes to the overflow page
+        assert_eq!(page("/c", 2), overflow_key(site));
+        store.incr_page(&overflow_key(site), "v");
+        store.incr_page(&overflow_key(site), "w");
+        assert_eq!(store.get_page(&overflow_key(site)), 2);
+        assert_eq!(store.get_page(&format!("{}:/c", site)), 0);
+
+        // The overflow page itself keeps counting once the cap is passed
+        assert_eq!(page("/d", 2), overflow_key(site));
+
+        // Raising the cap or removing it admits new pages again
+        assert_eq!(page("/d", 10), format!("{}:/d", site));
+        assert_eq!(page("/d", 0), format!("{}:/d", site));
+    }
+
+    #[test]
+    fn site_totals_include_overflow_hits() {
+        let store = Store::new();
+        let site = "example.com";
+        let first = keys(site, "/a");
+        let second = keys(site, "/b");
+        for k in [&first, &second, &second] {
+            let page_key = counted_page_key(&store, k, 1);
+            store.incr_site(&k.site_key, "v");
+            store.incr_page(&page_key, "v");
+        }
+        assert_eq!(store.get_site(site).0, 3);
+        assert_eq!(store.get_page(&first.page_key), 1);
+        assert_eq!(store.get_page(&overflow_key(site)), 2);
+    }
 }
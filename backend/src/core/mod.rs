@@ -1 +1,9 @@
+pub mod anomaly;
+pub mod bot;
+pub mod challenge;
 pub mod count;
+pub mod referer;
+pub mod site_quota;
+pub mod site_secret;
+pub mod totp;
+pub mod visitor_hash;
@@ -0,0 +1,93 @@
+//! Referer parsing: which site and page a counting request is for
+
+use crate::config::CONFIG;
+use axum::http::{header, HeaderMap};
+use url::Url;
+
+/// Split a page URL into (host, path).
+/// With BSZ_PATH_STYLE=false the query string stays part of the path.
+pub fn parse_referer(referer: &str) -> Result<(String, String), &'static str> {
+    parse_with(referer, CONFIG.bsz_path_style)
+}
+
+/// Parse the custom `x-bsz-referer` header (HEADER_REFERER)
+pub fn parse_bsz_referer(headers: &HeaderMap) -> Result<(String, String), &'static str> {
+    parse_referer(header_value(headers, &CONFIG.header_referer))
+}
+
+/// Parse the standard `Referer` header
+pub fn parse_referer_header(headers: &HeaderMap) -> Result<(String, String), &'static str> {
+    parse_referer(header_value(headers, header::REFERER.as_str()))
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+}
+
+fn parse_with(referer: &str, path_style: bool) -> Result<(String, String), &'static str> {
+    if referer.is_empty() {
+        return Err("invalid referer");
+    }
+
+    let u = Url::parse(referer).map_err(|_| "unable to parse referer")?;
+    let host = u.host_str().ok_or("invalid referer")?.to_string();
+
+    if host.is_empty() {
+        return Err("invalid referer");
+    }
+
+    let path = match u.query() {
+        Some(query) if !path_style => format!("{}?{}", u.path(), query),
+        _ => u.path().to_string(),
+    };
+
+    Ok((host, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_host_and_path() {
+        assert_eq!(
+            parse_with("https://Example.com/a/b?x=1#top", true),
+            Ok(("example.com".to_string(), "/a/b".to_string()))
+        );
+        assert_eq!(
+            parse_with("https://example.com/a/b?x=1", false),
+            Ok(("example.com".to_string(), "/a/b?x=1".to_string()))
+        );
+        assert_eq!(
+            parse_with("https://example.com", true),
+            Ok(("example.com".to_string(), "/".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_referers() {
+        assert_eq!(parse_with("", true), Err("invalid referer"));
+        assert_eq!(
+            parse_with("not a url", true),
+            Err("unable to parse referer")
+        );
+        assert_eq!(
+            parse_with("data:text/plain,hi", true),
+            Err("invalid referer")
+        );
+    }
+
+    #[test]
+    fn reads_either_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::REFERER, "https://a.com/x".parse().unwrap());
+        assert_eq!(
+            parse_referer_header(&headers),
+            Ok(("a.com".to_string(), "/x".to_string()))
+        );
+        assert!(parse_bsz_referer(&headers).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Cap on new site keys per client IP (`MAX_NEW_SITES_PER_IP_PER_HOUR`)
+//!
+//! A scanner sending made-up referer hosts would otherwise create one site per
+//! request. Only the public counting routes go through this; imports, syncs and
+//! admin edits create sites without a quota.
+
+use crate::config::runtime;
+use crate::state::{self, Store};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(3600);
+
+pub static QUOTA: Lazy<SiteQuota> =
+    Lazy::new(|| SiteQuota::new(runtime().max_new_sites_per_ip_per_hour));
+
+/// Sliding one-hour window of site creations per IP
+pub struct SiteQuota {
+    max: AtomicUsize,
+    ips: DashMap<String, Creations>,
+    refused: AtomicU64,
+}
+
+#[derive(Default)]
+struct Creations {
+    times: VecDeque<Instant>,
+    last_logged: Option<Instant>,
+}
+
+/// Outcome of asking to create a site
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// Over the quota; `log` is set at most once per IP per window
+    Refused {
+        log: bool,
+    },
+}
+
+impl SiteQuota {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: AtomicUsize::new(max),
+            ips: DashMap::new(),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a site creation by `ip`, unless it already made `max` in the last hour
+    pub fn check(&self, ip: &str, now: Instant) -> Verdict {
+        let max = self.max.load(Ordering::Relaxed);
+        if max == 0 {
+            return Verdict::Allowed;
+        }
+        let mut entry = self.ips.entry(ip.to_string()).or_default();
+        while entry
+            .times
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= WINDOW)
+        {
+            entry.times.pop_front();
+        }
+        if entry.times.len() < max {
+            entry.times.push_back(now);
+            return Verdict::Allowed;
+        }
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        let log = entry
+            .last_logged
+            .is_none_or(|t| now.saturating_duration_since(t) >= WINDOW);
+        if log {
+            entry.last_logged = Some(now);
+        }
+        Verdict::Refused { log }
+    }
+
+    /// Change the quota (config reload); creations already recorded still count
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    /// Forget IPs with no creation in the last hour. Returns the number removed.
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.ips.len();
+        self.ips.retain(|_, c| {
+            c.times
+                .back()
+                .is_some_and(|t| now.saturating_duration_since(*t) < WINDOW)
+        });
+        before - self.ips.len()
+    }
+
+    /// Site creations refused since startup
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether a counting request from `ip` may touch `site_key`: existing sites always
+/// may, new ones only within the creator's quota. Refusals are logged as `site_throttled`.
+pub fn allows(store: &Store, site_key: &str, ip: &str) -> bool {
+    let max = runtime().max_new_sites_per_ip_per_hour;
+    if max == 0 || store.site_pv.contains_key(site_key) {
+        return true;
+    }
+    match QUOTA.check(ip, Instant::now()) {
+        Verdict::Allowed => true,
+        Verdict::Refused { log } => {
+            if log {
+                state::add_log(
+                    "site_throttled",
+                    &format!(
+                        "more than {} new sites in an hour, refused {}",
+                        max, site_key
+                    ),
+                    ip,
+                );
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_beyond_quota_and_logs_once() {
+        let quota = SiteQuota::new(2);
+        let now = Instant::now();
+        assert_eq!(quota.check("a", now), Verdict::Allowed);
+        assert_eq!(quota.check("a", now), Verdict::Allowed);
+        assert_eq!(quota.check("a", now), Verdict::Refused { log: true });
+        assert_eq!(quota.check("a", now), Verdict::Refused { log: false });
+        assert_eq!(quota.check("b", now), Verdict::Allowed);
+        assert_eq!(quota.refused(), 2);
+    }
+
+    #[test]
+    fn window_slides() {
+        let quota = SiteQuota::new(2);
+        let start = Instant::now();
+        assert_eq!(quota.check("a", start), Verdict::Allowed);
+        let later = start + Duration::from_secs(1800);
+        assert_eq!(quota.check("a", later), Verdict::Allowed);
+        assert!(matches!(quota.check("a", later), Verdict::Refused { .. }));
+
+        // The first creation has left the window, the second hasn't
+        let hour = start + WINDOW;
+        assert_eq!(quota.check("a", hour), Verdict::Allowed);
+        assert!(matches!(quota.check("a", hour), Verdict::Refused { .. }));
+    }
+
+    #[test]
+    fn zero_disables() {
+        let quota = SiteQuota::new(0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(quota.check("a", now), Verdict::Allowed);
+        }
+        assert!(quota.ips.is_empty());
+    }
+
+    #[test]
+    fn evicts_only_idle_ips() {
+        let quota = SiteQuota::new(5);
+        let start = Instant::now();
+        quota.check("old", start);
+        quota.check("new", start + Duration::from_secs(3000));
+        assert_eq!(quota.evict_idle(start + WINDOW), 1);
+        assert!(quota.ips.contains_key("new"));
+    }
+}
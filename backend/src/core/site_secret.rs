@@ -0,0 +1,111 @@
+//! Per-site write keys
+//!
+//! A site with a key only counts `POST`/`PUT /api` requests that send it in
+//! `x-bsz-key`; others are served read-only. Only the SHA-256 of each key is kept.
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::state;
+
+/// site_key -> SHA-256 of the write key
+static SECRETS: Lazy<DashMap<String, [u8; 32]>> = Lazy::new(DashMap::new);
+
+fn digest(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+/// Read persisted keys on startup
+pub fn load() {
+    let rows = match state::load_site_secrets() {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::warn!("Failed to load site write keys: {}", e);
+            return;
+        }
+    };
+    for (site_key, hash) in rows {
+        match hex::decode(&hash)
+            .ok()
+            .and_then(|b| <[u8; 32]>::try_from(b).ok())
+        {
+            Some(hash) => {
+                SECRETS.insert(site_key, hash);
+            }
+            None => tracing::warn!("Ignoring malformed write key hash for {}", site_key),
+        }
+    }
+}
+
+/// Whether a write for `site_key` carrying `presented` may increment counters
+pub fn allows(site_key: &str, presented: Option<&str>) -> bool {
+    let Some(expected) = SECRETS.get(site_key).map(|e| *e.value()) else {
+        return true;
+    };
+    match presented {
+        Some(secret) => digest(secret).ct_eq(&expected).into(),
+        None => false,
+    }
+}
+
+pub fn has_secret(site_key: &str) -> bool {
+    SECRETS.contains_key(site_key)
+}
+
+/// Sites that have a write key
+pub fn count() -> usize {
+    SECRETS.len()
+}
+
+/// Issue (or rotate) the write key of a site, returning the plaintext once
+pub fn issue(site_key: &str) -> Result<String, String> {
+    let secret = hex::encode(rand::random::<[u8; 24]>());
+    let hash = digest(&secret);
+    state::set_site_secret(site_key, Some(&hex::encode(hash)))
+        .map_err(|e| format!("保存失败: {}", e))?;
+    SECRETS.insert(site_key.to_string(), hash);
+    Ok(secret)
+}
+
+/// Remove a site's write key; it goes back to open counting
+pub fn revoke(site_key: &str) -> Result<bool, String> {
+    state::set_site_secret(site_key, None).map_err(|e| format!("保存失败: {}", e))?;
+    Ok(SECRETS.remove(site_key).is_some())
+}
+
+/// Carry a key over when a site is renamed
+pub fn rename(old_key: &str, new_key: &str) {
+    if let Some((_, hash)) = SECRETS.remove(old_key) {
+        SECRETS.insert(new_key.to_string(), hash);
+        let _ = state::set_site_secret(old_key, None);
+        let _ = state::set_site_secret(new_key, Some(&hex::encode(hash)));
+    }
+}
+
+/// Forget the key of a deleted site
+pub fn forget(site_key: &str) {
+    if SECRETS.remove(site_key).is_some() {
+        let _ = state::set_site_secret(site_key, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_sites_allow_everything() {
+        assert!(allows("open.example", None));
+        assert!(allows("open.example", Some("anything")));
+    }
+
+    #[test]
+    fn keyed_sites_need_the_key() {
+        SECRETS.insert("keyed.example".to_string(), digest("s3cret"));
+        assert!(allows("keyed.example", Some("s3cret")));
+        assert!(!allows("keyed.example", Some("s3cre")));
+        assert!(!allows("keyed.example", None));
+    }
+}
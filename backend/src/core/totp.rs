@@ -0,0 +1,355 @@
+//! TOTP (RFC 6238) second factor for the admin API
+//!
+//! The enabled secret lives in `admin_settings`, encrypted with ChaCha20-Poly1305
+//! under a key derived from BSZ_SECRET. Changing BSZ_SECRET makes it unreadable;
+//! recover with DISABLE_2FA=1 and enroll again.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+use crate::config::CONFIG;
+use crate::state;
+
+const SETTING_KEY: &str = "totp_secret";
+const STEP_SECS: u64 = 30;
+const ISSUER: &str = "Busuanzi";
+
+/// Wrong codes tolerated per client IP and per credential within a window before
+/// code checks from either are refused
+const MAX_CODE_FAILS: u32 = 5;
+const CODE_FAIL_WINDOW: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct TotpState {
+    enabled: bool,
+    /// None while enabled means the stored secret could not be decrypted
+    secret: Option<Vec<u8>>,
+    /// Generated by setup, becomes `secret` once confirmed
+    pending: Option<Vec<u8>>,
+}
+
+static TOTP: Lazy<RwLock<TotpState>> = Lazy::new(|| RwLock::new(TotpState::default()));
+
+/// Last accepted time step, so a code can't be replayed within its window
+static LAST_STEP: AtomicU64 = AtomicU64::new(0);
+
+/// Failed code attempts by `ip:<addr>` and by credential: (count, first failure of
+/// the window). Like the admin lockout map, so one client's guesses don't lock out
+/// everyone else, while a stolen token can't reset its budget by changing IPs.
+static CODE_FAILS: Lazy<DashMap<String, (u32, Instant)>> = Lazy::new(DashMap::new);
+
+/// Tracked keys above which expired windows are swept on the next failure
+const CODE_FAILS_SWEEP_AT: usize = 1024;
+
+/// Who is entering a code
+pub struct Attempt<'a> {
+    pub ip: &'a str,
+    /// [`crate::middleware::admin_auth::Credential`] of the request
+    pub credential: &'a str,
+}
+
+impl Attempt<'_> {
+    fn keys(&self) -> [String; 2] {
+        [format!("ip:{}", self.ip), self.credential.to_string()]
+    }
+}
+
+/// Why a code was not accepted
+pub enum CodeError {
+    Invalid,
+    /// Too many wrong codes recently; seconds until attempts are allowed again
+    Throttled(u64),
+}
+
+impl std::fmt::Display for CodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodeError::Invalid => write!(f, "验证码错误"),
+            CodeError::Throttled(secs) => write!(f, "验证码错误次数过多，请 {} 秒后重试", secs),
+        }
+    }
+}
+
+/// Read the enabled secret from the database on startup
+pub fn load() {
+    let stored = match state::get_setting(SETTING_KEY) {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::warn!("Failed to load 2FA settings: {}", e);
+            return;
+        }
+    };
+    let Some(stored) = stored else {
+        return;
+    };
+    let mut secret = decrypt(&stored);
+    if secret.is_none() && !CONFIG.bsz_secret_previous.is_empty() {
+        // Encrypted before a BSZ_SECRET rotation: move it to the current key
+        secret = decrypt_with(&CONFIG.bsz_secret_previous, &stored);
+        if let Some(plain) = &secret {
+            match state::set_setting(SETTING_KEY, Some(&encrypt(plain))) {
+                Ok(()) => tracing::info!("2FA secret re-encrypted with the current BSZ_SECRET"),
+                Err(e) => tracing::warn!("Failed to re-encrypt the 2FA secret: {}", e),
+            }
+        }
+    }
+    if secret.is_none() {
+        tracing::error!(
+            "2FA is enabled but its secret can't be decrypted (BSZ_SECRET changed?); set DISABLE_2FA=1 to recover"
+        );
+    }
+    let mut totp = TOTP.write().unwrap();
+    totp.enabled = true;
+    totp.secret = secret;
+}
+
+pub fn enabled() -> bool {
+    TOTP.read().unwrap().enabled
+}
+
+/// Whether admin requests currently need a TOTP-backed session
+pub fn required() -> bool {
+    enabled() && !CONFIG.disable_2fa
+}
+
+pub fn has_pending() -> bool {
+    TOTP.read().unwrap().pending.is_some()
+}
+
+/// Start enrollment: generate a new secret, returned as (base32 secret, otpauth URL)
+pub fn begin_setup() -> (String, String) {
+    let secret = rand::random::<[u8; 20]>().to_vec();
+    let encoded = data_encoding::BASE32_NOPAD.encode(&secret);
+    let url = format!(
+        "otpauth://totp/{issuer}:admin?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period={period}",
+        issuer = ISSUER,
+        secret = encoded,
+        period = STEP_SECS
+    );
+    TOTP.write().unwrap().pending = Some(secret);
+    (encoded, url)
+}
+
+/// Confirm enrollment with a code from the pending secret and persist it
+pub fn confirm_setup(code: &str, attempt: &Attempt) -> Result<(), String> {
+    let pending = TOTP
+        .read()
+        .unwrap()
+        .pending
+        .clone()
+        .ok_or("请先调用 setup 生成密钥")?;
+    check_code(&pending, code, attempt).map_err(|e| e.to_string())?;
+    state::set_setting(SETTING_KEY, Some(&encrypt(&pending)))
+        .map_err(|e| format!("保存失败: {}", e))?;
+
+    let mut totp = TOTP.write().unwrap();
+    totp.enabled = true;
+    totp.secret = Some(pending);
+    totp.pending = None;
+    Ok(())
+}
+
+/// Turn 2FA off and forget the secret
+pub fn disable() -> Result<(), String> {
+    state::set_setting(SETTING_KEY, None).map_err(|e| format!("保存失败: {}", e))?;
+    *TOTP.write().unwrap() = TotpState::default();
+    Ok(())
+}
+
+/// Check a code against the enabled secret
+pub fn verify(code: &str, attempt: &Attempt) -> Result<(), CodeError> {
+    let secret = TOTP.read().unwrap().secret.clone();
+    match secret {
+        Some(secret) => check_code(&secret, code, attempt),
+        None => Err(CodeError::Invalid),
+    }
+}
+
+fn check_code(secret: &[u8], code: &str, attempt: &Attempt) -> Result<(), CodeError> {
+    let keys = attempt.keys();
+    if let Some(remaining) = keys.iter().filter_map(|key| throttled_for(key)).max() {
+        return Err(CodeError::Throttled(remaining.as_secs().max(1)));
+    }
+
+    let accepted = match_step(secret, code, unix_now())
+        .filter(|&step| LAST_STEP.fetch_max(step, Ordering::Relaxed) < step);
+    if accepted.is_some() {
+        for key in &keys {
+            CODE_FAILS.remove(key);
+        }
+        Ok(())
+    } else {
+        if CODE_FAILS.len() >= CODE_FAILS_SWEEP_AT {
+            CODE_FAILS.retain(|_, (_, since)| since.elapsed() < CODE_FAIL_WINDOW);
+        }
+        for key in keys {
+            let mut fails = CODE_FAILS.entry(key).or_insert((0, Instant::now()));
+            if fails.1.elapsed() >= CODE_FAIL_WINDOW {
+                *fails = (0, Instant::now());
+            }
+            fails.0 += 1;
+        }
+        Err(CodeError::Invalid)
+    }
+}
+
+/// Time left in the window of `key` once it has used up its attempts
+fn throttled_for(key: &str) -> Option<Duration> {
+    let fails = CODE_FAILS.get(key)?;
+    let elapsed = fails.1.elapsed();
+    (fails.0 >= MAX_CODE_FAILS && elapsed < CODE_FAIL_WINDOW).then(|| CODE_FAIL_WINDOW - elapsed)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// RFC 4226 HOTP value (6 digits)
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 1_000_000
+}
+
+/// Time step whose code equals `code`, allowing one step of clock drift either way
+fn match_step(secret: &[u8], code: &str, unix_secs: u64) -> Option<u64> {
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = unix_secs / STEP_SECS;
+    let mut matched = None;
+    // Check every candidate so timing doesn't reveal which step matched
+    for step in current.saturating_sub(1)..=current + 1 {
+        if bool::from(hotp(secret, step).ct_eq(&code)) {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+fn cipher(bsz_secret: &str) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain_update(b"busuanzi-totp:")
+        .chain_update(bsz_secret.as_bytes())
+        .finalize();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// hex(nonce || ciphertext)
+fn encrypt(secret: &[u8]) -> String {
+    let nonce = rand::random::<[u8; 12]>();
+    let ciphertext = cipher(&CONFIG.bsz_secret)
+        .encrypt(Nonce::from_slice(&nonce), secret)
+        .expect("encrypting a short secret cannot fail");
+    hex::encode([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(stored: &str) -> Option<Vec<u8>> {
+    decrypt_with(&CONFIG.bsz_secret, stored)
+}
+
+fn decrypt_with(bsz_secret: &str, stored: &str) -> Option<Vec<u8>> {
+    let bytes = hex::decode(stored).ok()?;
+    if bytes.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = bytes.split_at(12);
+    cipher(bsz_secret)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1 secret, truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc6238_vectors() {
+        assert_eq!(hotp(RFC_SECRET, 59 / 30), 287082);
+        assert_eq!(hotp(RFC_SECRET, 1111111109 / 30), 81804);
+        assert_eq!(hotp(RFC_SECRET, 1234567890 / 30), 5924);
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let t = 1234567890;
+        let code = format!("{:06}", hotp(RFC_SECRET, t / 30));
+        assert_eq!(code, "005924");
+        assert_eq!(match_step(RFC_SECRET, &code, t), Some(t / 30));
+        assert_eq!(match_step(RFC_SECRET, &code, t + 30), Some(t / 30));
+        assert_eq!(match_step(RFC_SECRET, &code, t - 30), Some(t / 30));
+        assert_eq!(match_step(RFC_SECRET, &code, t + 60), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(match_step(RFC_SECRET, "5924", 1234567890), None);
+        assert_eq!(match_step(RFC_SECRET, "00592a", 1234567890), None);
+        assert_eq!(match_step(RFC_SECRET, "+05924", 1234567890), None);
+    }
+
+    #[test]
+    fn throttles_per_ip_and_per_credential() {
+        let attempt = |ip, credential| Attempt { ip, credential };
+        let wrong = |a: &Attempt| check_code(RFC_SECRET, "wrong!", a);
+
+        let attacker = attempt("198.51.100.1", "token:throttle-a");
+        for _ in 0..MAX_CODE_FAILS {
+            assert!(matches!(wrong(&attacker), Err(CodeError::Invalid)));
+        }
+        assert!(matches!(wrong(&attacker), Err(CodeError::Throttled(_))));
+
+        // The same token from another IP, or another token from the same IP, is refused too
+        let moved = attempt("198.51.100.2", "token:throttle-a");
+        assert!(matches!(wrong(&moved), Err(CodeError::Throttled(_))));
+        let other_token = attempt("198.51.100.1", "token:throttle-b");
+        assert!(matches!(wrong(&other_token), Err(CodeError::Throttled(_))));
+
+        // Everyone else keeps their attempts
+        let bystander = attempt("198.51.100.3", "token:throttle-c");
+        assert!(matches!(wrong(&bystander), Err(CodeError::Invalid)));
+    }
+
+    #[test]
+    fn encrypts_round_trip() {
+        let stored = encrypt(RFC_SECRET);
+        assert_ne!(stored, encrypt(RFC_SECRET), "nonce must differ per write");
+        assert_eq!(decrypt(&stored).as_deref(), Some(RFC_SECRET));
+        assert_eq!(decrypt("00"), None);
+
+        let mut tampered = hex::decode(&stored).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypt(&hex::encode(tampered)), None);
+
+        // Another BSZ_SECRET (e.g. the one before a rotation) is a different key
+        assert_eq!(decrypt_with("some-other-secret", &stored), None);
+        assert_eq!(
+            decrypt_with(&CONFIG.bsz_secret, &stored).as_deref(),
+            Some(RFC_SECRET)
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Visitor hashes stored in the UV sets (`visitors` / `page_visitors` tables)
+//!
+//! Version 1 is SipHash-1-3 with zero keys over the identity bytes plus a 0xFF
+//! terminator, which is exactly what `DefaultHasher::new()` + `str::hash` produced
+//! when the sets were first written. std makes no promise that DefaultHasher stays
+//! that way, so it is spelled out here: hashes already in a database keep matching
+//! after any toolchain upgrade and no visitor is counted twice.
+//!
+//! The version is recorded in `admin_settings` (see `state::init_db`). A future
+//! change of algorithm must bump it and keep recognising the old hashes, because
+//! one-way hashes can't be migrated.
+
+/// Written to `admin_settings.visitor_hash`
+pub const VERSION: &str = "siphash13-v1";
+
+/// Hash of a visitor identity (the `busuanziId` cookie value or its derived fallback)
+pub fn hash(identity: &str) -> u64 {
+    let mut data = Vec::with_capacity(identity.len() + 1);
+    data.extend_from_slice(identity.as_bytes());
+    data.push(0xFF);
+    siphash13(0, 0, &data)
+}
+
+fn siphash13(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut blocks = data.chunks_exact(8);
+    for block in &mut blocks {
+        let m = u64::from_le_bytes(block.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    let mut last = [0u8; 8];
+    let tail = blocks.remainder();
+    last[..tail.len()].copy_from_slice(tail);
+    let b = u64::from_le_bytes(last) | ((data.len() as u64 & 0xff) << 56);
+    v[3] ^= b;
+    sip_round(&mut v);
+    v[0] ^= b;
+
+    v[2] ^= 0xff;
+    for _ in 0..3 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_pinned() {
+        // Changing any of these re-counts every stored visitor; bump VERSION instead
+        assert_eq!(hash(""), 3_476_900_567_878_811_119);
+        assert_eq!(hash("visitor"), 8_297_756_048_855_821_567);
+        assert_eq!(
+            hash("5F4DCC3B5AA765D61D8327DEB882CF99"),
+            11_912_928_895_582_050_843
+        );
+    }
+
+    #[test]
+    fn matches_legacy_default_hasher() {
+        // True for the toolchains existing data was written with. If a future std
+        // changes DefaultHasher this fails while the pinned values above still hold.
+        use std::hash::{Hash, Hasher};
+        for identity in [
+            "",
+            "a",
+            "1234567",
+            "12345678",
+            "5F4DCC3B5AA765D61D8327DEB882CF99",
+        ] {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            identity.hash(&mut hasher);
+            assert_eq!(hash(identity), hasher.finish(), "{:?}", identity);
+        }
+    }
+}
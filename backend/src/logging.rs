@@ -0,0 +1,86 @@
+//! Log output: format (`LOG_FORMAT`), destination (`LOG_FILE`) and filter (`RUST_LOG`)
+//!
+//! Every request runs inside a `request` span carrying `method`, `path` and
+//! `request_id`. Its response is logged at DEBUG under the `access` target with
+//! `status` and `latency_ms`, so access logs are switched on and off apart from
+//! the rest, e.g. `RUST_LOG=info,access=debug`. In JSON output the span's
+//! fields appear under `span`, the event's at the top level.
+
+use axum::response::Response;
+use std::path::Path;
+use std::time::Duration;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Config, LogFormat};
+
+/// Filter used when RUST_LOG is unset
+const DEFAULT_FILTER: &str = "info";
+
+/// Install the global subscriber. With `LOG_FILE` set, the returned guard flushes
+/// the background writer when dropped and must live until the process exits.
+/// If the file cannot be opened, logging goes to stdout and the error is returned.
+pub fn init(config: &Config) -> Result<Option<WorkerGuard>, String> {
+    let (writer, guard, error) = if config.log_file.is_empty() {
+        (BoxMakeWriter::new(std::io::stdout), None, None)
+    } else {
+        match file_appender(&config.log_file) {
+            Ok(appender) => {
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                (BoxMakeWriter::new(writer), Some(guard), None)
+            }
+            Err(e) => (BoxMakeWriter::new(std::io::stdout), None, Some(e)),
+        }
+    };
+    let to_file = guard.is_some();
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(!to_file);
+    match config.log_format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Compact => builder.compact().init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_span_list(false)
+            .init(),
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(guard),
+    }
+}
+
+fn file_appender(path: &str) -> Result<RollingFileAppender, String> {
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("LOG_FILE={} has no file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name)
+        .build(dir)
+        .map_err(|e| format!("LOG_FILE={}: {}", path.display(), e))
+}
+
+/// TraceLayer `on_response`: one access log event per request
+pub fn on_response(response: &Response, latency: Duration, _span: &tracing::Span) {
+    tracing::debug!(
+        target: "access",
+        status = response.status().as_u16(),
+        latency_ms = latency.as_millis() as u64,
+        "response"
+    );
+}
@@ -1,11 +1,17 @@
 mod api;
 mod config;
 mod core;
+mod logging;
 mod middleware;
 mod state;
+mod storage;
+mod tls;
 
-use axum::extract::DefaultBodyLimit;
-use axum::http::{header, HeaderName, Method};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, HeaderName, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
 use axum::{
     middleware as axum_middleware,
     routing::{delete, get, post, put},
@@ -13,78 +19,350 @@ use axum::{
 };
 use serde_json::json;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 
-use crate::config::CONFIG;
+use crate::config::{runtime, CONFIG};
+use crate::state::Store;
 
-fn admin_routes() -> Router {
+fn admin_routes(store: Arc<Store>) -> Router {
     Router::new()
         .route("/keys", get(api::admin::list_keys_handler))
         .route("/keys", delete(api::admin::delete_key_handler))
+        .route("/keys/count", get(api::admin::count_keys_handler))
         .route("/keys/update", post(api::admin::update_key_handler))
         .route("/keys/rename", post(api::admin::rename_key_handler))
         .route("/keys/merge", post(api::admin::merge_key_handler))
+        .route("/keys/site-secret", post(api::admin::site_secret_handler))
         .route(
             "/keys/batch-delete",
             post(api::admin::batch_delete_keys_handler),
         )
+        .route(
+            "/keys/{site_key}/pages/stats",
+            get(api::admin::page_stats_handler),
+        )
         .route("/pages", get(api::admin::list_pages_handler))
         .route("/pages/update", post(api::admin::update_page_handler))
+        .route(
+            "/pages/orphans",
+            delete(api::admin::cleanup_orphans_handler),
+        )
         .route(
             "/pages/batch-delete",
             post(api::admin::batch_delete_pages_handler),
         )
         .route("/stats", get(api::admin::stats_handler))
+        .route("/health", get(api::admin::health_handler))
+        .route("/config", get(api::admin::config_handler))
+        .route("/config/reload", post(api::admin::config_reload_handler))
+        .route("/memory", get(api::admin::memory_handler))
+        .route("/check", get(api::admin::check_handler))
+        .route("/repair", post(api::admin::repair_handler))
+        .route("/save", post(api::admin::save_handler))
+        .route("/login", post(api::admin::login_handler))
+        .route("/csrf", get(api::admin::csrf_handler))
+        .route("/2fa/status", get(api::admin::two_factor_status_handler))
+        .route("/2fa/setup", post(api::admin::two_factor_setup_handler))
+        .route("/2fa/enable", post(api::admin::two_factor_enable_handler))
+        .route("/2fa/disable", post(api::admin::two_factor_disable_handler))
+        .route("/bots", get(api::admin::bots_handler))
+        .route("/bots", post(api::admin::add_bot_handler))
+        .route("/bots", delete(api::admin::delete_bot_handler))
+        .route("/security/lockouts", get(api::admin::lockouts_handler))
+        .route("/security/unlock", post(api::admin::unlock_handler))
+        .route("/security/secret", get(api::admin::secret_rotation_handler))
         .route("/logs", get(api::admin::logs_handler))
+        .route("/logs/export.csv", get(api::admin::logs_csv_handler))
         .route("/export", get(api::admin::export_handler))
         .route("/import", post(api::admin::import_handler))
+        .route("/export-site", get(api::admin::export_site_handler))
+        .route("/import-site", post(api::admin::import_site_handler))
         .route("/sync", get(api::admin::sync_handler))
         .route("/sync/upload", post(api::admin::sync_upload_handler))
+        .route("/sync/ping", get(api::admin::sync_ping_handler))
         .layer(DefaultBodyLimit::max(CONFIG.max_body_size))
+        .layer(axum_middleware::from_fn(
+            middleware::audit::audit_middleware,
+        ))
+        .layer(axum_middleware::from_fn_with_state(
+            store.clone(),
+            mark_dirty_on_write,
+        ))
+        // Runs after admin_auth (layers added later wrap the earlier ones)
+        .layer(axum_middleware::from_fn(middleware::csrf::csrf_middleware))
         .layer(axum_middleware::from_fn(
             middleware::admin_auth::admin_auth_middleware,
         ))
+        .layer(axum_middleware::from_fn(
+            middleware::rate_limit::admin_rate_limit_middleware,
+        ))
+        .layer(axum_middleware::from_fn(
+            middleware::service_stats::count_admin,
+        ))
+        .with_state(store)
+}
+
+/// Public routes, with the admin API nested under /api/admin when `admin_cors` is
+/// given. Without it the admin paths don't exist at all (404, not 401).
+fn routes(store: Arc<Store>, public_cors: CorsLayer, admin_cors: Option<CorsLayer>) -> Router {
+    let app = public_routes(store.clone()).layer(public_cors);
+    match admin_cors {
+        Some(cors) => app.nest("/api/admin", admin_routes(store).layer(cors)),
+        None => app,
+    }
+}
+
+fn public_routes(store: Arc<Store>) -> Router {
+    Router::new()
+        .route("/api", post(api::handlers::api_handler))
+        .route("/api", get(api::handlers::get_handler))
+        .route("/api", put(api::handlers::put_handler))
+        .route("/api/challenge", get(api::handlers::challenge_handler))
+        .route_layer(axum_middleware::from_fn(
+            middleware::rate_limit::rate_limit_middleware,
+        ))
+        // Outside the rate limit, so refused requests are counted as served too
+        .route_layer(axum_middleware::from_fn(
+            middleware::service_stats::count_api,
+        ))
+        // Rate limited inside the handler, so an over-limit client still gets the image
+        .route("/pixel.gif", get(api::handlers::pixel_handler))
+        // Visitor identity (and its cookie) only matters to the counting routes above
+        .route_layer(axum_middleware::from_fn(
+            middleware::identity::identity_middleware,
+        ))
+        .route("/", get(root))
+        .route("/ping", get(api::handlers::ping_handler))
+        // Public routes only read headers. DefaultBodyLimit would not help here since
+        // no extractor consumes the body, so reject oversized bodies up front instead.
+        // Added before the admin nest, so the upload routes keep MAX_BODY_SIZE.
+        .layer(RequestBodyLimitLayer::new(CONFIG.api_max_body_size))
+        .with_state(store)
+}
+
+/// Any admin write may have edited the store, so the next scheduled save must not be skipped
+async fn mark_dirty_on_write(
+    State(store): State<Arc<Store>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let write = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let response = next.run(req).await;
+    if write {
+        store.mark_dirty();
+        api::admin::clear_pages_cache();
+    }
+    response
+}
+
+/// Trace span per request, carrying the request id so every log line inside it does too
+fn request_span(req: &Request<Body>) -> tracing::Span {
+    let request_id = req
+        .extensions()
+        .get::<middleware::request_id::RequestId>()
+        .map(|id| id.0.as_str())
+        .unwrap_or("");
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        request_id = %request_id,
+    )
+}
+
+/// Response headers the public API lets cross-origin scripts read: the ones this
+/// server sets, then CORS_EXPOSE_HEADERS, without duplicates. Set-Cookie is not
+/// among them: browsers never expose it to scripts.
+fn public_exposed_headers(identity_header: &str, extra: &[String]) -> Vec<HeaderName> {
+    let mut headers = vec![HeaderName::from_static(
+        middleware::request_id::REQUEST_ID_HEADER,
+    )];
+    // All validated when CONFIG is loaded
+    let names = std::iter::once(identity_header).chain(extra.iter().map(String::as_str));
+    for name in names.filter_map(|n| HeaderName::try_from(n).ok()) {
+        if !headers.contains(&name) {
+            headers.push(name);
+        }
+    }
+    headers
+}
+
+/// Wait for Ctrl+C (SIGINT) or, on Unix, SIGTERM (`docker stop`, Kubernetes,
+/// `systemctl stop`). Returns the signal's name.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+        "Ctrl+C"
+    }
 }
 
 async fn root() -> Json<serde_json::Value> {
     Json(json!({
         "name": env!("CARGO_PKG_NAME"),
         "version": env!("CARGO_PKG_VERSION"),
-        "admin_enabled": !CONFIG.admin_token.is_empty(),
+        "admin_enabled": CONFIG.admin_enabled(),
     }))
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    // Dropped before every exit(1) below, so buffered file output is flushed
+    let log_guard = match logging::init(&CONFIG) {
+        Ok(guard) => guard,
+        Err(e) => {
+            tracing::error!("Cannot open log file: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    if let Err(e) = state::load() {
+    for warning in &CONFIG.warnings {
+        tracing::warn!("Config: {}", warning);
+    }
+    if !CONFIG.errors.is_empty() {
+        tracing::error!(
+            "Config: {} problem(s), fix these variables and restart:",
+            CONFIG.errors.len()
+        );
+        for (i, error) in CONFIG.errors.iter().enumerate() {
+            tracing::error!("  {}. {}", i + 1, error);
+        }
+        drop(log_guard);
+        std::process::exit(1);
+    }
+
+    let store = Arc::new(Store::new().with_visit_hook(core::anomaly::observe));
+    if let Err(e) = state::load(&store) {
         tracing::error!("Failed to load data: {}", e);
     }
+    middleware::admin_auth::load_lockouts();
+    core::totp::load();
+    core::site_secret::load();
+    core::bot::load();
+    middleware::service_stats::load();
 
-    tokio::spawn(async {
-        let interval = Duration::from_secs(CONFIG.save_interval);
+    // Save every SAVE_INTERVAL, or sooner once SAVE_EVERY_N_WRITES increments are pending
+    let saved = store.clone();
+    tokio::spawn(async move {
         loop {
-            tokio::time::sleep(interval).await;
-            if let Err(e) = state::save().await {
+            // Read every round, so a reloaded SAVE_INTERVAL applies after the current wait
+            let interval = runtime().save_interval;
+            state::schedule_next_save(interval);
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(interval)) => {}
+                _ = saved.save_requested.notified() => {
+                    // Debounce: let the burst that crossed the threshold land in this save
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+            // Nothing changed: leave data.db (and its mtime) alone. While the database is
+            // unavailable every save is still attempted, since that is what reopens it.
+            if state::persistence_enabled() && !saved.has_unsaved_changes() {
+                continue;
+            }
+            if let Err(e) = state::save(saved.clone()).await {
                 tracing::error!("Failed to save data: {}", e);
             }
         }
     });
 
-    let shutdown = async {
-        tokio::signal::ctrl_c().await.ok();
-        tracing::info!("Shutting down, saving data...");
-        if let Err(e) = state::save().await {
+    // Drop idle rate-limit buckets and site quotas, expired admin sessions and spent nonces,
+    // so the maps don't grow with every IP ever seen
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            middleware::rate_limit::LIMITER.evict_idle(std::time::Instant::now());
+            middleware::rate_limit::ADMIN_LIMITER.evict_idle(std::time::Instant::now());
+            middleware::admin_auth::sweep_expired_sessions();
+            core::challenge::sweep_used();
+            core::site_quota::QUOTA.evict_idle(std::time::Instant::now());
+        }
+    });
+
+    // Expired admin auth failure records (MAX_TRACKED_FAILURES bounds them in between)
+    tokio::spawn(async {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(CONFIG.lockout_sweep_interval));
+        loop {
+            interval.tick().await;
+            let removed = middleware::admin_auth::sweep_expired_lockouts();
+            if removed > 0 {
+                tracing::debug!("Swept {} expired admin auth failure records", removed);
+            }
+        }
+    });
+
+    // SIGHUP re-reads the runtime settings, like POST /api/admin/config/reload
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            match api::admin::reload_config("SIGHUP") {
+                Ok(report) => tracing::info!("Configuration reloaded: {}", report),
+                Err(errors) => {
+                    tracing::error!("Configuration not reloaded: {}", errors.join("; "))
+                }
+            }
+        }
+    });
+
+    let saved = store.clone();
+    let shutdown = async move {
+        let signal = shutdown_signal().await;
+        tracing::info!("{} received, shutting down and saving data...", signal);
+        if let Err(e) = state::save(saved).await {
             tracing::error!("Failed to save on shutdown: {}", e);
         }
     };
 
     // CORS — frontend may be hosted on a different origin (GitHub Pages, Cloudflare Pages, ...).
-    let cors_layer = CorsLayer::new()
-        .allow_origin(tower_http::cors::AllowOrigin::mirror_request())
+    // Allowed origins are mirrored (a literal `*` can't be combined with credentials),
+    // so CORS=* still sends credentials to any origin; Config warns about it.
+    // The admin API has its own, narrower policy: ADMIN_CORS origins, token headers,
+    // no credentials (the admin panel authenticates with headers, not cookies).
+    api::admin::apply_runtime_config(&runtime());
+    let public_cors = CorsLayer::new()
+        .allow_origin(middleware::cors::PUBLIC.allow_origin())
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            // Validated when CONFIG is loaded
+            HeaderName::try_from(CONFIG.header_referer.as_str()).unwrap(),
+            HeaderName::try_from(CONFIG.header_key.as_str()).unwrap(),
+            HeaderName::try_from(CONFIG.header_nonce.as_str()).unwrap(),
+            HeaderName::try_from(CONFIG.header_identity.as_str()).unwrap(),
+            HeaderName::from_static(middleware::request_id::REQUEST_ID_HEADER),
+        ])
+        .allow_credentials(CONFIG.cors_allow_credentials)
+        .expose_headers(public_exposed_headers(
+            &CONFIG.header_identity,
+            &CONFIG.cors_expose_headers,
+        ));
+    let admin_cors = CorsLayer::new()
+        .allow_origin(middleware::cors::ADMIN.allow_origin())
         .allow_methods([
             Method::GET,
             Method::POST,
@@ -96,43 +374,213 @@ async fn main() {
             header::CONTENT_TYPE,
             header::AUTHORIZATION,
             HeaderName::from_static("x-admin-token"),
-            HeaderName::from_static("x-bsz-referer"),
+            HeaderName::from_static("x-admin-session"),
+            HeaderName::from_static("x-csrf-token"),
+            HeaderName::from_static(middleware::request_id::REQUEST_ID_HEADER),
         ])
-        .allow_credentials(true)
-        .expose_headers([header::SET_COOKIE]);
-
-    let mut app = Router::new()
-        .route("/", get(root))
-        .route("/api", post(api::handlers::api_handler))
-        .route("/api", get(api::handlers::get_handler))
-        .route("/api", put(api::handlers::put_handler))
-        .route("/ping", get(api::handlers::ping_handler));
+        .expose_headers([HeaderName::from_static(
+            middleware::request_id::REQUEST_ID_HEADER,
+        )]);
 
-    // Admin API is mounted only when ADMIN_TOKEN is configured.
+    // Admin API is mounted only when ADMIN_TOKEN is configured and ADMIN_ENABLED isn't false.
     // Empty token means the operator does not want a remotely-reachable control plane.
-    if !CONFIG.admin_token.is_empty() {
-        app = app.nest("/api/admin", admin_routes());
+    let app = routes(
+        store,
+        public_cors,
+        CONFIG.admin_enabled().then_some(admin_cors),
+    );
+    if CONFIG.admin_enabled() && runtime().admin_cors.is_empty() {
+        tracing::info!(
+            "Admin API answers same-origin browsers only; set ADMIN_CORS to the admin panel's origin if it is hosted elsewhere"
+        );
     }
 
     let app = app
         .layer(axum_middleware::from_fn(
-            middleware::identity::identity_middleware,
+            middleware::real_ip::real_ip_middleware,
         ))
-        .layer(cors_layer)
-        .layer(TraceLayer::new_for_http());
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_request(())
+                .on_response(logging::on_response),
+        )
+        // Outside the trace layer, so the span already sees the id
+        .layer(axum_middleware::from_fn(
+            middleware::request_id::request_id_middleware,
+        ));
 
     let addr: SocketAddr = CONFIG.web_addr.parse().expect("Invalid address");
     tracing::info!("Busuanzi listening on {}", addr);
-    if CONFIG.admin_token.is_empty() {
-        tracing::info!("Admin API disabled (set ADMIN_TOKEN to enable)");
+    if !CONFIG.admin_api {
+        tracing::info!("Admin API disabled by ADMIN_ENABLED=false, /api/admin/* answers 404");
+    } else if !CONFIG.admin_enabled() {
+        tracing::info!("Admin API disabled (set ADMIN_TOKEN or ADMIN_TOKEN_HASH to enable)");
     } else {
         tracing::info!("Admin API mounted at /api/admin/*");
     }
-    tracing::info!("Data saves every {}s", CONFIG.save_interval);
+    let rt = runtime();
+    if rt.save_every_n_writes > 0 {
+        tracing::info!(
+            "Data saves every {}s or after {} writes",
+            rt.save_interval,
+            rt.save_every_n_writes
+        );
+    } else {
+        tracing::info!("Data saves every {}s", rt.save_interval);
+    }
+
+    // Load certificates before binding, so a bad path fails fast
+    let tls_config = if CONFIG.tls_enabled() {
+        match tls::load_config(&CONFIG.tls_cert, &CONFIG.tls_key) {
+            Ok((config, certificate)) => {
+                tokio::spawn(tls::watch(certificate));
+                Some(config)
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                drop(log_guard);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown)
+    match tls_config {
+        Some(config) => {
+            tracing::info!("TLS enabled (HTTPS, certificate {})", CONFIG.tls_cert);
+            tls::serve(listener, app, config, shutdown).await;
+        }
+        None => {
+            tracing::info!(
+                "TLS disabled (plain HTTP; set TLS_CERT_PATH and TLS_KEY_PATH to serve HTTPS)"
+            );
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ConnectInfo;
+    use axum::http::StatusCode;
+    use axum::Extension;
+    use tower::ServiceExt;
+
+    async fn set_cookie(req: Request<Body>) -> Option<String> {
+        let res = public_routes(Arc::new(Store::new()))
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_ne!(res.status(), StatusCode::NOT_FOUND);
+        res.headers()
+            .get(header::SET_COOKIE)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn admin_routes_absent_when_disabled() {
+        let stats = || {
+            Request::get("/api/admin/stats")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = routes(Arc::new(Store::new()), CorsLayer::new(), None)
+            .oneshot(stats())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = routes(Arc::new(Store::new()), CorsLayer::new(), None)
+            .oneshot(
+                Request::post("/api/admin/login")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = routes(
+            Arc::new(Store::new()),
+            CorsLayer::new(),
+            Some(CorsLayer::new()),
+        )
+        .oneshot(stats())
         .await
         .unwrap();
+        assert_ne!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn exposed_headers_merge_without_duplicates() {
+        let extra = vec!["server-timing".to_string(), "x-request-id".to_string()];
+        let names: Vec<String> = public_exposed_headers("x-bsz-identity", &extra)
+            .iter()
+            .map(|h| h.as_str().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["x-request-id", "x-bsz-identity", "server-timing"]
+        );
+    }
+
+    #[tokio::test]
+    async fn identity_cookie_only_on_counting_routes() {
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert_eq!(set_cookie(get("/")).await, None);
+        assert_eq!(set_cookie(get("/ping")).await, None);
+
+        let count = Request::post("/api")
+            .header("x-bsz-referer", "https://identity-routes.test/")
+            .body(Body::empty())
+            .unwrap();
+        assert!(set_cookie(count)
+            .await
+            .is_some_and(|c| c.starts_with("busuanziId=")));
+        let pixel = get("/pixel.gif?host=identity-routes.test");
+        assert!(set_cookie(pixel).await.is_some());
+    }
+
+    /// Identity cookie of a first visit from `peer`, as served with connect info
+    async fn identity_from(peer: &str, forwarded_for: Option<&str>) -> String {
+        let app = public_routes(Arc::new(Store::new()))
+            .layer(axum_middleware::from_fn(
+                middleware::real_ip::real_ip_middleware,
+            ))
+            // What into_make_service_with_connect_info inserts for each connection
+            .layer(Extension(ConnectInfo(peer.parse::<SocketAddr>().unwrap())));
+        let mut req = Request::post("/api")
+            .header("x-bsz-referer", "https://connect-info.test/")
+            .header(header::USER_AGENT, "Mozilla/5.0");
+        if let Some(xff) = forwarded_for {
+            req = req.header("X-Forwarded-For", xff);
+        }
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        res.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn identity_follows_socket_peer() {
+        let a = identity_from("203.0.113.1:40000", None).await;
+        assert_eq!(identity_from("203.0.113.1:40001", None).await, a);
+        assert_ne!(identity_from("203.0.113.2:40000", None).await, a);
+        // TRUST_PROXY_HEADERS is off by default: a spoofed header changes nothing
+        assert_eq!(
+            identity_from("203.0.113.1:40000", Some("198.51.100.7")).await,
+            a
+        );
+    }
 }
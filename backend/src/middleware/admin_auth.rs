@@ -1,35 +1,523 @@
 use crate::config::CONFIG;
+use crate::middleware::identity::parse_cookie;
 use axum::{
     body::Body,
-    http::{Request, Response, StatusCode},
+    http::{header, Method, Request, Response, StatusCode},
     middleware::Next,
     response::IntoResponse,
 };
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use once_cell::sync::Lazy;
-use std::time::Instant;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::{Choice, ConstantTimeEq};
+use tokio::sync::Semaphore;
 
-/// Track failed login attempts per IP: (fail_count, last_fail_time)
-static FAIL_MAP: Lazy<DashMap<String, (u32, Instant)>> = Lazy::new(DashMap::new);
+/// Track failed login attempts per IP: (fail_count, last_fail_time).
+/// Mirrored to the `admin_lockouts` table so a restart doesn't hand out fresh attempts;
+/// wall-clock times so the two line up.
+static FAIL_MAP: Lazy<DashMap<String, (u32, SystemTime)>> = Lazy::new(DashMap::new);
 
 const MAX_FAILS: u32 = 5;
 const LOCKOUT_SECS: u64 = 300; // 5 minutes
 
+/// Sessions issued by POST /api/admin/login (and 2FA enrollment)
+static SESSIONS: Lazy<DashMap<String, Session>> = Lazy::new(DashMap::new);
+pub const SESSION_TTL: Duration = Duration::from_secs(12 * 3600);
+
+/// Cookie the session id is also set in, so the panel can authenticate without resending the token
+pub const SESSION_COOKIE: &str = "bsz_admin_session";
+
+struct Session {
+    expires: Instant,
+    /// Access of the token that logged in
+    access: AdminAccess,
+    /// Must accompany state-changing requests authenticated by the cookie alone
+    csrf_token: String,
+}
+
+pub struct NewSession {
+    pub id: String,
+    pub csrf_token: String,
+}
+
+/// How a request got past admin_auth, for the CSRF check that runs after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAuth {
+    /// A token the client attached itself (Bearer, X-Admin-Token, Basic, query)
+    Token,
+    /// Only the session cookie, which the browser sends on its own
+    Cookie(String),
+}
+
+/// Which credential authenticated a request (a token digest, or the session), for
+/// limits that must follow the credential across client IPs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credential(pub String);
+
+impl Credential {
+    fn token(token: &str) -> Self {
+        Credential(format!(
+            "token:{}",
+            hex::encode(&Sha1::digest(token.as_bytes())[..8])
+        ))
+    }
+}
+
+/// What an authenticated admin request may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAccess {
+    /// ADMIN_TOKEN(S) / ADMIN_TOKEN_HASH: every route
+    Full,
+    /// ADMIN_READONLY_TOKENS: only routes that don't change anything
+    ReadOnly,
+}
+
+/// Routes a read-only token may use: every GET except /sync (which pulls counts
+/// from busuanzi and overwrites local ones), plus /login to obtain a session
+fn read_only_allowed(method: &Method, path: &str) -> bool {
+    match *method {
+        Method::GET | Method::HEAD => path != "/sync",
+        Method::POST => path == "/login",
+        _ => false,
+    }
+}
+
+/// Issue a new session after a successful login
+pub fn create_session(access: AdminAccess) -> NewSession {
+    let id = hex::encode(rand::random::<[u8; 32]>());
+    let csrf_token = hex::encode(rand::random::<[u8; 32]>());
+    SESSIONS.insert(
+        id.clone(),
+        Session {
+            expires: Instant::now() + SESSION_TTL,
+            access,
+            csrf_token: csrf_token.clone(),
+        },
+    );
+    NewSession { id, csrf_token }
+}
+
+/// `Set-Cookie` value carrying a session. Only a same-origin panel can use it: the
+/// admin CORS policy doesn't allow credentials, so a panel on another origin sends
+/// X-Admin-Session instead. `SameSite=Strict` keeps other sites from riding on it;
+/// the CSRF token is the second line of defence.
+pub fn session_cookie(id: &str) -> String {
+    format!(
+        "{}={}; Path=/api/admin; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE,
+        id,
+        SESSION_TTL.as_secs()
+    )
+}
+
+/// Access of a live session
+fn session_access(id: &str) -> Option<AdminAccess> {
+    SESSIONS
+        .get(id)
+        .filter(|session| session.expires > Instant::now())
+        .map(|session| session.access)
+}
+
+/// CSRF token of a live session
+pub fn session_csrf(id: &str) -> Option<String> {
+    SESSIONS
+        .get(id)
+        .filter(|session| session.expires > Instant::now())
+        .map(|session| session.csrf_token.clone())
+}
+
+pub fn sweep_expired_sessions() {
+    let now = Instant::now();
+    SESSIONS.retain(|_, session| session.expires > now);
+}
+
+/// Drop every session, e.g. when 2FA is turned off or re-enrolled
+pub fn clear_sessions() {
+    SESSIONS.clear();
+}
+
+/// Last `auth_failed` log entry per IP, so a brute-force run can't flood operation_logs
+static AUTH_LOG_THROTTLE: Lazy<DashMap<String, Instant>> = Lazy::new(DashMap::new);
+const AUTH_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether an `auth_failed` entry for `ip` may be written now
+fn auth_log_allowed(ip: &str, now: Instant) -> bool {
+    match AUTH_LOG_THROTTLE.entry(ip.to_string()) {
+        Entry::Occupied(mut last) => {
+            if now.saturating_duration_since(*last.get()) < AUTH_LOG_INTERVAL {
+                return false;
+            }
+            last.insert(now);
+            true
+        }
+        Entry::Vacant(slot) => {
+            slot.insert(now);
+            true
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Seconds until the failures recorded at `last_time` stop counting. A clock set
+/// back before `last_time` counts as no time having passed.
+fn remaining_secs(last_time: SystemTime) -> u64 {
+    let elapsed = last_time.elapsed().map_or(0, |d| d.as_secs());
+    LOCKOUT_SECS.saturating_sub(elapsed)
+}
+
+/// Restore persisted failure records on startup
+pub fn load_lockouts() {
+    let rows = match crate::state::load_lockouts() {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::warn!("Failed to load admin lockouts: {}", e);
+            return;
+        }
+    };
+    let now = unix_now();
+    let mut restored = 0;
+    for (ip, count, locked_until) in rows {
+        if locked_until <= now {
+            continue;
+        }
+        let last_fail = (locked_until - LOCKOUT_SECS as i64).max(0) as u64;
+        FAIL_MAP.insert(ip, (count, UNIX_EPOCH + Duration::from_secs(last_fail)));
+        restored += 1;
+    }
+    crate::state::delete_expired_lockouts(now);
+    for evicted in evict_oldest(&FAIL_MAP, CONFIG.max_tracked_failures) {
+        crate::state::delete_lockout(&evicted);
+    }
+    if restored > 0 {
+        tracing::info!("Restored {} admin auth failure records", restored);
+    }
+}
+
+/// Drop expired entries from the map and the table. Returns how many map entries were removed.
+pub fn sweep_expired_lockouts() -> usize {
+    let before = FAIL_MAP.len();
+    FAIL_MAP.retain(|_, (_, last_time)| remaining_secs(*last_time) > 0);
+    AUTH_LOG_THROTTLE.retain(|_, last| last.elapsed() < AUTH_LOG_INTERVAL);
+    crate::state::delete_expired_lockouts(unix_now());
+    before - FAIL_MAP.len()
+}
+
+/// Evict entries until at most `max` remain: unlocked before locked, then least
+/// recently failed first. Evicts down to 90% of `max` so a flood of new IPs doesn't
+/// rescan the map on every request. Returns the evicted IPs.
+fn evict_oldest(map: &DashMap<String, (u32, SystemTime)>, max: usize) -> Vec<String> {
+    if max == 0 || map.len() <= max {
+        return Vec::new();
+    }
+    let target = max - max / 10;
+    let mut entries: Vec<(bool, SystemTime, String)> = map
+        .iter()
+        .map(|e| {
+            let (count, last_time) = *e.value();
+            (count >= MAX_FAILS, last_time, e.key().clone())
+        })
+        .collect();
+    entries.sort();
+    let excess = entries.len().saturating_sub(target);
+    entries
+        .into_iter()
+        .take(excess)
+        .map(|(_, _, ip)| {
+            map.remove(&ip);
+            ip
+        })
+        .collect()
+}
+
+/// Number of IPs with a failure record in memory
+pub fn tracked_failures() -> usize {
+    FAIL_MAP.len()
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockoutInfo {
+    pub ip: String,
+    pub fail_count: u32,
+    /// Whether the IP is currently refused (fail_count reached the limit)
+    pub locked: bool,
+    pub remaining_secs: u64,
+}
+
+/// Current (unexpired) failure records, locked IPs first
+pub fn lockouts() -> Vec<LockoutInfo> {
+    let mut list: Vec<LockoutInfo> = FAIL_MAP
+        .iter()
+        .filter_map(|entry| {
+            let (count, last_time) = *entry.value();
+            let remaining = remaining_secs(last_time);
+            (remaining > 0).then(|| LockoutInfo {
+                ip: entry.key().clone(),
+                fail_count: count,
+                locked: count >= MAX_FAILS,
+                remaining_secs: remaining,
+            })
+        })
+        .collect();
+    list.sort_by(|a, b| b.locked.cmp(&a.locked).then(a.ip.cmp(&b.ip)));
+    list
+}
+
+/// Forget all failures for `ip`. Returns false if there was nothing to clear.
+pub fn unlock(ip: &str) -> bool {
+    let removed = FAIL_MAP.remove(ip).is_some();
+    crate::state::delete_lockout(ip);
+    removed
+}
+
+/// Hash verification is deliberately slow (tens of ms of CPU). Bound how many run
+/// at once so a flood of bad tokens from rotating IPs can't exhaust the CPU.
+static HASH_VERIFY_SLOTS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(2));
+
+/// How long a request queues for a verification slot before getting 429
+const HASH_VERIFY_WAIT: Duration = Duration::from_millis(500);
+
+/// SHA-1 of the last token that verified against ADMIN_TOKEN_HASH, so the admin
+/// panel's steady stream of requests doesn't pay for argon2/bcrypt every time
+static VERIFIED_TOKEN: Lazy<RwLock<Option<[u8; 20]>>> = Lazy::new(|| RwLock::new(None));
+
+/// Outcome of checking a presented token
+enum Verdict {
+    Valid(AdminAccess),
+    Invalid,
+    /// All hash verification slots stayed in use for [`HASH_VERIFY_WAIT`]
+    Busy,
+}
+
+pub(crate) fn ct_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Compare against every token without stopping at the first match, so the timing
+/// doesn't reveal which (or how many) tokens are configured
+fn matches_any(token: &str, tokens: &[String]) -> bool {
+    tokens
+        .iter()
+        .fold(Choice::from(0), |acc, t| {
+            acc | token.as_bytes().ct_eq(t.as_bytes())
+        })
+        .into()
+}
+
+async fn verify_token(token: &str) -> Verdict {
+    if token.is_empty() {
+        return Verdict::Invalid;
+    }
+    if matches_any(token, &CONFIG.admin_tokens) {
+        return Verdict::Valid(AdminAccess::Full);
+    }
+    if matches_any(token, &CONFIG.admin_readonly_tokens) {
+        return Verdict::Valid(AdminAccess::ReadOnly);
+    }
+    if CONFIG.admin_token_hash.is_empty() {
+        return Verdict::Invalid;
+    }
+
+    let digest: [u8; 20] = Sha1::digest(token.as_bytes()).into();
+    if let Some(verified) = *VERIFIED_TOKEN.read().unwrap() {
+        if bool::from(verified.ct_eq(&digest)) {
+            return Verdict::Valid(AdminAccess::Full);
+        }
+    }
+
+    let Ok(Ok(_permit)) = tokio::time::timeout(HASH_VERIFY_WAIT, HASH_VERIFY_SLOTS.acquire()).await
+    else {
+        return Verdict::Busy;
+    };
+    let token = token.to_string();
+    let valid = tokio::task::spawn_blocking(move || verify_hash(&token, &CONFIG.admin_token_hash))
+        .await
+        .unwrap_or(false);
+
+    if valid {
+        *VERIFIED_TOKEN.write().unwrap() = Some(digest);
+        Verdict::Valid(AdminAccess::Full)
+    } else {
+        Verdict::Invalid
+    }
+}
+
+/// Routes that take `?token=` / `?session=`: the SSE sync stream and the database
+/// download, whose clients (EventSource, a plain link) can't set headers. Anywhere
+/// else a credential in the URL would only end up in access logs and browser history.
+fn accepts_query_credentials(path: &str) -> bool {
+    matches!(path, "/sync" | "/export")
+}
+
+/// `name=` values from a query string. Values that don't percent-decode to valid
+/// UTF-8 are dropped rather than compared as an empty string.
+fn query_values(query: &str, name: &str) -> Vec<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .filter_map(|token| urlencoding::decode(token).ok())
+        .map(|token| token.into_owned())
+        .collect()
+}
+
+/// Password part of an HTTP Basic credential (`base64(user:pass)`)
+fn basic_password(encoded: &str) -> Option<String> {
+    use base64::Engine;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
+}
+
+fn verify_hash(token: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+        PasswordHash::new(hash)
+            .map(|parsed| {
+                argon2::Argon2::default()
+                    .verify_password(token.as_bytes(), &parsed)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else {
+        bcrypt::verify(token, hash).unwrap_or(false)
+    }
+}
+
 fn get_client_ip(req: &Request<Body>) -> String {
-    req.headers()
-        .get("X-Forwarded-For")
-        .or_else(|| req.headers().get("X-Real-IP"))
+    crate::middleware::real_ip::request_ip(req).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Presented tokens: Authorization (Bearer <token> or raw), else X-Admin-Token, plus
+/// `?token=` where allowed. Each is tagged with the credential form for the audit log.
+/// One per source: `true` when a header or `?token=` is repeated, since that would be
+/// several guesses for one failure.
+fn presented_tokens(
+    req: &Request<Body>,
+    query_credentials: bool,
+) -> (Vec<(&'static str, String)>, bool) {
+    let mut candidates: Vec<(&'static str, String)> = Vec::new();
+    let mut duplicated = ["Authorization", "X-Admin-Token"]
+        .iter()
+        .any(|name| req.headers().get_all(*name).iter().count() > 1);
+    match req
+        .headers()
+        .get("Authorization")
         .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .unwrap_or("unknown")
-        .trim()
-        .to_string()
+    {
+        Some(header) => {
+            if let Some(token) = header.strip_prefix("Bearer ") {
+                candidates.push(("bearer", token.to_string()));
+            } else if let Some(encoded) = header.strip_prefix("Basic ") {
+                // The username is ignored; the password is the admin token
+                if let Some(password) = basic_password(encoded) {
+                    candidates.push(("basic", password));
+                }
+            } else {
+                candidates.push(("authorization", header.to_string()));
+            }
+        }
+        None => {
+            if let Some(token) = req
+                .headers()
+                .get("X-Admin-Token")
+                .and_then(|h| h.to_str().ok())
+            {
+                candidates.push(("header", token.to_string()));
+            }
+        }
+    }
+
+    // Also check token in query string (for SSE which doesn't support headers)
+    if let Some(query) = req.uri().query().filter(|_| query_credentials) {
+        let tokens = query_values(query, "token");
+        duplicated |= tokens.len() > 1;
+        candidates.extend(tokens.into_iter().take(1).map(|t| ("query", t)));
+    }
+    (candidates, duplicated)
+}
+
+/// Count a failed attempt from `ip` towards its lockout and log it
+fn record_failure(ip: &str, candidates: &[(&str, String)]) {
+    let mut created = false;
+    let mut entry = FAIL_MAP.entry(ip.to_string()).or_insert_with(|| {
+        created = true;
+        (0, SystemTime::now())
+    });
+    let (count, last_time) = entry.value_mut();
+    // Reset if lockout expired
+    if remaining_secs(*last_time) == 0 {
+        *count = 0;
+    }
+    *count += 1;
+    *last_time = SystemTime::now();
+    let count = *count;
+    drop(entry);
+    crate::state::save_lockout(ip, count, unix_now() + LOCKOUT_SECS as i64);
+    if created {
+        for evicted in evict_oldest(&FAIL_MAP, CONFIG.max_tracked_failures) {
+            crate::state::delete_lockout(&evicted);
+        }
+    }
+
+    let forms = if candidates.is_empty() {
+        "none".to_string()
+    } else {
+        let mut forms: Vec<&str> = candidates.iter().map(|(form, _)| *form).collect();
+        forms.dedup();
+        forms.join(",")
+    };
+    if count == MAX_FAILS {
+        // Once per lockout, so never throttled
+        crate::state::add_log(
+            "auth_locked",
+            &format!(
+                "locked for {}s after {} failures (via {})",
+                LOCKOUT_SECS, count, forms
+            ),
+            ip,
+        );
+    } else if auth_log_allowed(ip, Instant::now()) {
+        crate::state::add_log(
+            "auth_failed",
+            &format!("attempt {}/{} (via {})", count, MAX_FAILS, forms),
+            ip,
+        );
+    }
+}
+
+/// 403 for a read-only credential on a route that changes state
+fn deny_read_only(req: &Request<Body>, access: AdminAccess) -> Option<Response<Body>> {
+    if access == AdminAccess::Full || read_only_allowed(req.method(), req.uri().path()) {
+        return None;
+    }
+    Some(
+        (
+            StatusCode::FORBIDDEN,
+            [("Content-Type", "application/json")],
+            r#"{"success":false,"message":"read-only token"}"#,
+        )
+            .into_response(),
+    )
 }
 
-pub async fn admin_auth_middleware(req: Request<Body>, next: Next) -> Response<Body> {
-    // ADMIN_TOKEN being empty is unreachable: main.rs refuses to mount the
+pub async fn admin_auth_middleware(mut req: Request<Body>, next: Next) -> Response<Body> {
+    // No admin credential is unreachable: main.rs refuses to mount the
     // /api/admin/* router in that case. Defense-in-depth fall-through.
-    if CONFIG.admin_token.is_empty() {
+    if !CONFIG.admin_enabled() {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
             [("Content-Type", "application/json")],
@@ -40,11 +528,21 @@ pub async fn admin_auth_middleware(req: Request<Body>, next: Next) -> Response<B
 
     let ip = get_client_ip(&req);
 
+    // Outside the allowlist nothing about tokens is revealed, not even lockout state
+    if !CONFIG.admin_ip_allowed(&ip) {
+        return (
+            StatusCode::FORBIDDEN,
+            [("Content-Type", "application/json")],
+            r#"{"success":false,"message":"ip not allowed"}"#,
+        )
+            .into_response();
+    }
+
     // Check if IP is locked out
     if let Some(entry) = FAIL_MAP.get(&ip) {
         let (count, last_time) = entry.value();
-        if *count >= MAX_FAILS && last_time.elapsed().as_secs() < LOCKOUT_SECS {
-            let remaining = LOCKOUT_SECS - last_time.elapsed().as_secs();
+        let remaining = remaining_secs(*last_time);
+        if *count >= MAX_FAILS && remaining > 0 {
             return (
                 StatusCode::TOO_MANY_REQUESTS,
                 [("Content-Type", "application/json")],
@@ -57,58 +555,107 @@ pub async fn admin_auth_middleware(req: Request<Body>, next: Next) -> Response<B
         }
     }
 
-    // Check Authorization header: Bearer <token>
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok());
+    let query_credentials = accepts_query_credentials(req.uri().path());
+    let (candidates, duplicated) = presented_tokens(&req, query_credentials);
+    if duplicated {
+        record_failure(&ip, &candidates);
+        return (
+            StatusCode::BAD_REQUEST,
+            [("Content-Type", "application/json")],
+            r#"{"success":false,"message":"duplicate credentials"}"#,
+        )
+            .into_response();
+    }
 
-    let mut is_authorized = match auth_header {
-        Some(header) => {
-            if let Some(token) = header.strip_prefix("Bearer ") {
-                token == CONFIG.admin_token
-            } else {
-                header == CONFIG.admin_token
+    // Every credential form goes through the same constant-time check
+    let mut token_access = None;
+    for (_, token) in &candidates {
+        match verify_token(token).await {
+            Verdict::Valid(access) => {
+                token_access = Some((access, Credential::token(token)));
+                break;
             }
-        }
-        None => req
-            .headers()
-            .get("X-Admin-Token")
-            .and_then(|h| h.to_str().ok())
-            .map(|t| t == CONFIG.admin_token)
-            .unwrap_or(false),
-    };
-
-    // Also check token in query string (for SSE which doesn't support headers)
-    if !is_authorized {
-        if let Some(query) = req.uri().query() {
-            for pair in query.split('&') {
-                if let Some(token) = pair.strip_prefix("token=") {
-                    let decoded = urlencoding::decode(token).unwrap_or_default();
-                    if decoded == CONFIG.admin_token {
-                        is_authorized = true;
-                        break;
-                    }
-                }
+            Verdict::Invalid => {}
+            Verdict::Busy => {
+                // Counted like a wrong token, so saturating the slots doesn't buy
+                // unlimited attempts at the candidates checked before it
+                record_failure(&ip, &candidates);
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [("Content-Type", "application/json"), ("Retry-After", "1")],
+                    r#"{"success":false,"message":"busy, retry shortly"}"#,
+                )
+                    .into_response();
             }
         }
     }
 
-    if is_authorized {
-        // Clear fail count on success
-        FAIL_MAP.remove(&ip);
+    // Without a token, a live session cookie authenticates on its own (not for /login,
+    // which must keep requiring the token so a session can't renew itself)
+    let cookie = req
+        .headers()
+        .get(header::COOKIE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|cookies| parse_cookie(cookies, SESSION_COOKIE));
+    let cookie_session = cookie
+        .clone()
+        .filter(|_| token_access.is_none() && req.uri().path() != "/login")
+        .and_then(|id| session_access(&id).map(|access| (id, access)));
+
+    if let Some((id, access)) = cookie_session {
+        if let Some(denied) = deny_read_only(&req, access) {
+            return denied;
+        }
+        req.extensions_mut()
+            .insert(Credential(format!("session:{}", id)));
+        req.extensions_mut().insert(AdminAuth::Cookie(id));
+        req.extensions_mut().insert(access);
         next.run(req).await
-    } else {
-        // Record failure
-        let mut entry = FAIL_MAP.entry(ip.clone()).or_insert((0, Instant::now()));
-        let (count, last_time) = entry.value_mut();
-        // Reset if lockout expired
-        if last_time.elapsed().as_secs() >= LOCKOUT_SECS {
-            *count = 0;
+    } else if let Some((access, credential)) = token_access {
+        req.extensions_mut().insert(AdminAuth::Token);
+        req.extensions_mut().insert(credential);
+        req.extensions_mut().insert(access);
+
+        // Clear fail count on success
+        if let Some((_, (count, _))) = FAIL_MAP.remove(&ip) {
+            crate::state::delete_lockout(&ip);
+            crate::state::add_log(
+                "auth_recovered",
+                &format!("after {} failed attempts", count),
+                &ip,
+            );
+        }
+
+        // With TOTP enabled the token alone only reaches /login, which trades a code for a session
+        if crate::core::totp::required() && req.uri().path() != "/login" {
+            let session = req
+                .headers()
+                .get("X-Admin-Session")
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string)
+                .or_else(|| {
+                    req.uri()
+                        .query()
+                        .filter(|_| query_credentials)
+                        .and_then(|q| query_values(q, "session").into_iter().next())
+                })
+                .or(cookie);
+            if session.as_deref().and_then(session_access).is_none() {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [("Content-Type", "application/json")],
+                    r#"{"success":false,"message":"2fa required","two_factor":true}"#,
+                )
+                    .into_response();
+            }
         }
-        *count += 1;
-        *last_time = Instant::now();
 
+        if let Some(denied) = deny_read_only(&req, access) {
+            return denied;
+        }
+        next.run(req).await
+    } else {
+        record_failure(&ip, &candidates);
         (
             StatusCode::UNAUTHORIZED,
             [("Content-Type", "application/json")],
@@ -117,3 +664,196 @@ pub async fn admin_auth_middleware(req: Request<Body>, next: Next) -> Response<B
             .into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only_routes() {
+        assert!(read_only_allowed(&Method::GET, "/stats"));
+        assert!(read_only_allowed(&Method::GET, "/export"));
+        assert!(read_only_allowed(&Method::HEAD, "/logs"));
+        assert!(read_only_allowed(&Method::POST, "/login"));
+        assert!(!read_only_allowed(&Method::GET, "/sync"));
+        assert!(!read_only_allowed(&Method::POST, "/save"));
+        assert!(!read_only_allowed(&Method::DELETE, "/keys"));
+        assert!(!read_only_allowed(&Method::PUT, "/stats"));
+    }
+
+    #[test]
+    fn sessions_keep_their_access() {
+        let full = create_session(AdminAccess::Full);
+        let read_only = create_session(AdminAccess::ReadOnly);
+        assert_eq!(session_access(&full.id), Some(AdminAccess::Full));
+        assert_eq!(session_access(&read_only.id), Some(AdminAccess::ReadOnly));
+        assert_eq!(session_access("missing"), None);
+    }
+
+    #[test]
+    fn matches_any_configured_token() {
+        let tokens = vec!["alice".to_string(), "bob".to_string()];
+        assert!(matches_any("alice", &tokens));
+        assert!(matches_any("bob", &tokens));
+        assert!(!matches_any("carol", &tokens));
+        assert!(!matches_any("ali", &tokens));
+        assert!(!matches_any("alice", &[]));
+    }
+
+    #[test]
+    fn evicts_unlocked_then_oldest() {
+        let map = DashMap::new();
+        let now = SystemTime::now();
+        let ago = |secs| now.checked_sub(Duration::from_secs(secs)).unwrap();
+        map.insert("locked-old".to_string(), (MAX_FAILS, ago(200)));
+        map.insert("old".to_string(), (1, ago(100)));
+        map.insert("mid".to_string(), (2, ago(50)));
+        map.insert("new".to_string(), (1, ago(1)));
+
+        assert!(evict_oldest(&map, 4).is_empty());
+        assert!(evict_oldest(&map, 0).is_empty());
+
+        // Oldest unlocked entries go first; locked ones only once none are left
+        assert_eq!(evict_oldest(&map, 3), vec!["old"]);
+        assert_eq!(evict_oldest(&map, 1), vec!["mid", "new"]);
+        assert!(map.contains_key("locked-old"));
+
+        // Larger caps evict down to 90% in one go
+        for i in 0..21 {
+            map.insert(format!("ip{}", i), (1, ago(100 + i)));
+        }
+        assert_eq!(evict_oldest(&map, 20).len(), 4);
+        assert_eq!(map.len(), 18);
+        assert!(map.contains_key("locked-old"));
+        assert!(!map.contains_key("ip20"));
+        assert!(map.contains_key("ip0"));
+    }
+
+    #[test]
+    fn lockout_follows_wall_clock() {
+        let now = SystemTime::now();
+        assert_eq!(remaining_secs(now), LOCKOUT_SECS);
+        assert_eq!(remaining_secs(now - Duration::from_secs(LOCKOUT_SECS)), 0);
+        // A clock set back doesn't end the lockout early
+        assert_eq!(remaining_secs(now + Duration::from_secs(60)), LOCKOUT_SECS);
+    }
+
+    #[test]
+    fn one_token_per_source() {
+        let request = |uri: &str, headers: &[(&str, &str)]| {
+            let mut req = Request::get(uri);
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let req = request("/sync?token=q", &[("Authorization", "Bearer a")]);
+        let (candidates, duplicated) = presented_tokens(&req, true);
+        assert!(!duplicated);
+        assert_eq!(
+            candidates,
+            [("bearer", "a".to_string()), ("query", "q".to_string())]
+        );
+        // Query tokens only count where the route takes them
+        let (candidates, _) = presented_tokens(&req, false);
+        assert_eq!(candidates.len(), 1);
+
+        for req in [
+            request("/stats", &[("Authorization", "a"), ("Authorization", "b")]),
+            request("/stats", &[("X-Admin-Token", "a"), ("X-Admin-Token", "b")]),
+            request("/sync?token=a&token=b", &[]),
+        ] {
+            let (candidates, duplicated) = presented_tokens(&req, true);
+            assert!(duplicated);
+            assert!(candidates.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn extracts_query_tokens() {
+        assert_eq!(query_values("sync_id=1&token=a%2Fb", "token"), vec!["a/b"]);
+        assert_eq!(query_values("token=x&token=y", "token"), vec!["x", "y"]);
+        // %FF is not valid UTF-8 once decoded
+        assert!(query_values("token=%FF", "token").is_empty());
+        assert!(query_values("tokens=x", "token").is_empty());
+    }
+
+    #[test]
+    fn query_credentials_only_for_sse_and_download() {
+        assert!(accepts_query_credentials("/sync"));
+        assert!(accepts_query_credentials("/export"));
+        assert!(!accepts_query_credentials("/keys"));
+        assert!(!accepts_query_credentials("/sync/upload"));
+        assert!(!accepts_query_credentials("/logs/export.csv"));
+    }
+
+    #[test]
+    fn decodes_basic_password() {
+        // admin:s3cret
+        assert_eq!(
+            basic_password("YWRtaW46czNjcmV0").as_deref(),
+            Some("s3cret")
+        );
+        // :tok:with:colons
+        assert_eq!(
+            basic_password("OnRvazp3aXRoOmNvbG9ucw==").as_deref(),
+            Some("tok:with:colons")
+        );
+        // no colon
+        assert_eq!(basic_password("YWRtaW4="), None);
+        assert_eq!(basic_password("not base64!"), None);
+    }
+
+    #[test]
+    fn throttles_auth_logs_per_ip() {
+        let start = Instant::now();
+        assert!(auth_log_allowed("throttle-test", start));
+        assert!(!auth_log_allowed(
+            "throttle-test",
+            start + Duration::from_secs(30)
+        ));
+        assert!(auth_log_allowed(
+            "throttle-test-2",
+            start + Duration::from_secs(30)
+        ));
+        assert!(auth_log_allowed(
+            "throttle-test",
+            start + Duration::from_secs(61)
+        ));
+    }
+
+    #[test]
+    fn compares_in_constant_time_helper() {
+        assert!(ct_eq("abc", "abc"));
+        assert!(!ct_eq("abc", "abd"));
+        assert!(!ct_eq("abc", "abcd"));
+        assert!(!ct_eq("", "abc"));
+    }
+
+    #[test]
+    fn verifies_argon2_hash() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        let salt = SaltString::encode_b64(b"bsz-test-salt").unwrap();
+        let hash = argon2::Argon2::default()
+            .hash_password(b"s3cret", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(verify_hash("s3cret", &hash));
+        assert!(!verify_hash("wrong", &hash));
+    }
+
+    #[test]
+    fn verifies_bcrypt_hash() {
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        assert!(verify_hash("s3cret", &hash));
+        assert!(!verify_hash("wrong", &hash));
+    }
+
+    #[test]
+    fn rejects_malformed_hash() {
+        assert!(!verify_hash("s3cret", "$argon2id$garbage"));
+        assert!(!verify_hash("s3cret", "not-a-hash"));
+    }
+}
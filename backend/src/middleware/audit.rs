@@ -0,0 +1,216 @@
+//! Audit trail for mutating admin calls
+//!
+//! For POST/PUT/PATCH/DELETE, records an `audit` operation log entry with the method,
+//! path, request body (JSON only, secrets redacted, truncated), status, the response's
+//! `success` flag and the duration. Handlers keep writing their own log entries.
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::OriginalUri,
+    http::{header, HeaderMap, Method, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+use crate::middleware::real_ip::client_ip;
+use crate::state;
+
+/// Bodies larger than this are not buffered, only their size is recorded
+const MAX_BUFFERED: u64 = 64 * 1024;
+/// Longest body text kept in the log entry
+const MAX_LOGGED_CHARS: usize = 2000;
+
+const REDACTED: &str = "[redacted]";
+
+/// Field names whose values never reach the log
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "code"
+        || name == "session"
+        || ["token", "secret", "password", "totp"]
+            .iter()
+            .any(|s| name.contains(s))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if is_sensitive(k) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Path plus query string, with sensitive query parameters redacted
+fn redacted_path(uri: &Uri) -> String {
+    let path = uri.path();
+    let Some(query) = uri.query() else {
+        return path.to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((k, _)) if is_sensitive(k) => format!("{}={}", k, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", path, query)
+}
+
+fn truncate(s: String) -> String {
+    match s.char_indices().nth(MAX_LOGGED_CHARS) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s,
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"))
+}
+
+/// What the audit entry says about a request body
+fn describe_body(json: bool, bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return String::new();
+    }
+    if json {
+        if let Ok(mut value) = serde_json::from_slice::<Value>(bytes) {
+            redact(&mut value);
+            return truncate(value.to_string());
+        }
+    }
+    format!("<{} bytes>", bytes.len())
+}
+
+/// Whether a body's size is known up front and small enough to buffer
+fn bufferable(body: &Body) -> bool {
+    body.size_hint().exact().is_some_and(|n| n <= MAX_BUFFERED)
+}
+
+pub async fn audit_middleware(req: Request<Body>, next: Next) -> Response {
+    if !matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+        return next.run(req).await;
+    }
+
+    let start = Instant::now();
+    let ip = client_ip(req.headers());
+    let method = req.method().to_string();
+    // Full path, not the one relative to the /api/admin nest
+    let path = match req.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => redacted_path(uri),
+        None => redacted_path(req.uri()),
+    };
+    let request_json = is_json(req.headers());
+
+    // Buffer small bodies only; uploads stream through untouched
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (req, body) = match declared {
+        Some(len) if len <= MAX_BUFFERED => {
+            let (parts, body) = req.into_parts();
+            let bytes = match axum::body::to_bytes(body, MAX_BUFFERED as usize).await {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"success": false, "message": "读取请求体失败"})),
+                    )
+                        .into_response()
+                }
+            };
+            let body = describe_body(request_json, &bytes);
+            (Request::from_parts(parts, Body::from(bytes)), body)
+        }
+        Some(len) => (req, format!("<{} bytes>", len)),
+        None => (req, String::new()),
+    };
+
+    let response = next.run(req).await;
+    let status = response.status();
+
+    // `success` from JSON responses; other responses go by status alone
+    let (response, success) = if is_json(response.headers()) && bufferable(response.body()) {
+        let (parts, body) = response.into_parts();
+        let bytes = axum::body::to_bytes(body, MAX_BUFFERED as usize)
+            .await
+            .unwrap_or_else(|_| Bytes::new());
+        let success = serde_json::from_slice::<Value>(&bytes)
+            .ok()
+            .and_then(|v| v.get("success").and_then(Value::as_bool))
+            .unwrap_or(status.is_success());
+        (Response::from_parts(parts, Body::from(bytes)), success)
+    } else {
+        (response, status.is_success())
+    };
+
+    let entry = json!({
+        "method": method,
+        "path": path,
+        "body": body,
+        "status": status.as_u16(),
+        "success": success,
+        "duration_ms": start.elapsed().as_millis() as u64
+    });
+    state::add_log("audit", &entry.to_string(), &ip);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_sensitive_fields() {
+        let body = br#"{"site_key":"a.com","code":"123456","nested":[{"admin_token":"t","pv":1}],"totp":"9"}"#;
+        let logged = describe_body(true, body);
+        let value: Value = serde_json::from_str(&logged).unwrap();
+        assert_eq!(value["site_key"], "a.com");
+        assert_eq!(value["code"], REDACTED);
+        assert_eq!(value["totp"], REDACTED);
+        assert_eq!(value["nested"][0]["admin_token"], REDACTED);
+        assert_eq!(value["nested"][0]["pv"], 1);
+    }
+
+    #[test]
+    fn redacts_query_parameters() {
+        let uri: Uri = "/api/admin/export?token=abc&site_key=a.com"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redacted_path(&uri),
+            "/api/admin/export?token=[redacted]&site_key=a.com"
+        );
+    }
+
+    #[test]
+    fn truncates_and_summarizes() {
+        let long = format!(r#"{{"detail":"{}"}}"#, "é".repeat(MAX_LOGGED_CHARS));
+        let logged = describe_body(true, long.as_bytes());
+        assert_eq!(logged.chars().count(), MAX_LOGGED_CHARS + 1);
+        assert!(logged.ends_with('…'));
+
+        assert_eq!(describe_body(false, b"SQLite format 3\0"), "<16 bytes>");
+        assert_eq!(describe_body(true, b"not json"), "<8 bytes>");
+        assert_eq!(describe_body(true, b""), "");
+    }
+}
@@ -0,0 +1,162 @@
+//! Which request origins the CORS layers answer for (`CORS`, `ADMIN_CORS`)
+//!
+//! Entries are `*` (any origin), an exact origin (`https://blog.example.com`)
+//! or a subdomain wildcard (`*.example.com`, optionally with a scheme:
+//! `https://*.example.com`). Allowed origins are mirrored back, never `*`.
+//! The rules are looked up per request, so a config reload applies them at once.
+
+use axum::http::HeaderValue;
+use std::sync::RwLock;
+use tower_http::cors::AllowOrigin;
+
+/// Origins allowed to call the public API
+pub static PUBLIC: Origins = Origins::new();
+/// Origins allowed to call /api/admin/*; none (same-origin only) unless ADMIN_CORS is set
+pub static ADMIN: Origins = Origins::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginRule {
+    Any,
+    Exact(String),
+    /// Any subdomain of `suffix` (not the apex), with `scheme` when one was given
+    Subdomain {
+        scheme: Option<String>,
+        suffix: String,
+    },
+}
+
+impl OriginRule {
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim().trim_end_matches('/').to_ascii_lowercase();
+        if entry == "*" {
+            return Some(OriginRule::Any);
+        }
+        let (scheme, rest) = match entry.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_string()), rest),
+            None => (None, entry.as_str()),
+        };
+        if let Some(suffix) = rest.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains(['*', '/']) {
+                return None;
+            }
+            return Some(OriginRule::Subdomain {
+                scheme,
+                suffix: suffix.to_string(),
+            });
+        }
+        if scheme.is_none() || rest.is_empty() || rest.contains(['*', '/']) {
+            return None;
+        }
+        Some(OriginRule::Exact(entry))
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Any => true,
+            OriginRule::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            OriginRule::Subdomain { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                let Some((origin_scheme, host)) = origin.split_once("://") else {
+                    return false;
+                };
+                if scheme.as_deref().is_some_and(|s| s != origin_scheme) {
+                    return false;
+                }
+                host.strip_suffix(suffix.as_str())
+                    .and_then(|label| label.strip_suffix('.'))
+                    .is_some_and(|label| !label.is_empty())
+            }
+        }
+    }
+}
+
+/// Parse the comma-separated CORS setting; unparsable entries are returned separately
+pub fn parse_rules(s: &str) -> (Vec<OriginRule>, Vec<String>) {
+    let mut rules = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match OriginRule::parse(entry) {
+            Some(rule) => rules.push(rule),
+            None => invalid.push(entry.to_string()),
+        }
+    }
+    (rules, invalid)
+}
+
+/// The origin rules of one CORS layer
+pub struct Origins {
+    rules: RwLock<Vec<OriginRule>>,
+}
+
+impl Origins {
+    const fn new() -> Self {
+        Self {
+            rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Replace the rules (at startup and on config reload)
+    pub fn set(&self, rules: Vec<OriginRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .any(|rule| rule.matches(origin))
+    }
+
+    /// `AllowOrigin` for a CORS layer, following the rules of the last `set`
+    pub fn allow_origin(&'static self) -> AllowOrigin {
+        AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            origin.to_str().is_ok_and(|origin| self.allows(origin))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(setting: &str, origin: &str) -> bool {
+        parse_rules(setting).0.iter().any(|r| r.matches(origin))
+    }
+
+    #[test]
+    fn exact_origins() {
+        assert!(allows("https://a.com, https://b.com/", "https://b.com"));
+        assert!(allows("https://a.com", "https://A.com"));
+        assert!(!allows("https://a.com", "http://a.com"));
+        assert!(!allows("https://a.com", "https://sub.a.com"));
+    }
+
+    #[test]
+    fn wildcard_subdomains() {
+        assert!(allows("*.example.com", "https://blog.example.com"));
+        assert!(allows("*.example.com", "http://a.b.example.com"));
+        assert!(!allows("*.example.com", "https://example.com"));
+        assert!(!allows("*.example.com", "https://evilexample.com"));
+        assert!(!allows("*.example.com", "https://example.com.evil.net"));
+        assert!(allows("https://*.example.com", "https://blog.example.com"));
+        assert!(!allows("https://*.example.com", "http://blog.example.com"));
+    }
+
+    #[test]
+    fn empty_origins_allow_nothing() {
+        let origins = Origins::new();
+        assert!(!origins.allows("https://a.com"));
+        origins.set(parse_rules("https://a.com").0);
+        assert!(origins.allows("https://a.com"));
+        assert!(!origins.allows("https://b.com"));
+    }
+
+    #[test]
+    fn reports_invalid_entries() {
+        let (rules, invalid) =
+            parse_rules("*, example.com, *., https://a.com/path, https://*.b.com");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(invalid, vec!["example.com", "*.", "https://a.com/path"]);
+    }
+}
@@ -0,0 +1,85 @@
+//! CSRF check for admin requests authenticated by the session cookie alone.
+//! Runs after admin_auth, which records how the request was authenticated.
+
+use crate::middleware::admin_auth::{ct_eq, session_csrf, AdminAuth};
+use axum::{
+    body::Body,
+    http::{Method, Request, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+
+/// Header carrying the token returned by /login (or GET /api/admin/csrf)
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Whether a request may proceed. Safe methods and requests that attached a token
+/// themselves never need one; cookie-only writes need their session's CSRF token.
+fn allowed(method: &Method, auth: Option<&AdminAuth>, presented: Option<&str>) -> bool {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+    match auth {
+        Some(AdminAuth::Cookie(id)) => match (presented, session_csrf(id)) {
+            (Some(presented), Some(expected)) => ct_eq(presented, &expected),
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+pub async fn csrf_middleware(req: Request<Body>, next: Next) -> Response<Body> {
+    let presented = req.headers().get(CSRF_HEADER).and_then(|h| h.to_str().ok());
+    if !allowed(req.method(), req.extensions().get::<AdminAuth>(), presented) {
+        // `code` lets the panel tell this apart from other 403s and re-fetch the token
+        return (
+            StatusCode::FORBIDDEN,
+            [("Content-Type", "application/json")],
+            r#"{"success":false,"message":"csrf token missing or invalid","code":"csrf_failed"}"#,
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::admin_auth::{create_session, AdminAccess};
+
+    #[test]
+    fn cookie_without_token_is_refused() {
+        let session = create_session(AdminAccess::Full);
+        let auth = AdminAuth::Cookie(session.id);
+        assert!(!allowed(&Method::POST, Some(&auth), None));
+        assert!(!allowed(&Method::DELETE, Some(&auth), None));
+        // Reads stay available so the panel can fetch a token
+        assert!(allowed(&Method::GET, Some(&auth), None));
+    }
+
+    #[test]
+    fn token_mismatch_is_refused() {
+        let session = create_session(AdminAccess::Full);
+        let other = create_session(AdminAccess::Full);
+        let auth = AdminAuth::Cookie(session.id);
+        assert!(!allowed(&Method::POST, Some(&auth), Some("nope")));
+        assert!(!allowed(
+            &Method::POST,
+            Some(&auth),
+            Some(&other.csrf_token)
+        ));
+        assert!(allowed(
+            &Method::POST,
+            Some(&auth),
+            Some(&session.csrf_token)
+        ));
+        // Unknown or expired session: nothing to match against
+        let gone = AdminAuth::Cookie("missing".to_string());
+        assert!(!allowed(&Method::POST, Some(&gone), Some("anything")));
+    }
+
+    #[test]
+    fn bearer_only_needs_no_token() {
+        assert!(allowed(&Method::POST, Some(&AdminAuth::Token), None));
+        assert!(allowed(&Method::DELETE, Some(&AdminAuth::Token), None));
+    }
+}
@@ -1,5 +1,10 @@
 //! Visitor identity middleware using Cookie (compatible with original busuanzi)
+//!
+//! Requests that can't carry cookies (third-party cookies blocked in an embed)
+//! may send the identity in the HEADER_IDENTITY header instead. New identities
+//! are returned in that header too, for the client to store and send back.
 
+use crate::config::{IdentityMode, CONFIG};
 use axum::{
     body::Body,
     http::{header, Request, Response},
@@ -8,27 +13,51 @@ use axum::{
 
 const COOKIE_NAME: &str = "busuanziId";
 
+/// Cookie lifetime outside the daily mode: one year
+const COOKIE_MAX_AGE: i64 = 31_536_000;
+
+/// Material hashed into a new identity under `mode`. `date` is today's UTC date,
+/// only used by the daily mode.
+fn raw_identity(mode: IdentityMode, secret: &str, ip: &str, ua: &str, date: &str) -> String {
+    match mode {
+        IdentityMode::IpUa => format!("{}{}{}", secret, ip, ua),
+        IdentityMode::Ip => format!("{}{}", secret, ip),
+        IdentityMode::Ua => format!("{}{}", secret, ua),
+        IdentityMode::IpUaSaltedDaily => format!("{}{}{}{}", secret, ip, ua, date),
+    }
+}
+
+/// Seconds the identity cookie lives: until the next UTC midnight in the daily
+/// mode, so tomorrow's visit gets a new identity; a year otherwise
+fn cookie_max_age(mode: IdentityMode, now: chrono::DateTime<chrono::Utc>) -> i64 {
+    if mode != IdentityMode::IpUaSaltedDaily {
+        return COOKIE_MAX_AGE;
+    }
+    let midnight = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight exists")
+        .and_utc();
+    (midnight - now).num_seconds().max(1)
+}
+
 pub async fn identity_middleware(mut req: Request<Body>, next: Next) -> Response<Body> {
-    // Check existing busuanziId cookie
+    // Check existing busuanziId cookie, then the identity header
     let existing_id = req
         .headers()
         .get(header::COOKIE)
         .and_then(|h| h.to_str().ok())
-        .and_then(|cookies| parse_cookie(cookies, COOKIE_NAME));
+        .and_then(|cookies| parse_cookie(cookies, COOKIE_NAME))
+        .or_else(|| identity_header(&req));
 
+    let now = chrono::Utc::now();
     let (user_identity, is_new) = if let Some(id) = existing_id {
-        // Use existing cookie value directly (compatible with original busuanzi)
+        // Use existing cookie value directly (compatible with original busuanzi),
+        // whatever IDENTITY_MODE it was issued under
         (id, false)
     } else {
-        // Generate new identity: MD5(IP + UserAgent), uppercase
-        let ip = req
-            .headers()
-            .get("X-Forwarded-For")
-            .or_else(|| req.headers().get("X-Real-IP"))
-            .and_then(|h| h.to_str().ok())
-            .and_then(|s| s.split(',').next()) // Take first IP if multiple
-            .unwrap_or("127.0.0.1")
-            .trim();
+        // Generate new identity: MD5(BSZ_SECRET + IDENTITY_MODE material), uppercase
+        let ip =
+            crate::middleware::real_ip::request_ip(&req).unwrap_or_else(|| "127.0.0.1".to_string());
 
         let ua = req
             .headers()
@@ -36,7 +65,8 @@ pub async fn identity_middleware(mut req: Request<Body>, next: Next) -> Response
             .and_then(|h| h.to_str().ok())
             .unwrap_or("");
 
-        let raw = format!("{}{}", ip, ua);
+        let date = now.format("%Y-%m-%d").to_string();
+        let raw = raw_identity(CONFIG.identity_mode, &CONFIG.bsz_secret, &ip, ua, &date);
         let id = format!("{:X}", md5::compute(raw)); // Uppercase hex like original
         (id, true)
     };
@@ -45,22 +75,44 @@ pub async fn identity_middleware(mut req: Request<Body>, next: Next) -> Response
 
     let mut response = next.run(req).await;
 
-    // Set cookie if new visitor
+    // Hand out the identity if new visitor
     if is_new {
-        // Set cookie with long expiry, SameSite=None for cross-site requests
-        let cookie = format!(
-            "{}={}; Path=/; Max-Age=31536000; SameSite=None; Secure",
-            COOKIE_NAME, user_identity
-        );
-        if let Ok(value) = cookie.parse() {
-            response.headers_mut().insert(header::SET_COOKIE, value);
+        if let Ok(value) = user_identity.parse() {
+            response
+                .headers_mut()
+                .insert(CONFIG.header_identity.as_str(), value);
+        }
+        if CONFIG.identity_cookie {
+            // Set cookie with long expiry, SameSite=None for cross-site requests
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; SameSite=None; Secure",
+                COOKIE_NAME,
+                user_identity,
+                cookie_max_age(CONFIG.identity_mode, now)
+            );
+            if let Ok(value) = cookie.parse() {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
         }
     }
 
     response
 }
 
-fn parse_cookie(cookies: &str, name: &str) -> Option<String> {
+/// Identity sent in the HEADER_IDENTITY header, if it looks like one we issue:
+/// up to 64 ASCII letters and digits
+fn identity_header(req: &Request<Body>) -> Option<String> {
+    let id = req
+        .headers()
+        .get(CONFIG.header_identity.as_str())?
+        .to_str()
+        .ok()?
+        .trim();
+    let valid = !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric());
+    valid.then(|| id.to_string())
+}
+
+pub fn parse_cookie(cookies: &str, name: &str) -> Option<String> {
     for cookie in cookies.split(';') {
         let cookie = cookie.trim();
         if let Some(value) = cookie.strip_prefix(name) {
@@ -71,3 +123,104 @@ fn parse_cookie(cookies: &str, name: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn raw_identity_per_mode() {
+        let raw = |mode, date| raw_identity(mode, "s", "1.2.3.4", "UA", date);
+        assert_eq!(raw(IdentityMode::IpUa, "2026-01-01"), "s1.2.3.4UA");
+        assert_eq!(raw(IdentityMode::Ip, "2026-01-01"), "s1.2.3.4");
+        assert_eq!(raw(IdentityMode::Ua, "2026-01-01"), "sUA");
+        assert_ne!(
+            raw(IdentityMode::IpUaSaltedDaily, "2026-01-01"),
+            raw(IdentityMode::IpUaSaltedDaily, "2026-01-02")
+        );
+        // The non-daily modes ignore the date
+        assert_eq!(
+            raw(IdentityMode::IpUa, "2026-01-01"),
+            raw(IdentityMode::IpUa, "2026-01-02")
+        );
+    }
+
+    #[test]
+    fn daily_cookie_expires_at_midnight() {
+        let now = chrono::Utc.with_ymd_and_hms(2026, 3, 1, 23, 0, 0).unwrap();
+        assert_eq!(cookie_max_age(IdentityMode::IpUaSaltedDaily, now), 3600);
+        assert_eq!(cookie_max_age(IdentityMode::IpUa, now), COOKIE_MAX_AGE);
+    }
+
+    #[tokio::test]
+    async fn existing_cookies_survive_a_mode_change() {
+        use axum::{routing::get, Extension, Router};
+        use tower::ServiceExt;
+
+        // Whatever mode the cookie was issued under, it is used as-is and not reissued
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(id): Extension<String>| async move { id }),
+            )
+            .layer(axum::middleware::from_fn(identity_middleware));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::COOKIE, "busuanziId=ISSUED-BEFORE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ISSUED-BEFORE");
+    }
+
+    #[tokio::test]
+    async fn identity_header_stands_in_for_the_cookie() {
+        use axum::{routing::get, Extension, Router};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(id): Extension<String>| async move { id }),
+            )
+            .layer(axum::middleware::from_fn(identity_middleware));
+        let request = |id: Option<&str>| {
+            let mut builder = Request::builder().uri("/");
+            if let Some(id) = id {
+                builder = builder.header("x-bsz-identity", id);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // A new visitor gets the identity in both the header and the cookie
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        let issued = response.headers()["x-bsz-identity"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(issued.len(), 32);
+        assert!(response.headers().get(header::SET_COOKIE).is_some());
+
+        // Sending it back keeps the identity and issues nothing
+        let response = app.clone().oneshot(request(Some(&issued))).await.unwrap();
+        assert!(response.headers().get("x-bsz-identity").is_none());
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], issued.as_bytes());
+
+        // Anything else is ignored
+        let response = app.oneshot(request(Some("a;b=c"))).await.unwrap();
+        assert!(response.headers().get("x-bsz-identity").is_some());
+    }
+}
@@ -1,2 +1,9 @@
 pub mod admin_auth;
+pub mod audit;
+pub mod cors;
+pub mod csrf;
 pub mod identity;
+pub mod rate_limit;
+pub mod real_ip;
+pub mod request_id;
+pub mod service_stats;
@@ -0,0 +1,230 @@
+//! Per-IP rate limiting (token bucket) for the public counting API and, with its
+//! own budget, the admin API
+
+use crate::config::runtime;
+use crate::middleware::real_ip::{self, client_ip};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, Request, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub static LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(runtime().rate_limit_per_minute));
+
+pub static ADMIN_LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(runtime().admin_rate_limit_per_minute));
+
+/// Each IP gets a bucket of `per_minute` tokens refilled continuously over a minute,
+/// so short bursts are fine but the sustained rate is capped.
+pub struct RateLimiter {
+    per_minute: AtomicU64,
+    buckets: DashMap<String, Bucket>,
+    limited: AtomicU64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u64) -> Self {
+        Self {
+            per_minute: AtomicU64::new(per_minute),
+            buckets: DashMap::new(),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Change the limit (config reload); fuller buckets are trimmed on their next check
+    pub fn set_per_minute(&self, per_minute: u64) {
+        self.per_minute.store(per_minute, Ordering::Relaxed);
+    }
+
+    /// Take a token for `key`, or return how long until one is available
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let per_minute = self.per_minute.load(Ordering::Relaxed);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let rate = capacity / WINDOW.as_secs_f64();
+
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            last: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Drop buckets that have been idle long enough to be full again.
+    /// Returns the number of entries removed.
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.buckets.len();
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.last) < WINDOW);
+        before - self.buckets.len()
+    }
+
+    /// Requests rejected since startup
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+fn get_client_ip(req: &Request<Body>) -> String {
+    real_ip::request_ip(req).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// For counting routes that must answer normally even when limited (the tracking
+/// pixel): take a token and report whether the request may count
+pub fn try_count(headers: &HeaderMap) -> bool {
+    let rt = runtime();
+    if rt.rate_limit_per_minute == 0 {
+        return true;
+    }
+    let ip = client_ip(headers);
+    rt.rate_limit_exempt.contains(&ip) || LIMITER.check(&ip, Instant::now()).is_ok()
+}
+
+/// Applied to `/api`: only POST/PUT (the requests that increment) are limited.
+pub async fn rate_limit_middleware(req: Request<Body>, next: Next) -> Response<Body> {
+    let rt = runtime();
+    if rt.rate_limit_per_minute == 0 || !matches!(*req.method(), Method::POST | Method::PUT) {
+        return next.run(req).await;
+    }
+
+    let ip = get_client_ip(&req);
+    if rt.rate_limit_exempt.contains(&ip) {
+        return next.run(req).await;
+    }
+
+    match LIMITER.check(&ip, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => too_many_requests(retry_after),
+    }
+}
+
+/// Applied to the admin router, outside auth: every admin request takes a token, so
+/// a leaked token can't loop on expensive endpoints. An SSE sync stream is one
+/// request however long it runs.
+pub async fn admin_rate_limit_middleware(req: Request<Body>, next: Next) -> Response<Body> {
+    let rt = runtime();
+    if rt.admin_rate_limit_per_minute == 0 {
+        return next.run(req).await;
+    }
+
+    let ip = get_client_ip(&req);
+    if rt.rate_limit_exempt.contains(&ip) {
+        return next.run(req).await;
+    }
+
+    match ADMIN_LIMITER.check(&ip, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => too_many_requests(retry_after),
+    }
+}
+
+fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::RETRY_AFTER,
+                retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+            ),
+        ],
+        r#"{"success":false,"message":"rate limited"}"#,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_burst_up_to_limit() {
+        let limiter = RateLimiter::new(3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check("a", now).is_ok());
+        }
+        let retry = limiter.check("a", now).unwrap_err();
+        // One token refills every 20s at 3/min
+        assert_eq!(retry.as_secs(), 20);
+        assert_eq!(limiter.limited(), 1);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..60 {
+            assert!(limiter.check("a", start).is_ok());
+        }
+        assert!(limiter.check("a", start).is_err());
+
+        // 1 token per second
+        let later = start + Duration::from_millis(1500);
+        assert!(limiter.check("a", later).is_ok());
+        let retry = limiter.check("a", later).unwrap_err();
+        assert!(retry <= Duration::from_millis(500), "{:?}", retry);
+    }
+
+    #[test]
+    fn refill_is_capped_at_limit() {
+        let limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.check("a", start).is_ok());
+
+        let much_later = start + Duration::from_secs(3600);
+        assert!(limiter.check("a", much_later).is_ok());
+        assert!(limiter.check("a", much_later).is_ok());
+        assert!(limiter.check("a", much_later).is_err());
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        assert!(limiter.check("a", now).is_ok());
+        assert!(limiter.check("a", now).is_err());
+        assert!(limiter.check("b", now).is_ok());
+    }
+
+    #[test]
+    fn evicts_only_idle_entries() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        limiter.check("idle", start).unwrap();
+        limiter
+            .check("active", start + Duration::from_secs(50))
+            .unwrap();
+
+        let removed = limiter.evict_idle(start + Duration::from_secs(61));
+        assert_eq!(removed, 1);
+        assert!(!limiter.buckets.contains_key("idle"));
+        assert!(limiter.buckets.contains_key("active"));
+    }
+}
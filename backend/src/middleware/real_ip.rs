@@ -0,0 +1,420 @@
+//! Client address normalization (`TRUST_PROXY_HEADERS`, `TRUSTED_PROXIES`)
+//!
+//! The middleware resolves the client IP once and leaves it in `X-Real-IP`, with
+//! `X-Forwarded-For` and `Forwarded` removed; everything downstream reads it through
+//! [`client_ip`]. Unless the proxy headers are trusted and the socket peer is one of
+//! `TRUSTED_PROXIES`, the peer address is the client, so a direct client can't pick
+//! its own IP for lockouts, rate limits or visitor identity. Behind a trusted proxy
+//! the client is the rightmost hop of the chain that isn't a trusted proxy: entries
+//! left of it were sent by the client and prove nothing. An RFC 7239 `Forwarded`
+//! header is read as the same chain (`PROXY_HEADER_PRECEDENCE` decides which wins if
+//! both are sent), and a lone `X-Real-IP` from the proxy is taken as is.
+
+use crate::config::{ProxyHeader, CONFIG};
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const FORWARDED: &str = "forwarded";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// TCP peer address of a request, when served with connect info
+pub fn peer_ip<B>(req: &Request<B>) -> Option<String> {
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+/// Client IP of a request, `"unknown"` when there is none
+pub fn client_ip(headers: &HeaderMap) -> String {
+    header_client(headers, |ip| CONFIG.is_trusted_proxy(ip))
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Client IP of a request, falling back to the socket peer when no header names one
+/// (routes served without [`real_ip_middleware`])
+pub fn request_ip<B>(req: &Request<B>) -> Option<String> {
+    header_client(req.headers(), |ip| CONFIG.is_trusted_proxy(ip))
+        .map(|ip| ip.to_string())
+        .or_else(|| peer_ip(req))
+}
+
+/// The client named by the headers. After the middleware that is `X-Real-IP`; a
+/// chain that didn't go through it is still resolved from the right.
+fn header_client(headers: &HeaderMap, trusted: impl Fn(IpAddr) -> bool) -> Option<IpAddr> {
+    client_of(&forwarded_for(headers), trusted).or_else(|| real_ip(headers))
+}
+
+/// Client at the end of a proxy chain (client first, nearest proxy last): the
+/// rightmost hop that isn't a trusted proxy, or the first when all of them are
+fn client_of(chain: &[IpAddr], trusted: impl Fn(IpAddr) -> bool) -> Option<IpAddr> {
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !trusted(**ip))
+        .or(chain.first())
+        .copied()
+}
+
+/// Hops of all `X-Forwarded-For` lines, in order; entries without an IP are skipped
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+fn real_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get(X_REAL_IP)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_node(v.trim()))
+}
+
+/// Split on `sep`, ignoring separators inside quoted strings
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// A token or quoted-string value, with quotes and escapes removed
+fn unquote(value: &str) -> Option<String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Some(value.to_string());
+    };
+    let inner = inner.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' { chars.next()? } else { c });
+    }
+    Some(out)
+}
+
+/// IP of a `for=` node: `1.2.3.4`, `1.2.3.4:80`, `[2001:db8::1]`, `[2001:db8::1]:80`.
+/// `unknown` and obfuscated (`_hidden`) nodes have no IP.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']')?;
+        if !(port.is_empty() || port.starts_with(':')) {
+            return None;
+        }
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    // Bare IPv6 is not valid RFC 7239, but some proxies send it anyway
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    let (ip, _port) = node.split_once(':')?;
+    ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+/// Client addresses from the `for=` parameters of `Forwarded` header values,
+/// client first then each proxy, in the order the elements appear. Elements
+/// without a usable IP are skipped.
+fn parse_forwarded<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+    for value in values {
+        for element in split_unquoted(value, ',') {
+            let node = split_unquoted(element, ';').into_iter().find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| unquote(value.trim()))
+                    .flatten()
+            });
+            if let Some(ip) = node.as_deref().and_then(parse_node) {
+                ips.push(ip);
+            }
+        }
+    }
+    ips
+}
+
+fn normalize(
+    headers: &mut HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_proxy: bool,
+    precedence: ProxyHeader,
+    trusted: impl Fn(IpAddr) -> bool,
+) {
+    let peer = peer.map(|p| p.ip());
+    let via_proxy = trust_proxy && peer.is_none_or(&trusted);
+    let client = if via_proxy {
+        let has_xff = headers.contains_key(X_FORWARDED_FOR) || headers.contains_key(X_REAL_IP);
+        let forwarded = parse_forwarded(
+            headers
+                .get_all(FORWARDED)
+                .iter()
+                .filter_map(|v| v.to_str().ok()),
+        );
+        let client = if !forwarded.is_empty() && (!has_xff || precedence == ProxyHeader::Forwarded)
+        {
+            client_of(&forwarded, &trusted)
+        } else {
+            header_client(headers, &trusted)
+        };
+        client.or(peer)
+    } else {
+        peer
+    };
+    headers.remove(FORWARDED);
+    headers.remove(X_FORWARDED_FOR);
+    headers.remove(X_REAL_IP);
+    if let Some(ip) = client {
+        if let Ok(value) = HeaderValue::from_str(&ip.to_string()) {
+            headers.insert(X_REAL_IP, value);
+        }
+    }
+}
+
+pub async fn real_ip_middleware(mut req: Request<Body>, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    normalize(
+        req.headers_mut(),
+        peer,
+        CONFIG.trust_proxy_headers,
+        CONFIG.proxy_header_precedence,
+        |ip| CONFIG.is_trusted_proxy(ip),
+    );
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spoofed() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.2.3.4, 10.0.0.1"),
+        );
+        headers.insert(X_REAL_IP, HeaderValue::from_static("5.6.7.8"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=9.9.9.9"));
+        headers
+    }
+
+    fn forwarded(values: &[&str]) -> Vec<String> {
+        parse_forwarded(values.iter().copied())
+            .iter()
+            .map(IpAddr::to_string)
+            .collect()
+    }
+
+    /// Loopback and RFC 1918, like the TRUSTED_PROXIES default
+    fn private(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v4) => v4.is_loopback() || v4.is_private(),
+            IpAddr::V6(v6) => v6.is_loopback(),
+        }
+    }
+
+    /// Normalize `headers` as sent by `peer` and return the resolved client
+    fn resolve(
+        mut headers: HeaderMap,
+        peer: Option<&str>,
+        trust_proxy: bool,
+        precedence: ProxyHeader,
+    ) -> Option<String> {
+        let peer = peer.map(|p| p.parse().unwrap());
+        normalize(&mut headers, peer, trust_proxy, precedence, private);
+        assert!(headers.get(X_FORWARDED_FOR).is_none());
+        assert!(headers.get(FORWARDED).is_none());
+        headers
+            .get(X_REAL_IP)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    fn xff(chain: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static(chain));
+        headers
+    }
+
+    #[test]
+    fn untrusted_headers_are_replaced_by_peer() {
+        let peer = Some("203.0.113.9:51234");
+        assert_eq!(
+            resolve(spoofed(), peer, false, ProxyHeader::Forwarded).as_deref(),
+            Some("203.0.113.9")
+        );
+        // No peer known: drop the headers rather than believe them
+        assert_eq!(
+            resolve(spoofed(), None, false, ProxyHeader::Forwarded),
+            None
+        );
+        // Trusted headers from a peer that isn't a trusted proxy: a direct client
+        assert_eq!(
+            resolve(spoofed(), peer, true, ProxyHeader::XForwardedFor).as_deref(),
+            Some("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn trusted_chain_resolves_rightmost_untrusted_hop() {
+        let peer = Some("10.0.0.1:80");
+        let client = |headers| resolve(headers, peer, true, ProxyHeader::XForwardedFor);
+
+        assert_eq!(client(spoofed()).as_deref(), Some("1.2.3.4"));
+        // Whatever the client prepends is left of the hop our proxy appended
+        assert_eq!(
+            client(xff("6.6.6.6, 1.2.3.4, 10.0.0.1")).as_deref(),
+            Some("1.2.3.4")
+        );
+        assert_eq!(client(xff("6.6.6.6, 1.2.3.4")).as_deref(), Some("1.2.3.4"));
+        assert_eq!(
+            client(xff("1.2.3.4, 10.0.0.2, 10.0.0.1")).as_deref(),
+            Some("1.2.3.4")
+        );
+        // Only proxies in the chain: the first of them
+        assert_eq!(
+            client(xff("10.0.0.5, 10.0.0.1")).as_deref(),
+            Some("10.0.0.5")
+        );
+        // Junk hops are skipped, ports dropped
+        assert_eq!(
+            client(xff("garbage, 1.2.3.4:5678, unknown")).as_deref(),
+            Some("1.2.3.4")
+        );
+
+        // X-Real-IP alone, as set by the proxy
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REAL_IP, HeaderValue::from_static("5.6.7.8"));
+        assert_eq!(client(headers).as_deref(), Some("5.6.7.8"));
+
+        // Without proxy headers the peer is still filled in
+        assert_eq!(
+            resolve(
+                HeaderMap::new(),
+                Some("[::1]:80"),
+                true,
+                ProxyHeader::XForwardedFor
+            )
+            .as_deref(),
+            Some("::1")
+        );
+    }
+
+    #[test]
+    fn raw_headers_resolve_like_the_middleware() {
+        assert_eq!(
+            header_client(&xff("6.6.6.6, 1.2.3.4, 10.0.0.1"), private),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(
+            header_client(&spoofed(), private),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(header_client(&HeaderMap::new(), private), None);
+    }
+
+    #[test]
+    fn parses_forwarded_nodes() {
+        assert_eq!(
+            forwarded(&["for=192.0.2.60;proto=http;by=203.0.113.43"]),
+            ["192.0.2.60"]
+        );
+        assert_eq!(forwarded(&["For=\"192.0.2.60:4711\""]), ["192.0.2.60"]);
+        assert_eq!(
+            forwarded(&["for=\"[2001:db8:cafe::17]:4711\""]),
+            ["2001:db8:cafe::17"]
+        );
+        assert_eq!(forwarded(&["for=\"[2001:db8::1]\""]), ["2001:db8::1"]);
+        assert_eq!(forwarded(&["for=2001:db8::1"]), ["2001:db8::1"]);
+        assert_eq!(forwarded(&["proto=https; for = 1.2.3.4 "]), ["1.2.3.4"]);
+        assert_eq!(forwarded(&["for=\"1.2.\\3.4\""]), ["1.2.3.4"]);
+
+        assert!(forwarded(&["for=unknown"]).is_empty());
+        assert!(forwarded(&["for=_hidden, for=\"_SEVKISEK\""]).is_empty());
+        assert!(forwarded(&["for=[2001:db8::1]x"]).is_empty());
+        assert!(forwarded(&["for=\"1.2.3.4"]).is_empty());
+        assert!(forwarded(&["by=1.2.3.4;proto=http"]).is_empty());
+        assert!(forwarded(&["for=example.com"]).is_empty());
+        assert!(forwarded(&[""]).is_empty());
+    }
+
+    #[test]
+    fn parses_multiple_forwarded_elements() {
+        assert_eq!(
+            forwarded(&["for=192.0.2.43, for=\"[2001:db8:cafe::17]\", for=unknown, for=10.0.0.1"]),
+            ["192.0.2.43", "2001:db8:cafe::17", "10.0.0.1"]
+        );
+        // Commas and semicolons inside quoted values don't split elements
+        assert_eq!(
+            forwarded(&["for=1.1.1.1;host=\"a,b;c\", for=2.2.2.2"]),
+            ["1.1.1.1", "2.2.2.2"]
+        );
+        // Repeated header lines are one list
+        assert_eq!(
+            forwarded(&["for=1.1.1.1", "for=2.2.2.2;proto=https"]),
+            ["1.1.1.1", "2.2.2.2"]
+        );
+    }
+
+    #[test]
+    fn trusted_forwarded_is_read_as_the_chain() {
+        let peer = Some("10.0.0.1:80");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static("for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2"),
+        );
+        assert_eq!(
+            resolve(headers, peer, true, ProxyHeader::XForwardedFor).as_deref(),
+            Some("2001:db8::1")
+        );
+
+        // Both present: precedence decides
+        assert_eq!(
+            resolve(spoofed(), peer, true, ProxyHeader::XForwardedFor).as_deref(),
+            Some("1.2.3.4")
+        );
+        assert_eq!(
+            resolve(spoofed(), peer, true, ProxyHeader::Forwarded).as_deref(),
+            Some("9.9.9.9")
+        );
+
+        // Nothing usable in Forwarded: fall back to X-Forwarded-For, then the peer
+        let mut headers = spoofed();
+        headers.insert(FORWARDED, HeaderValue::from_static("for=unknown"));
+        assert_eq!(
+            resolve(headers, peer, true, ProxyHeader::Forwarded).as_deref(),
+            Some("1.2.3.4")
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED, HeaderValue::from_static("for=_hidden"));
+        assert_eq!(
+            resolve(headers, peer, true, ProxyHeader::Forwarded).as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+}
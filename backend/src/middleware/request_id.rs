@@ -0,0 +1,113 @@
+//! Request IDs for correlating access logs, tracing output and operation logs
+//!
+//! An incoming `X-Request-Id` is kept when it looks sane, otherwise a random UUID
+//! is generated. The id is written back onto the request, stored in extensions
+//! (where the trace span picks it up), echoed on the response and recorded by
+//! `state::add_log` for anything logged while the request is handled.
+
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use rand::Rng;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest client-supplied id that is accepted as-is
+const MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// Id of the request being handled on this task, if any
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Run blocking work under a request id captured with `current()`
+/// (task-locals don't follow into `spawn_blocking`)
+pub fn sync_scope<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    match id {
+        Some(id) => CURRENT.sync_scope(id, f),
+        None => f(),
+    }
+}
+
+fn accept(value: &str) -> Option<&str> {
+    let valid = !value.is_empty()
+        && value.len() <= MAX_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+    valid.then_some(value)
+}
+
+/// Random version 4 UUID
+fn generate() -> String {
+    let mut bytes: [u8; 16] = rand::rng().random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+pub async fn request_id_middleware(mut req: Request<Body>, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(accept)
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+    let value = HeaderValue::from_str(&id).expect("request id is visible ASCII");
+
+    req.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = CURRENT.scope(id, next.run(req)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_sane_ids_only() {
+        assert_eq!(accept("abc-123_x.y:z"), Some("abc-123_x.y:z"));
+        assert_eq!(accept(""), None);
+        assert_eq!(accept("has space"), None);
+        assert_eq!(accept(&"a".repeat(MAX_LEN + 1)), None);
+    }
+
+    #[test]
+    fn generates_uuid_v4() {
+        let id = generate();
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(id, generate());
+    }
+
+    #[test]
+    fn scopes_blocking_work() {
+        assert_eq!(current(), None);
+        let seen = sync_scope(Some("req-1".to_string()), current);
+        assert_eq!(seen.as_deref(), Some("req-1"));
+        assert_eq!(sync_scope(None, current), None);
+    }
+}
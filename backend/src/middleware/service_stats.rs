@@ -0,0 +1,139 @@
+//! Requests served by the service itself, for sizing the host
+//!
+//! Counts `/api` GET/POST/PUT and every admin call (including rejected ones) since
+//! boot. Lifetime totals are the since-boot counts plus what the previous runs left
+//! in `admin_settings.service_requests`, which is rewritten with every data save.
+
+use axum::{
+    body::Body,
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::state;
+
+/// `admin_settings` key holding the lifetime totals
+pub const SETTING: &str = "service_requests";
+
+static API_GET: AtomicU64 = AtomicU64::new(0);
+static API_POST: AtomicU64 = AtomicU64::new(0);
+static API_PUT: AtomicU64 = AtomicU64::new(0);
+static ADMIN: AtomicU64 = AtomicU64::new(0);
+
+/// Totals of previous runs, read once at startup
+static PREVIOUS: OnceCell<Counters> = OnceCell::new();
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    pub api_get: u64,
+    pub api_post: u64,
+    pub api_put: u64,
+    pub admin: u64,
+}
+
+impl Counters {
+    fn plus(self, other: Counters) -> Counters {
+        Counters {
+            api_get: self.api_get.saturating_add(other.api_get),
+            api_post: self.api_post.saturating_add(other.api_post),
+            api_put: self.api_put.saturating_add(other.api_put),
+            admin: self.admin.saturating_add(other.admin),
+        }
+    }
+}
+
+/// Requests since this process started
+pub fn since_boot() -> Counters {
+    Counters {
+        api_get: API_GET.load(Ordering::Relaxed),
+        api_post: API_POST.load(Ordering::Relaxed),
+        api_put: API_PUT.load(Ordering::Relaxed),
+        admin: ADMIN.load(Ordering::Relaxed),
+    }
+}
+
+/// Requests over every run that shared this database
+pub fn lifetime() -> Counters {
+    PREVIOUS
+        .get()
+        .copied()
+        .unwrap_or_default()
+        .plus(since_boot())
+}
+
+/// Read the previous runs' totals. Call once, after the database is open.
+pub fn load() {
+    let previous = match state::get_setting(SETTING) {
+        Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable {}: {}", SETTING, e);
+            Counters::default()
+        }),
+        Ok(None) => Counters::default(),
+        Err(e) => {
+            tracing::warn!("Failed to load service request counters: {}", e);
+            Counters::default()
+        }
+    };
+    let _ = PREVIOUS.set(previous);
+}
+
+/// Applied to the public routes: counts requests to `/api` by method
+pub async fn count_api(req: Request<Body>, next: Next) -> Response {
+    if req.uri().path() == "/api" {
+        match *req.method() {
+            Method::GET => API_GET.fetch_add(1, Ordering::Relaxed),
+            Method::POST => API_POST.fetch_add(1, Ordering::Relaxed),
+            Method::PUT => API_PUT.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+    next.run(req).await
+}
+
+/// Applied outside admin auth, so rejected calls count too
+pub async fn count_admin(req: Request<Body>, next: Next) -> Response {
+    ADMIN.fetch_add(1, Ordering::Relaxed);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifetime_adds_previous_runs() {
+        let previous = Counters {
+            api_get: 5,
+            api_post: 3,
+            api_put: 1,
+            admin: 2,
+        };
+        let now = Counters {
+            api_get: 1,
+            admin: 1,
+            ..Counters::default()
+        };
+        assert_eq!(
+            previous.plus(now),
+            Counters {
+                api_get: 6,
+                api_post: 3,
+                api_put: 1,
+                admin: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_partial_settings() {
+        // Counters added later default to 0 for databases written before them
+        let c: Counters = serde_json::from_str(r#"{"api_get":7}"#).unwrap();
+        assert_eq!(c.api_get, 7);
+        assert_eq!(c.admin, 0);
+    }
+}
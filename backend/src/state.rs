@@ -1,26 +1,47 @@
-//! In-memory data store with SQLite persistence
+//! In-memory data store, persisted through storage::STORAGE
 
 use dashmap::{DashMap, DashSet};
-use once_cell::sync::Lazy;
-use rusqlite::{params, Connection};
-use std::collections::HashSet;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Mutex, RwLock};
 
-const DB_FILE: &str = "data.db";
+use crate::config::{runtime, CONFIG};
+use crate::storage::STORAGE;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
-/// Global data store
-/// Only 3 metrics: site_pv, site_uv, page_pv (matching original busuanzi)
+pub use crate::storage::{LockoutEntry, LogEntry, SaveStats, StorageResult};
+
+/// Data store. main.rs creates one and hands it to the routers as state
+/// (`Arc<Store>`); tests build their own with `Store::new()`.
+/// Core metrics: site_pv, site_uv, page_pv (matching original busuanzi),
+/// plus page_uv when UV_SCOPE tracks pages
 /// Keys are plaintext: site_key = host, page_key = host:path
 pub struct Store {
     pub site_pv: DashMap<String, AtomicU64>,
     pub site_uv: DashMap<String, AtomicU64>,
     pub site_visitors: DashMap<String, DashSet<u64>>,
     pub page_pv: DashMap<String, AtomicU64>,
+    /// Only populated when UV_SCOPE is `page` or `both`
+    pub page_uv: DashMap<String, AtomicU64>,
+    pub page_visitors: DashMap<String, DashSet<u64>>,
+    /// Number of page_pv keys per site; go through add_page_pv / set_page_pv / take_page_pv
+    pub site_page_count: DashMap<String, AtomicU64>,
     /// Track new visitors since last save (for incremental persistence)
     pub new_visitors: RwLock<Vec<(String, u64)>>,
+    /// Sites that gained a visitor since the last save; only their rows of the
+    /// `visitors` table are rewritten
+    pub visitors_dirty: DashSet<String>,
+    /// Counter increments since the last save (SAVE_EVERY_N_WRITES)
+    pending_writes: AtomicU64,
+    /// Set by admin edits and sync imports, which change the store without going through incr_*
+    dirty: AtomicBool,
+    /// Woken once pending_writes reaches SAVE_EVERY_N_WRITES; the save loop in main.rs waits on it
+    pub save_requested: tokio::sync::Notify,
+    /// Sees every counted site visit (the anomaly detector on the server's store)
+    on_visit: Option<VisitHook>,
 }
 
+/// Called with (site_key, visitor hash, visitor identity) for each site hit
+pub type VisitHook = fn(&str, u64, &str);
+
 impl Store {
     pub fn new() -> Self {
         Self {
@@ -28,442 +49,524 @@ impl Store {
             site_uv: DashMap::new(),
             site_visitors: DashMap::new(),
             page_pv: DashMap::new(),
+            page_uv: DashMap::new(),
+            page_visitors: DashMap::new(),
+            site_page_count: DashMap::new(),
             new_visitors: RwLock::new(Vec::new()),
+            visitors_dirty: DashSet::new(),
+            pending_writes: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+            save_requested: tokio::sync::Notify::new(),
+            on_visit: None,
+        }
+    }
+
+    pub fn with_visit_hook(mut self, hook: VisitHook) -> Self {
+        self.on_visit = Some(hook);
+        self
+    }
+
+    /// Count one un-persisted increment, requesting a save when the threshold is crossed
+    fn note_write(&self) {
+        let pending = self.pending_writes.fetch_add(1, Ordering::Relaxed) + 1;
+        let every = runtime().save_every_n_writes;
+        if every > 0 && pending == every {
+            self.save_requested.notify_one();
         }
     }
+
+    /// Record that the store was changed outside of counting
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the store holds anything the last save didn't write
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.pending_writes.load(Ordering::Relaxed) > 0
+            || self.dirty.load(Ordering::Relaxed)
+            || !self.new_visitors.read().unwrap().is_empty()
+    }
+
+    /// Drop every site, page and visitor
+    pub fn clear(&self) {
+        self.site_pv.clear();
+        self.site_uv.clear();
+        self.site_visitors.clear();
+        self.page_pv.clear();
+        self.page_uv.clear();
+        self.page_visitors.clear();
+        self.site_page_count.clear();
+        self.new_visitors.write().unwrap().clear();
+        self.visitors_dirty.clear();
+    }
 }
 
-pub static STORE: Lazy<Store> = Lazy::new(Store::new);
+/// Unix time of the last successful save, 0 = none since startup
+static LAST_SAVE: AtomicU64 = AtomicU64::new(0);
 
-// SQLite connection (single writer)
-static DB: Lazy<Mutex<Connection>> = Lazy::new(|| {
-    let conn = Connection::open(DB_FILE).expect("Failed to open database");
-    init_db(&conn).expect("Failed to initialize database");
-    Mutex::new(conn)
-});
+/// Unix time the save loop in main.rs next wakes up at
+static NEXT_SAVE: AtomicU64 = AtomicU64::new(0);
 
-fn init_db(conn: &Connection) -> rusqlite::Result<()> {
-    conn.execute_batch(
-        "
-        CREATE TABLE IF NOT EXISTS sites (
-            key TEXT PRIMARY KEY,
-            pv INTEGER NOT NULL DEFAULT 0,
-            uv INTEGER NOT NULL DEFAULT 0
-        );
-        CREATE TABLE IF NOT EXISTS pages (
-            key TEXT PRIMARY KEY,
-            pv INTEGER NOT NULL DEFAULT 0
-        );
-        CREATE TABLE IF NOT EXISTS visitors (
-            site_key TEXT NOT NULL,
-            hash INTEGER NOT NULL,
-            PRIMARY KEY (site_key, hash)
-        );
-        CREATE INDEX IF NOT EXISTS idx_visitors_site ON visitors(site_key);
-        CREATE TABLE IF NOT EXISTS operation_logs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp TEXT NOT NULL,
-            action TEXT NOT NULL,
-            detail TEXT NOT NULL DEFAULT '',
-            ip TEXT NOT NULL DEFAULT ''
-        );
-        ",
-    )?;
-    Ok(())
+/// Unix time of the last successful save since startup
+pub fn last_save() -> Option<u64> {
+    Some(LAST_SAVE.load(Ordering::Relaxed)).filter(|&t| t > 0)
 }
 
-/// Add an operation log entry
-pub fn add_log(action: &str, detail: &str, ip: &str) {
-    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    if let Ok(conn) = DB.lock() {
-        let _ = conn.execute(
-            "INSERT INTO operation_logs (timestamp, action, detail, ip) VALUES (?1, ?2, ?3, ?4)",
-            params![now, action, detail, ip],
+/// Unix time of the next scheduled save (skipped if nothing changed by then)
+pub fn next_save() -> Option<u64> {
+    Some(NEXT_SAVE.load(Ordering::Relaxed)).filter(|&t| t > 0)
+}
+
+/// Called by the save loop before it sleeps for `secs`
+pub fn schedule_next_save(secs: u64) {
+    let now = chrono::Utc::now().timestamp() as u64;
+    NEXT_SAVE.store(now + secs, Ordering::Relaxed);
+}
+
+/// Path of the SQLite database (DATABASE_URL)
+pub fn db_file() -> &'static str {
+    &CONFIG.db_path
+}
+
+// ==================== Persistence ====================
+// Everything below goes to storage::STORAGE, the backend DATABASE_URL selects.
+
+/// Whether data is currently being persisted
+pub fn persistence_enabled() -> bool {
+    STORAGE.persistence_enabled()
+}
+
+/// Error from the last failed attempt to open the database, if any
+pub fn db_error() -> Option<String> {
+    STORAGE.error()
+}
+
+/// Whether UV deduplication recognises the stored visitors. False after opening a
+/// database whose `visitor_hash` differs from this build: every returning visitor
+/// then looks new and UV inflates until the sets are rebuilt.
+pub fn uv_accurate() -> bool {
+    STORAGE.uv_accurate()
+}
+
+/// Load the persisted data into `store`
+pub fn load(store: &Store) -> StorageResult<()> {
+    STORAGE.load(store)
+}
+
+/// Save `store` (async wrapper)
+pub async fn save(store: Arc<Store>) -> StorageResult<SaveStats> {
+    // A panic inside save_blocking surfaces as a JoinError; keep its message instead
+    // of the bare "task panicked"
+    tokio::task::spawn_blocking(move || save_blocking(&store))
+        .await
+        .unwrap_or_else(|e| Err(format!("save task panicked: {:?}", e).into()))
+}
+
+/// Save `store` (blocking, for use inside spawn_blocking)
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn save_blocking(store: &Store) -> StorageResult<SaveStats> {
+    // Increments made while writing count towards the next save
+    let pending = store.pending_writes.swap(0, Ordering::Relaxed);
+    let dirty = store.dirty.swap(false, Ordering::Relaxed);
+    let started = std::time::Instant::now();
+    // Admin edits, syncs and imports change visitor sets without marking sites,
+    // so after any of them every set is rewritten
+    let stats = STORAGE.save(store, dirty).inspect_err(|_| {
+        store.pending_writes.fetch_add(pending, Ordering::Relaxed);
+        store.dirty.fetch_or(dirty, Ordering::Relaxed);
+    })?;
+
+    // Clear incremental tracker
+    store.new_visitors.write().unwrap().clear();
+    LAST_SAVE.store(chrono::Utc::now().timestamp() as u64, Ordering::Relaxed);
+
+    let elapsed_ms = started.elapsed().as_millis();
+    if elapsed_ms > runtime().slow_save_threshold_ms as u128 {
+        tracing::info!(
+            "Saved {} sites, {} pages, {} visitors in {}ms (slow)",
+            stats.sites,
+            stats.pages,
+            stats.visitors,
+            elapsed_ms
+        );
+    } else {
+        tracing::debug!(
+            "Saved {} sites, {} pages, {} visitors in {}ms",
+            stats.sites,
+            stats.pages,
+            stats.visitors,
+            elapsed_ms
         );
     }
+    Ok(stats)
 }
 
-/// A single operation log entry: (id, timestamp, action, detail, ip)
-pub type LogEntry = (i64, String, String, String, String);
+/// Replace `store` and the persisted data with an uploaded data.db.
+/// Returns (sites_count, pages_count, visitors_count).
+pub fn import_from_file(store: &Store, temp_path: &str) -> StorageResult<(i64, i64, i64)> {
+    STORAGE.import(store, temp_path)
+}
+
+/// Add an operation log entry, tagged with the id of the request being handled
+pub fn add_log(action: &str, detail: &str, ip: &str) {
+    let request_id = crate::middleware::request_id::current().unwrap_or_default();
+    STORAGE.add_log(action, detail, ip, &request_id);
+}
 
-/// Query operation logs with pagination
 pub fn query_logs(
     page: usize,
     size: usize,
-) -> Result<(Vec<LogEntry>, usize), Box<dyn std::error::Error>> {
-    let conn = DB.lock().unwrap();
-    let total: i64 = conn.query_row("SELECT COUNT(*) FROM operation_logs", [], |r| {
-        r.get::<_, i64>(0)
-    })?;
-    let total = total as usize;
-
-    let offset = (page.saturating_sub(1)) * size;
-    let mut stmt = conn.prepare(
-        "SELECT id, timestamp, action, detail, ip FROM operation_logs ORDER BY id DESC LIMIT ?1 OFFSET ?2",
-    )?;
-    let rows = stmt
-        .query_map(params![size as i64, offset as i64], |row| {
-            Ok((
-                row.get::<_, i64>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-            ))
-        })?
-        .collect::<Result<Vec<_>, _>>()?;
-
-    Ok((rows, total))
-}
-
-/// Save store to SQLite (async wrapper)
-pub async fn save() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    tokio::task::spawn_blocking(save_sync).await??;
-    Ok(())
-}
-
-/// Save store to SQLite (blocking, for use inside spawn_blocking)
-pub fn save_blocking() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    save_sync()
-}
-
-fn save_sync() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let conn = DB.lock().unwrap();
-    let tx = conn.unchecked_transaction()?;
-
-    // Clear all tables and rewrite (ensures deletions are persisted)
-    tx.execute_batch("DELETE FROM sites; DELETE FROM pages; DELETE FROM visitors;")?;
-
-    // Write all sites
-    {
-        let mut stmt = tx.prepare_cached("INSERT INTO sites (key, pv, uv) VALUES (?1, ?2, ?3)")?;
-
-        for entry in STORE.site_pv.iter() {
-            let key = entry.key();
-            let pv = entry.value().load(Ordering::Relaxed);
-            let uv = STORE
-                .site_uv
-                .get(key)
-                .map(|v| v.load(Ordering::Relaxed))
-                .unwrap_or(0);
+    actions: &[String],
+) -> StorageResult<(Vec<LogEntry>, usize)> {
+    STORAGE.query_logs(page, size, actions)
+}
 
-            stmt.execute(params![key, pv as i64, uv as i64])?;
-        }
-    }
+pub fn query_logs_after(after_id: i64, limit: usize) -> StorageResult<Vec<LogEntry>> {
+    STORAGE.query_logs_after(after_id, limit)
+}
 
-    // Write all pages
-    {
-        let mut stmt = tx.prepare_cached("INSERT INTO pages (key, pv) VALUES (?1, ?2)")?;
+pub fn get_setting(key: &str) -> StorageResult<Option<String>> {
+    STORAGE.get_setting(key)
+}
 
-        for entry in STORE.page_pv.iter() {
-            let key = entry.key();
-            let pv = entry.value().load(Ordering::Relaxed);
+pub fn set_setting(key: &str, value: Option<&str>) -> StorageResult<()> {
+    STORAGE.set_setting(key, value)
+}
 
-            stmt.execute(params![key, pv as i64])?;
-        }
-    }
+pub fn set_site_secret(site_key: &str, secret_hash: Option<&str>) -> StorageResult<()> {
+    STORAGE.set_site_secret(site_key, secret_hash)
+}
 
-    // Write all visitors
-    {
-        let mut stmt =
-            tx.prepare_cached("INSERT INTO visitors (site_key, hash) VALUES (?1, ?2)")?;
+pub fn load_site_secrets() -> StorageResult<Vec<(String, String)>> {
+    STORAGE.load_site_secrets()
+}
 
-        for entry in STORE.site_visitors.iter() {
-            let site_key = entry.key();
-            for vh in entry.value().iter() {
-                stmt.execute(params![site_key, *vh as i64])?;
-            }
-        }
+pub fn set_bot_pattern(pattern: &str, add: bool) -> StorageResult<()> {
+    STORAGE.set_bot_pattern(pattern, add)
+}
 
-        // Clear incremental tracker
-        STORE.new_visitors.write().unwrap().clear();
-    }
+pub fn load_bot_patterns() -> StorageResult<Vec<String>> {
+    STORAGE.load_bot_patterns()
+}
 
-    tx.commit()?;
+pub fn save_lockout(ip: &str, fail_count: u32, locked_until: i64) {
+    STORAGE.save_lockout(ip, fail_count, locked_until)
+}
 
-    tracing::debug!(
-        "Saved {} sites, {} pages to {}",
-        STORE.site_pv.len(),
-        STORE.page_pv.len(),
-        DB_FILE
-    );
-    Ok(())
+pub fn delete_lockout(ip: &str) {
+    STORAGE.delete_lockout(ip)
 }
 
-/// Atomically import data from an external SQLite file.
-/// Holds DB lock during entire operation to prevent races with background save.
-/// Returns (sites_count, pages_count, visitors_count).
-pub fn import_from_file(
-    temp_path: &str,
-) -> Result<(i64, i64, i64), Box<dyn std::error::Error + Send + Sync>> {
-    // Lock main DB first — blocks background save_sync
-    let conn = DB.lock().unwrap();
-
-    // Open uploaded temp database
-    let temp_conn =
-        Connection::open(temp_path).map_err(|e| format!("打开临时数据库失败: {}", e))?;
-
-    // Read counts
-    let sites_count: i64 = temp_conn
-        .query_row("SELECT COUNT(*) FROM sites", [], |r| r.get(0))
-        .map_err(|e| format!("读取 sites 表失败: {}", e))?;
-    let pages_count: i64 = temp_conn
-        .query_row("SELECT COUNT(*) FROM pages", [], |r| r.get(0))
-        .map_err(|e| format!("读取 pages 表失败: {}", e))?;
-
-    // ---- Clear STORE ----
-    STORE.site_pv.clear();
-    STORE.site_uv.clear();
-    STORE.site_visitors.clear();
-    STORE.page_pv.clear();
-    STORE.new_visitors.write().unwrap().clear();
-
-    // ---- Load from temp into STORE ----
-    // Sites
-    {
-        let mut stmt = temp_conn.prepare("SELECT key, pv, uv FROM sites")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, i64>(1)?,
-                row.get::<_, i64>(2)?,
-            ))
-        })?;
-        for row in rows {
-            let (key, pv, uv) = row?;
-            STORE.site_pv.insert(key.clone(), AtomicU64::new(pv as u64));
-            STORE.site_uv.insert(key.clone(), AtomicU64::new(uv as u64));
-            STORE.site_visitors.insert(key, dashmap::DashSet::new());
+pub fn delete_expired_lockouts(now: i64) {
+    STORAGE.delete_expired_lockouts(now)
+}
+
+pub fn load_lockouts() -> StorageResult<Vec<LockoutEntry>> {
+    STORAGE.load_lockouts()
+}
+
+// ==================== Operations ====================
+
+fn visitor_hash(identity: &str) -> u64 {
+    crate::core::visitor_hash::hash(identity)
+}
+
+/// Site key of a `site_key:path` page key
+fn page_site(page_key: &str) -> &str {
+    page_key.split_once(':').map_or(page_key, |(site, _)| site)
+}
+
+impl Store {
+    /// Increment site stats, returns (pv, uv)
+    pub fn incr_site(&self, site_key: &str, user_identity: &str) -> (u64, u64) {
+        let pv = self
+            .site_pv
+            .entry(site_key.to_string())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        self.note_write();
+
+        let vh = visitor_hash(user_identity);
+        if let Some(on_visit) = self.on_visit {
+            on_visit(site_key, vh, user_identity);
         }
-    }
 
-    // Visitors (optional table in older exports)
-    let mut visitor_count = 0i64;
-    if let Ok(mut stmt) = temp_conn.prepare("SELECT site_key, hash FROM visitors") {
-        if let Ok(rows) = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
-        }) {
-            for row in rows.flatten() {
-                let (site_key, hash) = row;
-                let set = STORE.site_visitors.entry(site_key).or_default();
-                set.insert(hash as u64);
-                visitor_count += 1;
-            }
+        if !CONFIG.uv_scope.tracks_site() {
+            return (pv, self.get_site(site_key).1);
         }
+
+        let visitors = self.site_visitors.entry(site_key.to_string()).or_default();
+
+        let is_new = visitors.insert(vh);
+
+        let uv = if is_new {
+            // Track new visitor for persistence
+            self.new_visitors
+                .write()
+                .unwrap()
+                .push((site_key.to_string(), vh));
+            self.visitors_dirty.insert(site_key.to_string());
+
+            self.site_uv
+                .entry(site_key.to_string())
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_add(1, Ordering::Relaxed)
+                + 1
+        } else {
+            self.site_uv
+                .get(site_key)
+                .map(|v| v.load(Ordering::Relaxed))
+                .unwrap_or(0)
+        };
+
+        (pv, uv)
     }
 
-    // Pages
-    {
-        let mut stmt = temp_conn.prepare("SELECT key, pv FROM pages")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
-        })?;
-        for row in rows {
-            let (key, pv) = row?;
-            STORE.page_pv.insert(key, AtomicU64::new(pv as u64));
+    /// Increment page PV, and page UV when UV_SCOPE tracks pages.
+    /// Returns (pv, uv), uv is None when page UV is not tracked.
+    pub fn incr_page(&self, page_key: &str, user_identity: &str) -> (u64, Option<u64>) {
+        let pv = self.add_page_pv(page_key, 1);
+        self.note_write();
+
+        if !CONFIG.uv_scope.tracks_page() {
+            return (pv, None);
         }
+
+        let is_new = self
+            .page_visitors
+            .entry(page_key.to_string())
+            .or_default()
+            .insert(visitor_hash(user_identity));
+
+        let uv = if is_new {
+            self.page_uv
+                .entry(page_key.to_string())
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_add(1, Ordering::Relaxed)
+                + 1
+        } else {
+            self.get_page_uv(page_key)
+        };
+
+        (pv, Some(uv))
     }
 
-    drop(temp_conn);
+    fn count_page(&self, page_key: &str) {
+        self.site_page_count
+            .entry(page_site(page_key).to_string())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
 
-    // ---- Persist to main DB immediately (still holding lock) ----
-    let tx = conn.unchecked_transaction()?;
-    tx.execute_batch("DELETE FROM sites; DELETE FROM pages; DELETE FROM visitors;")?;
+    fn uncount_page(&self, page_key: &str) {
+        // Drops the entry along with the site's last page
+        self.site_page_count.remove_if(page_site(page_key), |_, n| {
+            n.fetch_sub(1, Ordering::Relaxed) <= 1
+        });
+    }
 
-    {
-        let mut stmt = tx.prepare_cached("INSERT INTO sites (key, pv, uv) VALUES (?1, ?2, ?3)")?;
-        for entry in STORE.site_pv.iter() {
-            let key = entry.key();
-            let pv = entry.value().load(Ordering::Relaxed);
-            let uv = STORE
-                .site_uv
-                .get(key)
-                .map(|v| v.load(Ordering::Relaxed))
-                .unwrap_or(0);
-            stmt.execute(params![key, pv as i64, uv as i64])?;
+    /// Add to a page's PV, creating the page if needed. Returns the new PV.
+    pub fn add_page_pv(&self, page_key: &str, pv: u64) -> u64 {
+        let mut created = false;
+        let total = self
+            .page_pv
+            .entry(page_key.to_string())
+            .or_insert_with(|| {
+                created = true;
+                AtomicU64::new(0)
+            })
+            .fetch_add(pv, Ordering::Relaxed)
+            + pv;
+        if created {
+            self.count_page(page_key);
         }
+        total
     }
-    {
-        let mut stmt = tx.prepare_cached("INSERT INTO pages (key, pv) VALUES (?1, ?2)")?;
-        for entry in STORE.page_pv.iter() {
-            let key = entry.key();
-            let pv = entry.value().load(Ordering::Relaxed);
-            stmt.execute(params![key, pv as i64])?;
+
+    /// Overwrite a page's PV, creating the page if needed
+    pub fn set_page_pv(&self, page_key: &str, pv: u64) {
+        let mut created = false;
+        self.page_pv
+            .entry(page_key.to_string())
+            .or_insert_with(|| {
+                created = true;
+                AtomicU64::new(0)
+            })
+            .store(pv, Ordering::Relaxed);
+        if created {
+            self.count_page(page_key);
         }
     }
-    {
-        let mut stmt =
-            tx.prepare_cached("INSERT INTO visitors (site_key, hash) VALUES (?1, ?2)")?;
-        for entry in STORE.site_visitors.iter() {
-            let site_key = entry.key();
-            for vh in entry.value().iter() {
-                stmt.execute(params![site_key, *vh as i64])?;
+
+    /// Remove a page's PV counter only (UV data stays), returning its PV if it existed
+    pub fn take_page_pv(&self, page_key: &str) -> Option<u64> {
+        let (_, pv) = self.page_pv.remove(page_key)?;
+        self.uncount_page(page_key);
+        Some(pv.into_inner())
+    }
+
+    /// Number of pages of a site, without scanning page_pv
+    pub fn site_page_count(&self, site_key: &str) -> u64 {
+        self.site_page_count
+            .get(site_key)
+            .map(|n| n.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Remove a page and its UV data, returning its PV if it existed
+    pub fn remove_page(&self, page_key: &str) -> Option<u64> {
+        self.page_uv.remove(page_key);
+        self.page_visitors.remove(page_key);
+        self.take_page_pv(page_key)
+    }
+
+    /// Remove every page whose key starts with `prefix`
+    pub fn remove_pages_with_prefix(&self, prefix: &str) {
+        self.page_pv.retain(|k, _| {
+            let keep = !k.starts_with(prefix);
+            if !keep {
+                self.uncount_page(k);
             }
-        }
+            keep
+        });
+        self.page_uv.retain(|k, _| !k.starts_with(prefix));
+        self.page_visitors.retain(|k, _| !k.starts_with(prefix));
     }
 
-    tx.commit()?;
-
-    tracing::info!(
-        "Imported {} sites, {} pages, {} visitors",
-        sites_count,
-        pages_count,
-        visitor_count
-    );
-    Ok((sites_count, pages_count, visitor_count))
-}
-
-/// Load store from SQLite
-pub fn load() -> Result<(), Box<dyn std::error::Error>> {
-    let conn = DB.lock().unwrap();
-
-    // Load sites
-    {
-        let mut stmt = conn.prepare("SELECT key, pv, uv FROM sites")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, i64>(1)?,
-                row.get::<_, i64>(2)?,
-            ))
-        })?;
-
-        for row in rows {
-            let (key, pv, uv) = row?;
-            STORE.site_pv.insert(key.clone(), AtomicU64::new(pv as u64));
-            STORE.site_uv.insert(key.clone(), AtomicU64::new(uv as u64));
-            STORE.site_visitors.insert(key, DashSet::new());
-        }
+    pub fn get_site(&self, site_key: &str) -> (u64, u64) {
+        let pv = self
+            .site_pv
+            .get(site_key)
+            .map(|v| v.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        let uv = self
+            .site_uv
+            .get(site_key)
+            .map(|v| v.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        (pv, uv)
+    }
+
+    pub fn get_page(&self, page_key: &str) -> u64 {
+        self.page_pv
+            .get(page_key)
+            .map(|v| v.load(Ordering::Relaxed))
+            .unwrap_or(0)
     }
 
-    // Load pages
-    {
-        let mut stmt = conn.prepare("SELECT key, pv FROM pages")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
-        })?;
+    pub fn get_page_uv(&self, page_key: &str) -> u64 {
+        self.page_uv
+            .get(page_key)
+            .map(|v| v.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+/// Rough per-set overhead of a DashSet (shards, table header)
+const SET_OVERHEAD_BYTES: u64 = 256;
+/// Rough per-entry overhead of a counter map entry besides the key bytes
+/// (String header, AtomicU64, hash table slot)
+const COUNTER_ENTRY_BYTES: u64 = 48;
+
+/// Entry count and estimated heap size of one store map
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct MapMemory {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Visitor sets of one kind: number of sets, total hashes, estimated bytes
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct VisitorMemory {
+    pub sets: u64,
+    pub visitors: u64,
+    pub bytes: u64,
+}
+
+fn counter_memory(map: &DashMap<String, AtomicU64>) -> MapMemory {
+    map.iter().fold(MapMemory::default(), |acc, e| MapMemory {
+        entries: acc.entries + 1,
+        bytes: acc.bytes + e.key().len() as u64 + COUNTER_ENTRY_BYTES,
+    })
+}
 
-        for row in rows {
-            let (key, pv) = row?;
-            STORE.page_pv.insert(key, AtomicU64::new(pv as u64));
+/// Each hash is a u64 (8 bytes) plus a fixed overhead per set
+fn visitor_memory(map: &DashMap<String, DashSet<u64>>) -> VisitorMemory {
+    map.iter().fold(VisitorMemory::default(), |acc, e| {
+        let len = e.value().len() as u64;
+        VisitorMemory {
+            sets: acc.sets + 1,
+            visitors: acc.visitors + len,
+            bytes: acc.bytes + len * 8 + SET_OVERHEAD_BYTES,
         }
+    })
+}
+
+impl Store {
+    pub fn site_visitor_memory(&self) -> VisitorMemory {
+        visitor_memory(&self.site_visitors)
     }
 
-    // Load visitors
-    let mut visitor_count = 0usize;
-    {
-        let mut stmt = conn.prepare("SELECT site_key, hash FROM visitors")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
-        })?;
-
-        // Group by site_key for efficiency
-        let mut site_visitors: std::collections::HashMap<String, HashSet<u64>> =
-            std::collections::HashMap::new();
-
-        for row in rows {
-            let (site_key, hash) = row?;
-            site_visitors
-                .entry(site_key)
-                .or_default()
-                .insert(hash as u64);
-            visitor_count += 1;
-        }
+    pub fn page_visitor_memory(&self) -> VisitorMemory {
+        visitor_memory(&self.page_visitors)
+    }
 
-        for (site_key, visitors) in site_visitors {
-            let set = STORE.site_visitors.entry(site_key).or_default();
-            for vh in visitors {
-                set.insert(vh);
-            }
-        }
+    /// Estimated memory of every counter map: (site_pv, site_uv, page_pv, page_uv)
+    pub fn counter_maps_memory(&self) -> [MapMemory; 4] {
+        [
+            counter_memory(&self.site_pv),
+            counter_memory(&self.site_uv),
+            counter_memory(&self.page_pv),
+            counter_memory(&self.page_uv),
+        ]
     }
 
-    tracing::info!(
-        "Loaded {} sites, {} pages, {} visitors from {}",
-        STORE.site_pv.len(),
-        STORE.page_pv.len(),
-        visitor_count,
-        DB_FILE
-    );
-    Ok(())
+    /// Visitors recorded since the last save
+    pub fn pending_visitors(&self) -> u64 {
+        self.new_visitors.read().unwrap().len() as u64
+    }
 }
 
-// ==================== Operations ====================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_pages_per_site() {
+        let store = Store::new();
+        store.incr_page("pagecount.test:/a", "v1");
+        store.incr_page("pagecount.test:/a", "v2");
+        store.add_page_pv("pagecount.test:/b", 5);
+        store.set_page_pv("pagecount.test:/c", 1);
+        assert_eq!(store.site_page_count("pagecount.test"), 3);
+
+        assert_eq!(store.remove_page("pagecount.test:/a"), Some(2));
+        assert_eq!(store.remove_page("pagecount.test:/a"), None);
+        assert_eq!(store.take_page_pv("pagecount.test:/b"), Some(5));
+        assert_eq!(store.site_page_count("pagecount.test"), 1);
+
+        store.remove_pages_with_prefix("pagecount.test:");
+        assert_eq!(store.site_page_count("pagecount.test"), 0);
+        assert!(store.site_page_count.is_empty());
+    }
 
-fn visitor_hash(identity: &str) -> u64 {
-    use std::hash::{Hash, Hasher};
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    identity.hash(&mut hasher);
-    hasher.finish()
-}
-
-/// Increment site stats, returns (pv, uv)
-pub fn incr_site(site_key: &str, user_identity: &str) -> (u64, u64) {
-    let pv = STORE
-        .site_pv
-        .entry(site_key.to_string())
-        .or_insert_with(|| AtomicU64::new(0))
-        .fetch_add(1, Ordering::Relaxed)
-        + 1;
-
-    let vh = visitor_hash(user_identity);
-    let visitors = STORE.site_visitors.entry(site_key.to_string()).or_default();
-
-    let is_new = visitors.insert(vh);
-
-    let uv = if is_new {
-        // Track new visitor for persistence
-        STORE
-            .new_visitors
-            .write()
-            .unwrap()
-            .push((site_key.to_string(), vh));
-
-        STORE
-            .site_uv
-            .entry(site_key.to_string())
-            .or_insert_with(|| AtomicU64::new(0))
-            .fetch_add(1, Ordering::Relaxed)
-            + 1
-    } else {
-        STORE
-            .site_uv
-            .get(site_key)
-            .map(|v| v.load(Ordering::Relaxed))
-            .unwrap_or(0)
-    };
-
-    (pv, uv)
-}
-
-/// Increment page PV only
-pub fn incr_page(page_key: &str) -> u64 {
-    STORE
-        .page_pv
-        .entry(page_key.to_string())
-        .or_insert_with(|| AtomicU64::new(0))
-        .fetch_add(1, Ordering::Relaxed)
-        + 1
-}
-
-pub fn get_site(site_key: &str) -> (u64, u64) {
-    let pv = STORE
-        .site_pv
-        .get(site_key)
-        .map(|v| v.load(Ordering::Relaxed))
-        .unwrap_or(0);
-    let uv = STORE
-        .site_uv
-        .get(site_key)
-        .map(|v| v.load(Ordering::Relaxed))
-        .unwrap_or(0);
-    (pv, uv)
-}
-
-pub fn get_page(page_key: &str) -> u64 {
-    STORE
-        .page_pv
-        .get(page_key)
-        .map(|v| v.load(Ordering::Relaxed))
-        .unwrap_or(0)
+    #[test]
+    fn tracks_unsaved_changes_per_store() {
+        static SEEN: AtomicU64 = AtomicU64::new(0);
+        fn seen(_: &str, _: u64, _: &str) {
+            SEEN.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let store = Store::new().with_visit_hook(seen);
+        let other = Store::new();
+        store.incr_site("hooked.test", "v1");
+        store.incr_page("hooked.test:/", "v1");
+        assert_eq!(SEEN.load(Ordering::Relaxed), 1);
+        assert!(store.has_unsaved_changes());
+        assert!(!other.has_unsaved_changes());
+
+        other.mark_dirty();
+        assert!(other.has_unsaved_changes());
+    }
 }
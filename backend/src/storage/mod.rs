@@ -0,0 +1,110 @@
+//! Persistence backends. Counters live in memory (state::Store); a Storage writes
+//! them out and reads them back, and keeps the admin tables (operation logs,
+//! settings, site write keys, bot patterns, auth failures).
+
+mod sqlite;
+
+use once_cell::sync::Lazy;
+
+use crate::config::{Config, CONFIG};
+use crate::state::Store;
+
+pub use sqlite::SqliteStorage;
+
+pub type StorageError = Box<dyn std::error::Error + Send + Sync>;
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// A single operation log entry: (id, timestamp, action, detail, ip, request_id)
+pub type LogEntry = (i64, String, String, String, String, String);
+
+/// A persisted auth failure record: (ip, fail_count, locked_until)
+pub type LockoutEntry = (String, u32, i64);
+
+/// Rows written by one save
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct SaveStats {
+    pub sites: usize,
+    pub pages: usize,
+    pub visitors: usize,
+    pub page_visitors: usize,
+}
+
+/// Where the data goes. Methods block; async callers go through spawn_blocking.
+/// A backend that can't be reached keeps answering: reads and writes fail (or,
+/// for the fire-and-forget ones, do nothing) and counting carries on in memory.
+pub trait Storage: Send + Sync {
+    /// Whether data is currently being persisted
+    fn persistence_enabled(&self) -> bool;
+
+    /// Error from the last failed attempt to open the backend, if any
+    fn error(&self) -> Option<String>;
+
+    /// Whether the stored visitor sets were written with this build's visitor hash
+    fn uv_accurate(&self) -> bool;
+
+    /// Merge the persisted data into `store`
+    fn load(&self, store: &Store) -> StorageResult<()>;
+
+    /// Write `store` in one transaction. The visitor sets are rewritten whole with
+    /// `all_visitors`, otherwise only those of the sites in `visitors_dirty`.
+    fn save(&self, store: &Store, all_visitors: bool) -> StorageResult<SaveStats>;
+
+    /// Replace `store` and the persisted data with an exported data.db file.
+    /// Returns (sites, pages, visitors).
+    fn import(&self, store: &Store, path: &str) -> StorageResult<(i64, i64, i64)>;
+
+    /// Add an operation log entry
+    fn add_log(&self, action: &str, detail: &str, ip: &str, request_id: &str);
+
+    /// Query operation logs with pagination (newest first, `page` from 1), optionally
+    /// restricted to some actions. Returns the page and the number of matching entries.
+    fn query_logs(
+        &self,
+        page: usize,
+        size: usize,
+        actions: &[String],
+    ) -> StorageResult<(Vec<LogEntry>, usize)>;
+
+    /// Read up to `limit` operation logs with id > `after_id`, oldest first.
+    /// Used to walk the whole table in batches without holding a lock throughout.
+    fn query_logs_after(&self, after_id: i64, limit: usize) -> StorageResult<Vec<LogEntry>>;
+
+    /// Read a value from `admin_settings`
+    fn get_setting(&self, key: &str) -> StorageResult<Option<String>>;
+
+    /// Write (or with `None`, delete) a value in `admin_settings`
+    fn set_setting(&self, key: &str, value: Option<&str>) -> StorageResult<()>;
+
+    /// Store (or with `None`, remove) the SHA-256 hex of a site's write key
+    fn set_site_secret(&self, site_key: &str, secret_hash: Option<&str>) -> StorageResult<()>;
+
+    /// All persisted site write keys: (site_key, secret_hash)
+    fn load_site_secrets(&self) -> StorageResult<Vec<(String, String)>>;
+
+    /// Add (`add = true`) or remove a custom bot User-Agent pattern
+    fn set_bot_pattern(&self, pattern: &str, add: bool) -> StorageResult<()>;
+
+    /// All custom bot patterns
+    fn load_bot_patterns(&self) -> StorageResult<Vec<String>>;
+
+    /// Persist an admin auth failure record. `locked_until` is a unix timestamp
+    /// (seconds) after which the failures no longer count.
+    fn save_lockout(&self, ip: &str, fail_count: u32, locked_until: i64);
+
+    fn delete_lockout(&self, ip: &str);
+
+    /// Remove records that expired before `now` (unix seconds)
+    fn delete_expired_lockouts(&self, now: i64);
+
+    /// All persisted auth failure records
+    fn load_lockouts(&self) -> StorageResult<Vec<LockoutEntry>>;
+}
+
+/// The process-wide backend, opened on first use
+pub static STORAGE: Lazy<Box<dyn Storage>> = Lazy::new(|| open(&CONFIG));
+
+/// The backend DATABASE_URL names. Config refuses every scheme but sqlite, so
+/// for now that is the only one to pick.
+fn open(config: &Config) -> Box<dyn Storage> {
+    Box::new(SqliteStorage::open(&config.db_path))
+}
@@ -0,0 +1,944 @@
+//! SQLite backend, the default (`DATABASE_URL=sqlite://data.db` or a bare path)
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use super::{LockoutEntry, LogEntry, SaveStats, Storage, StorageResult};
+use crate::state::Store;
+
+const DB_UNAVAILABLE: &str = "database unavailable (persistence disabled)";
+
+pub struct SqliteStorage {
+    path: String,
+    /// Single writer. `None` while the database can't be opened: counting keeps
+    /// working in memory and every save retries the open.
+    db: Mutex<Option<Connection>>,
+    /// Whether the database is currently open (readable without taking the lock)
+    persistence: AtomicBool,
+    /// Last error from opening the database, cleared once it opens again
+    error: RwLock<Option<String>>,
+    /// Whether the on-disk data has been merged into the store yet
+    loaded: AtomicBool,
+    /// Cleared when the database's visitor sets were written with another hash version
+    uv_accurate: AtomicBool,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Self {
+        let storage = Self {
+            path: path.to_string(),
+            db: Mutex::new(None),
+            persistence: AtomicBool::new(false),
+            error: RwLock::new(None),
+            loaded: AtomicBool::new(false),
+            uv_accurate: AtomicBool::new(true),
+        };
+        *storage.db.lock().unwrap() = storage.open_db();
+        storage
+    }
+
+    fn open_db(&self) -> Option<Connection> {
+        let result = Connection::open(&self.path)
+            .and_then(|conn| init_db(&conn).map(|accurate| (conn, accurate)));
+        match result {
+            Ok((conn, accurate)) => {
+                self.persistence.store(true, Ordering::Relaxed);
+                self.uv_accurate.store(accurate, Ordering::Relaxed);
+                *self.error.write().unwrap() = None;
+                Some(conn)
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to open database {}: {} (persistence disabled, counting continues in memory)",
+                    self.path,
+                    e
+                );
+                self.persistence.store(false, Ordering::Relaxed);
+                *self.error.write().unwrap() = Some(e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Retry opening the database if it is unavailable.
+    /// When it comes back and nothing was loaded at startup, the on-disk data is
+    /// merged into `store` first so the next save doesn't clobber it.
+    fn ensure_db<'a>(
+        &self,
+        db: &'a mut Option<Connection>,
+        store: &Store,
+    ) -> StorageResult<&'a Connection> {
+        if db.is_none() {
+            let conn = self.open_db().ok_or(DB_UNAVAILABLE)?;
+            tracing::info!("Database {} reopened, persistence restored", self.path);
+            if !self.loaded.load(Ordering::Relaxed) {
+                self.load_into(&conn, store)?;
+            }
+            *db = Some(conn);
+        }
+        Ok(db.as_ref().unwrap())
+    }
+
+    fn load_into(&self, conn: &Connection, store: &Store) -> StorageResult<()> {
+        let visitors = load_from(conn, store)?;
+        self.loaded.store(true, Ordering::Relaxed);
+        tracing::info!(
+            "Loaded {} sites, {} pages, {} visitors from {}",
+            store.site_pv.len(),
+            store.page_pv.len(),
+            visitors,
+            self.path
+        );
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn persistence_enabled(&self) -> bool {
+        self.persistence.load(Ordering::Relaxed)
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.read().unwrap().clone()
+    }
+
+    fn uv_accurate(&self) -> bool {
+        self.uv_accurate.load(Ordering::Relaxed)
+    }
+
+    fn load(&self, store: &Store) -> StorageResult<()> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        self.load_into(conn, store)
+    }
+
+    /// Concurrent saves (background loop, shutdown, POST /api/admin/save) are
+    /// serialized by the connection mutex, and each one is a single transaction, so
+    /// they can't interleave.
+    fn save(&self, store: &Store, all_visitors: bool) -> StorageResult<SaveStats> {
+        let mut db = self.db.lock().unwrap();
+        let conn = self.ensure_db(&mut db, store)?;
+        Ok(write_store(conn, store, all_visitors)?)
+    }
+
+    /// Holds the connection lock during the entire operation to prevent races with
+    /// the background save.
+    fn import(&self, store: &Store, temp_path: &str) -> StorageResult<(i64, i64, i64)> {
+        // Lock the main DB first — blocks a background save
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+
+        // Open uploaded temp database
+        let temp_conn =
+            Connection::open(temp_path).map_err(|e| format!("打开临时数据库失败: {}", e))?;
+
+        if let Ok(Some(version)) = stored_visitor_hash_version(&temp_conn) {
+            if version != crate::core::visitor_hash::VERSION {
+                return Err(format!(
+                    "导入文件的访客哈希版本为 {}，与当前版本 {} 不兼容",
+                    version,
+                    crate::core::visitor_hash::VERSION
+                )
+                .into());
+            }
+        }
+
+        // Read counts
+        let sites_count: i64 = temp_conn
+            .query_row("SELECT COUNT(*) FROM sites", [], |r| r.get(0))
+            .map_err(|e| format!("读取 sites 表失败: {}", e))?;
+        let pages_count: i64 = temp_conn
+            .query_row("SELECT COUNT(*) FROM pages", [], |r| r.get(0))
+            .map_err(|e| format!("读取 pages 表失败: {}", e))?;
+
+        // ---- Clear the store ----
+        store.clear();
+
+        // ---- Load from temp into the store ----
+        // Sites
+        {
+            let mut stmt = temp_conn.prepare("SELECT key, pv, uv FROM sites")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?;
+            for row in rows {
+                let (key, pv, uv) = row?;
+                store.site_pv.insert(key.clone(), AtomicU64::new(pv as u64));
+                store.site_uv.insert(key.clone(), AtomicU64::new(uv as u64));
+                store.site_visitors.insert(key, dashmap::DashSet::new());
+            }
+        }
+
+        // Visitors (optional table in older exports)
+        let mut visitor_count = 0i64;
+        if let Ok(mut stmt) = temp_conn.prepare("SELECT site_key, hash FROM visitors") {
+            if let Ok(rows) = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            }) {
+                for row in rows.flatten() {
+                    let (site_key, hash) = row;
+                    let set = store.site_visitors.entry(site_key).or_default();
+                    set.insert(hash as u64);
+                    visitor_count += 1;
+                }
+            }
+        }
+
+        // Pages
+        {
+            let mut stmt = temp_conn.prepare("SELECT key, pv FROM pages")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?;
+            for row in rows {
+                let (key, pv) = row?;
+                store.set_page_pv(&key, pv as u64);
+            }
+        }
+
+        // Page UV (optional column/table in older exports)
+        if let Ok(mut stmt) = temp_conn.prepare("SELECT key, uv FROM pages WHERE uv > 0") {
+            if let Ok(rows) = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            }) {
+                for (key, uv) in rows.flatten() {
+                    store.page_uv.insert(key, AtomicU64::new(uv as u64));
+                }
+            }
+        }
+        if let Ok(mut stmt) = temp_conn.prepare("SELECT page_key, hash FROM page_visitors") {
+            if let Ok(rows) = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            }) {
+                for (page_key, hash) in rows.flatten() {
+                    store
+                        .page_visitors
+                        .entry(page_key)
+                        .or_default()
+                        .insert(hash as u64);
+                }
+            }
+        }
+
+        drop(temp_conn);
+
+        // ---- Persist to main DB immediately (still holding lock) ----
+        write_store(conn, store, true)?;
+
+        tracing::info!(
+            "Imported {} sites, {} pages, {} visitors",
+            sites_count,
+            pages_count,
+            visitor_count
+        );
+        Ok((sites_count, pages_count, visitor_count))
+    }
+
+    fn add_log(&self, action: &str, detail: &str, ip: &str, request_id: &str) {
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        if let Ok(db) = self.db.lock() {
+            if let Some(conn) = db.as_ref() {
+                let _ = conn.execute(
+                    "INSERT INTO operation_logs (timestamp, action, detail, ip, request_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![now, action, detail, ip, request_id],
+                );
+            }
+        }
+    }
+
+    fn get_setting(&self, key: &str) -> StorageResult<Option<String>> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        let value = conn
+            .query_row(
+                "SELECT value FROM admin_settings WHERE key = ?1",
+                params![key],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn set_setting(&self, key: &str, value: Option<&str>) -> StorageResult<()> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        match value {
+            Some(value) => conn.execute(
+                "INSERT OR REPLACE INTO admin_settings (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?,
+            None => conn.execute("DELETE FROM admin_settings WHERE key = ?1", params![key])?,
+        };
+        Ok(())
+    }
+
+    fn set_site_secret(&self, site_key: &str, secret_hash: Option<&str>) -> StorageResult<()> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        match secret_hash {
+            Some(hash) => conn.execute(
+                "INSERT OR REPLACE INTO site_secrets (site_key, secret_hash) VALUES (?1, ?2)",
+                params![site_key, hash],
+            )?,
+            None => conn.execute(
+                "DELETE FROM site_secrets WHERE site_key = ?1",
+                params![site_key],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn load_site_secrets(&self) -> StorageResult<Vec<(String, String)>> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        let mut stmt = conn.prepare("SELECT site_key, secret_hash FROM site_secrets")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    fn set_bot_pattern(&self, pattern: &str, add: bool) -> StorageResult<()> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        if add {
+            conn.execute(
+                "INSERT OR IGNORE INTO bot_patterns (pattern) VALUES (?1)",
+                params![pattern],
+            )?;
+        } else {
+            conn.execute(
+                "DELETE FROM bot_patterns WHERE pattern = ?1",
+                params![pattern],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn load_bot_patterns(&self) -> StorageResult<Vec<String>> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        let mut stmt = conn.prepare("SELECT pattern FROM bot_patterns ORDER BY pattern")?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    fn save_lockout(&self, ip: &str, fail_count: u32, locked_until: i64) {
+        if let Ok(db) = self.db.lock() {
+            if let Some(conn) = db.as_ref() {
+                let _ = conn.execute(
+                    "INSERT OR REPLACE INTO admin_lockouts (ip, fail_count, locked_until) VALUES (?1, ?2, ?3)",
+                    params![ip, fail_count, locked_until],
+                );
+            }
+        }
+    }
+
+    fn delete_lockout(&self, ip: &str) {
+        if let Ok(db) = self.db.lock() {
+            if let Some(conn) = db.as_ref() {
+                let _ = conn.execute("DELETE FROM admin_lockouts WHERE ip = ?1", params![ip]);
+            }
+        }
+    }
+
+    fn delete_expired_lockouts(&self, now: i64) {
+        if let Ok(db) = self.db.lock() {
+            if let Some(conn) = db.as_ref() {
+                let _ = conn.execute(
+                    "DELETE FROM admin_lockouts WHERE locked_until <= ?1",
+                    params![now],
+                );
+            }
+        }
+    }
+
+    fn load_lockouts(&self) -> StorageResult<Vec<LockoutEntry>> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        let mut stmt = conn.prepare("SELECT ip, fail_count, locked_until FROM admin_lockouts")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    fn query_logs(
+        &self,
+        page: usize,
+        size: usize,
+        actions: &[String],
+    ) -> StorageResult<(Vec<LogEntry>, usize)> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+
+        let filter = if actions.is_empty() {
+            String::new()
+        } else {
+            let placeholders = (1..=actions.len())
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(" WHERE action IN ({})", placeholders)
+        };
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM operation_logs{}", filter),
+            rusqlite::params_from_iter(actions),
+            |r| r.get::<_, i64>(0),
+        )?;
+        let total = total as usize;
+
+        let offset = (page.saturating_sub(1)) * size;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, timestamp, action, detail, ip, request_id FROM operation_logs{} ORDER BY id DESC LIMIT {} OFFSET {}",
+            filter, size as i64, offset as i64
+        ))?;
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(actions), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((rows, total))
+    }
+
+    fn query_logs_after(&self, after_id: i64, limit: usize) -> StorageResult<Vec<LogEntry>> {
+        let db = self.db.lock().unwrap();
+        let conn = db.as_ref().ok_or(DB_UNAVAILABLE)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, timestamp, action, detail, ip, request_id FROM operation_logs WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let rows = stmt
+            .query_map(params![after_id, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+}
+
+/// Create missing tables and columns. Returns whether the stored visitor sets use
+/// this build's visitor hash.
+fn init_db(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS sites (
+            key TEXT PRIMARY KEY,
+            pv INTEGER NOT NULL DEFAULT 0,
+            uv INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS pages (
+            key TEXT PRIMARY KEY,
+            pv INTEGER NOT NULL DEFAULT 0,
+            uv INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS visitors (
+            site_key TEXT NOT NULL,
+            hash INTEGER NOT NULL,
+            PRIMARY KEY (site_key, hash)
+        );
+        CREATE INDEX IF NOT EXISTS idx_visitors_site ON visitors(site_key);
+        CREATE TABLE IF NOT EXISTS page_visitors (
+            page_key TEXT NOT NULL,
+            hash INTEGER NOT NULL,
+            PRIMARY KEY (page_key, hash)
+        );
+        CREATE TABLE IF NOT EXISTS operation_logs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            action TEXT NOT NULL,
+            detail TEXT NOT NULL DEFAULT '',
+            ip TEXT NOT NULL DEFAULT ''
+        );
+        CREATE TABLE IF NOT EXISTS admin_settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS site_secrets (
+            site_key TEXT PRIMARY KEY,
+            secret_hash TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS admin_lockouts (
+            ip TEXT PRIMARY KEY,
+            fail_count INTEGER NOT NULL,
+            locked_until INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS bot_patterns (
+            pattern TEXT PRIMARY KEY
+        );
+        ",
+    )?;
+    // pages.uv was added after the first release
+    add_column_if_missing(conn, "pages", "uv", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(
+        conn,
+        "operation_logs",
+        "request_id",
+        "TEXT NOT NULL DEFAULT ''",
+    )?;
+    check_visitor_hash_version(conn)
+}
+
+/// `admin_settings` key holding the visitor hash version of the stored UV sets
+const VISITOR_HASH_SETTING: &str = "visitor_hash";
+
+fn stored_visitor_hash_version(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT value FROM admin_settings WHERE key = ?1",
+        params![VISITOR_HASH_SETTING],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Record the visitor hash version, or warn when the stored sets were written with
+/// another one. Databases from before the marker existed hold version-1 hashes
+/// (see core::visitor_hash), so they are simply stamped.
+fn check_visitor_hash_version(conn: &Connection) -> rusqlite::Result<bool> {
+    let current = crate::core::visitor_hash::VERSION;
+    let accurate = match stored_visitor_hash_version(conn)? {
+        None => {
+            conn.execute(
+                "INSERT INTO admin_settings (key, value) VALUES (?1, ?2)",
+                params![VISITOR_HASH_SETTING, current],
+            )?;
+            true
+        }
+        Some(stored) if stored != current => {
+            tracing::warn!(
+                "Database stores visitor hashes as {}, this build writes {}: returning visitors may be counted again",
+                stored,
+                current
+            );
+            false
+        }
+        Some(_) => true,
+    };
+    Ok(accurate)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))?;
+    }
+    Ok(())
+}
+
+/// Rewrite the data tables from `store` in one transaction. The `visitors` table is
+/// rewritten whole with `all_visitors`, otherwise only for the sites in
+/// `visitors_dirty`.
+fn write_store(
+    conn: &Connection,
+    store: &Store,
+    all_visitors: bool,
+) -> rusqlite::Result<SaveStats> {
+    // Sites marked while writing are written again by the next save
+    let dirty_sites: Vec<String> = store.visitors_dirty.iter().map(|k| k.clone()).collect();
+    for key in &dirty_sites {
+        store.visitors_dirty.remove(key);
+    }
+    let only = if all_visitors {
+        None
+    } else {
+        Some(&dirty_sites[..])
+    };
+    write_tables(conn, store, only).inspect_err(|_| {
+        for key in dirty_sites.iter().cloned() {
+            store.visitors_dirty.insert(key);
+        }
+    })
+}
+
+/// `dirty_sites` limits the visitor rows rewritten; `None` rewrites them all
+fn write_tables(
+    conn: &Connection,
+    store: &Store,
+    dirty_sites: Option<&[String]>,
+) -> rusqlite::Result<SaveStats> {
+    let tx = conn.unchecked_transaction()?;
+    let mut stats = SaveStats::default();
+
+    // Clear the tables and rewrite (ensures deletions are persisted)
+    tx.execute_batch("DELETE FROM sites; DELETE FROM pages; DELETE FROM page_visitors;")?;
+
+    // Write all sites
+    {
+        let mut stmt = tx.prepare_cached("INSERT INTO sites (key, pv, uv) VALUES (?1, ?2, ?3)")?;
+
+        for entry in store.site_pv.iter() {
+            let key = entry.key();
+            let pv = entry.value().load(Ordering::Relaxed);
+            let uv = store
+                .site_uv
+                .get(key)
+                .map(|v| v.load(Ordering::Relaxed))
+                .unwrap_or(0);
+
+            stmt.execute(params![key, pv as i64, uv as i64])?;
+            stats.sites += 1;
+        }
+    }
+
+    // Write all pages
+    {
+        let mut stmt = tx.prepare_cached("INSERT INTO pages (key, pv, uv) VALUES (?1, ?2, ?3)")?;
+
+        for entry in store.page_pv.iter() {
+            let key = entry.key();
+            let pv = entry.value().load(Ordering::Relaxed);
+            let uv = store
+                .page_uv
+                .get(key)
+                .map(|v| v.load(Ordering::Relaxed))
+                .unwrap_or(0);
+
+            stmt.execute(params![key, pv as i64, uv as i64])?;
+            stats.pages += 1;
+        }
+    }
+
+    // Write visitors: all of them, or the sets of the sites that gained one
+    {
+        let mut stmt =
+            tx.prepare_cached("INSERT INTO visitors (site_key, hash) VALUES (?1, ?2)")?;
+
+        match dirty_sites {
+            None => {
+                tx.execute("DELETE FROM visitors", [])?;
+                for entry in store.site_visitors.iter() {
+                    let site_key = entry.key();
+                    for vh in entry.value().iter() {
+                        stmt.execute(params![site_key, *vh as i64])?;
+                        stats.visitors += 1;
+                    }
+                }
+            }
+            Some(sites) => {
+                let mut delete = tx.prepare_cached("DELETE FROM visitors WHERE site_key = ?1")?;
+                for site_key in sites {
+                    delete.execute(params![site_key])?;
+                    if let Some(set) = store.site_visitors.get(site_key) {
+                        for vh in set.iter() {
+                            stmt.execute(params![site_key, *vh as i64])?;
+                            stats.visitors += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Write all page visitors
+    {
+        let mut stmt =
+            tx.prepare_cached("INSERT INTO page_visitors (page_key, hash) VALUES (?1, ?2)")?;
+
+        for entry in store.page_visitors.iter() {
+            let page_key = entry.key();
+            for vh in entry.value().iter() {
+                stmt.execute(params![page_key, *vh as i64])?;
+                stats.page_visitors += 1;
+            }
+        }
+    }
+
+    // Lifetime request counters ride along with the data they describe
+    {
+        let service = crate::middleware::service_stats::lifetime();
+        tx.execute(
+            "INSERT OR REPLACE INTO admin_settings (key, value) VALUES (?1, ?2)",
+            params![
+                crate::middleware::service_stats::SETTING,
+                serde_json::to_string(&service).unwrap_or_default()
+            ],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(stats)
+}
+
+/// Merge the rows of `conn` into `store`.
+/// At startup the store is empty so this is a plain load; after a recovered open
+/// failure it adds the persisted counts to whatever was counted in memory.
+/// Returns the number of site visitors read.
+fn load_from(conn: &Connection, store: &Store) -> rusqlite::Result<usize> {
+    // Read everything before touching store so a failed read can be retried
+    // without double-counting a partial merge.
+    let sites = {
+        let mut stmt = conn.prepare("SELECT key, pv, uv FROM sites")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+
+    let pages = {
+        let mut stmt = conn.prepare("SELECT key, pv, uv FROM pages")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+
+    let page_visitors = {
+        let mut stmt = conn.prepare("SELECT page_key, hash FROM page_visitors")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Group visitors by site_key for efficiency
+    let mut visitor_count = 0usize;
+    let mut site_visitors: std::collections::HashMap<String, HashSet<u64>> =
+        std::collections::HashMap::new();
+    {
+        let mut stmt = conn.prepare("SELECT site_key, hash FROM visitors")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        for row in rows {
+            let (site_key, hash) = row?;
+            site_visitors
+                .entry(site_key)
+                .or_default()
+                .insert(hash as u64);
+            visitor_count += 1;
+        }
+    }
+
+    for (key, pv, uv) in sites {
+        store
+            .site_pv
+            .entry(key.clone())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(pv as u64, Ordering::Relaxed);
+        store
+            .site_uv
+            .entry(key.clone())
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(uv as u64, Ordering::Relaxed);
+        store.site_visitors.entry(key).or_default();
+    }
+
+    for (key, pv, uv) in pages {
+        if uv > 0 {
+            store
+                .page_uv
+                .entry(key.clone())
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_add(uv as u64, Ordering::Relaxed);
+        }
+        store.add_page_pv(&key, pv as u64);
+    }
+
+    for (page_key, hash) in page_visitors {
+        let set = store.page_visitors.entry(page_key.clone()).or_default();
+        if !set.insert(hash as u64) {
+            if let Some(uv) = store.page_uv.get(&page_key) {
+                let current = uv.load(Ordering::Relaxed);
+                uv.store(current.saturating_sub(1), Ordering::Relaxed);
+            }
+        }
+    }
+
+    for (site_key, visitors) in site_visitors {
+        let set = store.site_visitors.entry(site_key.clone()).or_default();
+        // A visitor already counted in memory was added to both uv totals
+        let mut duplicates = 0u64;
+        for vh in visitors {
+            if !set.insert(vh) {
+                duplicates += 1;
+            }
+        }
+        if duplicates > 0 {
+            if let Some(uv) = store.site_uv.get(&site_key) {
+                let current = uv.load(Ordering::Relaxed);
+                uv.store(current.saturating_sub(duplicates), Ordering::Relaxed);
+            }
+        }
+    }
+
+    Ok(visitor_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_trait() {
+        let storage: Box<dyn Storage> = Box::new(SqliteStorage::open(":memory:"));
+        assert!(storage.persistence_enabled());
+        assert!(storage.uv_accurate());
+
+        let saved = Store::new();
+        saved.incr_site("trait.test", "v1");
+        saved.incr_page("trait.test:/", "v1");
+        let stats = storage.save(&saved, true).unwrap();
+        assert_eq!((stats.sites, stats.pages, stats.visitors), (1, 1, 1));
+        let loaded = Store::new();
+        storage.load(&loaded).unwrap();
+        assert_eq!(loaded.get_site("trait.test"), (1, 1));
+        assert_eq!(loaded.get_page("trait.test:/"), 1);
+
+        storage.add_log("first", "", "127.0.0.1", "");
+        storage.add_log("second", "detail", "127.0.0.1", "req-1");
+        let (page, total) = storage.query_logs(1, 10, &["second".to_string()]).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(page[0].2, "second");
+        assert_eq!(page[0].5, "req-1");
+        assert_eq!(
+            storage.query_logs_after(page[0].0 - 1, 10).unwrap().len(),
+            1
+        );
+
+        storage.set_setting("k", Some("v")).unwrap();
+        assert_eq!(storage.get_setting("k").unwrap().as_deref(), Some("v"));
+        storage.set_setting("k", None).unwrap();
+        assert_eq!(storage.get_setting("k").unwrap(), None);
+    }
+
+    #[test]
+    fn flags_foreign_visitor_hash_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(init_db(&conn).unwrap());
+        assert_eq!(
+            stored_visitor_hash_version(&conn).unwrap().as_deref(),
+            Some(crate::core::visitor_hash::VERSION)
+        );
+
+        conn.execute(
+            "UPDATE admin_settings SET value = 'other-v9' WHERE key = ?1",
+            params![VISITOR_HASH_SETTING],
+        )
+        .unwrap();
+        assert!(!check_visitor_hash_version(&conn).unwrap());
+        // The marker is left alone: the stored sets still hold the other version
+        assert_eq!(
+            stored_visitor_hash_version(&conn).unwrap().as_deref(),
+            Some("other-v9")
+        );
+    }
+
+    #[test]
+    fn saves_and_loads_a_store() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+
+        let saved = Store::new();
+        saved.incr_site("a.test", "v1");
+        saved.incr_site("a.test", "v2");
+        saved.incr_site("a.test", "v1");
+        saved.incr_page("a.test:/x", "v1");
+        saved.add_page_pv("a.test:/y", 7);
+        let stats = write_store(&conn, &saved, true).unwrap();
+        assert_eq!((stats.sites, stats.pages), (1, 2));
+
+        let loaded = Store::new();
+        load_from(&conn, &loaded).unwrap();
+        assert_eq!(loaded.get_site("a.test"), saved.get_site("a.test"));
+        assert_eq!(loaded.get_page("a.test:/y"), 7);
+        assert_eq!(loaded.site_page_count("a.test"), 2);
+
+        // Loading again merges: the same visitors are not counted twice
+        load_from(&conn, &loaded).unwrap();
+        assert_eq!(loaded.get_site("a.test").1, saved.get_site("a.test").1);
+
+        loaded.clear();
+        assert_eq!(loaded.get_site("a.test"), (0, 0));
+        assert_eq!(loaded.site_page_count("a.test"), 0);
+    }
+
+    #[test]
+    fn rewrites_only_sites_with_new_visitors() {
+        let conn = Connection::open_in_memory().unwrap();
+        init_db(&conn).unwrap();
+        let rows = |site: &str| -> i64 {
+            conn.query_row(
+                "SELECT COUNT(*) FROM visitors WHERE site_key = ?1",
+                params![site],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+
+        let store = Store::new();
+        store.incr_site("a.test", "v1");
+        store.incr_site("b.test", "v1");
+        write_store(&conn, &store, false).unwrap();
+        assert!(store.visitors_dirty.is_empty());
+        assert_eq!((rows("a.test"), rows("b.test")), (1, 1));
+
+        // A stray row of an unchanged site survives an incremental save...
+        conn.execute(
+            "INSERT INTO visitors (site_key, hash) VALUES ('a.test', 42)",
+            [],
+        )
+        .unwrap();
+        store.incr_site("a.test", "v1");
+        store.incr_site("b.test", "v2");
+        let stats = write_store(&conn, &store, false).unwrap();
+        assert_eq!(stats.visitors, 2);
+        assert_eq!((rows("a.test"), rows("b.test")), (2, 2));
+
+        // ...and is dropped by a full one
+        write_store(&conn, &store, true).unwrap();
+        assert_eq!((rows("a.test"), rows("b.test")), (1, 2));
+    }
+}
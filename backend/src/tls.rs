@@ -0,0 +1,185 @@
+//! HTTPS without a reverse proxy (`TLS_CERT_PATH` / `TLS_KEY_PATH`)
+//!
+//! HTTP/1.1 over rustls, served the way `axum::serve` serves plain TCP: every
+//! request carries `ConnectInfo<SocketAddr>`, and shutdown lets open
+//! connections finish their current request.
+//!
+//! The certificate is re-read when either file changes (certbot renewals). Only
+//! handshakes after the swap see the new one; open connections keep theirs.
+
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Clients that don't finish the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Read the PEM certificate chain and private key, checking that they belong together
+fn load_key(
+    cert_path: &str,
+    key_path: &str,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("TLS_CERT_PATH {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("TLS_CERT_PATH {}: no certificate found", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("TLS_KEY_PATH {}: {}", key_path, e))?;
+    CertifiedKey::from_der(certs, key, provider).map_err(|e| {
+        format!(
+            "TLS_CERT_PATH {} / TLS_KEY_PATH {} can't be used together: {}",
+            cert_path, key_path, e
+        )
+    })
+}
+
+/// Modification times of the certificate and key, to notice renewals
+fn modified(cert_path: &str, key_path: &str) -> Option<(SystemTime, SystemTime)> {
+    let time = |path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((time(cert_path)?, time(key_path)?))
+}
+
+/// Hands every handshake the current certificate; [`watch`] swaps it
+#[derive(Debug)]
+pub struct Certificate {
+    cert_path: String,
+    key_path: String,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl Certificate {
+    /// Re-read both files and switch to them, keeping the old pair on error
+    fn reload(&self) -> Result<(), String> {
+        let key = load_key(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+/// Build the server config. Fails on unreadable files or a key that doesn't match
+/// the certificate, so a bad setup stops startup instead of every handshake.
+pub fn load_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<(ServerConfig, Arc<Certificate>), String> {
+    let builder = ServerConfig::builder().with_no_client_auth();
+    let provider = builder.crypto_provider().clone();
+    let key = load_key(cert_path, key_path, &provider)?;
+    let certificate = Arc::new(Certificate {
+        cert_path: cert_path.to_string(),
+        key_path: key_path.to_string(),
+        provider,
+        current: RwLock::new(Arc::new(key)),
+    });
+
+    let mut config = builder.with_cert_resolver(certificate.clone());
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok((config, certificate))
+}
+
+/// Reload the certificate whenever its files change. A renewal caught halfway
+/// (new certificate, old key) fails the check and is retried once the other
+/// file changes too.
+pub async fn watch(certificate: Arc<Certificate>) {
+    let mut seen = modified(&certificate.cert_path, &certificate.key_path);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let now = modified(&certificate.cert_path, &certificate.key_path);
+        if now.is_none() || now == seen {
+            continue;
+        }
+        seen = now;
+        match certificate.reload() {
+            Ok(()) => tracing::info!("TLS certificate {} reloaded", certificate.cert_path),
+            Err(e) => tracing::error!("TLS certificate not reloaded, keeping the old one: {}", e),
+        }
+    }
+}
+
+/// Accept TLS connections until `shutdown` resolves, then wait for open ones
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Usually out of file descriptors; back off like axum::serve does
+                    tracing::error!("Accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let tls = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls)) => tls,
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+
+            let service = tower::ServiceExt::map_request(app, move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                req
+            });
+            let conn = http1::Builder::new()
+                .serve_connection(TokioIo::new(tls), TowerToHyperService::new(service));
+            if let Err(e) = watcher.watch(conn).await {
+                tracing::debug!("Connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
@@ -0,0 +1,62 @@
+import { Show, createEffect, createSignal, type Component } from "solid-js";
+import { Button } from "@bsz/shared/components/ui/button";
+import {
+  Dialog,
+  DialogContent,
+  DialogDescription,
+  DialogFooter,
+  DialogHeader,
+  DialogTitle,
+} from "@bsz/shared/components/ui/dialog";
+import { TextField, TextFieldInput, TextFieldLabel } from "@bsz/shared/components/ui/text-field";
+import { codePrompt } from "~/lib/session";
+import { t } from "~/lib/i18n";
+
+const TwoFactorPrompt: Component = () => {
+  const [code, setCode] = createSignal("");
+
+  createEffect(() => {
+    if (codePrompt()) setCode("");
+  });
+
+  const submit = (e: Event) => {
+    e.preventDefault();
+    const value = code().trim();
+    if (value) codePrompt()?.resolve(value);
+  };
+
+  return (
+    <Dialog
+      open={!!codePrompt()}
+      onOpenChange={(open) => {
+        if (!open) codePrompt()?.resolve(null);
+      }}
+    >
+      <DialogContent>
+        <form onSubmit={submit} class="grid gap-4">
+          <DialogHeader>
+            <DialogTitle>{t("twofa.title")}</DialogTitle>
+            <DialogDescription>{t("twofa.description")}</DialogDescription>
+          </DialogHeader>
+          <TextField value={code()} onChange={setCode}>
+            <TextFieldLabel>{t("twofa.code")}</TextFieldLabel>
+            <TextFieldInput inputmode="numeric" autocomplete="one-time-code" maxlength={6} autofocus />
+          </TextField>
+          <Show when={codePrompt()?.error}>
+            {(error) => <p class="text-sm text-destructive">{error()}</p>}
+          </Show>
+          <DialogFooter>
+            <Button type="button" variant="ghost" onClick={() => codePrompt()?.resolve(null)}>
+              {t("common.cancel")}
+            </Button>
+            <Button type="submit" disabled={!code().trim()}>
+              {t("twofa.submit")}
+            </Button>
+          </DialogFooter>
+        </form>
+      </DialogContent>
+    </Dialog>
+  );
+};
+
+export default TwoFactorPrompt;
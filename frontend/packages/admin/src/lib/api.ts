@@ -1,4 +1,5 @@
-import { activeConnection } from "./connections";
+import { activeConnection, type Connection } from "./connections";
+import { askCode, clearSession, sessionFor, setSession } from "./session";
 
 export class ApiError extends Error {
   status: number;
@@ -29,20 +30,68 @@ export function publicUrl(path: string): string {
   return `${c.baseUrl}${path}`;
 }
 
-async function request<T>(path: string, init?: RequestInit): Promise<ApiResponse<T>> {
+let pendingLogin: Promise<void> | null = null;
+
+/// Trade a TOTP code for a session, asking again after a wrong code.
+async function login(c: Connection): Promise<void> {
+  let error: string | undefined;
+  for (;;) {
+    const code = await askCode(error);
+    if (code === null) throw new ApiError("2fa required", 401);
+    const res = await fetch(`${c.baseUrl}/api/admin/login`, {
+      method: "POST",
+      headers: { Authorization: `Bearer ${c.token}`, "Content-Type": "application/json" },
+      body: JSON.stringify({ totp: code }),
+    });
+    const body = await res.json().catch(() => ({}));
+    if (res.ok && body.success && body.session) {
+      setSession(c.id, {
+        session: body.session,
+        csrfToken: body.csrf_token,
+        expiresAt: Date.now() + (body.expires_in ?? 0) * 1000,
+      });
+      return;
+    }
+    if (!res.ok) throw new ApiError(body.message ?? `HTTP ${res.status}`, res.status);
+    error = body.message;
+  }
+}
+
+/// One prompt for all requests that hit the 2FA wall at the same time.
+function twoFactorLogin(c: Connection): Promise<void> {
+  pendingLogin ??= login(c).finally(() => {
+    pendingLogin = null;
+  });
+  return pendingLogin;
+}
+
+async function request<T>(path: string, init?: RequestInit, retry = true): Promise<ApiResponse<T>> {
   const c = require_connection();
 
   const headers: Record<string, string> = {
     Authorization: `Bearer ${c.token}`,
     ...((init?.headers as Record<string, string>) ?? {}),
   };
+  const s = sessionFor(c.id);
+  if (s) {
+    headers["X-Admin-Session"] = s.session;
+    headers["X-CSRF-Token"] = s.csrfToken;
+  }
   if (init?.body && !(init.body instanceof FormData) && !headers["Content-Type"]) {
     headers["Content-Type"] = "application/json";
   }
 
   const res = await fetch(`${c.baseUrl}/api/admin${path}`, { ...init, headers });
 
-  if (res.status === 401) throw new ApiError("unauthorized", 401);
+  if (res.status === 401) {
+    const body = await res.json().catch(() => ({}));
+    if (body.two_factor && retry) {
+      clearSession(c.id);
+      await twoFactorLogin(c);
+      return request<T>(path, init, false);
+    }
+    throw new ApiError("unauthorized", 401);
+  }
   if (res.status === 404) throw new ApiError("admin api not available", 404);
   if (res.status === 429) {
     const body = await res.json().catch(() => ({}));
@@ -68,6 +117,8 @@ export type SiteKey = {
   site_key: string;
   site_pv: number;
   site_uv: number;
+  /** Hits on new paths after the site reached MAX_PAGES_PER_SITE pages */
+  overflow_pv?: number;
 };
 
 export type PageInfo = {
@@ -82,6 +133,8 @@ export type Stats = {
   total_pages: number;
   total_site_pv: number;
   total_site_uv: number;
+  /// False when stored visitor hashes use another version than the backend (UV may inflate)
+  uv_accurate?: boolean;
 };
 
 export type LogEntry = {
@@ -96,11 +149,15 @@ export type LogEntry = {
 export function syncEventSourceUrl(params: Record<string, string>): string {
   const c = require_connection();
   const search = new URLSearchParams({ ...params, token: c.token });
+  const s = sessionFor(c.id);
+  if (s) search.set("session", s.session);
   return `${c.baseUrl}/api/admin/sync?${search.toString()}`;
 }
 
 /// Direct download URL for /api/admin/export.
 export function exportDownloadUrl(): string {
   const c = require_connection();
-  return `${c.baseUrl}/api/admin/export?token=${encodeURIComponent(c.token)}`;
+  const s = sessionFor(c.id);
+  const session = s ? `&session=${encodeURIComponent(s.session)}` : "";
+  return `${c.baseUrl}/api/admin/export?token=${encodeURIComponent(c.token)}${session}`;
 }
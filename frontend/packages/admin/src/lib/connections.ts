@@ -124,7 +124,12 @@ export async function verifyToken(
       headers: { Authorization: `Bearer ${c.token}` },
     });
     if (res.ok) return { ok: true, message: t("conn.test_ok") };
-    if (res.status === 401) return { ok: false, message: t("conn.test_token_invalid") };
+    if (res.status === 401) {
+      // A valid token without a session yet: the panel asks for the code on first use
+      const body = await res.json().catch(() => ({}));
+      if (body.two_factor) return { ok: true, message: t("conn.test_ok_2fa") };
+      return { ok: false, message: t("conn.test_token_invalid") };
+    }
     if (res.status === 404) return { ok: false, message: t("conn.test_admin_not_mounted") };
     return { ok: false, message: `HTTP ${res.status}` };
   } catch (e) {
@@ -53,6 +53,7 @@ const dict = {
     "overview.no_activity": "暂无日志",
     "overview.other": "其他",
     "overview.no_admin_token": "当前连接未配置 admin token，无法查看统计数据。",
+    "overview.uv_inaccurate": "数据库中的访客哈希版本与后端不一致，老访客会被重新计入，UV 可能偏高。",
 
     // sites
     "sites.title": "站点",
@@ -73,6 +74,7 @@ const dict = {
     "site.merge": "合并到…",
     "site.delete": "删除站点",
     "site.pages": "页面",
+    "site.overflow": "页面数已达到 MAX_PAGES_PER_SITE 上限，新路径的 {{n}} 次访问记在 __overflow__ 页面。",
     "site.danger": "危险操作",
     "site.danger_hint": "重命名/合并/删除不可撤销（仅编辑 PV/UV 可 undo）",
     "site.new_key": "新域名",
@@ -127,8 +129,15 @@ const dict = {
     "conn.test_unreachable": "无法连接",
     "conn.test_token_empty": "token 为空",
     "conn.test_token_invalid": "token 无效",
+    "conn.test_ok_2fa": "token 有效，已启用两步验证",
     "conn.test_admin_not_mounted": "后端未启用 admin（ADMIN_TOKEN 为空？）",
 
+    // two-factor prompt
+    "twofa.title": "两步验证",
+    "twofa.description": "输入验证器应用中的 6 位验证码",
+    "twofa.code": "验证码",
+    "twofa.submit": "登录",
+
     // common
     "common.save": "保存",
     "common.cancel": "取消",
@@ -215,6 +224,8 @@ const dict = {
     "overview.no_activity": "No activity yet",
     "overview.other": "Other",
     "overview.no_admin_token": "This connection has no admin token — stats unavailable.",
+    "overview.uv_inaccurate":
+      "The stored visitor hashes use a different version than the backend — returning visitors are counted again and UV may be inflated.",
 
     "sites.title": "Sites",
     "sites.search_placeholder": "Filter sites…",
@@ -233,6 +244,8 @@ const dict = {
     "site.merge": "Merge into…",
     "site.delete": "Delete site",
     "site.pages": "Pages",
+    "site.overflow":
+      "This site reached MAX_PAGES_PER_SITE; {{n}} hits on new paths were counted on the __overflow__ page.",
     "site.danger": "Danger zone",
     "site.danger_hint": "Rename/merge/delete are irreversible (only PV/UV edits support undo)",
     "site.new_key": "New site key",
@@ -285,8 +298,15 @@ const dict = {
     "conn.test_unreachable": "Unreachable",
     "conn.test_token_empty": "Token is empty",
     "conn.test_token_invalid": "Token is invalid",
+    "conn.test_ok_2fa": "Token is valid, two-factor authentication is on",
     "conn.test_admin_not_mounted": "Admin disabled on backend (ADMIN_TOKEN empty?)",
 
+    // two-factor prompt
+    "twofa.title": "Two-factor authentication",
+    "twofa.description": "Enter the 6-digit code from your authenticator app",
+    "twofa.code": "Code",
+    "twofa.submit": "Sign in",
+
     "common.save": "Save",
     "common.cancel": "Cancel",
     "common.delete": "Delete",
@@ -370,6 +390,8 @@ const dict = {
     "overview.no_activity": "アクティビティがありません",
     "overview.other": "その他",
     "overview.no_admin_token": "この接続には管理トークンが設定されていません — 統計を表示できません。",
+    "overview.uv_inaccurate":
+      "保存済みの訪問者ハッシュのバージョンがバックエンドと異なります — 再訪問者も新規として数えられ、UV が多めになる可能性があります。",
 
     "sites.title": "サイト",
     "sites.search_placeholder": "サイトをフィルタ…",
@@ -388,6 +410,7 @@ const dict = {
     "site.merge": "マージ先…",
     "site.delete": "サイトを削除",
     "site.pages": "ページ",
+    "site.overflow": "ページ数が MAX_PAGES_PER_SITE に達したため、新しいパスへの {{n}} 回のアクセスは __overflow__ ページに計上されています。",
     "site.danger": "危険な操作",
     "site.danger_hint": "名前変更・マージ・削除は元に戻せません（PV/UV 編集のみ undo 可）",
     "site.new_key": "新しいサイトキー",
@@ -440,8 +463,15 @@ const dict = {
     "conn.test_unreachable": "到達不可",
     "conn.test_token_empty": "トークンが空です",
     "conn.test_token_invalid": "トークンが無効です",
+    "conn.test_ok_2fa": "トークンは有効です（二段階認証が有効）",
     "conn.test_admin_not_mounted": "バックエンドで admin が無効です（ADMIN_TOKEN が空？）",
 
+    // two-factor prompt
+    "twofa.title": "二段階認証",
+    "twofa.description": "認証アプリに表示される 6 桁のコードを入力してください",
+    "twofa.code": "コード",
+    "twofa.submit": "ログイン",
+
     "common.save": "保存",
     "common.cancel": "キャンセル",
     "common.delete": "削除",
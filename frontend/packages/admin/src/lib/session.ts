@@ -0,0 +1,54 @@
+import { createSignal } from "solid-js";
+
+/// Session from POST /api/admin/login, needed next to the token once 2FA is on.
+export type AdminSession = {
+  session: string;
+  csrfToken: string;
+  expiresAt: number;
+};
+
+// Kept in memory only: a reload asks for a fresh code rather than leaving a
+// session id in storage.
+const sessions = new Map<string, AdminSession>();
+
+export function sessionFor(connectionId: string): AdminSession | null {
+  const s = sessions.get(connectionId);
+  if (!s) return null;
+  if (s.expiresAt <= Date.now()) {
+    sessions.delete(connectionId);
+    return null;
+  }
+  return s;
+}
+
+export function setSession(connectionId: string, s: AdminSession) {
+  sessions.set(connectionId, s);
+}
+
+export function clearSession(connectionId: string) {
+  sessions.delete(connectionId);
+}
+
+export type CodePrompt = {
+  /// Message from the previous attempt (wrong code, throttled)
+  error?: string;
+  /// Code entered, or null when the user gives up
+  resolve: (code: string | null) => void;
+};
+
+const [prompt, setPrompt] = createSignal<CodePrompt | null>(null);
+
+export { prompt as codePrompt };
+
+/// Ask for a TOTP code through the prompt mounted in the app shell.
+export function askCode(error?: string): Promise<string | null> {
+  return new Promise((resolve) => {
+    setPrompt({
+      error,
+      resolve: (code) => {
+        setPrompt(null);
+        resolve(code);
+      },
+    });
+  });
+}
@@ -5,6 +5,7 @@ import Sidebar from "~/components/app-shell/Sidebar";
 import TopBar from "~/components/app-shell/TopBar";
 import CommandPalette, { useCommandHotkey } from "~/components/app-shell/CommandPalette";
 import UndoToast from "~/components/app-shell/UndoToast";
+import TwoFactorPrompt from "~/components/app-shell/TwoFactorPrompt";
 import { activeConnection } from "~/lib/connections";
 
 const AppLayout: ParentComponent = (props) => {
@@ -27,6 +28,7 @@ const AppLayout: ParentComponent = (props) => {
         </div>
         <CommandPalette open={cmdOpen()} onOpenChange={setCmdOpen} />
         <UndoToast />
+        <TwoFactorPrompt />
       </div>
     </Show>
   );
@@ -98,6 +98,12 @@ const Overview: Component = () => {
         </Card>
       </Show>
 
+      <Show when={stats()?.uv_accurate === false}>
+        <Card class="mb-6 border-amber-500/30 bg-amber-500/5">
+          <CardContent class="py-4 text-sm">{t("overview.uv_inaccurate")}</CardContent>
+        </Card>
+      </Show>
+
       <div class="mb-6 grid grid-cols-2 gap-3 lg:grid-cols-4">
         <StatTile label={t("overview.sites")} loading={stats.loading} value={fmt(stats()?.total_sites)} />
         <StatTile label={t("overview.pages")} loading={stats.loading} value={fmt(stats()?.total_pages)} />
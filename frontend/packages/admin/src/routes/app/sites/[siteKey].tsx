@@ -174,6 +174,14 @@ const SiteDetail: Component = () => {
         <span class="font-mono text-foreground">{siteKey()}</span>
       </div>
 
+      <Show when={(siteRow()?.overflow_pv ?? 0) > 0}>
+        <Card class="mb-6 border-amber-500/30 bg-amber-500/5">
+          <CardContent class="py-4 text-sm">
+            {t("site.overflow", { n: (siteRow()?.overflow_pv ?? 0).toLocaleString() })}
+          </CardContent>
+        </Card>
+      </Show>
+
       <div class="mb-6 grid grid-cols-1 gap-4 lg:grid-cols-3">
         <Card>
           <CardHeader class="pb-2">
@@ -10,13 +10,29 @@ const DEMO_API = import.meta.env.VITE_DEMO_API;
 
 type ApiResponse = { success: boolean; data: { site_pv: number; site_uv: number; page_pv: number } };
 
+type ChallengeResponse = { success: boolean; data: { nonce: string } };
+
+// Backends running with REQUIRE_CHALLENGE only count requests that carry a
+// fresh nonce. Fetching one is harmless otherwise, and a failure just means
+// the count call goes out without it.
+async function fetchNonce(): Promise<Record<string, string>> {
+  try {
+    const res = await fetch(`${DEMO_API}/api/challenge?host=${encodeURIComponent(location.hostname)}`);
+    if (!res.ok) return {};
+    const body = (await res.json()) as ChallengeResponse;
+    return body.success ? { "x-bsz-nonce": body.data.nonce } : {};
+  } catch {
+    return {};
+  }
+}
+
 async function fetchStats(): Promise<ApiResponse["data"] | null> {
   if (!DEMO_API) return null;
   try {
     const res = await fetch(`${DEMO_API}/api`, {
       method: "POST",
       credentials: "include",
-      headers: { "x-bsz-referer": location.href },
+      headers: { "x-bsz-referer": location.href, ...(await fetchNonce()) },
     });
     if (!res.ok) return null;
     const body = (await res.json()) as ApiResponse;